@@ -3,7 +3,12 @@
 
 use cortex_m_rt::entry;
 use defmt::*;
-use embedded_db::{codec::Codec, db::Database, flash::FlashStorage};
+use embedded_db::{
+    codec::{Codec, CodecErrorKind},
+    db::Database,
+    flash,
+    flash::FlashStorage,
+};
 use hal::pac;
 use nrf52840_hal as hal;
 
@@ -15,6 +20,15 @@ pub enum EmbeddedError {
     Other,
 }
 
+impl From<EmbeddedError> for CodecErrorKind {
+    fn from(e: EmbeddedError) -> Self {
+        match e {
+            EmbeddedError::BufferTooSmall => CodecErrorKind::BufferTooSmall,
+            EmbeddedError::Other => CodecErrorKind::Malformed,
+        }
+    }
+}
+
 pub struct U32Codec;
 
 impl Codec<u32> for U32Codec {
@@ -45,6 +59,9 @@ impl Codec<u32> for U32Codec {
 // https://docs.nordicsemi.com/bundle/ps_nrf52840/page/memory.html
 const FLASH_STORAGE_ADDR: u32 = 0x000E_F000;
 
+// Firmware version stamped into the image header (0x00MMmmpp)
+const APP_VERSION: u32 = 0x0000_0100;
+
 #[entry]
 fn main() -> ! {
     info!("Flash Test Starting!");
@@ -57,18 +74,22 @@ fn main() -> ! {
 
     // Start with an empty database in memory
     let mut db = MyDb::new();
+    db.set_version_stamp(APP_VERSION, flash::device_id(&p.FICR));
 
     info!("Attempting to load from flash...");
 
     // Try to get data from flash, and load it into the database that is in memory
-    match db.load_from_flash(&mut flash, FLASH_STORAGE_ADDR) {
-        Ok(_) => {
+    match db.open(&mut flash, FLASH_STORAGE_ADDR) {
+        Ok(header) => {
             info!("Loaded {} entries from flash", db.len());
+            if let Some(header) = header {
+                info!("Image was written by app {:#x}", header.app_version);
+            }
 
             // Print what we loaded
-            for i in 0..16 {
-                if let Ok(Some(val)) = db.get_uncached(&i) {
-                    info!("  Key {}: Value {}", i, val);
+            for (key, val) in db.iter() {
+                if let Ok(val) = val {
+                    info!("  Key {}: Value {}", key, val);
                 }
             }
         }
@@ -105,9 +126,9 @@ fn main() -> ! {
 
     // Display final state of the in memory database
     info!("Final database contents:");
-    for i in 0..16 {
-        if let Ok(Some(val)) = db.get_uncached(&i) {
-            info!("  Key {}: Value {}", i, val);
+    for (key, val) in db.iter() {
+        if let Ok(val) = val {
+            info!("  Key {}: Value {}", key, val);
         }
     }
 
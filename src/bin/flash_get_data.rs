@@ -10,20 +10,34 @@
 
 use cortex_m_rt::entry;
 use defmt::*;
-use embedded_db::{codec::Codec, db::Database, flash::FlashStorage};
+use embedded_db::{
+    codec::{Codec, CodecErrorKind},
+    db::Database,
+    flash::FlashStorage,
+};
 use hal::pac;
 use nrf52840_hal as hal;
 
 const FLASH_STORAGE_ADDR: u32 = 0x000E_F000;
 
+// The buffer is shorter than a u32
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct TooShort;
+
+impl From<TooShort> for CodecErrorKind {
+    fn from(_: TooShort) -> Self {
+        CodecErrorKind::BufferTooSmall
+    }
+}
+
 pub struct U32Codec;
 
 impl Codec<u32> for U32Codec {
-    type Error = ();
+    type Error = TooShort;
 
     fn encode(buffer: &mut [u8], val: &u32) -> Result<usize, Self::Error> {
         if buffer.len() < 4 {
-            return Err(());
+            return Err(TooShort);
         }
         buffer[..4].copy_from_slice(&val.to_le_bytes());
         Ok(4)
@@ -31,7 +45,7 @@ impl Codec<u32> for U32Codec {
 
     fn decode(buffer: &[u8]) -> Result<u32, Self::Error> {
         if buffer.len() < 4 {
-            return Err(());
+            return Err(TooShort);
         }
         Ok(u32::from_le_bytes([
             buffer[0], buffer[1], buffer[2], buffer[3],
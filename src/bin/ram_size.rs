@@ -0,0 +1,29 @@
+#![no_main]
+#![no_std]
+
+// Size check of a RAM-only database (ram.rs)
+// Puts, gets and deletes through a RamDb. CI builds this for the release
+// profile without default features and fails if .text + .data grow past
+// the budget in .github/workflows/size.yml, so a change that drags flash or
+// image code into RamDb shows up there. To check locally:
+//
+// cargo size --release --bin ram_size --no-default-features
+//
+// It was about 15 KiB when this was written (hello.rs alone is under 5 KiB).
+
+use embedded_db as _; // global logger + panicking-behavior + memory layout
+use embedded_db::codec::Postcard;
+use embedded_db::ram::RamDb;
+
+#[cortex_m_rt::entry]
+fn main() -> ! {
+    let mut db: RamDb<u8, u32, Postcard, 8, 8, 2> = RamDb::new();
+    for key in 0..8u8 {
+        db.put(key, key as u32 * 1000).ok();
+    }
+    db.delete(&3);
+    let sum: u32 = db.keys().filter_map(|k| db.get_uncached(k).ok()?).sum();
+    defmt::println!("{} entries, sum {}", db.len(), sum);
+
+    embedded_db::idle_forever()
+}
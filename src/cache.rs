@@ -0,0 +1,132 @@
+// Cache replacement policies
+// The Database keeps up to CACH decoded values in RAM. Which one goes when
+// the cache is full is up to a CachePolicy, the last type parameter of
+// Database. Lru is the default, Fifo is cheaper when every key is read
+// about as often as the others. A policy of your own only has to follow
+// the keys it is told about, e.g. one that never gives up config keys:
+//
+// struct PinConfig(Lru<Key, 8>);
+// impl CachePolicy<Key> for PinConfig {
+//     fn touch(&mut self, key: &Key) { self.0.touch(key) }
+//     fn removed(&mut self, key: &Key) { self.0.removed(key) }
+//     fn clear(&mut self) { self.0.clear() }
+//     fn victim(&mut self) -> Option<Key> {
+//         self.0.oldest(|key| !key.is_config())
+//     }
+// }
+//
+// let mut db = Database::with_store_and_policy(KvStore::new(), PinConfig(Lru::new()));
+
+use heapless::{LinearMap, Vec};
+
+/// Decides which cache entry is evicted when the cache is full
+pub trait CachePolicy<K> {
+    /// key was read from or written to the cache (it may be new)
+    fn touch(&mut self, key: &K);
+    /// key left the cache (evicted, deleted or cleared)
+    fn removed(&mut self, key: &K);
+    /// Every key left the cache
+    fn clear(&mut self);
+    /// The key to evict to make room for another
+    /// None keeps the cache as it is, the new value just isn't cached.
+    fn victim(&mut self) -> Option<K>;
+}
+
+/// Evict the least recently used entry
+pub struct Lru<K, const CACH: usize> {
+    // Value of clock at the last touch of each cached key
+    used: LinearMap<K, u32, CACH>,
+    clock: u32,
+}
+
+impl<K, const CACH: usize> Lru<K, CACH> {
+    pub const fn new() -> Self {
+        Self {
+            used: LinearMap::new(),
+            clock: 0,
+        }
+    }
+}
+
+impl<K, const CACH: usize> Default for Lru<K, CACH> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Clone, const CACH: usize> Lru<K, CACH> {
+    /// The least recently used key that passes filter
+    pub fn oldest<F: Fn(&K) -> bool>(&self, filter: F) -> Option<K> {
+        self.used
+            .iter()
+            .filter(|(k, _)| filter(k))
+            .min_by_key(|(_, used)| **used)
+            .map(|(k, _)| k.clone())
+    }
+}
+
+impl<K: Eq + Clone, const CACH: usize> CachePolicy<K> for Lru<K, CACH> {
+    fn touch(&mut self, key: &K) {
+        // On wrap around the order is lost once, restart the ages from 0
+        if self.clock == u32::MAX {
+            self.clock = 0;
+            for (_, used) in self.used.iter_mut() {
+                *used = 0;
+            }
+        }
+        self.clock += 1;
+        let _ = self.used.insert(key.clone(), self.clock);
+    }
+
+    fn removed(&mut self, key: &K) {
+        let _ = self.used.remove(key);
+    }
+
+    fn clear(&mut self) {
+        self.used.clear();
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        self.oldest(|_| true)
+    }
+}
+
+/// Evict the entry that was cached first, reads don't count
+pub struct Fifo<K, const CACH: usize> {
+    // Oldest first
+    order: Vec<K, CACH>,
+}
+
+impl<K, const CACH: usize> Fifo<K, CACH> {
+    pub const fn new() -> Self {
+        Self { order: Vec::new() }
+    }
+}
+
+impl<K, const CACH: usize> Default for Fifo<K, CACH> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Eq + Clone, const CACH: usize> CachePolicy<K> for Fifo<K, CACH> {
+    fn touch(&mut self, key: &K) {
+        if !self.order.contains(key) {
+            let _ = self.order.push(key.clone());
+        }
+    }
+
+    fn removed(&mut self, key: &K) {
+        if let Some(i) = self.order.iter().position(|k| k == key) {
+            self.order.remove(i);
+        }
+    }
+
+    fn clear(&mut self) {
+        self.order.clear();
+    }
+
+    fn victim(&mut self) -> Option<K> {
+        self.order.first().cloned()
+    }
+}
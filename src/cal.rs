@@ -0,0 +1,171 @@
+// Calibration tables
+// A CalTable is a list of (raw, calibrated) points with linear interpolation
+// in between. Every product with an analog sensor ends up with one of these,
+// so it lives here once:
+//
+// let table = CalTable::new([0.0, 512.0, 1023.0], [-40.0, 25.0, 125.0])?;
+// db.put(KEY_TEMP_CAL, table)?;
+// let celsius = db.calibrate(&KEY_TEMP_CAL, adc_reading as f32)?;
+//
+// CalCodec stores the points as packed little endian f32 pairs, which is
+// smaller than Postcard/JSON would make them and checks the table on decode.
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::Database;
+use crate::kv::BlobStore;
+use core::marker::PhantomData;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum CalError {
+    // Raw values have to be strictly increasing, with at least two points
+    NotMonotonic,
+    // The stored bytes aren't a table of the right size
+    BadLength,
+    BufferTooSmall,
+    // No table stored under the key
+    NotFound,
+    // The stored table couldn't be read
+    Storage,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct CalTable<const POINTS: usize> {
+    raw: [f32; POINTS],
+    value: [f32; POINTS],
+}
+
+impl<const POINTS: usize> CalTable<POINTS> {
+    /// raw must be strictly increasing, value can go either way
+    pub fn new(raw: [f32; POINTS], value: [f32; POINTS]) -> Result<Self, CalError> {
+        // partial_cmp so NaN counts as out of order too
+        let increasing = raw
+            .windows(2)
+            .all(|w| w[0].partial_cmp(&w[1]) == Some(core::cmp::Ordering::Less));
+        if POINTS < 2 || !increasing {
+            return Err(CalError::NotMonotonic);
+        }
+        Ok(Self { raw, value })
+    }
+
+    pub fn points(&self) -> impl Iterator<Item = (f32, f32)> + '_ {
+        self.raw.iter().copied().zip(self.value.iter().copied())
+    }
+
+    /// Calibrated value for a raw reading
+    /// Readings outside the table are clamped to the first/last point,
+    /// extrapolating a calibration is how sensors report 900 degrees.
+    pub fn interpolate(&self, raw: f32) -> f32 {
+        if raw <= self.raw[0] {
+            return self.value[0];
+        }
+        for i in 1..POINTS {
+            if raw <= self.raw[i] {
+                let (x0, x1) = (self.raw[i - 1], self.raw[i]);
+                let (y0, y1) = (self.value[i - 1], self.value[i]);
+                return y0 + (y1 - y0) * (raw - x0) / (x1 - x0);
+            }
+        }
+        self.value[POINTS - 1]
+    }
+}
+
+// Serialized as a sequence of (raw, value) pairs, so the table also works
+// with the serde based codecs and the JSON command line.
+impl<const POINTS: usize> serde::Serialize for CalTable<POINTS> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        use serde::ser::SerializeSeq;
+        let mut seq = serializer.serialize_seq(Some(POINTS))?;
+        for point in self.points() {
+            seq.serialize_element(&point)?;
+        }
+        seq.end()
+    }
+}
+
+impl<'de, const POINTS: usize> serde::Deserialize<'de> for CalTable<POINTS> {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        struct Points<const POINTS: usize>;
+
+        impl<'de, const POINTS: usize> serde::de::Visitor<'de> for Points<POINTS> {
+            type Value = CalTable<POINTS>;
+
+            fn expecting(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                write!(f, "{} calibration points", POINTS)
+            }
+
+            fn visit_seq<A: serde::de::SeqAccess<'de>>(
+                self,
+                mut seq: A,
+            ) -> Result<Self::Value, A::Error> {
+                let mut raw = [0f32; POINTS];
+                let mut value = [0f32; POINTS];
+                for i in 0..POINTS {
+                    let (x, y) = seq
+                        .next_element::<(f32, f32)>()?
+                        .ok_or_else(|| serde::de::Error::invalid_length(i, &self))?;
+                    raw[i] = x;
+                    value[i] = y;
+                }
+                CalTable::new(raw, value)
+                    .map_err(|_| serde::de::Error::custom("calibration points not increasing"))
+            }
+        }
+
+        deserializer.deserialize_seq(Points::<POINTS>)
+    }
+}
+
+/// Packed codec for CalTable: [raw f32][value f32] per point, little endian
+pub struct CalCodec<const POINTS: usize>(PhantomData<[(); POINTS]>);
+
+impl<const POINTS: usize> CalCodec<POINTS> {
+    pub const ENCODED_SIZE: usize = POINTS * 8;
+}
+
+impl<const POINTS: usize> Codec<CalTable<POINTS>> for CalCodec<POINTS> {
+    type Error = CalError;
+    const MAX_ENCODED_SIZE: Option<usize> = Some(Self::ENCODED_SIZE);
+
+    fn encode(dst: &mut [u8], v: &CalTable<POINTS>) -> Result<usize, Self::Error> {
+        let dst = dst
+            .get_mut(..Self::ENCODED_SIZE)
+            .ok_or(CalError::BufferTooSmall)?;
+        for ((x, y), out) in v.points().zip(dst.chunks_exact_mut(8)) {
+            out[..4].copy_from_slice(&x.to_le_bytes());
+            out[4..].copy_from_slice(&y.to_le_bytes());
+        }
+        Ok(Self::ENCODED_SIZE)
+    }
+
+    fn decode(src: &[u8]) -> Result<CalTable<POINTS>, Self::Error> {
+        if src.len() != Self::ENCODED_SIZE {
+            return Err(CalError::BadLength);
+        }
+        let mut raw = [0f32; POINTS];
+        let mut value = [0f32; POINTS];
+        for (i, point) in src.chunks_exact(8).enumerate() {
+            raw[i] = f32::from_le_bytes([point[0], point[1], point[2], point[3]]);
+            value[i] = f32::from_le_bytes([point[4], point[5], point[6], point[7]]);
+        }
+        CalTable::new(raw, value)
+    }
+}
+
+impl<K, C, S, CP, const POINTS: usize, const N: usize, const B: usize, const CACH: usize>
+    Database<K, CalTable<POINTS>, C, N, B, CACH, S, CP>
+where
+    C: Codec<CalTable<POINTS>>,
+    K: Eq + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    /// Look up the table stored under key and apply it to a raw reading
+    pub fn calibrate(&mut self, key: &K, raw: f32) -> Result<f32, CalError> {
+        let table = self
+            .get(key)
+            .map_err(|_| CalError::Storage)?
+            .ok_or(CalError::NotFound)?;
+        Ok(table.interpolate(raw))
+    }
+}
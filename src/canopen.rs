@@ -0,0 +1,186 @@
+// CANopen object dictionary bridge
+// Maps OD index/subindex pairs to database keys so a node's object
+// dictionary stays persistent through this crate. The SDO handlers
+// take/return the little endian data bytes, the CAN framing and SDO
+// protocol state machine stay in the CANopen stack.
+//
+// const OD: &[OdEntry<u32>] = &[
+//     OdEntry::rw(0x2000, 1, KEY_SETPOINT),
+//     OdEntry::ro(0x2001, 0, KEY_SERIAL),
+// ];
+// let od = ObjectDictionary::new(OD);
+// let n = od.sdo_upload(&mut db, 0x2000, 1, &mut data)?;
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::Database;
+use crate::kv::BlobStore;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum OdAccess {
+    ReadOnly,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct OdEntry<K> {
+    pub index: u16,
+    pub subindex: u8,
+    pub access: OdAccess,
+    pub key: K,
+}
+
+impl<K> OdEntry<K> {
+    pub const fn ro(index: u16, subindex: u8, key: K) -> Self {
+        Self {
+            index,
+            subindex,
+            access: OdAccess::ReadOnly,
+            key,
+        }
+    }
+
+    pub const fn rw(index: u16, subindex: u8, key: K) -> Self {
+        Self {
+            index,
+            subindex,
+            access: OdAccess::ReadWrite,
+            key,
+        }
+    }
+}
+
+/// SDO abort reasons we can report (CiA 301)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum SdoAbort {
+    WriteToReadOnly,
+    ObjectDoesNotExist,
+    LengthMismatch,
+    SubindexDoesNotExist,
+    GeneralError,
+    CannotStore,
+    NoDataAvailable,
+}
+
+impl SdoAbort {
+    /// Abort code to send in the SDO abort transfer message
+    pub fn code(&self) -> u32 {
+        match self {
+            SdoAbort::WriteToReadOnly => 0x0601_0002,
+            SdoAbort::ObjectDoesNotExist => 0x0602_0000,
+            SdoAbort::LengthMismatch => 0x0607_0010,
+            SdoAbort::SubindexDoesNotExist => 0x0609_0011,
+            SdoAbort::GeneralError => 0x0800_0000,
+            SdoAbort::CannotStore => 0x0800_0020,
+            SdoAbort::NoDataAvailable => 0x0800_0024,
+        }
+    }
+}
+
+/// Values that map onto CANopen basic data types (UNSIGNEDx, INTEGERx, REAL32)
+pub trait OdValue: Sized {
+    const SIZE: usize;
+    fn to_od_bytes(&self, out: &mut [u8]);
+    fn from_od_bytes(bytes: &[u8]) -> Self;
+}
+
+macro_rules! od_value {
+    ($($t:ty),*) => {
+        $(
+            impl OdValue for $t {
+                const SIZE: usize = core::mem::size_of::<$t>();
+                fn to_od_bytes(&self, out: &mut [u8]) {
+                    out[..Self::SIZE].copy_from_slice(&self.to_le_bytes());
+                }
+                fn from_od_bytes(bytes: &[u8]) -> Self {
+                    let mut raw = [0u8; core::mem::size_of::<$t>()];
+                    raw.copy_from_slice(&bytes[..Self::SIZE]);
+                    <$t>::from_le_bytes(raw)
+                }
+            }
+        )*
+    };
+}
+
+od_value!(u8, u16, u32, i8, i16, i32, f32);
+
+pub struct ObjectDictionary<'a, K> {
+    entries: &'a [OdEntry<K>],
+}
+
+impl<'a, K> ObjectDictionary<'a, K>
+where
+    K: Eq + Clone,
+{
+    pub const fn new(entries: &'a [OdEntry<K>]) -> Self {
+        Self { entries }
+    }
+
+    fn find(&self, index: u16, subindex: u8) -> Result<&OdEntry<K>, SdoAbort> {
+        let mut index_exists = false;
+        for entry in self.entries.iter().filter(|e| e.index == index) {
+            index_exists = true;
+            if entry.subindex == subindex {
+                return Ok(entry);
+            }
+        }
+        if index_exists {
+            Err(SdoAbort::SubindexDoesNotExist)
+        } else {
+            Err(SdoAbort::ObjectDoesNotExist)
+        }
+    }
+
+    /// SDO upload (client reads from us)
+    /// Writes the value into out and returns how many bytes it used.
+    pub fn sdo_upload<V, C, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &self,
+        db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+        index: u16,
+        subindex: u8,
+        out: &mut [u8],
+    ) -> Result<usize, SdoAbort>
+    where
+        C: Codec<V>,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone + OdValue,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+    {
+        let entry = self.find(index, subindex)?;
+        if out.len() < V::SIZE {
+            return Err(SdoAbort::GeneralError);
+        }
+        let val = db
+            .get(&entry.key)
+            .map_err(|_| SdoAbort::GeneralError)?
+            .ok_or(SdoAbort::NoDataAvailable)?;
+        val.to_od_bytes(out);
+        Ok(V::SIZE)
+    }
+
+    /// SDO download (client writes to us)
+    /// data must be exactly the size of the value type.
+    pub fn sdo_download<V, C, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &self,
+        db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+        index: u16,
+        subindex: u8,
+        data: &[u8],
+    ) -> Result<(), SdoAbort>
+    where
+        C: Codec<V>,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone + OdValue,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+    {
+        let entry = self.find(index, subindex)?;
+        if entry.access == OdAccess::ReadOnly {
+            return Err(SdoAbort::WriteToReadOnly);
+        }
+        if data.len() != V::SIZE {
+            return Err(SdoAbort::LengthMismatch);
+        }
+        db.put(entry.key.clone(), V::from_od_bytes(data))
+            .map_err(|_| SdoAbort::CannotStore)
+    }
+}
@@ -0,0 +1,180 @@
+// Database commands for a serial/RTT command line
+// This doesn't own the line editor, products that already ship a CLI
+// (embedded-cli or similar) mount these as a "db" subcommand tree:
+//
+// "db" => {
+//     let cmd = DbCommand::parse(&args[1..])?;
+//     cmd.run(&mut db, &mut flash, FLASH_STORAGE_ADDR, &mut uart)?;
+// }
+//
+// db get <key>
+// db set <key> <json value>
+// db list
+// db save
+//
+// Keys are parsed with FromStr and printed with Display, values are
+// read and printed as JSON so any serde value type works. run_with_policy
+// normalizes the typed key first (see keys.rs).
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::{Database, FlashError};
+use crate::keys::KeyPolicy;
+use crate::kv::BlobStore;
+use core::fmt::Write;
+use core::str::FromStr;
+use embedded_storage::nor_flash::NorFlash;
+
+/// Largest value (as JSON text) get/list can print
+pub const MAX_JSON: usize = 256;
+/// Longest key run_with_policy accepts
+pub const MAX_KEY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DbCommand<'a> {
+    Get { key: &'a str },
+    Set { key: &'a str, value: &'a str },
+    List,
+    Save,
+}
+
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub enum CliError {
+    UnknownCommand,
+    MissingArgument,
+    BadKey,
+    BadValue,
+    NotFound,
+    // The value couldn't be stored (codec failed or the store is full)
+    Store,
+    Flash(FlashError),
+    // Writing to the output failed
+    Output,
+}
+
+impl From<core::fmt::Error> for CliError {
+    fn from(_: core::fmt::Error) -> Self {
+        CliError::Output
+    }
+}
+
+impl<'a> DbCommand<'a> {
+    /// Parse the arguments after "db", e.g. ["set", "sensor:1", "{...}"]
+    pub fn parse(args: &[&'a str]) -> Result<Self, CliError> {
+        let arg = |i: usize| args.get(i).copied().ok_or(CliError::MissingArgument);
+        match arg(0)? {
+            "get" => Ok(DbCommand::Get { key: arg(1)? }),
+            "set" => Ok(DbCommand::Set {
+                key: arg(1)?,
+                value: arg(2)?,
+            }),
+            "list" => Ok(DbCommand::List),
+            "save" => Ok(DbCommand::Save),
+            _ => Err(CliError::UnknownCommand),
+        }
+    }
+
+    /// Run the command and write the result to out
+    pub fn run<K, V, C, F, W, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        self,
+        db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+        flash: &mut F,
+        flash_offset: u32,
+        out: &mut W,
+    ) -> Result<(), CliError>
+    where
+        C: Codec<V>,
+        K: Eq + Clone + FromStr + core::fmt::Display + serde::Serialize,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+        F: NorFlash,
+        W: Write,
+    {
+        self.run_with_policy(&KeyPolicy::NONE, db, flash, flash_offset, out)
+    }
+
+    /// Same as run, but keys go through policy before they are parsed
+    pub fn run_with_policy<
+        K,
+        V,
+        C,
+        F,
+        W,
+        S,
+        CP,
+        const N: usize,
+        const B: usize,
+        const CACH: usize,
+    >(
+        self,
+        policy: &KeyPolicy,
+        db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+        flash: &mut F,
+        flash_offset: u32,
+        out: &mut W,
+    ) -> Result<(), CliError>
+    where
+        C: Codec<V>,
+        K: Eq + Clone + FromStr + core::fmt::Display + serde::Serialize,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+        F: NorFlash,
+        W: Write,
+    {
+        let parse_key = |key: &str| {
+            if *policy == KeyPolicy::NONE {
+                return K::from_str(key).map_err(|_| CliError::BadKey);
+            }
+            let key = policy
+                .normalize::<MAX_KEY>(key)
+                .map_err(|_| CliError::BadKey)?;
+            K::from_str(&key).map_err(|_| CliError::BadKey)
+        };
+
+        match self {
+            DbCommand::Get { key } => {
+                let key = parse_key(key)?;
+                let val = db
+                    .get(&key)
+                    .map_err(|_| CliError::Store)?
+                    .ok_or(CliError::NotFound)?;
+                write_json(out, &val)?;
+                out.write_str("\r\n")?;
+            }
+            DbCommand::Set { key, value } => {
+                let key = parse_key(key)?;
+                let (val, _) =
+                    serde_json_core::from_str::<V>(value).map_err(|_| CliError::BadValue)?;
+                db.put(key, val).map_err(|_| CliError::Store)?;
+                out.write_str("ok\r\n")?;
+            }
+            DbCommand::List => {
+                for (key, blob) in db.blobs() {
+                    write!(out, "{} = ", key)?;
+                    match C::decode(blob) {
+                        Ok(val) => write_json(out, &val)?,
+                        Err(_) => out.write_str("<undecodable>")?,
+                    }
+                    out.write_str("\r\n")?;
+                }
+                write!(out, "{}/{} entries\r\n", db.len(), db.capacity())?;
+            }
+            DbCommand::Save => {
+                db.save_to_flash(flash, core::mem::size_of::<u32>(), flash_offset)
+                    .map_err(CliError::Flash)?;
+                out.write_str("saved\r\n")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+fn write_json<W: Write, V: serde::Serialize>(out: &mut W, val: &V) -> Result<(), CliError> {
+    let mut buf = [0u8; MAX_JSON];
+    let n = serde_json_core::to_slice(val, &mut buf).map_err(|_| CliError::BadValue)?;
+    let text = core::str::from_utf8(&buf[..n]).map_err(|_| CliError::BadValue)?;
+    out.write_str(text)?;
+    Ok(())
+}
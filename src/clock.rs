@@ -0,0 +1,53 @@
+// Time sources for TTL entries
+// put_with_ttl() deadlines normally move with tick(), which the application
+// has to call from its own timer. With a Clock the Database reads the time
+// itself: expired entries read as absent (and are purged) on their own.
+//
+// rtc1_start(p.RTC1, 3276);          // LFCLK has to run, 10 ticks a second
+// db.set_ttl_clock::<Rtc1>();
+// db.put_with_ttl(KEY_SESSION, token, 600)?;   // a minute
+//
+// A Clock is a type rather than a value so the Database only needs to keep
+// a fn pointer to Clock::now.
+
+use core::sync::atomic::{AtomicU32, Ordering};
+use nrf52840_hal::pac::RTC1;
+
+/// A monotonic tick counter, the unit is up to the implementation
+pub trait Clock {
+    fn now() -> u64;
+}
+
+/// The RTC1 counter, extended from 24 to 64 bits
+/// The extension counts wraps it sees, so now() has to be called at least
+/// once per wrap (2^24 ticks, 8.5 minutes without a prescaler). The TTL
+/// reads of a busy Database do that, otherwise call it from a timer.
+pub struct Rtc1;
+
+// Counter value at the last now() and wraps seen so far
+static RTC1_LAST: AtomicU32 = AtomicU32::new(0);
+static RTC1_WRAPS: AtomicU32 = AtomicU32::new(0);
+
+/// Start RTC1 with a 12 bit prescaler, ticks are 32768 / (prescaler + 1) Hz
+/// The low frequency clock has to be running already.
+pub fn rtc1_start(rtc: RTC1, prescaler: u16) {
+    rtc.prescaler
+        .write(|w| unsafe { w.bits(prescaler as u32 & 0xFFF) });
+    rtc.tasks_start.write(|w| unsafe { w.bits(1) });
+}
+
+impl Clock for Rtc1 {
+    fn now() -> u64 {
+        cortex_m::interrupt::free(|_| {
+            // Only the counter is read, it doesn't matter who owns RTC1
+            let counter = unsafe { (*RTC1::ptr()).counter.read().bits() };
+            let mut wraps = RTC1_WRAPS.load(Ordering::Relaxed);
+            if counter < RTC1_LAST.load(Ordering::Relaxed) {
+                wraps = wraps.wrapping_add(1);
+                RTC1_WRAPS.store(wraps, Ordering::Relaxed);
+            }
+            RTC1_LAST.store(counter, Ordering::Relaxed);
+            ((wraps as u64) << 24) | counter as u64
+        })
+    }
+}
@@ -1,13 +1,165 @@
 // This Codec allows us to encode and decode data
 // Currently we support JSON and Postcard
-// Postcard will store the data in a more compact format
+// Postcard will store the data in a more compact format, Compressed
+// (compress.rs) wraps either of them for values that are mostly alike.
+// Delta (delta.rs) is for arrays of samples that change slowly, Fixed
+// (fixed.rs) for f32 values stored as fixed point integers,
+// Encrypted (crypto.rs) seals every value with its own nonce and Checked
+// adds a CRC16 to values that came over a link:
+//
+// type Db = Database<u8, Setpoint, Checked<Postcard>, 16, 32, 4>;
+// MsgPack writes MessagePack (msgpack.rs), for values the host tooling
+// reads and writes too, and Tlv type-length-value records (tlv.rs) that C
+// code can parse
+//
+// Multi mixes both in one Database: every blob starts with a Format tag, so
+// decoding picks the right one by itself, and Database::set_format_selector
+// decides per key which one put() writes:
+//
+// db.set_format_selector(|key| match namespace::split(key) {
+//     Some(("host", _)) => Format::Json,    // edited by the host tools
+//     _ => Format::Postcard,
+// });
+//
+// Tagged<P> is Multi for databases that were JSON before: it writes Multi
+// blobs in format P and still reads the blobs written by plain Json, which
+// have no tag (JSON text never starts with a tag byte):
+//
+// type Db = Database<u8, Config, Tagged<Postcard>, 16, 64, 4>;   // was Json
+//
+// Versioned<C> (versioned.rs) puts a schema version in front of C's blobs
+// and upgrades values written by older firmware when they are read.
+//
+// encode_into/decode_from move a value in pieces instead of one buffer, for
+// values kept outside a Database that are bigger than any buffer we want on
+// the stack, e.g. straight into flash. Database itself doesn't stream, its
+// values are still encoded whole into a B byte blob and a bigger one fails
+// put() with Encode.
+//
+// Every piece but the last is STREAM_CHUNK bytes, a multiple of the
+// WRITE_SIZE of any NorFlash up to 32 byte writes. The last one can be
+// shorter, pad it with 0xFF to a whole write. A sink error stops the encoding
+// with StreamError::Io:
+//
+// let mut at = REGION;
+// let len = Postcard::encode_into(&big_table, &mut |piece| {
+//     let mut padded = [0xFF; STREAM_CHUNK];
+//     padded[..piece.len()].copy_from_slice(piece);
+//     let n = piece.len().next_multiple_of(Flash::WRITE_SIZE);
+//     flash.write(at, &padded[..n]).map_err(|_| ())?;
+//     at += n as u32;
+//     Ok(())
+// })?;
+// let mut at = REGION;
+// let table: Table = Postcard::decode_from(len, &mut |buf| {
+//     flash.read(at, buf).map_err(|_| ())?;
+//     at += buf.len() as u32;
+//     Ok(())
+// })?;
+//
+// Only Postcard streams for real, the other codecs stage the value in a
+// STREAM_STAGING byte buffer.
+//
+// Codecs that store a struct as separate fields can also implement
+// FieldCodec, then Database::update_field swaps the bytes of one field in
+// the stored value without encoding the rest again:
+//
+// db.update_field(&KEY_CLIMATE, "rh_pct", b"41.5")?;   // Json: JSON text
+// db.update_field(&KEY_CLIMATE, "rh_pct", &[42])?;     // Tlv: the u8
+//
+// The serde codecs also implement BorrowedCodec, which decodes types that
+// borrow from the stored bytes. Database::get_with lends one to a closure,
+// no copy and no DeserializeOwned + Clone needed:
+//
+// #[derive(Deserialize)]
+// struct LabelRef<'a> { text: &'a str, color: u8 }
+// let n = db.get_with(&KEY_LABEL, |l: LabelRef| l.text.len())?;
 
 #![allow(dead_code)]
 
+use crate::cal::CalError;
+use crate::compress::CompressError;
+use crate::crc16;
+use crate::crypto::CryptoError;
+#[cfg(feature = "crypto")]
+pub use crate::crypto::{Encrypted, EncryptedError, ValueKey};
+use crate::delta::DeltaError;
+use crate::fixed::FixedError;
+use crate::geo::GeoError;
+use crate::msgpack::MsgPackError;
+use crate::tlv::TlvError;
+pub use crate::versioned::{Initial, Schema, Versioned, VersionedError};
+
 pub trait Codec<T> {
-    type Error;
+    /// Reported to callers that don't know the codec as a CodecErrorKind
+    type Error: Into<CodecErrorKind>;
+    /// Longest encoding of any T, None if the codec can't tell
+    /// A Database whose B is smaller doesn't compile.
+    const MAX_ENCODED_SIZE: Option<usize> = None;
+
     fn encode(dst: &mut [u8], v: &T) -> Result<usize, Self::Error>;
     fn decode(src: &[u8]) -> Result<T, Self::Error>;
+
+    /// Encode in a specific format, for codecs that can write more than one
+    /// Database uses this once a format selector is set, codecs with just
+    /// one format ignore format.
+    fn encode_as(dst: &mut [u8], v: &T, format: Format) -> Result<usize, Self::Error> {
+        let _ = format;
+        Self::encode(dst, v)
+    }
+
+    /// Encode v in pieces handed to out, returns the total length
+    /// See the top of the file. The default encodes into a STREAM_STAGING
+    /// byte buffer and hands that over in STREAM_CHUNK pieces.
+    fn encode_into(
+        v: &T,
+        out: &mut dyn FnMut(&[u8]) -> Result<(), ()>,
+    ) -> Result<usize, StreamError<Self::Error>> {
+        let mut staging = [0u8; STREAM_STAGING];
+        let n = Self::encode(&mut staging, v).map_err(StreamError::Codec)?;
+        for piece in staging[..n].chunks(STREAM_CHUNK) {
+            out(piece).map_err(|_| StreamError::Io)?;
+        }
+        Ok(n)
+    }
+
+    /// Decode a value of len bytes that input reads in pieces
+    /// input has to fill the whole buffer it is given (or return an error).
+    fn decode_from(
+        len: usize,
+        input: &mut dyn FnMut(&mut [u8]) -> Result<(), ()>,
+    ) -> Result<T, StreamError<Self::Error>> {
+        let mut staging = [0u8; STREAM_STAGING];
+        let buf = staging.get_mut(..len).ok_or(StreamError::TooLarge)?;
+        input(buf).map_err(|_| StreamError::Io)?;
+        Self::decode(buf).map_err(StreamError::Codec)
+    }
+
+    /// Write a short human readable preview of an encoded value
+    /// Used by the display helpers in hmi.rs. The default prints hex bytes,
+    /// text based codecs can print the encoded text as is.
+    fn preview(src: &[u8], out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        for b in src {
+            write!(out, "{:02x}", b)?;
+        }
+        Ok(())
+    }
+}
+
+/// Largest value the default encode_into/decode_from handle
+pub const STREAM_STAGING: usize = 256;
+/// Size of the pieces encode_into hands to out, all but the last
+pub const STREAM_CHUNK: usize = 32;
+/// Room for the str and byte fields of a value Postcard::decode_from reads
+/// (all of them together), they are staged while they are decoded
+pub const STREAM_SCRATCH: usize = 64;
+
+pub enum StreamError<E> {
+    Codec(E),
+    // The value is bigger than the staging buffer of a codec that can't stream
+    TooLarge,
+    // input or out failed
+    Io,
 }
 
 pub enum JsonError {
@@ -41,6 +193,14 @@ where
         let (v, _rem) = serde_json_core::from_slice(src).map_err(JsonError::from)?;
         Ok(v)
     }
+
+    // JSON is already readable, show it as is
+    fn preview(src: &[u8], out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        match core::str::from_utf8(src) {
+            Ok(text) => out.write_str(text),
+            Err(_) => out.write_str("?"),
+        }
+    }
 }
 
 pub struct Postcard;
@@ -57,4 +217,731 @@ where
     fn decode(src: &[u8]) -> Result<T, Self::Error> {
         postcard::from_bytes(src)
     }
+
+    fn encode_into(
+        v: &T,
+        out: &mut dyn FnMut(&[u8]) -> Result<(), ()>,
+    ) -> Result<usize, StreamError<Self::Error>> {
+        let mut failed = false;
+        let chunks = Chunks {
+            buf: [0; STREAM_CHUNK],
+            n: 0,
+            total: 0,
+            out,
+            failed: &mut failed,
+        };
+        postcard::serialize_with_flavor(v, chunks).map_err(|e| match failed {
+            true => StreamError::Io,
+            false => StreamError::Codec(e),
+        })
+    }
+
+    fn decode_from(
+        len: usize,
+        input: &mut dyn FnMut(&mut [u8]) -> Result<(), ()>,
+    ) -> Result<T, StreamError<Self::Error>> {
+        let mut scratch = [0u8; STREAM_SCRATCH];
+        let reader = Reader {
+            input,
+            left: len,
+            scratch: &mut scratch,
+        };
+        let mut de = postcard::Deserializer::from_flavor(reader);
+        T::deserialize(&mut de).map_err(StreamError::Codec)
+    }
+}
+
+// Postcard output collected into STREAM_CHUNK byte pieces
+// Postcard errors can't say the sink failed, failed tells encode_into.
+struct Chunks<'a> {
+    buf: [u8; STREAM_CHUNK],
+    n: usize,
+    total: usize,
+    out: &'a mut dyn FnMut(&[u8]) -> Result<(), ()>,
+    failed: &'a mut bool,
+}
+
+impl Chunks<'_> {
+    fn send(&mut self) -> postcard::Result<()> {
+        (self.out)(&self.buf[..self.n]).map_err(|_| {
+            *self.failed = true;
+            postcard::Error::SerializeBufferFull
+        })?;
+        self.n = 0;
+        Ok(())
+    }
+}
+
+impl postcard::ser_flavors::Flavor for Chunks<'_> {
+    type Output = usize;
+
+    fn try_push(&mut self, data: u8) -> postcard::Result<()> {
+        if self.n == STREAM_CHUNK {
+            self.send()?;
+        }
+        self.buf[self.n] = data;
+        self.n += 1;
+        self.total += 1;
+        Ok(())
+    }
+
+    fn finalize(mut self) -> postcard::Result<usize> {
+        if self.n > 0 {
+            self.send()?;
+        }
+        Ok(self.total)
+    }
+}
+
+// Postcard input read on demand, str and [u8] fields are read into the next
+// free part of scratch since the deserializer borrows them
+struct Reader<'de> {
+    input: &'de mut dyn FnMut(&mut [u8]) -> Result<(), ()>,
+    left: usize,
+    scratch: &'de mut [u8],
+}
+
+impl<'de> postcard::de_flavors::Flavor<'de> for Reader<'de> {
+    type Remainder = usize;
+    type Source = ();
+
+    fn pop(&mut self) -> postcard::Result<u8> {
+        let mut byte = [0u8];
+        self.read(&mut byte)?;
+        Ok(byte[0])
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+
+    fn try_take_n(&mut self, ct: usize) -> postcard::Result<&'de [u8]> {
+        if ct > self.scratch.len() {
+            return Err(postcard::Error::DeserializeUnexpectedEnd);
+        }
+        let (taken, rest) = core::mem::take(&mut self.scratch).split_at_mut(ct);
+        self.scratch = rest;
+        self.read(taken)?;
+        Ok(taken)
+    }
+
+    fn finalize(self) -> postcard::Result<usize> {
+        Ok(self.left)
+    }
+}
+
+impl Reader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> postcard::Result<()> {
+        self.left = self
+            .left
+            .checked_sub(buf.len())
+            .ok_or(postcard::Error::DeserializeUnexpectedEnd)?;
+        (self.input)(buf).map_err(|_| postcard::Error::DeserializeUnexpectedEnd)
+    }
+}
+
+/// MessagePack, structs as maps keyed by field name
+pub struct MsgPack;
+impl<T> Codec<T> for MsgPack
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    type Error = crate::msgpack::MsgPackError;
+
+    fn encode(dst: &mut [u8], v: &T) -> Result<usize, Self::Error> {
+        crate::msgpack::to_slice(v, dst)
+    }
+
+    fn decode(src: &[u8]) -> Result<T, Self::Error> {
+        crate::msgpack::from_slice(src)
+    }
+}
+
+/// Type-length-value records, see tlv.rs for the bytes
+pub struct Tlv;
+impl<T> Codec<T> for Tlv
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    type Error = crate::tlv::TlvError;
+
+    fn encode(dst: &mut [u8], v: &T) -> Result<usize, Self::Error> {
+        crate::tlv::to_slice(v, dst)
+    }
+
+    fn decode(src: &[u8]) -> Result<T, Self::Error> {
+        crate::tlv::from_slice(src)
+    }
+}
+
+// MAX_ENCODED_SIZE of a wrapper that adds extra bytes to the inner encoding
+pub(crate) const fn with_overhead(inner: Option<usize>, extra: usize) -> Option<usize> {
+    match inner {
+        Some(n) => Some(n + extra),
+        None => None,
+    }
+}
+
+/// Codecs that can decode a T borrowing from src, see the top of the file
+pub trait BorrowedCodec<'a, T> {
+    type Error;
+    fn decode_borrowed(src: &'a [u8]) -> Result<T, Self::Error>;
+}
+
+impl<'a, T: serde::Deserialize<'a>> BorrowedCodec<'a, T> for Postcard {
+    type Error = postcard::Error;
+
+    fn decode_borrowed(src: &'a [u8]) -> Result<T, Self::Error> {
+        postcard::from_bytes(src)
+    }
+}
+
+/// Strings with escapes in them can't be borrowed and fail to decode
+impl<'a, T: serde::Deserialize<'a>> BorrowedCodec<'a, T> for Json {
+    type Error = JsonError;
+
+    fn decode_borrowed(src: &'a [u8]) -> Result<T, Self::Error> {
+        let (v, _rem) = serde_json_core::from_slice(src).map_err(JsonError::from)?;
+        Ok(v)
+    }
+}
+
+impl<'a, T: serde::Deserialize<'a>> BorrowedCodec<'a, T> for MsgPack {
+    type Error = MsgPackError;
+
+    fn decode_borrowed(src: &'a [u8]) -> Result<T, Self::Error> {
+        crate::msgpack::from_slice(src)
+    }
+}
+
+impl<'a, T: serde::Deserialize<'a>> BorrowedCodec<'a, T> for Tlv {
+    type Error = TlvError;
+
+    fn decode_borrowed(src: &'a [u8]) -> Result<T, Self::Error> {
+        crate::tlv::from_slice(src)
+    }
+}
+
+pub enum CheckedError<E> {
+    Inner(E),
+    // No room for the CRC, or a blob shorter than it
+    TooSmall,
+    // The value was damaged on the way
+    CrcMismatch,
+}
+
+/// C's encoding followed by its CRC16 (crc16.rs), little endian
+pub struct Checked<C>(core::marker::PhantomData<C>);
+impl<T, C> Codec<T> for Checked<C>
+where
+    C: Codec<T>,
+{
+    type Error = CheckedError<C::Error>;
+    const MAX_ENCODED_SIZE: Option<usize> = with_overhead(C::MAX_ENCODED_SIZE, 2);
+
+    fn encode(dst: &mut [u8], v: &T) -> Result<usize, Self::Error> {
+        let room = dst.len().checked_sub(2).ok_or(CheckedError::TooSmall)?;
+        let n = C::encode(&mut dst[..room], v).map_err(CheckedError::Inner)?;
+        let crc = crc16::CRC16.checksum(&dst[..n]);
+        dst[n..n + 2].copy_from_slice(&crc.to_le_bytes());
+        Ok(n + 2)
+    }
+
+    fn decode(src: &[u8]) -> Result<T, Self::Error> {
+        C::decode(checked_body(src)?).map_err(CheckedError::Inner)
+    }
+
+    fn preview(src: &[u8], out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        match checked_body::<C::Error>(src) {
+            Ok(body) => C::preview(body, out),
+            Err(_) => out.write_str("?"),
+        }
+    }
+}
+
+// The encoding in front of the CRC, if the CRC matches
+fn checked_body<E>(src: &[u8]) -> Result<&[u8], CheckedError<E>> {
+    let n = src.len().checked_sub(2).ok_or(CheckedError::TooSmall)?;
+    let (body, crc) = src.split_at(n);
+    if crc16::CRC16.checksum(body) != u16::from_le_bytes([crc[0], crc[1]]) {
+        return Err(CheckedError::CrcMismatch);
+    }
+    Ok(body)
+}
+
+/// Formats Multi can write, the value is the tag byte in front of each blob
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Format {
+    Postcard = 1,
+    Json = 2,
+}
+
+pub enum MultiError {
+    // A blob without even the tag byte, or no room to write it
+    Empty,
+    UnknownFormat(u8),
+    Postcard(postcard::Error),
+    Json(JsonError),
+}
+
+/// Postcard or JSON per blob, see the top of the file
+/// Plain encode() writes Postcard.
+pub struct Multi;
+impl<T> Codec<T> for Multi
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    type Error = MultiError;
+
+    fn encode(dst: &mut [u8], v: &T) -> Result<usize, Self::Error> {
+        Self::encode_as(dst, v, Format::Postcard)
+    }
+
+    fn decode(src: &[u8]) -> Result<T, Self::Error> {
+        let (tag, body) = src.split_first().ok_or(MultiError::Empty)?;
+        match *tag {
+            t if t == Format::Postcard as u8 => {
+                Postcard::decode(body).map_err(MultiError::Postcard)
+            }
+            t if t == Format::Json as u8 => {
+                <Json as Codec<T>>::decode(body).map_err(MultiError::Json)
+            }
+            t => Err(MultiError::UnknownFormat(t)),
+        }
+    }
+
+    fn encode_as(dst: &mut [u8], v: &T, format: Format) -> Result<usize, Self::Error> {
+        let (tag, body) = dst.split_first_mut().ok_or(MultiError::Empty)?;
+        *tag = format as u8;
+        let n = match format {
+            Format::Postcard => Postcard::encode(body, v).map_err(MultiError::Postcard)?,
+            Format::Json => Json::encode(body, v).map_err(MultiError::Json)?,
+        };
+        Ok(1 + n)
+    }
+
+    fn preview(src: &[u8], out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        match src.split_first() {
+            Some((tag, body)) if *tag == Format::Json as u8 => {
+                <Json as Codec<T>>::preview(body, out)
+            }
+            Some((_, body)) => <Postcard as Codec<T>>::preview(body, out),
+            None => Ok(()),
+        }
+    }
+}
+
+/// Codecs Tagged can write, see the top of the file
+pub trait TagFormat {
+    const FORMAT: Format;
+}
+impl TagFormat for Postcard {
+    const FORMAT: Format = Format::Postcard;
+}
+impl TagFormat for Json {
+    const FORMAT: Format = Format::Json;
+}
+
+/// Multi blobs in format P, plus untagged JSON from before
+pub struct Tagged<P: TagFormat = Postcard>(core::marker::PhantomData<P>);
+impl<T, P> Codec<T> for Tagged<P>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+    P: TagFormat,
+{
+    type Error = MultiError;
+
+    fn encode(dst: &mut [u8], v: &T) -> Result<usize, Self::Error> {
+        Multi::encode_as(dst, v, P::FORMAT)
+    }
+
+    fn decode(src: &[u8]) -> Result<T, Self::Error> {
+        if is_tagged(src) {
+            Multi::decode(src)
+        } else {
+            <Json as Codec<T>>::decode(src).map_err(MultiError::Json)
+        }
+    }
+
+    fn encode_as(dst: &mut [u8], v: &T, format: Format) -> Result<usize, Self::Error> {
+        Multi::encode_as(dst, v, format)
+    }
+
+    fn preview(src: &[u8], out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        if is_tagged(src) {
+            <Multi as Codec<T>>::preview(src, out)
+        } else {
+            <Json as Codec<T>>::preview(src, out)
+        }
+    }
+}
+
+// Starts with one of the Format tags, else it is JSON text
+fn is_tagged(src: &[u8]) -> bool {
+    matches!(src.first(), Some(&t) if t == Format::Postcard as u8 || t == Format::Json as u8)
+}
+
+/// What went wrong in a codec, whichever codec it was
+/// Every codec error converts into one, DbError::into_kind does it for the
+/// errors of a Database.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum CodecErrorKind {
+    // Not enough room in the buffer for the encoding
+    BufferTooSmall,
+    // The bytes aren't a valid encoding: truncated, bad length, wrong type
+    Malformed,
+    // Written in a version, format or with a dictionary this build can't read
+    UnsupportedVersion,
+    // A CRC or authentication tag doesn't match
+    Integrity,
+    // A value the codec can't represent
+    Unsupported,
+    // Errors of the serde impls and anything else
+    Other,
+}
+
+impl From<postcard::Error> for CodecErrorKind {
+    fn from(e: postcard::Error) -> Self {
+        use postcard::Error::*;
+        match e {
+            SerializeBufferFull => CodecErrorKind::BufferTooSmall,
+            WontImplement | NotYetImplemented | SerializeSeqLengthUnknown => {
+                CodecErrorKind::Unsupported
+            }
+            DeserializeBadCrc => CodecErrorKind::Integrity,
+            SerdeSerCustom | SerdeDeCustom | CollectStrError => CodecErrorKind::Other,
+            _ => CodecErrorKind::Malformed,
+        }
+    }
+}
+
+impl From<JsonError> for CodecErrorKind {
+    fn from(e: JsonError) -> Self {
+        use serde_json_core::de::Error::{AnyIsUnsupported, BytesIsUnsupported};
+        match e {
+            JsonError::Ser(_) => CodecErrorKind::BufferTooSmall,
+            JsonError::De(AnyIsUnsupported | BytesIsUnsupported) => CodecErrorKind::Unsupported,
+            JsonError::De(_) => CodecErrorKind::Malformed,
+        }
+    }
+}
+
+impl From<MultiError> for CodecErrorKind {
+    fn from(e: MultiError) -> Self {
+        match e {
+            MultiError::Empty => CodecErrorKind::Malformed,
+            MultiError::UnknownFormat(_) => CodecErrorKind::UnsupportedVersion,
+            MultiError::Postcard(e) => e.into(),
+            MultiError::Json(e) => e.into(),
+        }
+    }
+}
+
+impl<E: Into<CodecErrorKind>> From<CheckedError<E>> for CodecErrorKind {
+    fn from(e: CheckedError<E>) -> Self {
+        match e {
+            CheckedError::Inner(e) => e.into(),
+            CheckedError::TooSmall => CodecErrorKind::Malformed,
+            CheckedError::CrcMismatch => CodecErrorKind::Integrity,
+        }
+    }
+}
+
+impl<E: Into<CodecErrorKind>> From<StreamError<E>> for CodecErrorKind {
+    fn from(e: StreamError<E>) -> Self {
+        match e {
+            StreamError::Codec(e) => e.into(),
+            StreamError::TooLarge => CodecErrorKind::BufferTooSmall,
+            StreamError::Io => CodecErrorKind::Other,
+        }
+    }
+}
+
+impl<E: Into<CodecErrorKind>> From<CompressError<E>> for CodecErrorKind {
+    fn from(e: CompressError<E>) -> Self {
+        match e {
+            CompressError::Inner(e) => e.into(),
+            CompressError::TooLarge => CodecErrorKind::BufferTooSmall,
+            CompressError::WrongDictionary(_) => CodecErrorKind::UnsupportedVersion,
+            CompressError::Corrupt => CodecErrorKind::Malformed,
+        }
+    }
+}
+
+impl From<CryptoError> for CodecErrorKind {
+    fn from(e: CryptoError) -> Self {
+        match e {
+            CryptoError::BufferTooSmall => CodecErrorKind::BufferTooSmall,
+            CryptoError::AuthenticationFailed => CodecErrorKind::Integrity,
+        }
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl<E: Into<CodecErrorKind>> From<EncryptedError<E>> for CodecErrorKind {
+    fn from(e: EncryptedError<E>) -> Self {
+        match e {
+            EncryptedError::Inner(e) => e.into(),
+            EncryptedError::Crypto(e) => e.into(),
+        }
+    }
+}
+
+impl<E: Into<CodecErrorKind>> From<VersionedError<E>> for CodecErrorKind {
+    fn from(e: VersionedError<E>) -> Self {
+        match e {
+            VersionedError::Inner(e) => e.into(),
+            VersionedError::Empty => CodecErrorKind::Malformed,
+            VersionedError::TooNew(_) | VersionedError::Upgrade(_) => {
+                CodecErrorKind::UnsupportedVersion
+            }
+        }
+    }
+}
+
+impl From<MsgPackError> for CodecErrorKind {
+    fn from(e: MsgPackError) -> Self {
+        match e {
+            MsgPackError::BufferFull => CodecErrorKind::BufferTooSmall,
+            MsgPackError::UnknownLength => CodecErrorKind::Unsupported,
+            MsgPackError::Custom => CodecErrorKind::Other,
+            MsgPackError::Eof
+            | MsgPackError::UnexpectedType
+            | MsgPackError::BadUtf8
+            | MsgPackError::TrailingBytes => CodecErrorKind::Malformed,
+        }
+    }
+}
+
+impl From<TlvError> for CodecErrorKind {
+    fn from(e: TlvError) -> Self {
+        match e {
+            TlvError::BufferFull => CodecErrorKind::BufferTooSmall,
+            TlvError::TooLarge | TlvError::Unsupported => CodecErrorKind::Unsupported,
+            TlvError::Custom => CodecErrorKind::Other,
+            TlvError::Eof | TlvError::BadLength | TlvError::BadUtf8 | TlvError::BadTag => {
+                CodecErrorKind::Malformed
+            }
+        }
+    }
+}
+
+impl From<DeltaError> for CodecErrorKind {
+    fn from(e: DeltaError) -> Self {
+        match e {
+            DeltaError::BufferFull => CodecErrorKind::BufferTooSmall,
+            _ => CodecErrorKind::Malformed,
+        }
+    }
+}
+
+impl From<FixedError> for CodecErrorKind {
+    fn from(e: FixedError) -> Self {
+        match e {
+            FixedError::BufferTooSmall => CodecErrorKind::BufferTooSmall,
+            FixedError::BadLength => CodecErrorKind::Malformed,
+        }
+    }
+}
+
+impl From<GeoError> for CodecErrorKind {
+    fn from(e: GeoError) -> Self {
+        match e {
+            GeoError::BufferTooSmall => CodecErrorKind::BufferTooSmall,
+            // Encoded points are always in range, a decoded one isn't
+            GeoError::OutOfRange | GeoError::BadLength => CodecErrorKind::Malformed,
+        }
+    }
+}
+
+impl From<CalError> for CodecErrorKind {
+    fn from(e: CalError) -> Self {
+        match e {
+            CalError::BufferTooSmall => CodecErrorKind::BufferTooSmall,
+            CalError::NotMonotonic | CalError::BadLength => CodecErrorKind::Malformed,
+            CalError::NotFound | CalError::Storage => CodecErrorKind::Other,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FieldError {
+    // The value has no field of that name
+    NoField,
+    // The stored value (or the one with the new field) isn't what the
+    // codec expects, e.g. not a map
+    Malformed,
+    // The updated value doesn't fit in a blob
+    TooLarge,
+    // No free slot in the store
+    Full,
+    // The update would use room reserved for critical keys
+    Reserved,
+    // A write-back value of the key didn't encode when it was flushed
+    Encode,
+}
+
+/// Codecs that can change one field of an encoded value in place
+pub trait FieldCodec<T>: Codec<T> {
+    /// Write blob with field set to value into dst, returns the length
+    /// value is the field on its own in this codec's encoding.
+    fn update_field(
+        blob: &[u8],
+        field: &str,
+        value: &[u8],
+        dst: &mut [u8],
+    ) -> Result<usize, FieldError>;
+}
+
+// Swap the bytes at span of blob for value, into dst
+fn splice(
+    blob: &[u8],
+    span: core::ops::Range<usize>,
+    value: &[u8],
+    dst: &mut [u8],
+) -> Result<usize, FieldError> {
+    let len = blob.len() - span.len() + value.len();
+    let out = dst.get_mut(..len).ok_or(FieldError::TooLarge)?;
+    let (head, rest) = out.split_at_mut(span.start);
+    let (middle, tail) = rest.split_at_mut(value.len());
+    head.copy_from_slice(&blob[..span.start]);
+    middle.copy_from_slice(value);
+    tail.copy_from_slice(&blob[span.end..]);
+    Ok(len)
+}
+
+/// Top level fields of a JSON object, value is JSON text (b"41.5", b"\"on\"")
+impl<T> FieldCodec<T> for Json
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    fn update_field(
+        blob: &[u8],
+        field: &str,
+        value: &[u8],
+        dst: &mut [u8],
+    ) -> Result<usize, FieldError> {
+        let span = json::find_field(blob, field.as_bytes())?;
+        splice(blob, span, value, dst)
+    }
+}
+
+/// Fields of a struct by name, value is the field's TLV encoding (an i16
+/// is its 2 bytes). A field that is None gets a record.
+impl<T> FieldCodec<T> for Tlv
+where
+    T: serde::Serialize + serde::de::DeserializeOwned,
+{
+    fn update_field(
+        blob: &[u8],
+        field: &str,
+        value: &[u8],
+        dst: &mut [u8],
+    ) -> Result<usize, FieldError> {
+        let tag = crate::tlv::field_tag::<T>(field).ok_or(FieldError::NoField)?;
+        crate::tlv::replace_record(blob, tag, value, dst).map_err(|e| match e {
+            TlvError::BufferFull | TlvError::TooLarge => FieldError::TooLarge,
+            _ => FieldError::Malformed,
+        })
+    }
+}
+
+// Just enough JSON scanning to find where a field's value is
+mod json {
+    use super::FieldError;
+    use core::ops::Range;
+
+    fn skip_ws(src: &[u8], mut i: usize) -> usize {
+        while matches!(src.get(i), Some(b' ' | b'\t' | b'\r' | b'\n')) {
+            i += 1;
+        }
+        i
+    }
+
+    fn expect(src: &[u8], i: usize, byte: u8) -> Result<usize, FieldError> {
+        match src.get(i) {
+            Some(b) if *b == byte => Ok(i + 1),
+            _ => Err(FieldError::Malformed),
+        }
+    }
+
+    // i is at the opening quote, returns the index after the closing one
+    fn skip_string(src: &[u8], mut i: usize) -> Result<usize, FieldError> {
+        i = expect(src, i, b'"')?;
+        loop {
+            match src.get(i) {
+                Some(b'"') => return Ok(i + 1),
+                Some(b'\\') => i += 2,
+                Some(_) => i += 1,
+                None => return Err(FieldError::Malformed),
+            }
+        }
+    }
+
+    // Returns the index after the value starting at i
+    fn skip_value(src: &[u8], mut i: usize) -> Result<usize, FieldError> {
+        match src.get(i) {
+            Some(b'"') => skip_string(src, i),
+            Some(b'{' | b'[') => {
+                let mut depth = 0;
+                loop {
+                    match src.get(i) {
+                        Some(b'"') => {
+                            i = skip_string(src, i)?;
+                            continue;
+                        }
+                        Some(b'{' | b'[') => depth += 1,
+                        Some(b'}' | b']') => {
+                            depth -= 1;
+                            if depth == 0 {
+                                return Ok(i + 1);
+                            }
+                        }
+                        Some(_) => {}
+                        None => return Err(FieldError::Malformed),
+                    }
+                    i += 1;
+                }
+            }
+            Some(_) => {
+                let start = i;
+                while !matches!(
+                    src.get(i),
+                    None | Some(b',' | b'}' | b']' | b' ' | b'\t' | b'\r' | b'\n')
+                ) {
+                    i += 1;
+                }
+                if i == start {
+                    return Err(FieldError::Malformed);
+                }
+                Ok(i)
+            }
+            None => Err(FieldError::Malformed),
+        }
+    }
+
+    /// Where the value of field is in the object src
+    /// Keys are compared as written, a key with escapes in it won't match.
+    pub fn find_field(src: &[u8], field: &[u8]) -> Result<Range<usize>, FieldError> {
+        let mut i = expect(src, skip_ws(src, 0), b'{')?;
+        if src.get(skip_ws(src, i)) == Some(&b'}') {
+            return Err(FieldError::NoField);
+        }
+        loop {
+            let key_start = skip_ws(src, i);
+            let key_end = skip_string(src, key_start)?;
+            i = expect(src, skip_ws(src, key_end), b':')?;
+            let start = skip_ws(src, i);
+            let end = skip_value(src, start)?;
+            if &src[key_start + 1..key_end - 1] == field {
+                return Ok(start..end);
+            }
+            i = skip_ws(src, end);
+            match src.get(i) {
+                Some(b',') => i += 1,
+                Some(b'}') => return Err(FieldError::NoField),
+                _ => return Err(FieldError::Malformed),
+            }
+        }
+    }
 }
@@ -0,0 +1,224 @@
+// Compression for small values, with a shared dictionary
+// General purpose compressors gain next to nothing on a 60 byte JSON value,
+// there is not enough repetition inside one value. Telemetry values look a
+// lot like each other though, so Compressed<C, D> lets the matches point
+// into a static dictionary D built offline from sample payloads as well.
+//
+// struct Telemetry;
+// impl Dictionary for Telemetry {
+//     const ID: u8 = 2;
+//     const BYTES: &'static [u8] = include_bytes!("../dict/telemetry.bin");
+// }
+// type Db = Database<u8, Reading, Compressed<Json, Telemetry>, 16, 64, 4>;
+//
+// A dictionary is just bytes that show up in the values: concatenating a
+// handful of typical encoded values (field names, common numbers) works
+// well. The matcher is a plain search over the whole dictionary, so keep it
+// to a few hundred bytes. Changing the bytes breaks every stored value,
+// give the new dictionary a new ID.
+//
+// Blob layout: [tag: u8][body], tag 0 means the body is the inner encoding
+// as is (used when compressing doesn't make it shorter), otherwise tag is
+// the ID of the dictionary and the body a run of tokens:
+// 0lllllll               l + 1 literal bytes follow
+// 1lllllll dist: u16     copy l + MIN_MATCH bytes from dist + 1 bytes back,
+//                        counting back into the dictionary
+
+use crate::codec::Codec;
+use core::marker::PhantomData;
+
+/// Largest inner encoding Compressed handles, it is staged on the stack
+pub const MAX_RAW: usize = 256;
+
+const STORED: u8 = 0;
+const MIN_MATCH: usize = 4;
+const MAX_MATCH: usize = 0x7F + MIN_MATCH;
+const MAX_LITERALS: usize = 0x80;
+const MAX_DISTANCE: usize = 1 << 16;
+
+/// Static bytes matches can point into, see the top of the file
+pub trait Dictionary {
+    // Stored in front of every compressed value, not 0
+    const ID: u8;
+    const BYTES: &'static [u8];
+}
+
+/// Compression without a dictionary, only repeats inside a value are found
+pub struct NoDictionary;
+impl Dictionary for NoDictionary {
+    const ID: u8 = 1;
+    const BYTES: &'static [u8] = &[];
+}
+
+pub enum CompressError<E> {
+    Inner(E),
+    // The inner encoding is longer than MAX_RAW, or dst is too small
+    TooLarge,
+    // Written with the dictionary of this ID
+    WrongDictionary(u8),
+    // Truncated body or a match pointing outside the data
+    Corrupt,
+}
+
+/// C's encoding compressed with dictionary D
+pub struct Compressed<C, D = NoDictionary>(PhantomData<(C, D)>);
+
+impl<T, C, D> Codec<T> for Compressed<C, D>
+where
+    C: Codec<T>,
+    D: Dictionary,
+{
+    type Error = CompressError<C::Error>;
+
+    fn encode(dst: &mut [u8], v: &T) -> Result<usize, Self::Error> {
+        let mut raw = [0u8; MAX_RAW];
+        let n = C::encode(&mut raw, v).map_err(CompressError::Inner)?;
+        compress(dst, &raw[..n], D::ID, D::BYTES)
+    }
+
+    fn decode(src: &[u8]) -> Result<T, Self::Error> {
+        let mut raw = [0u8; MAX_RAW];
+        let n = decompress(&mut raw, src, D::ID, D::BYTES)?;
+        C::decode(&raw[..n]).map_err(CompressError::Inner)
+    }
+
+    // What the inner codec shows for the decompressed value
+    fn preview(src: &[u8], out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        let mut raw = [0u8; MAX_RAW];
+        match decompress::<C::Error>(&mut raw, src, D::ID, D::BYTES) {
+            Ok(n) => C::preview(&raw[..n], out),
+            Err(_) => out.write_str("?"),
+        }
+    }
+}
+
+/// Compress raw into dst with dict, returns the length of the blob
+/// Falls back to storing raw as is if that is shorter.
+pub fn compress<E>(
+    dst: &mut [u8],
+    raw: &[u8],
+    id: u8,
+    dict: &[u8],
+) -> Result<usize, CompressError<E>> {
+    if let Some(n) = compress_tokens(dst, raw, id, dict) {
+        if n <= raw.len() {
+            return Ok(n);
+        }
+    }
+    let blob = dst
+        .get_mut(..1 + raw.len())
+        .ok_or(CompressError::TooLarge)?;
+    blob[0] = STORED;
+    blob[1..].copy_from_slice(raw);
+    Ok(blob.len())
+}
+
+/// Undo compress(), returns the length of the value in raw
+pub fn decompress<E>(
+    raw: &mut [u8],
+    src: &[u8],
+    id: u8,
+    dict: &[u8],
+) -> Result<usize, CompressError<E>> {
+    let (tag, mut body) = src.split_first().ok_or(CompressError::Corrupt)?;
+    if *tag == STORED {
+        let out = raw.get_mut(..body.len()).ok_or(CompressError::TooLarge)?;
+        out.copy_from_slice(body);
+        return Ok(body.len());
+    }
+    if *tag != id {
+        return Err(CompressError::WrongDictionary(*tag));
+    }
+
+    let mut n = 0;
+    while let Some((token, rest)) = body.split_first() {
+        if token & 0x80 == 0 {
+            let len = (token & 0x7F) as usize + 1;
+            let literals = rest.get(..len).ok_or(CompressError::Corrupt)?;
+            let out = raw.get_mut(n..n + len).ok_or(CompressError::TooLarge)?;
+            out.copy_from_slice(literals);
+            n += len;
+            body = &rest[len..];
+        } else {
+            let len = (token & 0x7F) as usize + MIN_MATCH;
+            let dist = match rest {
+                [lo, hi, ..] => u16::from_le_bytes([*lo, *hi]) as usize + 1,
+                _ => return Err(CompressError::Corrupt),
+            };
+            // Position in dictionary + output where the copy starts
+            let from = (dict.len() + n)
+                .checked_sub(dist)
+                .ok_or(CompressError::Corrupt)?;
+            if n + len > raw.len() {
+                return Err(CompressError::TooLarge);
+            }
+            // Byte by byte, the copy may overlap what it writes
+            for k in 0..len {
+                let at = from + k;
+                raw[n + k] = if at < dict.len() {
+                    dict[at]
+                } else {
+                    raw[at - dict.len()]
+                };
+            }
+            n += len;
+            body = &rest[2..];
+        }
+    }
+    Ok(n)
+}
+
+// Greedy LZ77 over dictionary + raw, None if dst runs out
+fn compress_tokens(dst: &mut [u8], raw: &[u8], id: u8, dict: &[u8]) -> Option<usize> {
+    *dst.first_mut()? = id;
+    let mut n = 1;
+    let mut pos = 0;
+    let mut literals = 0;
+    // Byte of dictionary + raw
+    let at = |i: usize| {
+        if i < dict.len() {
+            dict[i]
+        } else {
+            raw[i - dict.len()]
+        }
+    };
+
+    while pos < raw.len() {
+        let here = dict.len() + pos;
+        let longest = (raw.len() - pos).min(MAX_MATCH);
+        let mut best = (0, 0);
+        for from in here.saturating_sub(MAX_DISTANCE)..here {
+            let len = (0..longest)
+                .take_while(|k| at(from + k) == raw[pos + k])
+                .count();
+            if len > best.0 {
+                best = (len, here - from);
+            }
+        }
+
+        if best.0 >= MIN_MATCH {
+            n = flush_literals(dst, n, &raw[pos - literals..pos])?;
+            literals = 0;
+            let token = dst.get_mut(n..n + 3)?;
+            token[0] = 0x80 | (best.0 - MIN_MATCH) as u8;
+            token[1..].copy_from_slice(&((best.1 - 1) as u16).to_le_bytes());
+            n += 3;
+            pos += best.0;
+        } else {
+            literals += 1;
+            pos += 1;
+        }
+    }
+    flush_literals(dst, n, &raw[pos - literals..pos])
+}
+
+// Write literals as tokens at dst[n..], returns the new end
+fn flush_literals(dst: &mut [u8], mut n: usize, literals: &[u8]) -> Option<usize> {
+    for chunk in literals.chunks(MAX_LITERALS) {
+        let token = dst.get_mut(n..n + 1 + chunk.len())?;
+        token[0] = (chunk.len() - 1) as u8;
+        token[1..].copy_from_slice(chunk);
+        n += token.len();
+    }
+    Some(n)
+}
@@ -0,0 +1,48 @@
+// CRC-16/CCITT-FALSE for codec::Checked
+// Polynomial 0x1021, initial value 0xFFFF, not reflected, no final xor: the
+// one most radio stacks and bootloaders call "CRC16", so the sender can
+// compute it with whatever it already has. Check value: "123456789" =>
+// 0x29B1. Values are small, a single 256 entry table (512 bytes of flash)
+// is plenty fast.
+//
+// let crc = CRC16.checksum(&bytes);
+
+const POLY: u16 = 0x1021;
+
+static TABLE: [u16; 256] = table();
+
+const fn table() -> [u16; 256] {
+    let mut t = [0u16; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = (i as u16) << 8;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 0x8000 != 0 {
+                (crc << 1) ^ POLY
+            } else {
+                crc << 1
+            };
+            bit += 1;
+        }
+        t[i] = crc;
+        i += 1;
+    }
+    t
+}
+
+/// See the top of the file
+pub const CRC16: Crc16 = Crc16;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Crc16;
+
+impl Crc16 {
+    pub fn checksum(&self, bytes: &[u8]) -> u16 {
+        let mut crc = 0xFFFFu16;
+        for b in bytes {
+            crc = (crc << 8) ^ TABLE[((crc >> 8) as u8 ^ b) as usize];
+        }
+        crc
+    }
+}
@@ -0,0 +1,92 @@
+// CRC32 (zlib/Ethernet polynomial) for the image, snapshot and record checks
+// The nRF52840 has no CRC peripheral, and a bit at a time CRC over a whole
+// image is slow enough to show up in save/load times. This is slicing-by-4:
+// four 256 entry tables (4 KiB, built at compile time so they live in flash)
+// let every step eat a whole word instead of a single byte.
+//
+// Same API as the crc crate we used before:
+// let crc = CRC32.checksum(&bytes);
+// let mut digest = CRC32.digest();
+// digest.update(&header);
+// digest.update(&payload);
+// let crc = digest.finalize();
+
+const POLY: u32 = 0xEDB8_8320;
+
+static TABLES: [[u32; 256]; 4] = tables();
+
+const fn tables() -> [[u32; 256]; 4] {
+    let mut t = [[0u32; 256]; 4];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ POLY
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        t[0][i] = crc;
+        i += 1;
+    }
+    // t[k][i] is the CRC of byte i followed by k zero bytes
+    let mut k = 1;
+    while k < 4 {
+        let mut i = 0;
+        while i < 256 {
+            let prev = t[k - 1][i];
+            t[k][i] = (prev >> 8) ^ t[0][(prev & 0xFF) as usize];
+            i += 1;
+        }
+        k += 1;
+    }
+    t
+}
+
+/// See the top of the file, use image::CRC32
+#[derive(Debug, Clone, Copy)]
+pub struct Crc32;
+
+impl Crc32 {
+    pub fn checksum(&self, bytes: &[u8]) -> u32 {
+        let mut digest = self.digest();
+        digest.update(bytes);
+        digest.finalize()
+    }
+
+    /// For data that arrives in pieces
+    pub fn digest(&self) -> Digest {
+        Digest { crc: !0 }
+    }
+}
+
+/// Running CRC32, see Crc32::digest
+#[derive(Debug, Clone, Copy)]
+pub struct Digest {
+    crc: u32,
+}
+
+impl Digest {
+    pub fn update(&mut self, bytes: &[u8]) {
+        let mut crc = self.crc;
+        let mut words = bytes.chunks_exact(4);
+        for w in &mut words {
+            crc ^= u32::from_le_bytes([w[0], w[1], w[2], w[3]]);
+            crc = TABLES[3][(crc & 0xFF) as usize]
+                ^ TABLES[2][((crc >> 8) & 0xFF) as usize]
+                ^ TABLES[1][((crc >> 16) & 0xFF) as usize]
+                ^ TABLES[0][(crc >> 24) as usize];
+        }
+        for b in words.remainder() {
+            crc = TABLES[0][((crc ^ *b as u32) & 0xFF) as usize] ^ (crc >> 8);
+        }
+        self.crc = crc;
+    }
+
+    pub fn finalize(self) -> u32 {
+        !self.crc
+    }
+}
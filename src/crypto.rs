@@ -0,0 +1,314 @@
+// Encryption and authentication of the flash image
+// save_to_flash_sealed / open_sealed pass the image payload through an
+// ImageCipher. The header stays readable (so support can still see which
+// firmware wrote the image) but is authenticated along with the payload.
+//
+// SoftwareCcm encrypts, payload layout: [nonce][ciphertext][tag]
+// HmacSha256 only authenticates, payload layout: [records][tag]
+// The CRC still catches plain bit rot, the tag catches someone with SWD
+// access editing the image.
+//
+// SoftwareCcm is AES-128-CCM in software. The nRF52840 CryptoCell (CC310)
+// needs Nordic's closed source runtime library, so instead of linking that
+// here a CC310 binding can implement ImageCipher and be passed in the same way.
+//
+// Encrypted<C, K> is a codec that seals every value on its own with
+// ChaCha20-Poly1305, so a single blob read out of flash or RAM (or a record
+// copied off with records()/scan()) is still unreadable without the key:
+//
+// struct DeviceKey;
+// impl ValueKey for DeviceKey {
+//     fn key() -> [u8; 32] { keystore::value_key() }
+//     fn nonce() -> [u8; 12] { rng::nonce() }      // HardwareRng behind a Mutex
+// }
+// type Secrets = Database<u8, WifiCreds, Encrypted<Postcard, DeviceKey>, 4, 96, 1>;
+//
+// Blob layout: [nonce: 12][inner encoding, encrypted][tag: 16]. The blob is
+// not bound to its key, a blob copied under another key still opens. Use it
+// together with a sealed image if that matters.
+//
+// The ciphers and Encrypted need the crypto feature, ImageCipher itself is
+// always there.
+
+#[cfg(feature = "crypto")]
+use crate::codec::{with_overhead, Codec};
+#[cfg(feature = "crypto")]
+use crate::entropy::Entropy;
+#[cfg(feature = "persistence")]
+use crate::image::Sealing;
+#[cfg(feature = "crypto")]
+use ccm::aead::generic_array::GenericArray;
+#[cfg(feature = "crypto")]
+use ccm::aead::{AeadInPlace, KeyInit};
+#[cfg(feature = "crypto")]
+use ccm::consts::{U13, U16};
+#[cfg(feature = "crypto")]
+use chacha20poly1305::ChaCha20Poly1305;
+#[cfg(feature = "crypto")]
+use core::marker::PhantomData;
+#[cfg(feature = "crypto")]
+use hmac::{Hmac, Mac};
+#[cfg(feature = "crypto")]
+use sha2::Sha256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum CryptoError {
+    // Not enough room in the buffer for the nonce and tag
+    BufferTooSmall,
+    // Wrong key, or the image was modified
+    AuthenticationFailed,
+}
+
+/// Something that can seal/unseal an image payload in place
+#[cfg(feature = "persistence")]
+pub trait ImageCipher {
+    /// What the cipher does to the payload, stored in the image header
+    fn sealing(&self) -> Sealing;
+
+    /// Bytes the cipher adds to the payload (nonce + tag)
+    fn overhead(&self) -> usize;
+
+    /// Seal buf[..len] in place, return the new length
+    /// buf must have overhead() bytes of room after len.
+    fn seal(&mut self, aad: &[u8], buf: &mut [u8], len: usize) -> Result<usize, CryptoError>;
+
+    /// Check (and decrypt) buf in place, the plain payload ends up at the
+    /// start of buf. Returns its length.
+    fn open(&mut self, aad: &[u8], buf: &mut [u8]) -> Result<usize, CryptoError>;
+}
+
+#[cfg(feature = "crypto")]
+type Aes128Ccm = ccm::Ccm<aes::Aes128, U16, U13>;
+
+#[cfg(feature = "crypto")]
+const NONCE_SIZE: usize = 13;
+#[cfg(feature = "crypto")]
+const TAG_SIZE: usize = 16;
+
+/// AES-128-CCM with a random 13 byte nonce for every save
+/// The entropy source is given at construction, nonces must never repeat
+/// for the same key so don't use a fixed or counter seeded source.
+#[cfg(feature = "crypto")]
+pub struct SoftwareCcm<E: Entropy> {
+    key: [u8; 16],
+    entropy: E,
+}
+
+#[cfg(feature = "crypto")]
+impl<E: Entropy> SoftwareCcm<E> {
+    pub fn new(key: [u8; 16], entropy: E) -> Self {
+        Self { key, entropy }
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl<E: Entropy> ImageCipher for SoftwareCcm<E> {
+    fn sealing(&self) -> Sealing {
+        Sealing::Encrypted
+    }
+
+    fn overhead(&self) -> usize {
+        NONCE_SIZE + TAG_SIZE
+    }
+
+    fn seal(&mut self, aad: &[u8], buf: &mut [u8], len: usize) -> Result<usize, CryptoError> {
+        let total = len + NONCE_SIZE + TAG_SIZE;
+        if buf.len() < total {
+            return Err(CryptoError::BufferTooSmall);
+        }
+
+        // Make room for the nonce in front of the plaintext
+        buf.copy_within(..len, NONCE_SIZE);
+        let mut nonce = [0u8; NONCE_SIZE];
+        self.entropy.fill_bytes(&mut nonce);
+        buf[..NONCE_SIZE].copy_from_slice(&nonce);
+
+        let cipher = Aes128Ccm::new(GenericArray::from_slice(&self.key));
+        let tag = cipher
+            .encrypt_in_place_detached(
+                GenericArray::from_slice(&nonce),
+                aad,
+                &mut buf[NONCE_SIZE..NONCE_SIZE + len],
+            )
+            .map_err(|_| CryptoError::BufferTooSmall)?;
+        buf[NONCE_SIZE + len..total].copy_from_slice(&tag);
+
+        Ok(total)
+    }
+
+    fn open(&mut self, aad: &[u8], buf: &mut [u8]) -> Result<usize, CryptoError> {
+        if buf.len() < NONCE_SIZE + TAG_SIZE {
+            return Err(CryptoError::AuthenticationFailed);
+        }
+        let len = buf.len() - NONCE_SIZE - TAG_SIZE;
+
+        let mut nonce = [0u8; NONCE_SIZE];
+        nonce.copy_from_slice(&buf[..NONCE_SIZE]);
+        let mut tag = [0u8; TAG_SIZE];
+        tag.copy_from_slice(&buf[NONCE_SIZE + len..]);
+
+        let cipher = Aes128Ccm::new(GenericArray::from_slice(&self.key));
+        cipher
+            .decrypt_in_place_detached(
+                GenericArray::from_slice(&nonce),
+                aad,
+                &mut buf[NONCE_SIZE..NONCE_SIZE + len],
+                GenericArray::from_slice(&tag),
+            )
+            .map_err(|_| CryptoError::AuthenticationFailed)?;
+
+        buf.copy_within(NONCE_SIZE..NONCE_SIZE + len, 0);
+        Ok(len)
+    }
+}
+
+#[cfg(feature = "crypto")]
+const HMAC_SIZE: usize = 32;
+
+/// HMAC-SHA256 over the header and payload, the payload stays readable
+/// Use this when the values aren't secret but must not be changed, e.g.
+/// calibration or licensing flags.
+#[cfg(feature = "crypto")]
+pub struct HmacSha256 {
+    key: [u8; 32],
+}
+
+#[cfg(feature = "crypto")]
+impl HmacSha256 {
+    pub fn new(key: [u8; 32]) -> Self {
+        Self { key }
+    }
+
+    fn mac(&self, aad: &[u8], payload: &[u8]) -> Hmac<Sha256> {
+        // HMAC takes keys of any length, this can't fail
+        let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(&self.key).unwrap();
+        mac.update(aad);
+        mac.update(payload);
+        mac
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl ImageCipher for HmacSha256 {
+    fn sealing(&self) -> Sealing {
+        Sealing::Authenticated
+    }
+
+    fn overhead(&self) -> usize {
+        HMAC_SIZE
+    }
+
+    fn seal(&mut self, aad: &[u8], buf: &mut [u8], len: usize) -> Result<usize, CryptoError> {
+        let total = len + HMAC_SIZE;
+        if buf.len() < total {
+            return Err(CryptoError::BufferTooSmall);
+        }
+        let tag = self.mac(aad, &buf[..len]).finalize().into_bytes();
+        buf[len..total].copy_from_slice(&tag);
+        Ok(total)
+    }
+
+    fn open(&mut self, aad: &[u8], buf: &mut [u8]) -> Result<usize, CryptoError> {
+        let len = buf
+            .len()
+            .checked_sub(HMAC_SIZE)
+            .ok_or(CryptoError::AuthenticationFailed)?;
+        // verify_slice compares in constant time
+        self.mac(aad, &buf[..len])
+            .verify_slice(&buf[len..])
+            .map_err(|_| CryptoError::AuthenticationFailed)?;
+        Ok(len)
+    }
+}
+
+/// Largest inner encoding Encrypted handles, it is decrypted on the stack
+#[cfg(feature = "crypto")]
+pub const MAX_PLAIN: usize = 256;
+
+#[cfg(feature = "crypto")]
+const VALUE_NONCE_SIZE: usize = 12;
+
+/// Key and nonces for Encrypted, see the top of the file
+#[cfg(feature = "crypto")]
+pub trait ValueKey {
+    /// The ChaCha20-Poly1305 key
+    fn key() -> [u8; 32];
+    /// A nonce never used before with this key, random or a persisted counter
+    fn nonce() -> [u8; 12];
+}
+
+#[cfg(feature = "crypto")]
+pub enum EncryptedError<E> {
+    Inner(E),
+    Crypto(CryptoError),
+}
+
+/// C's encoding sealed with ChaCha20-Poly1305 under K's key
+#[cfg(feature = "crypto")]
+pub struct Encrypted<C, K>(PhantomData<(C, K)>);
+
+#[cfg(feature = "crypto")]
+impl<T, C, K> Codec<T> for Encrypted<C, K>
+where
+    C: Codec<T>,
+    K: ValueKey,
+{
+    type Error = EncryptedError<C::Error>;
+    const MAX_ENCODED_SIZE: Option<usize> = {
+        if let Some(max) = C::MAX_ENCODED_SIZE {
+            assert!(
+                max <= MAX_PLAIN,
+                "the inner codec can encode more than MAX_PLAIN"
+            );
+        }
+        with_overhead(C::MAX_ENCODED_SIZE, VALUE_NONCE_SIZE + TAG_SIZE)
+    };
+
+    fn encode(dst: &mut [u8], v: &T) -> Result<usize, Self::Error> {
+        let end = dst
+            .len()
+            .checked_sub(TAG_SIZE)
+            .filter(|end| *end >= VALUE_NONCE_SIZE)
+            .ok_or(EncryptedError::Crypto(CryptoError::BufferTooSmall))?
+            .min(VALUE_NONCE_SIZE + MAX_PLAIN);
+        let len = C::encode(&mut dst[VALUE_NONCE_SIZE..end], v).map_err(EncryptedError::Inner)?;
+        let nonce = K::nonce();
+        dst[..VALUE_NONCE_SIZE].copy_from_slice(&nonce);
+
+        let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&K::key()));
+        let body = VALUE_NONCE_SIZE..VALUE_NONCE_SIZE + len;
+        let tag = cipher
+            .encrypt_in_place_detached(
+                GenericArray::from_slice(&nonce),
+                &[],
+                &mut dst[body.clone()],
+            )
+            .map_err(|_| EncryptedError::Crypto(CryptoError::BufferTooSmall))?;
+        dst[body.end..body.end + TAG_SIZE].copy_from_slice(&tag);
+        Ok(body.end + TAG_SIZE)
+    }
+
+    fn decode(src: &[u8]) -> Result<T, Self::Error> {
+        if src.len() < VALUE_NONCE_SIZE + TAG_SIZE {
+            return Err(EncryptedError::Crypto(CryptoError::AuthenticationFailed));
+        }
+        let (nonce, rest) = src.split_at(VALUE_NONCE_SIZE);
+        let (body, tag) = rest.split_at(rest.len() - TAG_SIZE);
+        let mut plain = [0u8; MAX_PLAIN];
+        let plain = plain
+            .get_mut(..body.len())
+            .ok_or(EncryptedError::Crypto(CryptoError::BufferTooSmall))?;
+        plain.copy_from_slice(body);
+
+        let cipher = ChaCha20Poly1305::new(GenericArray::from_slice(&K::key()));
+        cipher
+            .decrypt_in_place_detached(
+                GenericArray::from_slice(nonce),
+                &[],
+                plain,
+                GenericArray::from_slice(tag),
+            )
+            .map_err(|_| EncryptedError::Crypto(CryptoError::AuthenticationFailed))?;
+        C::decode(plain).map_err(EncryptedError::Inner)
+    }
+}
@@ -4,23 +4,144 @@
 // It also allows us to encode and decode data
 // using the Codec trait
 
-use crate::codec::Codec;
-use crate::kv::KvStore;
+use crate::cache::{CachePolicy, Lru};
+use crate::clock::Clock;
+use crate::codec::{BorrowedCodec, Codec, CodecErrorKind, FieldCodec, FieldError, Format};
+use crate::crc32;
+#[cfg(feature = "persistence")]
+use crate::crypto::ImageCipher;
+#[cfg(feature = "persistence")]
+use crate::emergency::Partition;
+#[cfg(feature = "persistence")]
+use crate::hybrid::HybridTime;
+#[cfg(feature = "persistence")]
+use crate::image::{self, Artifact, Footer, ImageHeader, Sealing, HEADER_SIZE};
+#[cfg(feature = "persistence")]
+use crate::keycodec;
+use crate::kv::{BlobStore, KvStore, SortedStore, StoreError};
+#[cfg(feature = "persistence")]
+use crate::maintenance::PersistPolicy;
+#[cfg(feature = "persistence")]
+use crate::namespace;
+#[cfg(feature = "persistence")]
+use crate::tlv;
+use core::cell::RefCell;
+#[cfg(feature = "persistence")]
 use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+#[cfg(feature = "persistence")]
+use heapless::String;
 use heapless::{LinearMap, Vec};
 
-pub struct Database<K, V, C, const N: usize, const B: usize, const CACH: usize>
-where
+pub use crate::queue::Queue;
+
+/// Largest image save_to_flash writes, and so the size of the flash region
+/// it needs (rounded up to whole pages)
+#[cfg(feature = "persistence")]
+pub const MAX_IMAGE_SIZE: usize = 8192;
+
+/// Most puts/deletes one transaction can stage
+pub const MAX_TXN_OPS: usize = 8;
+
+/// Most computed keys one database can have
+pub const MAX_COMPUTED: usize = 4;
+
+/// How many decoded values get_uncached() remembers, see set_decode_memo()
+pub const DECODE_MEMO: usize = 4;
+
+// S picks how the encoded values are kept, see kv.rs. The default KvStore is
+// a hash map, Database::with_store(SortedStore::new()) keeps keys in order
+// (and takes keys without a Hash impl) and ArenaStore shares one byte arena
+// between all values.
+// CP picks which cached value goes when the cache is full, see cache.rs.
+pub struct Database<
+    K,
+    V,
+    C,
+    const N: usize,
+    const B: usize,
+    const CACH: usize,
+    S = KvStore<K, Vec<u8, B>, N>,
+    CP = Lru<K, CACH>,
+> where
     C: Codec<V>,
-    K: Eq + core::hash::Hash + Clone,
+    K: Eq + Clone,
     V: serde::Serialize + serde::de::DeserializeOwned + Clone,
 {
-    blobs: KvStore<K, Vec<u8, B>, N>,
+    blobs: S,
     // This cache is a small hot cache to speed up operations
     // The LinearMap is a fixed-size map that is used to store the data
-    // When the cache is full, the oldest entry is evicted
-    // I'm not sure if this is the best way to do this
+    // When the cache is full, cache_policy picks the entry to evict
     cache: LinearMap<K, V, CACH>,
+    cache_policy: CP,
+    // Values get_uncached() decoded, with the CRC of their blob. A RefCell
+    // because get_uncached() only borrows the database.
+    memo_enabled: bool,
+    decode_memo: RefCell<Vec<(K, u32, V), DECODE_MEMO>>,
+    // Keys the cache policy never sees, see cache_pin()
+    pinned: Vec<K, CACH>,
+    // Bumped on every change, saved_generation is its value at the last
+    // save or load. See needs_persist().
+    generation: u32,
+    #[cfg(feature = "persistence")]
+    saved_generation: u32,
+    // Generation of the last successful save (None after a wipe), and a
+    // count of loads and wipes that invalidates older tokens. See
+    // is_persisted().
+    #[cfg(feature = "persistence")]
+    durable_generation: Option<u32>,
+    #[cfg(feature = "persistence")]
+    epoch: u32,
+    // Drives maybe_persist(), with what was written since the last save
+    #[cfg(feature = "persistence")]
+    persist_policy: Option<PersistPolicy>,
+    changed_bytes: usize,
+    #[cfg(feature = "persistence")]
+    saved_at_us: u64,
+    // Cached values put() hasn't stored yet, see set_write_back()
+    write_back: bool,
+    dirty: Vec<K, CACH>,
+    // Stamped into the image header on every save
+    #[cfg(feature = "persistence")]
+    app_version: u32,
+    #[cfg(feature = "persistence")]
+    device_id: u64,
+    // Saved at the end of the payload, see start_boot()
+    #[cfg(feature = "persistence")]
+    boot_count: u32,
+    // Flash offset of the last image we saved or loaded, used by get_in_flash
+    #[cfg(feature = "persistence")]
+    persisted_at: Option<u32>,
+    // Optional second region that gets a mirror copy on every save
+    #[cfg(feature = "persistence")]
+    backup_offset: Option<u32>,
+    #[cfg(feature = "persistence")]
+    loaded_from: Option<ImageSource>,
+    // Called before anything is erased/written, false means the supply is too low
+    #[cfg(feature = "persistence")]
+    supply_check: Option<fn() -> bool>,
+    // Counters behind stats()
+    stats: Stats,
+    // Microsecond timestamps for the load timing report, see set_clock()
+    #[cfg(feature = "persistence")]
+    clock: Option<fn() -> u64>,
+    #[cfg(feature = "persistence")]
+    load_timing: LoadTiming,
+    // Room kept free for critical keys, see reserve()
+    reserved: Option<Reservation<K>>,
+    // Picks the format put() writes per key, see set_format_selector()
+    format_of: Option<fn(&K) -> Format>,
+    // Keys whose value is worked out on every get(), see compute()
+    computed: ComputedKeys<K, V, C, N, B, CACH, S, CP>,
+    // Secondary index, see set_index(). Rebuilt on the next lookup after
+    // anything but put/delete changed the store.
+    index: Option<fn(&V) -> IndexKey>,
+    index_entries: LinearMap<K, IndexKey, N>,
+    index_stale: bool,
+    // Deadlines of the entries stored with put_with_ttl, in ticks
+    expiry: LinearMap<K, u64, N>,
+    ticks: u64,
+    // Where the ticks come from instead, see set_ttl_clock()
+    ttl_clock: Option<fn() -> u64>,
     _c: core::marker::PhantomData<C>,
 }
 
@@ -31,201 +152,2302 @@ where
     V: serde::Serialize + serde::de::DeserializeOwned + Clone,
 {
     pub const fn new() -> Self {
+        Self::with_store(KvStore::<K, Vec<u8, B>, N>::new())
+    }
+}
+
+impl<K, V, C, CP, const N: usize, const B: usize, const CACH: usize>
+    Database<K, V, C, N, B, CACH, SortedStore<K, N, B>, CP>
+where
+    C: Codec<V>,
+    K: Ord + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    /// Entries with keys in range, in key order, decoded as the iterator goes
+    /// e.g. db.range(t1..t2) for timestamp keys.
+    pub fn range<R>(&self, range: R) -> impl Iterator<Item = (&K, Result<V, C::Error>)>
+    where
+        R: core::ops::RangeBounds<K>,
+    {
+        self.blobs
+            .range(range)
+            .map(|(k, blob)| (k, C::decode(blob)))
+    }
+}
+
+impl<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>
+    Database<K, V, C, N, B, CACH, S, CP>
+where
+    C: FieldCodec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    /// Set one field of the value of key, see FieldCodec
+    /// The stored bytes are changed in place, nothing is encoded again. The
+    /// result is decoded once to check it (and to update the cached value).
+    /// Returns false if key has no value.
+    pub fn update_field(&mut self, key: &K, field: &str, value: &[u8]) -> Result<bool, FieldError> {
+        // A write-back value isn't in the store yet
+        if self.dirty.contains(key) {
+            self.flush().map_err(|e| match e {
+                DbError::Full => FieldError::Full,
+                DbError::TooLarge => FieldError::TooLarge,
+                DbError::Reserved => FieldError::Reserved,
+                _ => FieldError::Encode,
+            })?;
+        }
+        let mut tmp = [0u8; B];
+        let used = match self.blobs.get(key) {
+            Some(blob) => C::update_field(blob, field, value, &mut tmp)?,
+            None => return Ok(false),
+        };
+        let val = C::decode(&tmp[..used]).map_err(|_| FieldError::Malformed)?;
+        if !self.reservation_allows(key, used) {
+            return Err(FieldError::Reserved);
+        }
+
+        self.blobs
+            .insert(key.clone(), &tmp[..used])
+            .map_err(FieldError::from)?;
+        if let Some(index) = self.index {
+            let _ = self.index_entries.insert(key.clone(), index(&val));
+        }
+        if let Some(cached) = self.cache.get_mut(key) {
+            *cached = val;
+        }
+        self.changed_bytes = self.changed_bytes.saturating_add(used);
+        self.changed();
+        Ok(true)
+    }
+}
+
+impl<K, V, C, S, const N: usize, const B: usize, const CACH: usize> Database<K, V, C, N, B, CACH, S>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+{
+    /// Database on top of a specific store, e.g. SortedStore::new()
+    pub const fn with_store(store: S) -> Self {
+        Self::with_store_and_policy(store, Lru::new())
+    }
+}
+
+impl<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>
+    Database<K, V, C, N, B, CACH, S, CP>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    /// Same as with_store, with a cache policy other than Lru
+    pub const fn with_store_and_policy(store: S, policy: CP) -> Self {
+        const {
+            if let Some(max) = C::MAX_ENCODED_SIZE {
+                assert!(max <= B, "B is smaller than the codec's MAX_ENCODED_SIZE");
+            }
+        }
         Self {
-            blobs: KvStore::<K, Vec<u8, B>, N>::new(),
+            blobs: store,
             cache: LinearMap::new(),
+            cache_policy: policy,
+            memo_enabled: false,
+            decode_memo: RefCell::new(Vec::new()),
+            pinned: Vec::new(),
+            generation: 0,
+            #[cfg(feature = "persistence")]
+            saved_generation: 0,
+            #[cfg(feature = "persistence")]
+            durable_generation: None,
+            #[cfg(feature = "persistence")]
+            epoch: 0,
+            #[cfg(feature = "persistence")]
+            persist_policy: None,
+            changed_bytes: 0,
+            #[cfg(feature = "persistence")]
+            saved_at_us: 0,
+            write_back: false,
+            dirty: Vec::new(),
+            #[cfg(feature = "persistence")]
+            app_version: 0,
+            #[cfg(feature = "persistence")]
+            device_id: 0,
+            #[cfg(feature = "persistence")]
+            boot_count: 0,
+            #[cfg(feature = "persistence")]
+            persisted_at: None,
+            #[cfg(feature = "persistence")]
+            backup_offset: None,
+            #[cfg(feature = "persistence")]
+            loaded_from: None,
+            #[cfg(feature = "persistence")]
+            supply_check: None,
+            stats: Stats {
+                cache_hits: 0,
+                cache_misses: 0,
+                evictions: 0,
+                encode_errors: 0,
+                decode_errors: 0,
+                entries: 0,
+                blob_bytes: 0,
+            },
+            #[cfg(feature = "persistence")]
+            clock: None,
+            #[cfg(feature = "persistence")]
+            load_timing: LoadTiming {
+                flash_read_us: 0,
+                crc_us: 0,
+                unseal_us: 0,
+                decode_us: 0,
+                total_us: 0,
+            },
+            reserved: None,
+            format_of: None,
+            computed: Vec::new(),
+            index: None,
+            index_entries: LinearMap::new(),
+            index_stale: false,
+            expiry: LinearMap::new(),
+            ticks: 0,
+            ttl_clock: None,
             _c: core::marker::PhantomData,
         }
     }
 
-    pub fn put(&mut self, key: K, val: V) -> Result<(), ()> {
-        let mut tmp = [0u8; B];
-        let used = C::encode(&mut tmp, &val).map_err(|_| ())?;
+    /// Set the firmware version and device ID written into the image header
+    /// device_id is usually flash::device_id() on the nRF52840
+    #[cfg(feature = "persistence")]
+    pub fn set_version_stamp(&mut self, app_version: u32, device_id: u64) {
+        self.app_version = app_version;
+        self.device_id = device_id;
+    }
+
+    /// Mirror every save into a second flash region
+    /// If the primary image fails its header/CRC checks on load (or was
+    /// erased by a save that lost power), open() falls back to this copy.
+    /// Each copy takes up image::region_len(erase_size), RegionOverlap if
+    /// the backup is closer than that to the primary. Saves to any other
+    /// offset are checked again.
+    #[cfg(feature = "persistence")]
+    pub fn set_backup_region(
+        &mut self,
+        primary_offset: u32,
+        backup_offset: u32,
+        erase_size: usize,
+    ) -> Result<(), FlashError> {
+        if image::regions_overlap(primary_offset, backup_offset, erase_size) {
+            return Err(FlashError::RegionOverlap);
+        }
+        self.backup_offset = Some(backup_offset);
+        Ok(())
+    }
+
+    /// Refuse to touch flash while check() returns false
+    /// Saves and wipes return FlashError::LowVoltage instead of starting an
+    /// erase that a brown-out could leave half done. On the nRF52840 use
+    /// flash::supply_ok after flash::enable_pof_warning.
+    #[cfg(feature = "persistence")]
+    pub fn set_supply_check(&mut self, check: fn() -> bool) {
+        self.supply_check = Some(check);
+    }
+
+    /// Keep slots and value bytes free for the keys critical() accepts
+    /// Other keys can't use the last `slots` entries of the store or the last
+    /// key_budget_bytes of the image, so e.g. storing a crash report still
+    /// works after telemetry filled everything else. Critical entries that
+    /// exist already count against the reservation. Applies to put() and
+    /// everything built on it. Only value bytes are counted, leave some slack
+    /// for the keys and length fields.
+    pub fn reserve(&mut self, critical: fn(&K) -> bool, slots: usize, key_budget_bytes: usize) {
+        self.reserved = Some(Reservation {
+            critical,
+            slots,
+            bytes: key_budget_bytes,
+        });
+    }
+
+    // Does the store have the slots and bytes for changes, all at once, and
+    // do they leave the reservation alone (see reservation_allows)
+    // changes are (key, new value length) for puts and (key, None) for
+    // deletes, every key at most once. Returns Full or Reserved.
+    fn batch_fits<'k>(
+        &self,
+        changes: impl Iterator<Item = (&'k K, Option<usize>)>,
+    ) -> Result<(), DbError<C::Error>>
+    where
+        K: 'k,
+    {
+        let critical = |key: &K| self.reserved.as_ref().is_some_and(|r| (r.critical)(key));
+        let mut len = self.blobs.len();
+        let mut bytes = self.blob_bytes_used();
+        let (mut critical_slots, mut critical_bytes) = (0, 0);
+        if self.reserved.is_some() {
+            for (_, blob) in self.blobs.iter().filter(|(key, _)| critical(key)) {
+                critical_slots += 1;
+                critical_bytes += blob.len();
+            }
+        }
+
+        // Only ordinary keys that take more room have to leave the reservation
+        let mut grows = false;
+        for (key, new_len) in changes {
+            let old = self.blobs.get(key).map(|blob| blob.len());
+            if let Some(old) = old {
+                len -= 1;
+                bytes -= old;
+                if critical(key) {
+                    critical_slots -= 1;
+                    critical_bytes -= old;
+                }
+            }
+            if let Some(new_len) = new_len {
+                len += 1;
+                bytes += new_len;
+                if critical(key) {
+                    critical_slots += 1;
+                    critical_bytes += new_len;
+                } else {
+                    grows |= old.is_none_or(|old| new_len > old);
+                }
+            }
+        }
+        if len > self.blobs.capacity() || bytes > self.blobs.byte_capacity() {
+            return Err(DbError::Full);
+        }
+
+        match &self.reserved {
+            Some(r) if grows => {
+                let owed_slots = r.slots.saturating_sub(critical_slots);
+                let owed_bytes = r.bytes.saturating_sub(critical_bytes);
+                if len + owed_slots > self.blobs.capacity()
+                    || bytes + owed_bytes > self.reservation_budget()
+                {
+                    return Err(DbError::Reserved);
+                }
+                Ok(())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Store blobs batch_fits said fit. Values that don't grow go first, so an
+    // ArenaStore has the bytes for the ones that do. puts() lists them.
+    fn put_blobs<'k, I>(&mut self, puts: impl Fn() -> I)
+    where
+        K: 'k,
+        I: Iterator<Item = (&'k K, &'k [u8])>,
+    {
+        for growing in [false, true] {
+            for (key, blob) in puts() {
+                let grows = self.blobs.get(key).is_none_or(|old| blob.len() > old.len());
+                if grows != growing {
+                    continue;
+                }
+                // Can't fail, batch_fits checked the slots and bytes
+                let _ = self.blobs.insert(key.clone(), blob);
+                self.cache_remove(key);
+                let _ = self.expiry.remove(key);
+                self.index_stale = true;
+                self.changed();
+            }
+        }
+    }
+
+    // Would storing new_len value bytes under key eat into the reservation
+    fn reservation_allows(&self, key: &K, new_len: usize) -> bool {
+        let r = match &self.reserved {
+            Some(r) if !(r.critical)(key) => r,
+            _ => return true,
+        };
+
+        let (mut bytes, mut critical_slots, mut critical_bytes) = (0, 0, 0);
+        for (k, blob) in self.blobs.iter() {
+            bytes += blob.len();
+            if (r.critical)(k) {
+                critical_slots += 1;
+                critical_bytes += blob.len();
+            }
+        }
+        let owed_slots = r.slots.saturating_sub(critical_slots);
+        let owed_bytes = r.bytes.saturating_sub(critical_bytes);
+
+        let existing = self.blobs.get(key).map(|blob| blob.len());
+        let len = self.blobs.len() + existing.is_none() as usize;
+        let bytes = bytes - existing.unwrap_or(0) + new_len;
+        len + owed_slots <= self.blobs.capacity() && bytes + owed_bytes <= self.reservation_budget()
+    }
+
+    // Value bytes the reservation comes out of, the image payload when the
+    // database gets saved and the store otherwise
+    fn reservation_budget(&self) -> usize {
+        #[cfg(feature = "persistence")]
+        return image::MAX_IMAGE_LEN - HEADER_SIZE;
+        #[cfg(not(feature = "persistence"))]
+        self.blobs.byte_capacity()
+    }
+
+    /// Cache and store counters since start (or reset_stats), for tuning
+    /// CACH and B on real workloads
+    pub fn stats(&self) -> Stats {
+        Stats {
+            entries: self.blobs.len(),
+            blob_bytes: self.blob_bytes_used(),
+            ..self.stats
+        }
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+    }
+
+    /// Time every open()/load_from_flash with now_us (a free running
+    /// microsecond counter, e.g. an RTC or TIMER), see load_timing()
+    #[cfg(feature = "persistence")]
+    pub fn set_clock(&mut self, now_us: fn() -> u64) {
+        self.clock = Some(now_us);
+    }
+
+    /// Where the last open()/load_from_flash spent its time
+    /// All zero unless a clock was set. A fallback to the backup image
+    /// counts both attempts.
+    #[cfg(feature = "persistence")]
+    pub fn load_timing(&self) -> LoadTiming {
+        self.load_timing
+    }
+
+    /// Count this boot, call once per reset after loading
+    /// The count is saved with the image, it needs a save to stick.
+    /// Returns the new boot count, see hybrid.rs.
+    #[cfg(feature = "persistence")]
+    pub fn start_boot(&mut self) -> u32 {
+        self.boot_count = self.boot_count.wrapping_add(1);
+        self.changed();
+        self.boot_count
+    }
 
-        let mut blob = Vec::<u8, B>::new();
-        blob.extend_from_slice(&tmp[..used]).map_err(|_| ())?;
+    #[cfg(feature = "persistence")]
+    pub fn boot_count(&self) -> u32 {
+        self.boot_count
+    }
+
+    /// The boot count and the time on the clock from set_clock()
+    /// Without a clock the ticks are always 0.
+    #[cfg(feature = "persistence")]
+    pub fn hybrid_now(&self) -> HybridTime {
+        HybridTime {
+            boot: self.boot_count,
+            ticks: self.now_us(),
+        }
+    }
+
+    #[cfg(feature = "persistence")]
+    fn now_us(&self) -> u64 {
+        self.clock.map_or(0, |now| now())
+    }
+
+    /// Choose per key which format put() writes, for codecs that can write
+    /// more than one (codec::Multi). Other codecs ignore it.
+    pub fn set_format_selector(&mut self, format_of: fn(&K) -> Format) {
+        self.format_of = Some(format_of);
+    }
 
-        let _ = self.blobs.put(key.clone(), blob).map_err(|_| ())?;
+    fn encode(&self, key: &K, dst: &mut [u8], val: &V) -> Result<usize, C::Error> {
+        match self.format_of {
+            Some(format_of) => C::encode_as(dst, val, format_of(key)),
+            None => C::encode(dst, val),
+        }
+    }
+
+    #[cfg(feature = "persistence")]
+    fn check_supply(&self) -> Result<(), FlashError> {
+        match self.supply_check {
+            Some(ok) if !ok() => Err(FlashError::LowVoltage),
+            _ => Ok(()),
+        }
+    }
+
+    /// Which copy the last successful load came from
+    #[cfg(feature = "persistence")]
+    pub fn loaded_from(&self) -> Option<ImageSource> {
+        self.loaded_from
+    }
 
-        if self.cache.is_full() {
-            if let Some((k0, _)) = self.cache.iter().next() {
-                let victim = k0.clone();
-                let _ = self.cache.remove(&victim);
+    pub fn put(&mut self, key: K, val: V) -> Result<(), DbError<C::Error>> {
+        let mut tmp = [0u8; B];
+        let used = self.encode_checked(&key, &mut tmp, &val)?;
+        // Only a value that fits where the stored one is waits in the cache,
+        // so flush() never needs a slot or bytes it might not get
+        if self.write_back && self.blobs.get(&key).is_some_and(|old| used <= old.len()) {
+            let _ = self.expiry.remove(&key);
+            if let Some(index) = self.index {
+                let _ = self.index_entries.insert(key.clone(), index(&val));
             }
+            if self.cache_insert(key.clone(), val.clone())? {
+                if !self.dirty.contains(&key) {
+                    // Can't be full, only cached keys are dirty
+                    let _ = self.dirty.push(key);
+                }
+                self.changed();
+                return Ok(());
+            }
+            // No room in the cache, write through
+        }
+
+        self.store_encoded(&key, &tmp[..used], &val)?;
+        self.cache_insert(key, val)?;
+        Ok(())
+    }
+
+    // encode() counting the failures
+    fn encode_checked(
+        &mut self,
+        key: &K,
+        dst: &mut [u8],
+        val: &V,
+    ) -> Result<usize, DbError<C::Error>> {
+        self.encode(key, dst, val).map_err(|e| {
+            self.stats.encode_errors += 1;
+            DbError::Encode(e)
+        })
+    }
+
+    // Encode val and put it into the blob store
+    fn store(&mut self, key: &K, val: &V) -> Result<(), DbError<C::Error>> {
+        let mut tmp = [0u8; B];
+        let used = self.encode_checked(key, &mut tmp, val)?;
+        self.store_encoded(key, &tmp[..used], val)
+    }
+
+    // Put the encoding blob of val into the blob store
+    fn store_encoded(&mut self, key: &K, blob: &[u8], val: &V) -> Result<(), DbError<C::Error>> {
+        let used = blob.len();
+        // A value that doesn't grow takes none of the reserved room
+        let grows = self.blobs.get(key).is_none_or(|old| used > old.len());
+        if grows && !self.reservation_allows(key, used) {
+            return Err(DbError::Reserved);
+        }
+
+        self.blobs
+            .insert(key.clone(), blob)
+            .map_err(DbError::from)?;
+        let _ = self.expiry.remove(key);
+        if let Some(index) = self.index {
+            // Can't be full, there is a slot for every entry in the store
+            let _ = self.index_entries.insert(key.clone(), index(val));
+        }
+        self.changed_bytes = self.changed_bytes.saturating_add(used);
+        self.changed();
+        Ok(())
+    }
+
+    /// Keep put() values in the cache only, until flush() or an eviction
+    /// For values that change many times between saves, each put() then
+    /// leaves the store alone. put() still encodes the value, so encode
+    /// errors show up right away, and only a value that fits in the bytes of
+    /// the stored one waits in the cache: new keys and values that grow are
+    /// written through, so flush() never runs out of room. save_to_flash()
+    /// and transaction() flush first, but everything else that reads the
+    /// store directly (iter(), keys(), len(), export(), get_uncached()) only
+    /// sees flushed values. A put() that finds the cache full of pinned keys
+    /// writes through. Turning it off flushes.
+    pub fn set_write_back(&mut self, enabled: bool) -> Result<(), DbError<C::Error>> {
+        if !enabled {
+            self.flush()?;
         }
-        let _ = self.cache.insert(key, val);
+        self.write_back = enabled;
         Ok(())
     }
 
-    pub fn get(&mut self, key: &K) -> Result<Option<V>, ()> {
+    /// Encode and store every value put() left in the cache (write-back)
+    /// Returns how many were written. On an error the rest stays dirty.
+    pub fn flush(&mut self) -> Result<usize, DbError<C::Error>> {
+        let mut written = 0;
+        while let Some(key) = self.dirty.last().cloned() {
+            if let Some(val) = self.cache.get(&key).cloned() {
+                self.store(&key, &val)?;
+                written += 1;
+            }
+            self.dirty.pop();
+        }
+        Ok(written)
+    }
+
+    /// Whether anything changed since the last save_to_flash() or load
+    /// For saving on a timer without erasing flash for nothing:
+    /// if db.needs_persist() { db.save_to_flash(...)?; }
+    #[cfg(feature = "persistence")]
+    pub fn needs_persist(&self) -> bool {
+        self.generation != self.saved_generation
+    }
+
+    /// Counts changes to the entries, wraps around
+    /// Compare two readings to see whether anything changed in between.
+    pub fn generation(&self) -> u32 {
+        self.generation
+    }
+
+    /// Token for everything written so far, for is_persisted()
+    /// Puts still waiting in the write-back cache count as written.
+    /// let token = db.sync_epoch();
+    /// ...
+    /// if db.is_persisted(token) { ack(command_id); }
+    #[cfg(feature = "persistence")]
+    pub fn sync_epoch(&self) -> SyncToken {
+        // flush() bumps the generation at least once for the dirty keys
+        let pending = !self.dirty.is_empty() as u32;
+        SyncToken {
+            epoch: self.epoch,
+            generation: self.generation.wrapping_add(pending),
+        }
+    }
+
+    /// Whether the state token was taken at is on flash now
+    /// Anything saved later counts, the token only has to be older than the
+    /// last successful save. Tokens taken before a load or secure_wipe()
+    /// never report persisted, that state was replaced.
+    #[cfg(feature = "persistence")]
+    pub fn is_persisted(&self, token: SyncToken) -> bool {
+        // Wrapping compare, the saved generation is at or past the token
+        token.epoch == self.epoch
+            && self
+                .durable_generation
+                .is_some_and(|saved| (saved.wrapping_sub(token.generation) as i32) >= 0)
+    }
+
+    // Where the last image this database loaded or saved is
+    #[cfg(feature = "persistence")]
+    pub(crate) fn persisted_at(&self) -> Option<u32> {
+        self.persisted_at
+    }
+
+    fn changed(&mut self) {
+        self.generation = self.generation.wrapping_add(1);
+    }
+
+    // The low-power saver finished writing the state of generation
+    #[cfg(feature = "persistence")]
+    pub(crate) fn mark_persisted(&mut self, flash_offset: u32, generation: u32) {
+        self.mark_saved();
+        self.persisted_at = Some(flash_offset);
+        self.saved_generation = generation;
+        self.durable_generation = Some(generation);
+    }
+
+    // The state in RAM was replaced by a load or a wipe, older tokens don't
+    // describe it. durable is what's on flash now, if any of it.
+    #[cfg(feature = "persistence")]
+    fn new_epoch(&mut self, durable: bool) {
+        self.epoch = self.epoch.wrapping_add(1);
+        self.durable_generation = durable.then_some(self.generation);
+    }
+
+    #[cfg(feature = "persistence")]
+    fn mark_saved(&mut self) {
+        self.saved_generation = self.generation;
+        self.changed_bytes = 0;
+        self.saved_at_us = self.now_us();
+    }
+
+    /// Let maybe_persist() decide when to save, see PersistPolicy
+    #[cfg(feature = "persistence")]
+    pub fn set_persist_policy(&mut self, policy: PersistPolicy) {
+        self.persist_policy = Some(policy);
+    }
+
+    /// Save if the persist policy says it is time, returns whether it saved
+    /// Cheap when nothing is due, call it from the main loop. Without a
+    /// policy this never saves. Databases kept with save_to_flash_sealed
+    /// pass their cipher, a plain save would replace the sealed image.
+    #[cfg(feature = "persistence")]
+    pub fn maybe_persist<F>(
+        &mut self,
+        flash: &mut F,
+        cipher: Option<&mut dyn ImageCipher>,
+    ) -> Result<bool, FlashError>
+    where
+        F: NorFlash,
+        K: serde::Serialize,
+    {
+        let policy = match self.persist_policy {
+            Some(policy) if self.needs_persist() => policy,
+            _ => return Ok(false),
+        };
+        let changes = self.generation.wrapping_sub(self.saved_generation);
+        let due = (policy.after_changes != 0 && changes >= policy.after_changes)
+            || (policy.after_bytes != 0 && self.changed_bytes >= policy.after_bytes)
+            || policy
+                .should_persist
+                .is_some_and(|f| f(self.now_us().wrapping_sub(self.saved_at_us)));
+        if !due {
+            return Ok(false);
+        }
+        self.save_image(
+            flash,
+            core::mem::size_of::<u32>(),
+            policy.flash_offset,
+            &mut |_| {},
+            cipher,
+        )?;
+        Ok(true)
+    }
+
+    /// put() for keys that already exist
+    /// Returns false (and writes nothing) if key isn't in the database, so a
+    /// typo'd key doesn't quietly become a new entry.
+    pub fn update(&mut self, key: K, val: V) -> Result<bool, DbError<C::Error>> {
+        if !self.contains_key(&key) {
+            return Ok(false);
+        }
+        self.put(key, val)?;
+        Ok(true)
+    }
+
+    /// Write new only if the current value of key equals expected
+    /// Returns whether it was written, a missing key never matches.
+    /// Wrap the call in a critical section if an interrupt can change the
+    /// key in between.
+    pub fn compare_and_swap(
+        &mut self,
+        key: K,
+        expected: &V,
+        new: V,
+    ) -> Result<bool, DbError<C::Error>>
+    where
+        V: PartialEq,
+    {
+        match self.get(&key)? {
+            Some(current) if current == *expected => {
+                self.put(key, new)?;
+                Ok(true)
+            }
+            _ => Ok(false),
+        }
+    }
+
+    /// Add delta to a counter, starting at zero if key doesn't exist yet
+    /// Saturates instead of wrapping. Returns the new value.
+    pub fn incr(&mut self, key: K, delta: V) -> Result<V, DbError<C::Error>>
+    where
+        V: Counter,
+    {
+        let val = self.get(&key)?.unwrap_or_default().add(delta);
+        self.put(key, val)?;
+        Ok(val)
+    }
+
+    /// incr() the other way, unsigned counters stop at zero
+    pub fn decr(&mut self, key: K, delta: V) -> Result<V, DbError<C::Error>>
+    where
+        V: Counter,
+    {
+        let val = self.get(&key)?.unwrap_or_default().sub(delta);
+        self.put(key, val)?;
+        Ok(val)
+    }
+
+    /// Get the value of key, storing default() first if it isn't there yet
+    pub fn get_or_insert_with<F>(&mut self, key: K, default: F) -> Result<V, DbError<C::Error>>
+    where
+        F: FnOnce() -> V,
+    {
+        if let Some(val) = self.get(&key)? {
+            return Ok(val);
+        }
+        let val = default();
+        self.put(key, val.clone())?;
+        Ok(val)
+    }
+
+    /// Make key a computed entry, f runs on every get() of it
+    /// For derived values ("temp_f", "uptime_hours") that should be readable
+    /// like any other key. Computed entries shadow stored ones, are never
+    /// cached or persisted and don't show up in iter()/keys().
+    ///
+    /// db.compute(KEY_TEMP_F, |db| {
+    ///     let c = db.get_uncached(&KEY_TEMP_C).ok()??;
+    ///     Some(c * 9 / 5 + 32)
+    /// })?;
+    pub fn compute(
+        &mut self,
+        key: K,
+        f: Computed<K, V, C, N, B, CACH, S, CP>,
+    ) -> Result<(), DbError<C::Error>> {
+        if let Some((_, existing)) = self.computed.iter_mut().find(|(k, _)| *k == key) {
+            *existing = f;
+            return Ok(());
+        }
+        self.computed
+            .push((key, f))
+            .map_err(|_| DbError::TooManyComputed)
+    }
+
+    fn is_computed(&self, key: &K) -> bool {
+        self.computed.iter().any(|(k, _)| k == key)
+    }
+
+    fn computed(&self, key: &K) -> Option<Option<V>> {
+        let (_, f) = self.computed.iter().find(|(k, _)| k == key)?;
+        Some(f(self))
+    }
+
+    pub fn get(&mut self, key: &K) -> Result<Option<V>, DbError<C::Error>> {
+        if let Some(v) = self.computed(key) {
+            return Ok(v);
+        }
+        if self.expired(key) {
+            self.delete(key);
+            return Ok(None);
+        }
         if let Some(v) = self.cache.get(key).cloned() {
+            if !self.pinned.contains(key) {
+                self.cache_policy.touch(key);
+            }
+            self.stats.cache_hits += 1;
             return Ok(Some(v));
         }
-        let blob_opt: Option<&Vec<u8, B>> = self.blobs.get(key);
-        let blob = match blob_opt {
+        self.stats.cache_misses += 1;
+        let blob = match self.blobs.get(key) {
             Some(b) => b,
             None => return Ok(None),
         };
 
-        let val = C::decode(blob.as_slice()).map_err(|_| ())?;
-
-        if self.cache.is_full() {
-            if let Some((k0, _)) = self.cache.iter().next() {
-                let victim = k0.clone();
-                let _ = self.cache.remove(&victim);
+        let val = match C::decode(blob) {
+            Ok(val) => val,
+            Err(e) => {
+                self.stats.decode_errors += 1;
+                return Err(DbError::Decode(e));
             }
-        }
-        let _ = self.cache.insert(key.clone(), val.clone());
+        };
+        self.cache_insert(key.clone(), val.clone())?;
 
         Ok(Some(val))
     }
 
-    pub fn get_uncached(&self, key: &K) -> Result<Option<V>, ()> {
-        let blob_opt: Option<&Vec<u8, B>> = self.blobs.get(key);
-        let blob = match blob_opt {
+    /// Decode the value of key as T borrowing from the stored bytes and lend
+    /// it to f, see BorrowedCodec. Returns what f returns.
+    /// The cache holds V values so it isn't used, and a computed key has no
+    /// stored bytes (None). A write-back value is flushed first.
+    pub fn get_with<'s, T, R>(
+        &'s mut self,
+        key: &K,
+        f: impl FnOnce(T) -> R,
+    ) -> Result<Option<R>, DbError<<C as Codec<V>>::Error>>
+    where
+        C: BorrowedCodec<'s, T, Error = <C as Codec<V>>::Error>,
+    {
+        if self.expired(key) {
+            self.delete(key);
+            return Ok(None);
+        }
+        if self.dirty.contains(key) {
+            self.flush()?;
+        }
+        let Self { blobs, stats, .. } = self;
+        let blob = match blobs.get(key) {
             Some(b) => b,
             None => return Ok(None),
         };
-        C::decode(blob.as_slice()).map(Some).map_err(|_| ())
+        match C::decode_borrowed(blob) {
+            Ok(val) => Ok(Some(f(val))),
+            Err(e) => {
+                stats.decode_errors += 1;
+                Err(DbError::Decode(e))
+            }
+        }
     }
 
-    pub fn delete(&mut self, key: &K) -> bool {
-        let removed = self.blobs.remove(key).is_some();
-        let _ = self.cache.remove(key);
-        removed
+    /// get() for several keys in one pass, out[i] gets the value of keys[i]
+    /// Cached values are filled in first and the rest decoded after, so the
+    /// decoded values can't evict a cached one the same call still needs.
+    /// Keys past the end of out (or slots past the end of keys) are skipped.
+    pub fn get_many(&mut self, keys: &[K], out: &mut [Option<V>]) -> Result<(), DbError<C::Error>> {
+        let mut misses = 0;
+        for (key, slot) in keys.iter().zip(out.iter_mut()) {
+            *slot = None;
+            if self.expired(key) {
+                self.delete(key);
+            }
+            if let Some(v) = self.computed(key) {
+                *slot = v;
+            } else if let Some(v) = self.cache.get(key) {
+                *slot = Some(v.clone());
+                if !self.pinned.contains(key) {
+                    self.cache_policy.touch(key);
+                }
+                self.stats.cache_hits += 1;
+            } else {
+                misses += 1;
+            }
+        }
+        if misses == 0 {
+            return Ok(());
+        }
+
+        for (key, slot) in keys.iter().zip(out.iter_mut()) {
+            if slot.is_some() || self.is_computed(key) {
+                continue;
+            }
+            // Listed twice, decoded earlier in this pass
+            if let Some(v) = self.cache.get(key) {
+                *slot = Some(v.clone());
+                continue;
+            }
+            self.stats.cache_misses += 1;
+            let blob = match self.blobs.get(key) {
+                Some(b) => b,
+                None => continue,
+            };
+            let val = match C::decode(blob) {
+                Ok(val) => val,
+                Err(e) => {
+                    self.stats.decode_errors += 1;
+                    return Err(DbError::Decode(e));
+                }
+            };
+            self.cache_insert(key.clone(), val.clone())?;
+            *slot = Some(val);
+        }
+        Ok(())
     }
 
-    pub fn len(&self) -> usize {
-        self.blobs.len()
+    // Cache val, evicting the entry cache_policy picks if the cache is full
+    // Returns whether val was cached. A dirty victim is stored first.
+    fn cache_insert(&mut self, key: K, val: V) -> Result<bool, DbError<C::Error>> {
+        if self.cache.is_full() && !self.cache.contains_key(&key) {
+            match self.cache_policy.victim() {
+                Some(victim) => {
+                    if self.dirty.contains(&victim) {
+                        if let Some(old) = self.cache.get(&victim).cloned() {
+                            self.store(&victim, &old)?;
+                        }
+                    }
+                    self.cache_remove(&victim);
+                    self.stats.evictions += 1;
+                }
+                None => return Ok(false),
+            }
+        }
+        let cached = self.cache.insert(key.clone(), val).is_ok();
+        if cached && !self.pinned.contains(&key) {
+            self.cache_policy.touch(&key);
+        }
+        Ok(cached)
     }
-    pub fn capacity(&self) -> usize {
-        self.blobs.capacity()
+
+    // Also drops an unflushed (write-back) value
+    fn cache_remove(&mut self, key: &K) {
+        if self.cache.remove(key).is_some() {
+            self.cache_policy.removed(key);
+        }
+        if let Some(i) = self.dirty.iter().position(|k| k == key) {
+            self.dirty.remove(i);
+        }
     }
 
-    /// Save the database to flash storage
-    /// This writes to flash with a simple format:
-    /// [num_entries: u32][key1_len: u32][key1_data][val1_len: u32][val1_data]...
-    ///
-    /// flash_offset: The offset in flash where to write (must be aligned)
-    /// flash: The flash storage device
-    pub fn save_to_flash<F>(
-        &self,
+    /// Drop the cached value of key, the next get() decodes it again
+    /// A pinned key stays pinned. An unflushed write-back value is lost.
+    pub fn cache_invalidate(&mut self, key: &K) {
+        self.cache_remove(key);
+    }
+
+    /// Drop every cached value, pins stay
+    /// Unflushed write-back values are lost, flush() first to keep them.
+    pub fn cache_clear(&mut self) {
+        self.cache.clear();
+        self.cache_policy.clear();
+        self.dirty.clear();
+    }
+
+    /// Keep key in the cache no matter what the cache policy says
+    /// For latency critical keys. The value is decoded right away if it is
+    /// stored, otherwise on the next put(). Up to CACH keys can be pinned,
+    /// but every pin leaves one slot less for the rest.
+    pub fn cache_pin(&mut self, key: &K) -> Result<(), DbError<C::Error>> {
+        if !self.pinned.contains(key) {
+            self.pinned.push(key.clone()).map_err(|_| DbError::Full)?;
+            // The policy only gets to pick from keys that aren't pinned
+            self.cache_policy.removed(key);
+        }
+        if !self.cache.contains_key(key) {
+            self.get(key)?;
+        }
+        Ok(())
+    }
+
+    /// Let the cache policy evict key again
+    pub fn cache_unpin(&mut self, key: &K) {
+        if let Some(i) = self.pinned.iter().position(|k| k == key) {
+            self.pinned.remove(i);
+            if self.cache.contains_key(key) {
+                self.cache_policy.touch(key);
+            }
+        }
+    }
+
+    pub fn get_uncached(&self, key: &K) -> Result<Option<V>, DbError<C::Error>> {
+        if let Some(v) = self.computed(key) {
+            return Ok(v);
+        }
+        if self.expired(key) {
+            return Ok(None);
+        }
+        let blob = match self.blobs.get(key) {
+            Some(b) => b,
+            None => return Ok(None),
+        };
+        if !self.memo_enabled {
+            return C::decode(blob).map(Some).map_err(DbError::Decode);
+        }
+
+        // The CRC tells whether the blob changed since it was memoized
+        let crc = crc32::Crc32.checksum(blob);
+        let mut memo = self.decode_memo.borrow_mut();
+        if let Some((_, _, v)) = memo.iter().find(|(k, c, _)| k == key && *c == crc) {
+            return Ok(Some(v.clone()));
+        }
+        let val = C::decode(blob).map_err(DbError::Decode)?;
+        if let Some(i) = memo.iter().position(|(k, _, _)| k == key) {
+            memo.remove(i);
+        } else if memo.is_full() {
+            memo.remove(0);
+        }
+        let _ = memo.push((key.clone(), crc, val.clone()));
+        Ok(Some(val))
+    }
+
+    /// Remember the last DECODE_MEMO values get_uncached() decoded
+    /// For read-only code that skips the cache on purpose but reads the
+    /// same keys over and over. A memoized value is only used while the
+    /// stored blob is unchanged (same CRC32), so there is nothing to
+    /// invalidate. Costs a CRC over the blob on every get_uncached().
+    pub fn set_decode_memo(&mut self, enabled: bool) {
+        self.memo_enabled = enabled;
+        self.decode_memo.get_mut().clear();
+    }
+
+    /// Get the encoded bytes of a value straight out of memory-mapped flash
+    /// This avoids copying the blob into RAM. The bytes are from the last image
+    /// saved or loaded, so a put() since then is not visible here.
+    /// Returns None if nothing was persisted yet or the key isn't in the image.
+    ///
+    /// # Safety
+    /// The flash the image was saved to must be memory-mapped at the same
+    /// addresses as the offsets used (true for the nRF52840 internal flash),
+    /// and that region must not be erased or rewritten while the returned
+    /// slice is in use.
+    #[cfg(feature = "persistence")]
+    pub unsafe fn get_in_flash(&self, key: &K) -> Option<&'static [u8]>
+    where
+        K: serde::Serialize,
+    {
+        let offset = self.persisted_at?;
+
+        // Read the header first to know how big the image is
+        let header_bytes = core::slice::from_raw_parts(offset as *const u8, HEADER_SIZE);
+        let header = ImageHeader::from_bytes(header_bytes).ok()??;
+        let image = core::slice::from_raw_parts(
+            offset as *const u8,
+            HEADER_SIZE + header.payload_len as usize,
+        );
+
+        // records() refuses sealed images, they have to be opened with the key first
+        let mut records = image::records(image).ok()?;
+        let mut key_buf = [0u8; B];
+        let key_bytes = match records.key_width() {
+            0 => postcard::to_slice(key, &mut key_buf).ok()?,
+            width => {
+                let len = keycodec::encode_fixed(key, width, &mut key_buf)?;
+                &key_buf[..len]
+            }
+        };
+        records
+            .find(|(stored_key, _)| *stored_key == key_bytes)
+            .map(|(_, value)| value)
+    }
+
+    /// Is key in the database, without decoding anything
+    pub fn contains_key(&self, key: &K) -> bool {
+        (self.blobs.get(key).is_some() || self.dirty.contains(key)) && !self.expired(key)
+    }
+
+    /// The stored key and the encoded bytes of its value
+    /// Neither the codec nor the cache are involved.
+    pub fn get_key_value(&self, key: &K) -> Option<(&K, &[u8])> {
+        self.blobs.get_key_value(key)
+    }
+
+    pub fn delete(&mut self, key: &K) -> bool {
+        // A write-back value that was never flushed counts too
+        let removed = self.blobs.remove(key) | self.dirty.contains(key);
+        self.cache_remove(key);
+        let _ = self.expiry.remove(key);
+        let _ = self.index_entries.remove(key);
+        if removed {
+            self.changed();
+        }
+        removed
+    }
+
+    /// Move the value of old_key to new_key, without decoding or encoding it
+    /// The cached value, TTL deadline and index entry move along, a pin
+    /// stays with old_key (as with delete). Returns false if old_key has no
+    /// value (or it expired), DbError::Exists if new_key has one. Nothing
+    /// changes on error.
+    pub fn rename(&mut self, old_key: &K, new_key: K) -> Result<bool, DbError<C::Error>> {
+        if !self.contains_key(old_key) {
+            return Ok(false);
+        }
+        if *old_key == new_key {
+            return Ok(true);
+        }
+        if self.contains_key(&new_key) {
+            return Err(DbError::Exists);
+        }
+
+        // A write-back value isn't in the store yet, it moves encoded
+        let mut tmp = [0u8; B];
+        let cached = self.cache.get(old_key).cloned();
+        let used = match &cached {
+            Some(val) if self.dirty.contains(old_key) => {
+                self.encode_checked(old_key, &mut tmp, val)?
+            }
+            _ => {
+                let blob = self.blobs.get(old_key).ok_or(DbError::TooLarge)?;
+                let dst = tmp.get_mut(..blob.len()).ok_or(DbError::TooLarge)?;
+                dst.copy_from_slice(blob);
+                blob.len()
+            }
+        };
+        // An expired value of new_key is replaced like any other
+        self.batch_fits([(old_key, None), (&new_key, Some(used))].into_iter())?;
+
+        // Nothing below can fail. An expired new_key's deadline mustn't
+        // carry over.
+        self.delete(&new_key);
+        self.blobs.remove(old_key);
+        let _ = self.blobs.insert(new_key.clone(), &tmp[..used]);
+        if let Some(deadline) = self.expiry.remove(old_key) {
+            let _ = self.expiry.insert(new_key.clone(), deadline);
+        }
+        if let Some(entry) = self.index_entries.remove(old_key) {
+            let _ = self.index_entries.insert(new_key.clone(), entry);
+        }
+        self.cache_remove(old_key);
+        if let Some(val) = cached {
+            // The value is stored, the cache can do without it
+            let _ = self.cache_insert(new_key, val);
+        }
+        self.changed_bytes = self.changed_bytes.saturating_add(used);
+        self.changed();
+        Ok(true)
+    }
+
+    /// Delete every entry, flash is left alone until the next save
+    /// Pins and settings (codec selector, index, TTL clock...) stay.
+    pub fn clear(&mut self) {
+        self.blobs.clear();
+        self.cache_clear();
+        self.decode_memo.get_mut().clear();
+        self.expiry.clear();
+        self.index_stale = true;
+        self.changed();
+    }
+
+    /// Delete every entry whose key starts with prefix, returns how many
+    /// e.g. db.delete_prefix("log:") for namespaced String keys.
+    pub fn delete_prefix(&mut self, prefix: &str) -> usize
+    where
+        K: AsRef<str>,
+    {
+        let mut deleted = 0;
+        loop {
+            let next = self
+                .blobs
+                .iter()
+                .map(|(key, _)| key)
+                .chain(self.dirty.iter())
+                .find(|key| key.as_ref().starts_with(prefix))
+                .cloned();
+            match next {
+                Some(key) => {
+                    self.delete(&key);
+                    deleted += 1;
+                }
+                None => return deleted,
+            }
+        }
+    }
+
+    /// Index the entries by a field of their value, e.g. the device type
+    ///
+    /// db.set_index(|asset| asset.device_type as IndexKey);
+    /// for key in db.find_by_index(DeviceType::Thermostat as IndexKey) { ... }
+    pub fn set_index(&mut self, index: fn(&V) -> IndexKey) {
+        self.index = Some(index);
+        self.index_stale = true;
+    }
+
+    /// Keys of the entries whose index key is idx, nothing without set_index
+    /// Entries that fail to decode aren't indexed.
+    pub fn find_by_index(&mut self, idx: IndexKey) -> impl Iterator<Item = &K> {
+        if let (Some(index), true) = (self.index, self.index_stale) {
+            self.index_entries.clear();
+            for (key, blob) in self.blobs.iter() {
+                if let Ok(val) = C::decode(blob) {
+                    let _ = self.index_entries.insert(key.clone(), index(&val));
+                }
+            }
+            self.index_stale = false;
+        }
+        self.index_entries
+            .iter()
+            .filter(move |(_, i)| **i == idx)
+            .map(|(key, _)| key)
+    }
+
+    /// put() for an entry that goes away ttl_ticks after now
+    /// Time only moves when tick() is called, so a tick can be whatever
+    /// the application likes (seconds from the RTC, 100ms timer events, ...),
+    /// unless a clock was set with set_ttl_clock(). A plain put() on the key
+    /// makes it permanent again. Deadlines only live in RAM, an entry that
+    /// is saved and loaded again doesn't expire.
+    pub fn put_with_ttl(
+        &mut self,
+        key: K,
+        val: V,
+        ttl_ticks: u32,
+    ) -> Result<(), DbError<C::Error>> {
+        self.put(key.clone(), val)?;
+        // Can't be full, there is a slot for every entry in the store
+        let _ = self.expiry.insert(key, self.now_ticks() + ttl_ticks as u64);
+        Ok(())
+    }
+
+    /// Take the TTL ticks from clock T instead of tick()
+    /// Reads of an entry past its deadline (get, get_many, get_uncached,
+    /// contains_key) then see it as gone, get() and get_many() delete it.
+    /// Everything else still sees it until purge_expired() or tick().
+    pub fn set_ttl_clock<T: Clock>(&mut self) {
+        self.ttl_clock = Some(T::now);
+    }
+
+    fn now_ticks(&self) -> u64 {
+        self.ttl_clock.map_or(self.ticks, |now| now())
+    }
+
+    // The clock is only read for keys with a deadline
+    fn expired(&self, key: &K) -> bool {
+        self.expiry
+            .get(key)
+            .is_some_and(|deadline| *deadline <= self.now_ticks())
+    }
+
+    /// Advance time by elapsed ticks and drop what expired
+    /// Returns how many entries were dropped. With a TTL clock elapsed is
+    /// ignored, the clock says what time it is.
+    pub fn tick(&mut self, elapsed: u32) -> usize {
+        self.ticks += elapsed as u64;
+        self.purge_expired()
+    }
+
+    /// Drop the entries whose TTL ran out from the store and the cache
+    pub fn purge_expired(&mut self) -> usize {
+        let now = self.now_ticks();
+        let mut purged = 0;
+        loop {
+            let next = self
+                .expiry
+                .iter()
+                .find(|(_, deadline)| **deadline <= now)
+                .map(|(key, _)| key.clone());
+            match next {
+                Some(key) => self.delete(&key),
+                None => break,
+            };
+            purged += 1;
+        }
+        purged
+    }
+
+    /// Read-modify-write a single key with one decode and at most one encode
+    ///
+    /// let boots = db.entry(KEY_BOOTS)?.and_modify(|n| *n += 1).or_insert(1)?;
+    pub fn entry(&mut self, key: K) -> EntryResult<'_, K, V, C, N, B, CACH, S, CP> {
+        let value = self.get(&key)?;
+        Ok(Entry {
+            db: self,
+            key,
+            value,
+            modified: false,
+        })
+    }
+
+    /// Apply several puts/deletes together or not at all
+    /// Changes are staged while f runs and only applied if it returns Ok,
+    /// and only if all of them fit. Otherwise the database is left as it was.
+    ///
+    /// db.transaction(|txn| {
+    ///     txn.put(KEY_SSID, ssid)?;
+    ///     txn.put(KEY_PSK, psk)?;
+    ///     txn.delete(&KEY_OLD_NETWORK)
+    /// })?;
+    ///
+    /// This is all-or-nothing in RAM, call save_to_flash afterwards (or use
+    /// transaction_and_save) to persist the result as one image.
+    pub fn transaction<R, T>(&mut self, f: T) -> Result<R, TxnError>
+    where
+        T: FnOnce(&mut Transaction<'_, K, V, C, N, B, CACH, S, CP>) -> Result<R, TxnError>,
+    {
+        // The transaction reads and replaces the stored values, write-back
+        // values have to be among them
+        self.flush().map_err(|e| match e {
+            DbError::Full | DbError::TooLarge => TxnError::Full,
+            DbError::Reserved => TxnError::Reserved,
+            DbError::Encode(e) | DbError::Decode(e) => TxnError::Encode(e.into()),
+            _ => TxnError::Encode(CodecErrorKind::Other),
+        })?;
+        let mut txn = Transaction {
+            db: self,
+            ops: Vec::new(),
+        };
+        let result = f(&mut txn)?;
+        let ops = txn.ops;
+
+        // Make sure everything fits (slots and, for an ArenaStore, bytes)
+        // and keeps out of the reservation before touching the store
+        let changes = ops
+            .iter()
+            .map(|(key, blob)| (key, blob.as_ref().map(|b| b.len())));
+        self.batch_fits(changes).map_err(|e| match e {
+            DbError::Reserved => TxnError::Reserved,
+            _ => TxnError::Full,
+        })?;
+
+        // Deletes first so their slots and bytes are free for the puts
+        for (key, _) in ops.iter().filter(|(_, blob)| blob.is_none()) {
+            self.delete(key);
+        }
+        self.put_blobs(|| {
+            ops.iter()
+                .filter_map(|(key, blob)| Some((key, blob.as_deref()?)))
+        });
+        Ok(result)
+    }
+
+    /// transaction() followed by save_to_flash() if it was applied
+    #[cfg(feature = "persistence")]
+    pub fn transaction_and_save<F, R, T>(
+        &mut self,
+        flash: &mut F,
+        flash_offset: u32,
+        f: T,
+    ) -> Result<R, TxnError>
+    where
+        F: NorFlash,
+        K: serde::Serialize,
+        T: FnOnce(&mut Transaction<'_, K, V, C, N, B, CACH, S, CP>) -> Result<R, TxnError>,
+    {
+        let result = self.transaction(f)?;
+        self.save_to_flash(flash, core::mem::size_of::<u32>(), flash_offset)
+            .map_err(TxnError::Flash)?;
+        Ok(result)
+    }
+
+    /// Every entry with its value still encoded
+    /// For code that only forwards the bytes (dumps, checksums) and doesn't
+    /// need to pay for decoding.
+    pub fn blobs(&self) -> impl Iterator<Item = (&K, &[u8])> {
+        self.blobs.iter()
+    }
+
+    /// Every entry, values are decoded one at a time as the iterator goes
+    /// This reads the stored bytes directly, it doesn't use or fill the cache.
+    pub fn iter(&self) -> impl Iterator<Item = (&K, Result<V, C::Error>)> {
+        self.blobs().map(|(k, blob)| (k, C::decode(blob)))
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.blobs.iter().map(|(k, _)| k)
+    }
+
+    pub fn values(&self) -> impl Iterator<Item = Result<V, C::Error>> + '_ {
+        self.blobs().map(|(_, blob)| C::decode(blob))
+    }
+
+    pub fn len(&self) -> usize {
+        self.blobs.len()
+    }
+    pub fn capacity(&self) -> usize {
+        self.blobs.capacity()
+    }
+
+    /// Bytes of encoded values currently stored
+    pub fn blob_bytes_used(&self) -> usize {
+        self.blobs.iter().map(|(_, blob)| blob.len()).sum()
+    }
+
+    /// Bytes of encoded values the store can hold, B per entry (or the
+    /// arena size of an ArenaStore)
+    pub fn blob_bytes_capacity(&self) -> usize {
+        self.blobs.byte_capacity()
+    }
+
+    /// RAM the database takes, set by N, B and CACH (and the key, value and
+    /// store types). Nothing is allocated later, so this is all of it.
+    /// const EDB_RAM: usize = Db::ram_footprint();
+    pub const fn ram_footprint() -> usize {
+        core::mem::size_of::<Self>()
+    }
+}
+
+// Saving to and loading from flash, exporting and importing
+#[cfg(feature = "persistence")]
+impl<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>
+    Database<K, V, C, N, B, CACH, S, CP>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    /// Export the whole database as a byte stream for RTT/UART transport
+    /// The stream is handed to sink in small pieces:
+    /// [magic: u32][version: u16][reserved: u16][num_entries: u32]
+    /// [key_len: u32][key][val_len: u32][val]... [crc32: u32]
+    /// The CRC covers everything before it.
+    pub fn export<O>(&self, sink: O) -> Result<(), FlashError>
+    where
+        K: serde::Serialize,
+        O: FnMut(&[u8]),
+    {
+        self.export_where(sink, |_| true)
+    }
+
+    // export() limited to the keys matching filter
+    fn export_where<O, P>(&self, sink: O, filter: P) -> Result<(), FlashError>
+    where
+        K: serde::Serialize,
+        O: FnMut(&[u8]),
+        P: Fn(&K) -> bool,
+    {
+        let num_entries = self.blobs.iter().filter(|(key, _)| filter(key)).count();
+        let mut out = CrcSink {
+            sink,
+            digest: image::CRC32.digest(),
+        };
+
+        out.write(&image::SNAPSHOT_MAGIC.to_le_bytes());
+        out.write(&image::SNAPSHOT_VERSION.to_le_bytes());
+        out.write(&0u16.to_le_bytes());
+        out.write(&(num_entries as u32).to_le_bytes());
+
+        let mut key_buf = [0u8; B];
+        for (key, blob) in self.blobs.iter().filter(|(key, _)| filter(key)) {
+            let key_bytes = postcard::to_slice(key, &mut key_buf)
+                .map_err(|_| FlashError::SerializationError)?;
+            out.write(&(key_bytes.len() as u32).to_le_bytes());
+            out.write(key_bytes);
+            out.write(&(blob.len() as u32).to_le_bytes());
+            out.write(blob);
+        }
+
+        let crc = out.digest.finalize();
+        (out.sink)(&crc.to_le_bytes());
+        Ok(())
+    }
+
+    /// Replace the contents of the database with a stream from export()
+    /// reader must fill the whole buffer it is given (or return an error).
+    /// The stream is read into a second store (another N * B bytes of stack
+    /// with the default KvStore) that only replaces the current one once the
+    /// CRC checked out. On any error the database keeps what it had.
+    pub fn import<R, E>(&mut self, reader: R) -> Result<(), FlashError>
+    where
+        K: serde::de::DeserializeOwned,
+        R: FnMut(&mut [u8]) -> Result<(), E>,
+        S: Default,
+    {
+        let staged = self.import_entries(reader, |_| true)?;
+        // The stream can't break the reservation either
+        let deletes = self
+            .blobs
+            .iter()
+            .filter(|(key, _)| staged.get(key).is_none())
+            .map(|(key, _)| (key, None));
+        let puts = staged.iter().map(|(key, blob)| (key, Some(blob.len())));
+        self.batch_fits(deletes.chain(puts))
+            .map_err(|_| FlashError::DatabaseFull)?;
+        self.clear();
+        self.blobs = staged;
+        Ok(())
+    }
+
+    // Reads an export() stream into a new store, entries for which accept
+    // returns false are skipped. The database itself isn't touched.
+    fn import_entries<R, E, A>(&self, reader: R, accept: A) -> Result<S, FlashError>
+    where
+        K: serde::de::DeserializeOwned,
+        R: FnMut(&mut [u8]) -> Result<(), E>,
+        A: Fn(&K) -> bool,
+        S: Default,
+    {
+        let mut input = CrcReader {
+            reader,
+            digest: image::CRC32.digest(),
+        };
+
+        if input.read_u32()? != image::SNAPSHOT_MAGIC {
+            return Err(FlashError::BadHeader);
+        }
+        // version: u16 followed by the reserved u16, little endian
+        let version = input.read_u32()? as u16;
+        if version > image::SNAPSHOT_VERSION {
+            return Err(FlashError::UnsupportedVersion);
+        }
+        let num_entries = input.read_u32()?;
+
+        let mut staged = S::default();
+        let mut buf = [0u8; B];
+        for _ in 0..num_entries {
+            let key_len = input.read_u32()? as usize;
+            if key_len > B {
+                return Err(FlashError::BufferTooSmall);
+            }
+            input.read(&mut buf[..key_len])?;
+            let key: K = postcard::from_bytes(&buf[..key_len])
+                .map_err(|_| FlashError::DeserializationError)?;
+
+            let val_len = input.read_u32()? as usize;
+            if val_len > B {
+                return Err(FlashError::BufferTooSmall);
+            }
+            input.read(&mut buf[..val_len])?;
+            if accept(&key) {
+                staged
+                    .insert(key, &buf[..val_len])
+                    .map_err(FlashError::from)?;
+            }
+        }
+
+        let expected = input.digest.finalize();
+        let mut crc = [0u8; 4];
+        (input.reader)(&mut crc).map_err(|_| FlashError::ReadError)?;
+        if u32::from_le_bytes(crc) != expected {
+            return Err(FlashError::CrcMismatch);
+        }
+        Ok(staged)
+    }
+
+    /// Size of the (unsealed) image save_to_flash would write, header included
+    pub fn image_size(&self) -> Result<usize, FlashError>
+    where
+        K: serde::Serialize,
+    {
+        let key_width = keycodec::image_width(self.keys());
+        let mut size = HEADER_SIZE + 4 + 1 + 4;
+        for (key, blob) in self.blobs.iter() {
+            size += record_size::<K, B>(key, blob, key_width)?;
+        }
+        Ok(size)
+    }
+
+    /// Drop entries until the image fits in max_bytes, lowest priority first
+    /// For moving into a smaller partition (e.g. one an OTA update shrank),
+    /// where losing some telemetry beats failing the whole save. on_drop gets
+    /// every key before it is removed, ties go in iteration order. Returns
+    /// how many entries were dropped. Leave room for cipher.overhead() in
+    /// max_bytes if the image is going to be sealed.
+    pub fn shrink_to_fit<P, D>(
+        &mut self,
+        max_bytes: usize,
+        priority: P,
+        mut on_drop: D,
+    ) -> Result<usize, FlashError>
+    where
+        K: serde::Serialize,
+        P: Fn(&K) -> u8,
+        D: FnMut(&K),
+    {
+        let mut size = self.image_size()?;
+        let key_width = keycodec::image_width(self.keys());
+        let mut dropped = 0;
+        while size > max_bytes {
+            let (victim, victim_size) = match self.blobs.iter().min_by_key(|(k, _)| priority(k)) {
+                Some((key, blob)) => (key.clone(), record_size::<K, B>(key, blob, key_width)?),
+                // Even an empty image doesn't fit
+                None => return Err(FlashError::BufferTooSmall),
+            };
+            on_drop(&victim);
+            self.delete(&victim);
+            size -= victim_size;
+            dropped += 1;
+        }
+        Ok(dropped)
+    }
+
+    /// Save the database to flash storage
+    /// This writes to flash with a simple format:
+    /// [header][num_entries: u32][key_width: u8][key1_len: u32][key1_data][val1_len: u32][val1_data]...
+    /// (integer keys without key_len, see keycodec.rs) in the image region with a footer at its end, see image.rs.
+    ///
+    /// flash_offset: The offset in flash where to write (must be aligned)
+    /// flash: The flash storage device
+    pub fn save_to_flash<F>(
+        &mut self,
+        flash: &mut F,
+        flash_size: usize,
+        flash_offset: u32,
+    ) -> Result<(), FlashError>
+    where
+        F: NorFlash,
+        K: serde::Serialize,
+    {
+        self.save_to_flash_with_progress(flash, flash_size, flash_offset, |_| {})
+    }
+
+    /// Same as save_to_flash, but calls progress after every entry serialized,
+    /// page erased and page written. Saving a large image blocks for a while,
+    /// so this is the place to feed a watchdog or blink a status LED.
+    pub fn save_to_flash_with_progress<F, P>(
+        &mut self,
+        flash: &mut F,
+        flash_size: usize,
+        flash_offset: u32,
+        mut progress: P,
+    ) -> Result<(), FlashError>
+    where
+        F: NorFlash,
+        K: serde::Serialize,
+        P: FnMut(FlashProgress),
+    {
+        self.save_image(flash, flash_size, flash_offset, &mut progress, None)
+    }
+
+    /// Same as save_to_flash, but the payload is sealed (encrypted or
+    /// authenticated) with cipher. The header stays readable so the
+    /// versions can still be seen.
+    pub fn save_to_flash_sealed<F>(
+        &mut self,
+        flash: &mut F,
+        flash_size: usize,
+        flash_offset: u32,
+        cipher: &mut dyn ImageCipher,
+    ) -> Result<(), FlashError>
+    where
+        F: NorFlash,
+        K: serde::Serialize,
+    {
+        self.save_image(flash, flash_size, flash_offset, &mut |_| {}, Some(cipher))
+    }
+
+    fn save_image<F, P>(
+        &mut self,
+        flash: &mut F,
+        flash_size: usize,
+        flash_offset: u32,
+        progress: &mut P,
+        cipher: Option<&mut dyn ImageCipher>,
+    ) -> Result<(), FlashError>
+    where
+        F: NorFlash,
+        K: serde::Serialize,
+        P: FnMut(FlashProgress),
+    {
+        self.check_supply()?;
+        if let Some(backup) = self.backup_offset {
+            if image::regions_overlap(flash_offset, backup, F::ERASE_SIZE) {
+                return Err(FlashError::RegionOverlap);
+            }
+        }
+        // Write-back values have to be in the store to end up in the image
+        self.flush()?;
+
+        const MAX_SERIALIZED_SIZE: usize = MAX_IMAGE_SIZE; // 8KB buffer
+        let mut buffer = [0u8; MAX_SERIALIZED_SIZE];
+        let mut status = FlashProgress::default();
+        let len = self.build_image(&mut buffer, flash_size, &mut status, progress, cipher)?;
+        let footer = Footer {
+            image_len: len as u32,
+            image_crc: image::CRC32.checksum(&buffer[..len]),
+            sequence: image::NO_SEQUENCE,
+        };
+
+        // Pad to word alignment (4 bytes)
+        let aligned_size = (len + 3) & !3;
+
+        // Every copy goes through the same erase + write steps
+        let copies = if self.backup_offset.is_some() { 2 } else { 1 };
+        status.pages_total = image::region_len(F::ERASE_SIZE) / F::ERASE_SIZE * copies;
+        status.bytes_total = (aligned_size + image::FOOTER_SIZE) * copies;
+
+        // Primary first, so if we lose power half way the backup still
+        // holds the previous image
+        write_image(
+            flash,
+            flash_offset,
+            &buffer[..aligned_size],
+            &footer,
+            &mut status,
+            progress,
+        )?;
+        if let Some(backup) = self.backup_offset {
+            write_image(
+                flash,
+                backup,
+                &buffer[..aligned_size],
+                &footer,
+                &mut status,
+                progress,
+            )?;
+        }
+
+        self.persisted_at = Some(flash_offset);
+        self.mark_saved();
+        self.durable_generation = Some(self.generation);
+        Ok(())
+    }
+
+    /// The image save_to_flash would write at flash_offset, for programming
+    /// it at the factory together with the firmware
+    /// out gets the whole image region for erase_size (image::region_len),
+    /// padded with 0xFF and footer included, so the bytes can be merged into the
+    /// firmware hex/bin as they are. Call again with the backup offset if
+    /// there is one.
+    pub fn to_artifact<'b>(
+        &self,
+        flash_offset: u32,
+        erase_size: usize,
+        out: &'b mut [u8],
+    ) -> Result<Artifact<'b>, FlashError>
+    where
+        K: serde::Serialize,
+    {
+        let len = self.build_image(
+            out,
+            core::mem::size_of::<u32>(),
+            &mut FlashProgress::default(),
+            &mut |_| {},
+            None,
+        )?;
+        let padded = image::region_len(erase_size);
+        if padded > out.len() {
+            return Err(FlashError::BufferTooSmall);
+        }
+        let footer = Footer {
+            image_len: len as u32,
+            image_crc: image::CRC32.checksum(&out[..len]),
+            sequence: image::NO_SEQUENCE,
+        };
+        out[len..padded].fill(0xFF);
+        out[padded - image::FOOTER_SIZE..padded].copy_from_slice(&footer.to_bytes());
+        Ok(Artifact {
+            offset: flash_offset,
+            bytes: &out[..padded],
+        })
+    }
+
+    /// Write the entries as TLV records at flash_offset, for C code that
+    /// shares the region (layout in tlv.rs)
+    /// Keys have to be u8 or u16, or newtypes around them. This is not an
+    /// image, open() can't read it back, load_tlv() can. Returns the length
+    /// of the records.
+    pub fn save_tlv<F>(&mut self, flash: &mut F, flash_offset: u32) -> Result<usize, FlashError>
+    where
+        F: NorFlash,
+        K: serde::Serialize,
+    {
+        self.check_supply()?;
+        self.flush()?;
+
+        // 0xFF past the end record, that is what erased flash reads as anyway
+        let mut buffer = [0xFFu8; MAX_IMAGE_SIZE];
+        let key_width = keycodec::image_width(self.keys());
+        let mut pos = 0;
+        for (key, blob) in self.blobs.iter() {
+            let mut tag = [0u8; 2];
+            keycodec::encode_fixed(key, key_width, &mut tag)
+                .ok_or(FlashError::SerializationError)?;
+            let tag = u16::from_le_bytes(tag);
+            if tag >= tlv::REGION_END {
+                return Err(FlashError::SerializationError);
+            }
+            pos += tlv::write_region_record(&mut buffer[pos..], tag, blob)
+                .ok_or(FlashError::BufferTooSmall)?;
+        }
+        let crc = image::CRC32.checksum(&buffer[..pos]);
+        pos += tlv::write_region_record(&mut buffer[pos..], tlv::REGION_END, &crc.to_le_bytes())
+            .ok_or(FlashError::BufferTooSmall)?;
+
+        let records = buffer
+            .get(..pos.div_ceil(F::WRITE_SIZE) * F::WRITE_SIZE)
+            .ok_or(FlashError::BufferTooSmall)?;
+        let erase_len = pos.div_ceil(F::ERASE_SIZE) * F::ERASE_SIZE;
+        flash
+            .erase(flash_offset, flash_offset + erase_len as u32)
+            .map_err(|_| FlashError::EraseError)?;
+        flash
+            .write(flash_offset, records)
+            .map_err(|_| FlashError::WriteError)?;
+        Ok(pos)
+    }
+
+    /// Replace the contents of the database with the records save_tlv wrote
+    /// An erased or torn region (no end record, or its CRC doesn't match)
+    /// is CrcMismatch. On any error the database is left empty. Returns how
+    /// many entries were loaded.
+    pub fn load_tlv<F>(&mut self, flash: &mut F, flash_offset: u32) -> Result<usize, FlashError>
+    where
+        F: ReadNorFlash,
+        K: serde::de::DeserializeOwned,
+    {
+        self.clear();
+
+        let result = self.load_tlv_records(flash, flash_offset);
+        if result.is_err() {
+            self.blobs.clear();
+        }
+        result
+    }
+
+    fn load_tlv_records<F>(&mut self, flash: &mut F, flash_offset: u32) -> Result<usize, FlashError>
+    where
+        F: ReadNorFlash,
+        K: serde::de::DeserializeOwned,
+    {
+        let mut digest = image::CRC32.digest();
+        let mut header = [0u8; tlv::REGION_HEADER];
+        let mut buf = [0u8; B];
+        let mut pos = 0;
+        let mut count = 0;
+        while pos + tlv::REGION_HEADER <= MAX_IMAGE_SIZE {
+            flash
+                .read(flash_offset + pos as u32, &mut header)
+                .map_err(|_| FlashError::ReadError)?;
+            let tag = u16::from_le_bytes([header[0], header[1]]);
+            let len = u16::from_le_bytes([header[2], header[3]]) as usize;
+            if tag == 0xFFFF {
+                break;
+            }
+            let value = buf.get_mut(..len).ok_or(FlashError::BufferTooSmall)?;
+            flash
+                .read(flash_offset + (pos + tlv::REGION_HEADER) as u32, value)
+                .map_err(|_| FlashError::ReadError)?;
+            pos += tlv::REGION_HEADER + len;
+
+            if tag == tlv::REGION_END {
+                let stored: [u8; 4] = (*value).try_into().map_err(|_| FlashError::CrcMismatch)?;
+                if u32::from_le_bytes(stored) != digest.finalize() {
+                    return Err(FlashError::CrcMismatch);
+                }
+                return Ok(count);
+            }
+            digest.update(&header);
+            digest.update(value);
+            let key: K =
+                keycodec::decode_fixed(&header[..2]).ok_or(FlashError::DeserializationError)?;
+            self.blobs.insert(key, value).map_err(FlashError::from)?;
+            count += 1;
+        }
+        Err(FlashError::CrcMismatch)
+    }
+
+    // Serialize header and payload into buffer, returns the image length
+    fn build_image<P>(
+        &self,
+        buffer: &mut [u8],
+        flash_size: usize,
+        status: &mut FlashProgress,
+        progress: &mut P,
+        cipher: Option<&mut dyn ImageCipher>,
+    ) -> Result<usize, FlashError>
+    where
+        K: serde::Serialize,
+        P: FnMut(FlashProgress),
+    {
+        // The rest of the region is the footer's
+        let limit = buffer.len().min(image::MAX_IMAGE_LEN);
+        let buffer = &mut buffer[..limit];
+        if buffer.len() < HEADER_SIZE + flash_size + 1 {
+            return Err(FlashError::BufferTooSmall);
+        }
+        // Leave room for the header, it is filled in once we know the payload
+        let mut pos = HEADER_SIZE;
+
+        // Write number of entries
+        let num_entries = self.len() as u32;
+        buffer[pos..pos + flash_size].copy_from_slice(&num_entries.to_le_bytes());
+        pos += flash_size;
+
+        // Integer keys go in without postcard and length, see keycodec.rs
+        let key_width = keycodec::image_width(self.keys());
+        buffer[pos] = key_width;
+        pos += 1;
+
+        status.entries_total = self.len();
+
+        // Iterate through all entries and serialize them
+        for (key, blob) in self.blobs.iter() {
+            if key_width == 0 {
+                // Serialize the key
+                // using postcard because it is a compact format
+                let key_bytes = postcard::to_slice(key, &mut buffer[pos + flash_size..])
+                    .map_err(|_| FlashError::SerializationError)?;
+                let key_len = key_bytes.len() as u32;
+
+                // Write key length
+                buffer[pos..pos + 4].copy_from_slice(&key_len.to_le_bytes());
+                pos += 4 + key_len as usize;
+            } else {
+                pos += keycodec::encode_fixed(key, key_width, &mut buffer[pos..])
+                    .ok_or(FlashError::BufferTooSmall)?;
+            }
+
+            // Write value length and data
+            let val_len = blob.len() as u32;
+            if pos + 4 + val_len as usize > buffer.len() {
+                return Err(FlashError::BufferTooSmall);
+            }
+
+            buffer[pos..pos + 4].copy_from_slice(&val_len.to_le_bytes());
+            pos += 4;
+            buffer[pos..pos + val_len as usize].copy_from_slice(blob);
+            pos += val_len as usize;
+
+            status.entries_processed += 1;
+            progress(*status);
+        }
+
+        // Trailer behind the entries, readers that only walk them skip it
+        if pos + 4 > buffer.len() {
+            return Err(FlashError::BufferTooSmall);
+        }
+        buffer[pos..pos + 4].copy_from_slice(&self.boot_count.to_le_bytes());
+        pos += 4;
+
+        let mut header = ImageHeader {
+            format_version: image::FORMAT_VERSION,
+            app_version: self.app_version,
+            device_id: self.device_id,
+            payload_len: 0,
+            payload_crc: 0,
+            sealing: cipher.as_ref().map_or(Sealing::None, |c| c.sealing()),
+        };
+
+        if let Some(cipher) = cipher {
+            let len = cipher
+                .seal(
+                    &header.auth_bytes(),
+                    &mut buffer[HEADER_SIZE..],
+                    pos - HEADER_SIZE,
+                )
+                .map_err(|_| FlashError::BufferTooSmall)?;
+            pos = HEADER_SIZE + len;
+        }
+
+        // The CRC covers what is actually in flash (including the tag)
+        header.payload_len = (pos - HEADER_SIZE) as u32;
+        header.payload_crc = image::CRC32.checksum(&buffer[HEADER_SIZE..pos]);
+        buffer[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+        Ok(pos)
+    }
+
+    /// Load the database from flash storage
+    /// Reads data saved by save_to_flash and populates the database
+    pub fn load_from_flash<F>(&mut self, flash: &mut F, flash_offset: u32) -> Result<(), FlashError>
+    where
+        F: ReadNorFlash,
+        K: serde::de::DeserializeOwned,
+    {
+        self.open(flash, flash_offset).map(|_| ())
+    }
+
+    /// Same as load_from_flash, but calls progress after every entry loaded
+    pub fn load_from_flash_with_progress<F, P>(
+        &mut self,
+        flash: &mut F,
+        flash_offset: u32,
+        progress: P,
+    ) -> Result<(), FlashError>
+    where
+        F: ReadNorFlash,
+        K: serde::de::DeserializeOwned,
+        P: FnMut(FlashProgress),
+    {
+        self.open_with_progress(flash, flash_offset, progress)
+            .map(|_| ())
+    }
+
+    /// Load the database from flash storage and return the image header
+    /// Returns Ok(None) if the flash is erased and nothing was loaded.
+    /// Logs a startup banner with the versions that wrote the image, so a
+    /// flash dump can be matched to the firmware that produced it.
+    pub fn open<F>(
+        &mut self,
+        flash: &mut F,
+        flash_offset: u32,
+    ) -> Result<Option<ImageHeader>, FlashError>
+    where
+        F: ReadNorFlash,
+        K: serde::de::DeserializeOwned,
+    {
+        self.open_with_progress(flash, flash_offset, |_| {})
+    }
+
+    /// Same as open, but calls progress after every entry loaded
+    pub fn open_with_progress<F, P>(
+        &mut self,
+        flash: &mut F,
+        flash_offset: u32,
+        mut progress: P,
+    ) -> Result<Option<ImageHeader>, FlashError>
+    where
+        F: ReadNorFlash,
+        K: serde::de::DeserializeOwned,
+        P: FnMut(FlashProgress),
+    {
+        self.open_image(flash, flash_offset, &mut progress, None)
+    }
+
+    /// Same as open, for images written by save_to_flash_sealed
+    /// Returns FlashError::AuthenticationFailed if the key is wrong or the
+    /// image was modified. Unsealed images are refused the same way, otherwise
+    /// anyone could swap a sealed image for a plain one. Devices that were
+    /// never sealed can be moved over by one open() + save_to_flash_sealed().
+    pub fn open_sealed<F>(
+        &mut self,
+        flash: &mut F,
+        flash_offset: u32,
+        cipher: &mut dyn ImageCipher,
+    ) -> Result<Option<ImageHeader>, FlashError>
+    where
+        F: ReadNorFlash,
+        K: serde::de::DeserializeOwned,
+    {
+        self.open_image(flash, flash_offset, &mut |_| {}, Some(cipher))
+    }
+
+    fn open_image<F, P>(
+        &mut self,
         flash: &mut F,
-        flash_size: usize,
         flash_offset: u32,
-    ) -> Result<(), FlashError>
+        progress: &mut P,
+        cipher: Option<&mut dyn ImageCipher>,
+    ) -> Result<Option<ImageHeader>, FlashError>
     where
-        F: NorFlash,
-        K: serde::Serialize,
+        F: ReadNorFlash,
+        K: serde::de::DeserializeOwned,
+        P: FnMut(FlashProgress),
     {
-        const MAX_SERIALIZED_SIZE: usize = 8192; // 8KB buffer
-        let mut buffer = [0u8; MAX_SERIALIZED_SIZE];
-        let mut pos = 0;
-
-        // Write number of entries
-        let num_entries = self.len() as u32;
-        buffer[pos..pos + flash_size].copy_from_slice(&num_entries.to_le_bytes());
-        pos += flash_size;
+        self.load_timing = LoadTiming::default();
+        let start = self.now_us();
+        let result = self.open_either(flash, flash_offset, progress, cipher);
+        self.load_timing.total_us = self.now_us().wrapping_sub(start);
+        result
+    }
 
-        // Iterate through all entries and serialize them
-        for (key, blob) in self.blobs.iter() {
-            // Serialize the key
-            // using postcard because it is a compact format
-            let key_bytes = postcard::to_slice(key, &mut buffer[pos + flash_size..])
-                .map_err(|_| FlashError::SerializationError)?;
-            let key_len = key_bytes.len() as u32;
+    // Primary image, or the backup if the primary is unusable
+    fn open_either<F, P>(
+        &mut self,
+        flash: &mut F,
+        flash_offset: u32,
+        progress: &mut P,
+        mut cipher: Option<&mut dyn ImageCipher>,
+    ) -> Result<Option<ImageHeader>, FlashError>
+    where
+        F: ReadNorFlash,
+        K: serde::de::DeserializeOwned,
+        P: FnMut(FlashProgress),
+    {
+        let primary = self.read_image(
+            flash,
+            flash_offset,
+            progress,
+            cipher.as_mut().map(|c| &mut **c as _),
+        );
 
-            // Write key length
-            buffer[pos..pos + 4].copy_from_slice(&key_len.to_le_bytes());
-            pos += 4 + key_len as usize;
+        let backup = match (self.backup_offset, &primary) {
+            (Some(backup), Ok(None))
+            | (Some(backup), Err(FlashError::BadHeader))
+            | (Some(backup), Err(FlashError::CrcMismatch))
+            | (Some(backup), Err(FlashError::AuthenticationFailed)) => backup,
+            _ => {
+                if let Ok(Some(_)) = primary {
+                    self.loaded_from = Some(ImageSource::Primary);
+                }
+                return primary;
+            }
+        };
 
-            // Write value length and data
-            let val_len = blob.len() as u32;
-            if pos + 4 + val_len as usize > MAX_SERIALIZED_SIZE {
-                return Err(FlashError::BufferTooSmall);
+        defmt::warn!("primary image unusable ({:?}), trying backup", primary);
+        match self.read_image(flash, backup, progress, cipher)? {
+            Some(header) => {
+                self.loaded_from = Some(ImageSource::Backup);
+                Ok(Some(header))
             }
+            // Backup is empty too, report what was wrong with the primary
+            None => primary,
+        }
+    }
 
-            buffer[pos..pos + 4].copy_from_slice(&val_len.to_le_bytes());
-            pos += 4;
-            buffer[pos..pos + val_len as usize].copy_from_slice(blob.as_slice());
-            pos += val_len as usize;
+    /// Destroy everything stored, for provisioning reset and decommissioning
+    /// All of region is overwritten with zeros before it is erased, so no old
+    /// image can be recovered from a flash dump. Pass the whole storage
+    /// partition: primary and backup images, LowPowerSaver slots, TLV
+    /// regions, whatever lives in it. It has to start and end on erase pages
+    /// (EraseError otherwise, before anything is written). The in-RAM store
+    /// and cache are cleared too.
+    pub fn secure_wipe<F>(&mut self, flash: &mut F, region: Partition) -> Result<(), FlashError>
+    where
+        F: NorFlash,
+    {
+        self.check_supply()?;
+        let erase_size = F::ERASE_SIZE as u32;
+        if !region.offset.is_multiple_of(erase_size) || !region.len.is_multiple_of(erase_size) {
+            return Err(FlashError::EraseError);
         }
+        wipe_region(flash, region.offset, region.len as usize)?;
 
-        // Pad to word alignment (4 bytes)
-        let aligned_size = (pos + 3) & !3;
+        self.clear();
+        self.persisted_at = None;
+        self.loaded_from = None;
+        // Nothing in RAM, nothing in flash. Still not persisted until the
+        // next save, a reboot finds no image at all.
+        self.mark_saved();
+        self.new_epoch(false);
+        Ok(())
+    }
 
-        // Erase the flash region first
-        let page_size = F::ERASE_SIZE;
-        let pages_needed = (aligned_size + page_size - 1) / page_size;
-        let erase_end = flash_offset + (pages_needed * page_size) as u32;
+    /// Copy the persisted image to another flash device, e.g. from the
+    /// internal NVMC to an external QSPI part added in a hardware revision
+    /// The image is checked against its CRC first, streamed over in small
+    /// chunks and read back from other afterwards. Sealed images are copied
+    /// as they are. The database itself keeps using the original region.
+    pub fn clone_to<F, G>(
+        &self,
+        flash: &mut F,
+        other: &mut G,
+        other_offset: u32,
+    ) -> Result<(), FlashError>
+    where
+        F: ReadNorFlash,
+        G: NorFlash,
+    {
+        self.check_supply()?;
+        let offset = self.persisted_at.ok_or(FlashError::NotPersisted)?;
+        let header = image::verify(flash, offset)?.ok_or(FlashError::NotPersisted)?;
+        let image_len = HEADER_SIZE + header.payload_len as usize;
+        // An image from before the footer that reaches into its place
+        if image_len > image::MAX_IMAGE_LEN {
+            return Err(FlashError::DatabaseFull);
+        }
+        let len = image_len
+            .next_multiple_of(4)
+            .next_multiple_of(G::WRITE_SIZE);
 
-        flash
-            .erase(flash_offset, erase_end)
+        let padded = image::region_len(G::ERASE_SIZE);
+        other
+            .erase(other_offset, other_offset + padded as u32)
             .map_err(|_| FlashError::EraseError)?;
 
-        // Write to flash
-        flash
-            .write(flash_offset, &buffer[..aligned_size])
-            .map_err(|_| FlashError::WriteError)?;
+        let mut chunk = [0u8; 256];
+        let mut readback = [0u8; 256];
+        let step = chunk.len() - chunk.len() % G::WRITE_SIZE;
+        let mut digest = image::CRC32.digest();
+        let mut pos = 0;
+        while pos < len {
+            let n = step.min(len - pos);
+            flash
+                .read(offset + pos as u32, &mut chunk[..n])
+                .map_err(|_| FlashError::ReadError)?;
+            other
+                .write(other_offset + pos as u32, &chunk[..n])
+                .map_err(|_| FlashError::WriteError)?;
+            other
+                .read(other_offset + pos as u32, &mut readback[..n])
+                .map_err(|_| FlashError::ReadError)?;
+            if chunk[..n] != readback[..n] {
+                return Err(FlashError::VerifyFailed);
+            }
+            digest.update(&chunk[..n.min(image_len.saturating_sub(pos))]);
+            pos += n;
+        }
 
-        Ok(())
+        let footer = Footer {
+            image_len: image_len as u32,
+            image_crc: digest.finalize(),
+            sequence: image::NO_SEQUENCE,
+        };
+        other
+            .write(
+                other_offset + (padded - image::FOOTER_SIZE) as u32,
+                &footer.to_bytes(),
+            )
+            .map_err(|_| FlashError::WriteError)
     }
 
-    /// Load the database from flash storage
-    /// Reads data saved by save_to_flash and populates the database
-    pub fn load_from_flash<F>(&mut self, flash: &mut F, flash_offset: u32) -> Result<(), FlashError>
+    // Add the time since t to one field of the load timing, returns now
+    fn lap(&mut self, t: u64, field: fn(&mut LoadTiming) -> &mut u64) -> u64 {
+        let now = self.now_us();
+        *field(&mut self.load_timing) += now.wrapping_sub(t);
+        now
+    }
+
+    fn read_image<F, P>(
+        &mut self,
+        flash: &mut F,
+        flash_offset: u32,
+        progress: &mut P,
+        cipher: Option<&mut dyn ImageCipher>,
+    ) -> Result<Option<ImageHeader>, FlashError>
     where
         F: ReadNorFlash,
         K: serde::de::DeserializeOwned,
+        P: FnMut(FlashProgress),
     {
-        const MAX_READ_SIZE: usize = 8192;
+        const MAX_READ_SIZE: usize = MAX_IMAGE_SIZE;
         let mut buffer = [0u8; MAX_READ_SIZE];
 
-        // Read from flash
+        // Read the header first so we only read as much as was written
+        let mut t = self.now_us();
         flash
-            .read(flash_offset, &mut buffer)
+            .read(flash_offset, &mut buffer[..HEADER_SIZE])
             .map_err(|_| FlashError::ReadError)?;
 
-        let mut pos = 0;
+        let header = match ImageHeader::from_bytes(&buffer[..HEADER_SIZE])? {
+            Some(h) => h,
+            // Flash is erased, nothing to load
+            None => return Ok(None),
+        };
+
+        defmt::debug!(
+            "embedded-db image: format v{=u16} app {=u32:#x} device {=u64:#x}",
+            header.format_version,
+            header.app_version,
+            header.device_id
+        );
+
+        let mut end = HEADER_SIZE + header.payload_len as usize;
+        if end > MAX_READ_SIZE {
+            return Err(FlashError::BufferTooSmall);
+        }
+
+        flash
+            .read(
+                flash_offset + HEADER_SIZE as u32,
+                &mut buffer[HEADER_SIZE..end],
+            )
+            .map_err(|_| FlashError::ReadError)?;
+        t = self.lap(t, |timing| &mut timing.flash_read_us);
+
+        if image::CRC32.checksum(&buffer[HEADER_SIZE..end]) != header.payload_crc {
+            return Err(FlashError::CrcMismatch);
+        }
+        t = self.lap(t, |timing| &mut timing.crc_us);
+
+        match (cipher, header.sealing) {
+            (None, Sealing::None) => {}
+            (None, _) => return Err(FlashError::Sealed),
+            (Some(cipher), sealing) => {
+                if sealing != cipher.sealing() {
+                    return Err(FlashError::AuthenticationFailed);
+                }
+                let len = cipher
+                    .open(&header.auth_bytes(), &mut buffer[HEADER_SIZE..end])
+                    .map_err(|_| FlashError::AuthenticationFailed)?;
+                end = HEADER_SIZE + len;
+            }
+        }
+        t = self.lap(t, |timing| &mut timing.unseal_us);
+
+        // Format 3 ends in [boot_count: u32], kept aside until the entries parsed
+        let mut boot_count = None;
+        if header.format_version >= 3 {
+            if end < HEADER_SIZE + 4 {
+                return Err(FlashError::BufferTooSmall);
+            }
+            end -= 4;
+            boot_count = Some(u32::from_le_bytes([
+                buffer[end],
+                buffer[end + 1],
+                buffer[end + 2],
+                buffer[end + 3],
+            ]));
+        }
+
+        let mut pos = HEADER_SIZE;
 
         // Read number of entries
-        let num_entries = u32::from_le_bytes([buffer[0], buffer[1], buffer[2], buffer[3]]);
+        if pos + 4 > end {
+            return Err(FlashError::BufferTooSmall);
+        }
+        let num_entries = u32::from_le_bytes([
+            buffer[pos],
+            buffer[pos + 1],
+            buffer[pos + 2],
+            buffer[pos + 3],
+        ]);
         pos += 4;
 
-        // Check if flash is empty (all 0xFF)
-        if num_entries == 0xFFFFFFFF {
-            // Flash is erased, nothing to load
-            return Ok(());
+        let mut key_width = 0;
+        if header.format_version >= 2 {
+            if pos >= end {
+                return Err(FlashError::BufferTooSmall);
+            }
+            key_width = image::check_key_width(buffer[pos])? as usize;
+            pos += 1;
         }
 
+        let mut status = FlashProgress {
+            entries_total: num_entries as usize,
+            ..FlashProgress::default()
+        };
+
         // Clear existing data
         self.blobs.clear();
-        self.cache.clear();
+        self.cache_clear();
+        self.expiry.clear();
+        self.index_stale = true;
 
         // Read each entry
         for _ in 0..num_entries {
-            // Read key length
-            if pos + 4 > MAX_READ_SIZE {
-                return Err(FlashError::BufferTooSmall);
-            }
-            let key_len = u32::from_le_bytes([
-                buffer[pos],
-                buffer[pos + 1],
-                buffer[pos + 2],
-                buffer[pos + 3],
-            ]) as usize;
-            pos += 4;
+            let key_len = if key_width == 0 {
+                // Read key length
+                if pos + 4 > end {
+                    return Err(FlashError::BufferTooSmall);
+                }
+                let key_len = u32::from_le_bytes([
+                    buffer[pos],
+                    buffer[pos + 1],
+                    buffer[pos + 2],
+                    buffer[pos + 3],
+                ]) as usize;
+                pos += 4;
+                key_len
+            } else {
+                key_width
+            };
 
             // Read key
-            if pos + key_len > MAX_READ_SIZE {
+            if pos + key_len > end {
                 return Err(FlashError::BufferTooSmall);
             }
-            let key: K = postcard::from_bytes(&buffer[pos..pos + key_len])
-                .map_err(|_| FlashError::DeserializationError)?;
+            let key: K = if key_width == 0 {
+                postcard::from_bytes(&buffer[pos..pos + key_len])
+                    .map_err(|_| FlashError::DeserializationError)?
+            } else {
+                keycodec::decode_fixed(&buffer[pos..pos + key_len])
+                    .ok_or(FlashError::DeserializationError)?
+            };
             pos += key_len;
 
             // Read value length
-            if pos + 4 > MAX_READ_SIZE {
+            if pos + 4 > end {
                 return Err(FlashError::BufferTooSmall);
             }
             let val_len = u32::from_le_bytes([
@@ -237,22 +2459,527 @@ where
             pos += 4;
 
             // Read value
-            if pos + val_len > MAX_READ_SIZE {
+            if pos + val_len > end {
                 return Err(FlashError::BufferTooSmall);
             }
-            let mut blob = Vec::<u8, B>::new();
-            blob.extend_from_slice(&buffer[pos..pos + val_len])
-                .map_err(|_| FlashError::BufferTooSmall)?;
-            pos += val_len;
-
             // Insert into store
             self.blobs
-                .put(key, blob)
-                .map_err(|_| FlashError::DatabaseFull)?;
+                .insert(key, &buffer[pos..pos + val_len])
+                .map_err(FlashError::from)?;
+            pos += val_len;
+
+            status.entries_processed += 1;
+            progress(status);
+        }
+        self.lap(t, |timing| &mut timing.decode_us);
+
+        if let Some(boot_count) = boot_count {
+            self.boot_count = boot_count;
+        }
+        self.persisted_at = Some(flash_offset);
+        self.mark_saved();
+        self.new_epoch(true);
+        Ok(Some(header))
+    }
+}
+
+// Databases keyed by strings can move single namespaces around, e.g. to back
+// up the factory calibration in "cal" without the user settings next to it.
+#[cfg(feature = "persistence")]
+impl<V, C, S, CP, const L: usize, const N: usize, const B: usize, const CACH: usize>
+    Database<String<L>, V, C, N, B, CACH, S, CP>
+where
+    C: Codec<V>,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<String<L>>,
+    CP: CachePolicy<String<L>>,
+{
+    /// export() for the keys in namespace ns only
+    /// The stream has the same format, so it can also go through import().
+    pub fn export_namespace<O>(&self, ns: &str, sink: O) -> Result<(), FlashError>
+    where
+        O: FnMut(&[u8]),
+    {
+        self.export_where(sink, |key| namespace::strip(key, ns).is_some())
+    }
+
+    /// Import the entries of namespace ns from an export() stream
+    /// Entries of other namespaces in the stream are skipped, the rest of the
+    /// database is never touched. The stream is staged and checked first
+    /// like in import(), on any error (a bad CRC, no room) nothing changes.
+    pub fn import_namespace<R, E>(
+        &mut self,
+        ns: &str,
+        reader: R,
+        policy: ImportPolicy,
+    ) -> Result<(), FlashError>
+    where
+        R: FnMut(&mut [u8]) -> Result<(), E>,
+        S: Default,
+    {
+        // Write-back values have to be in the store to be replaced or kept
+        self.flush()?;
+        let staged = self.import_entries(reader, |key| {
+            namespace::strip(key, ns).is_some()
+                && (policy != ImportPolicy::SkipExisting || self.blobs.get(key).is_none())
+        })?;
+
+        // Replace drops the keys of the namespace the stream doesn't have
+        let dropped = |key: &String<L>| {
+            policy == ImportPolicy::Replace
+                && namespace::strip(key, ns).is_some()
+                && staged.get(key).is_none()
+        };
+        let deletes = self
+            .blobs
+            .iter()
+            .map(|(key, _)| key)
+            .filter(|key| dropped(key));
+        let puts = staged.iter().map(|(key, blob)| (key, Some(blob.len())));
+        self.batch_fits(deletes.map(|key| (key, None)).chain(puts))
+            .map_err(|_| FlashError::DatabaseFull)?;
+
+        loop {
+            let next = self.keys().find(|key| dropped(key)).cloned();
+            match next {
+                Some(key) => self.delete(&key),
+                None => break,
+            };
+        }
+        self.put_blobs(|| staged.iter());
+        Ok(())
+    }
+}
+
+/// What import_namespace does with keys that already exist
+#[cfg(feature = "persistence")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ImportPolicy {
+    // Drop the whole namespace first, afterwards it holds exactly the stream
+    Replace,
+    // Stream entries overwrite existing ones, other keys in the namespace stay
+    Merge,
+    // Existing keys keep their value, only new keys are added
+    SkipExisting,
+}
+
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub enum TxnError {
+    // More than MAX_TXN_OPS different keys were changed
+    TooManyOps,
+    // A value couldn't be encoded (or is bigger than B), or a staged one
+    // read back with get() doesn't decode
+    Encode(CodecErrorKind),
+    // The changes need more slots than the store has
+    Full,
+    // The changes would use room reserved for critical keys, see reserve()
+    Reserved,
+    // Returned by the closure to roll back on purpose
+    Aborted,
+    // The changes were applied but saving them failed
+    Flash(FlashError),
+}
+
+/// Staged changes of Database::transaction
+/// Reads see the staged changes, the database itself is untouched until
+/// the closure returns Ok.
+pub struct Transaction<'a, K, V, C, const N: usize, const B: usize, const CACH: usize, S, CP>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    db: &'a Database<K, V, C, N, B, CACH, S, CP>,
+    // Encoded value per key, None = delete. One entry per key, the last
+    // change to a key wins.
+    ops: Vec<(K, Option<Vec<u8, B>>), MAX_TXN_OPS>,
+}
+
+impl<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>
+    Transaction<'_, K, V, C, N, B, CACH, S, CP>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    pub fn put(&mut self, key: K, val: V) -> Result<(), TxnError> {
+        let mut tmp = [0u8; B];
+        let used = self
+            .db
+            .encode(&key, &mut tmp, &val)
+            .map_err(|e| TxnError::Encode(e.into()))?;
+        let blob = Vec::from_slice(&tmp[..used])
+            .map_err(|_| TxnError::Encode(CodecErrorKind::BufferTooSmall))?;
+        self.stage(key, Some(blob))
+    }
+
+    pub fn delete(&mut self, key: &K) -> Result<(), TxnError> {
+        self.stage(key.clone(), None)
+    }
+
+    pub fn get(&self, key: &K) -> Result<Option<V>, TxnError> {
+        let blob = match self.ops.iter().find(|(k, _)| k == key) {
+            Some((_, staged)) => staged.as_deref(),
+            None => self.db.blobs.get(key),
+        };
+        match blob {
+            Some(blob) => C::decode(blob)
+                .map(Some)
+                .map_err(|e| TxnError::Encode(e.into())),
+            None => Ok(None),
+        }
+    }
+
+    fn stage(&mut self, key: K, blob: Option<Vec<u8, B>>) -> Result<(), TxnError> {
+        if let Some((_, staged)) = self.ops.iter_mut().find(|(k, _)| *k == key) {
+            *staged = blob;
+            return Ok(());
+        }
+        self.ops.push((key, blob)).map_err(|_| TxnError::TooManyOps)
+    }
+}
+
+/// A key of the database and its decoded value (if any), see Database::entry
+/// Changes are only written back by or_insert/or_insert_with.
+pub struct Entry<'a, K, V, C, const N: usize, const B: usize, const CACH: usize, S, CP>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    db: &'a mut Database<K, V, C, N, B, CACH, S, CP>,
+    key: K,
+    value: Option<V>,
+    modified: bool,
+}
+
+impl<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>
+    Entry<'_, K, V, C, N, B, CACH, S, CP>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// Change the value if the key exists, nothing happens otherwise
+    pub fn and_modify<F: FnOnce(&mut V)>(mut self, f: F) -> Self {
+        if let Some(value) = self.value.as_mut() {
+            f(value);
+            self.modified = true;
+        }
+        self
+    }
+
+    /// The (possibly modified) value, or default stored under the key
+    pub fn or_insert(self, default: V) -> Result<V, DbError<C::Error>> {
+        self.or_insert_with(|| default)
+    }
+
+    /// Same as or_insert, default is only called if the key doesn't exist
+    pub fn or_insert_with<F: FnOnce() -> V>(self, default: F) -> Result<V, DbError<C::Error>> {
+        match self.value {
+            Some(value) if !self.modified => Ok(value),
+            Some(value) => {
+                self.db.put(self.key, value.clone())?;
+                Ok(value)
+            }
+            None => {
+                let value = default();
+                self.db.put(self.key, value.clone())?;
+                Ok(value)
+            }
+        }
+    }
+}
+
+// Erase and write one copy of a serialized image
+// One page at a time so we can report progress in between
+// Erase the whole image region, then write the image and its footer
+#[cfg(feature = "persistence")]
+fn write_image<F, P>(
+    flash: &mut F,
+    flash_offset: u32,
+    image: &[u8],
+    footer: &Footer,
+    status: &mut FlashProgress,
+    progress: &mut P,
+) -> Result<(), FlashError>
+where
+    F: NorFlash,
+    P: FnMut(FlashProgress),
+{
+    let page_size = F::ERASE_SIZE;
+    let padded = image::region_len(page_size);
+    let pages_needed = padded / page_size;
+
+    for page in 0..pages_needed {
+        let from = flash_offset + (page * page_size) as u32;
+        flash
+            .erase(from, from + page_size as u32)
+            .map_err(|_| FlashError::EraseError)?;
+        status.pages_erased += 1;
+        progress(*status);
+    }
+
+    // Write to flash, a page worth of bytes at a time
+    for (i, chunk) in image.chunks(page_size).enumerate() {
+        flash
+            .write(flash_offset + (i * page_size) as u32, chunk)
+            .map_err(|_| FlashError::WriteError)?;
+        status.bytes_written += chunk.len();
+        progress(*status);
+    }
+
+    flash
+        .write(
+            flash_offset + (padded - image::FOOTER_SIZE) as u32,
+            &footer.to_bytes(),
+        )
+        .map_err(|_| FlashError::WriteError)?;
+    status.bytes_written += image::FOOTER_SIZE;
+    progress(*status);
+    Ok(())
+}
+
+// Bytes one entry takes up in the image payload
+#[cfg(feature = "persistence")]
+fn record_size<K: serde::Serialize, const B: usize>(
+    key: &K,
+    blob: &[u8],
+    key_width: u8,
+) -> Result<usize, FlashError> {
+    if key_width != 0 {
+        return Ok(key_width as usize + 4 + blob.len());
+    }
+    let mut key_buf = [0u8; B];
+    let key_bytes =
+        postcard::to_slice(key, &mut key_buf).map_err(|_| FlashError::SerializationError)?;
+    Ok(4 + key_bytes.len() + 4 + blob.len())
+}
+
+// NOR flash can always clear bits, so zeros go on top of whatever is there
+#[cfg(feature = "persistence")]
+fn wipe_region<F: NorFlash>(flash: &mut F, offset: u32, len: usize) -> Result<(), FlashError> {
+    let zeros = [0u8; 256];
+    let chunk = zeros.len() - zeros.len() % F::WRITE_SIZE;
+    let mut pos = 0;
+    while pos < len {
+        let n = chunk.min(len - pos);
+        flash
+            .write(offset + pos as u32, &zeros[..n])
+            .map_err(|_| FlashError::WriteError)?;
+        pos += n;
+    }
+    flash
+        .erase(offset, offset + len as u32)
+        .map_err(|_| FlashError::EraseError)
+}
+
+/// What set_index() maps values to
+pub type IndexKey = u32;
+
+/// Function behind a computed key, see Database::compute
+pub type Computed<K, V, C, const N: usize, const B: usize, const CACH: usize, S, CP> =
+    fn(&Database<K, V, C, N, B, CACH, S, CP>) -> Option<V>;
+
+type ComputedKeys<K, V, C, const N: usize, const B: usize, const CACH: usize, S, CP> =
+    Vec<(K, Computed<K, V, C, N, B, CACH, S, CP>), MAX_COMPUTED>;
+
+type EntryResult<'a, K, V, C, const N: usize, const B: usize, const CACH: usize, S, CP> =
+    Result<Entry<'a, K, V, C, N, B, CACH, S, CP>, DbError<<C as Codec<V>>::Error>>;
+
+/// Integer values incr()/decr() work on
+pub trait Counter: Copy + Default {
+    fn add(self, delta: Self) -> Self;
+    fn sub(self, delta: Self) -> Self;
+}
+
+macro_rules! counter {
+    ($($t:ty),*) => {$(
+        impl Counter for $t {
+            fn add(self, delta: Self) -> Self {
+                self.saturating_add(delta)
+            }
+            fn sub(self, delta: Self) -> Self {
+                self.saturating_sub(delta)
+            }
+        }
+    )*};
+}
+
+counter!(u8, u16, u32, u64, usize, i8, i16, i32, i64, isize);
+
+struct Reservation<K> {
+    critical: fn(&K) -> bool,
+    slots: usize,
+    bytes: usize,
+}
+
+/// Why a Database operation failed
+/// E is the error type of the codec.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DbError<E> {
+    // The codec couldn't encode the value (e.g. it is bigger than B)
+    Encode(E),
+    // The stored bytes don't decode, wrong codec or a corrupted image
+    Decode(E),
+    // No free slot for another key
+    Full,
+    // The encoded value doesn't fit in a blob
+    TooLarge,
+    // The put would use room reserved for critical keys, see reserve()
+    Reserved,
+    // All MAX_COMPUTED computed keys are in use
+    TooManyComputed,
+    // The entry changed since it was read, see put_if_version()
+    VersionMismatch,
+    // The key rename() was to move the value to already has one
+    Exists,
+}
+
+impl<E: Into<CodecErrorKind>> DbError<E> {
+    /// The same error with the codec's error reduced to its kind, so code
+    /// handling databases with different codecs sees one error type
+    /// db.put(key, v).map_err(DbError::into_kind)?;
+    pub fn into_kind(self) -> DbError<CodecErrorKind> {
+        match self {
+            DbError::Encode(e) => DbError::Encode(e.into()),
+            DbError::Decode(e) => DbError::Decode(e.into()),
+            DbError::Full => DbError::Full,
+            DbError::TooLarge => DbError::TooLarge,
+            DbError::Reserved => DbError::Reserved,
+            DbError::TooManyComputed => DbError::TooManyComputed,
+            DbError::VersionMismatch => DbError::VersionMismatch,
+            DbError::Exists => DbError::Exists,
+        }
+    }
+}
+
+impl<E> From<StoreError> for DbError<E> {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::Full => DbError::Full,
+            StoreError::TooLarge => DbError::TooLarge,
+        }
+    }
+}
+
+impl From<StoreError> for FieldError {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::Full => FieldError::Full,
+            StoreError::TooLarge => FieldError::TooLarge,
         }
+    }
+}
+
+/// See Database::sync_epoch
+#[cfg(feature = "persistence")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct SyncToken {
+    epoch: u32,
+    generation: u32,
+}
+
+/// See Database::stats
+/// Only get() and put() are counted, get_uncached()/iter() don't touch the
+/// cache and can't update counters.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Stats {
+    pub cache_hits: u32,
+    pub cache_misses: u32,
+    /// Cache entries dropped to make room for another
+    pub evictions: u32,
+    pub encode_errors: u32,
+    pub decode_errors: u32,
+    pub entries: usize,
+    /// Encoded bytes of all values, compare with entries * B
+    pub blob_bytes: usize,
+}
+
+/// Time spent in the phases of a load, in microseconds
+#[cfg(feature = "persistence")]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct LoadTiming {
+    /// Reading header and payload from flash
+    pub flash_read_us: u64,
+    /// Checking the payload CRC
+    pub crc_us: u64,
+    /// Decrypting/authenticating a sealed image
+    pub unseal_us: u64,
+    /// Splitting the payload into entries. Keys are deserialized here,
+    /// values stay encoded until they are read (or use lazy.rs).
+    pub decode_us: u64,
+    /// The whole load, including a fallback to the backup image
+    pub total_us: u64,
+}
+
+/// Which copy of the image a load used
+#[cfg(feature = "persistence")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ImageSource {
+    Primary,
+    Backup,
+}
+
+/// Progress report for save_to_flash_with_progress / load_from_flash_with_progress
+/// Loading only fills in the entry counts.
+#[cfg(feature = "persistence")]
+#[derive(Debug, Clone, Copy, Default, defmt::Format)]
+pub struct FlashProgress {
+    pub bytes_written: usize,
+    pub bytes_total: usize,
+    pub pages_erased: usize,
+    pub pages_total: usize,
+    pub entries_processed: usize,
+    pub entries_total: usize,
+}
+
+// Keeps a running CRC over everything handed to the sink
+#[cfg(feature = "persistence")]
+struct CrcSink<S: FnMut(&[u8])> {
+    sink: S,
+    digest: crc32::Digest,
+}
+
+#[cfg(feature = "persistence")]
+impl<S: FnMut(&[u8])> CrcSink<S> {
+    fn write(&mut self, bytes: &[u8]) {
+        self.digest.update(bytes);
+        (self.sink)(bytes);
+    }
+}
+
+// Pulls bytes from the reader while keeping a running CRC
+#[cfg(feature = "persistence")]
+struct CrcReader<R> {
+    reader: R,
+    digest: crc32::Digest,
+}
 
+#[cfg(feature = "persistence")]
+impl<R, E> CrcReader<R>
+where
+    R: FnMut(&mut [u8]) -> Result<(), E>,
+{
+    fn read(&mut self, buf: &mut [u8]) -> Result<(), FlashError> {
+        (self.reader)(buf).map_err(|_| FlashError::ReadError)?;
+        self.digest.update(buf);
         Ok(())
     }
+
+    fn read_u32(&mut self) -> Result<u32, FlashError> {
+        let mut bytes = [0u8; 4];
+        self.read(&mut bytes)?;
+        Ok(u32::from_le_bytes(bytes))
+    }
 }
 
 #[derive(Debug, Clone, Copy, defmt::Format)]
@@ -264,4 +2991,45 @@ pub enum FlashError {
     WriteError,
     ReadError,
     DatabaseFull,
+    // The image header is missing or corrupt
+    BadHeader,
+    // The image was written by a newer format version
+    UnsupportedVersion,
+    // The payload does not match the CRC in the header
+    CrcMismatch,
+    // The image is sealed and has to be opened with open_sealed
+    Sealed,
+    // Wrong key, or the sealed image was modified
+    AuthenticationFailed,
+    // The supply check failed, nothing was written
+    LowVoltage,
+    // Nothing has been saved or loaded yet
+    NotPersisted,
+    // What was read back from flash differs from what was written
+    VerifyFailed,
+    // The backup region shares an erase block with the primary one
+    RegionOverlap,
+    // The codec failed on a value
+    Codec(CodecErrorKind),
+}
+
+// What a flush() before a save or import reports
+impl<E: Into<CodecErrorKind>> From<DbError<E>> for FlashError {
+    fn from(e: DbError<E>) -> Self {
+        match e {
+            DbError::Encode(e) | DbError::Decode(e) => FlashError::Codec(e.into()),
+            DbError::Full | DbError::Reserved => FlashError::DatabaseFull,
+            DbError::TooLarge => FlashError::BufferTooSmall,
+            _ => FlashError::SerializationError,
+        }
+    }
+}
+
+impl From<StoreError> for FlashError {
+    fn from(e: StoreError) -> Self {
+        match e {
+            StoreError::Full => FlashError::DatabaseFull,
+            StoreError::TooLarge => FlashError::BufferTooSmall,
+        }
+    }
 }
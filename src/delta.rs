@@ -0,0 +1,191 @@
+// Delta encoding for arrays of samples that change slowly
+// A row of readings from the same sensor ([i32; 16] of temperatures, a
+// [u16; 32] ADC buffer) differs little from one element to the next, so
+// Delta stores the first sample and then only the differences, each as a
+// zig-zag varint: a difference of -64..=63 is one byte no matter how big
+// the samples themselves are.
+//
+// type Db = Database<u8, [i16; 16], Delta, 8, 48, 2>;
+// [2150, 2151, 2151, 2149, ...] => 2 bytes for 2150, then 1 byte per sample
+//
+// B still has to fit the worst case, every difference as long as it gets
+// (3 bytes for i16, Sample::MAX_VARINT), the Database checks that.
+//
+// Blob layout, every number a LEB128 varint of the zig-zag encoded value
+// (0 => 0, -1 => 1, 1 => 2, -2 => 3, ...):
+// [first][sample 1 - sample 0][sample 2 - sample 1]...
+// heapless::Vec<_, N> values have [count] in front. Differences wrap around
+// (computed on the 64 bit two's complement value), so u64 samples that jump
+// across the whole range still round trip, they just don't get shorter.
+
+use crate::codec::Codec;
+use heapless::Vec;
+
+// Longest varint of a u64
+const MAX_VARINT: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DeltaError {
+    // Not enough room in the buffer for the encoding
+    BufferFull,
+    // The blob ends in the middle of a sample, or a varint is too long
+    Truncated,
+    // A decoded sample doesn't fit the sample type
+    OutOfRange,
+    // A Vec with more samples than it has room for
+    TooMany,
+    // Bytes left after the last sample
+    TrailingBytes,
+}
+
+/// Integers Delta can encode
+pub trait Sample: Copy + Default {
+    /// The value as a 64 bit two's complement integer
+    fn to_bits(self) -> i64;
+    /// Back from to_bits(), None if it doesn't fit
+    fn from_bits(bits: i64) -> Option<Self>;
+    /// Longest varint of a sample or a difference of two
+    const MAX_VARINT: usize;
+}
+
+macro_rules! sample {
+    ($($ty:ty),*) => {
+        $(
+            impl Sample for $ty {
+                // A difference needs one bit more than the samples
+                const MAX_VARINT: usize = (<$ty>::BITS as usize + 1).div_ceil(7);
+
+                fn to_bits(self) -> i64 {
+                    self as i64
+                }
+                fn from_bits(bits: i64) -> Option<Self> {
+                    <$ty>::try_from(bits).ok()
+                }
+            }
+        )*
+    };
+}
+
+sample!(i8, i16, i32, i64, u8, u16, u32);
+
+impl Sample for u64 {
+    const MAX_VARINT: usize = MAX_VARINT;
+
+    fn to_bits(self) -> i64 {
+        self as i64
+    }
+    fn from_bits(bits: i64) -> Option<Self> {
+        Some(bits as u64)
+    }
+}
+
+/// First sample and the differences, see the top of the file
+pub struct Delta;
+
+impl<S: Sample, const N: usize> Codec<[S; N]> for Delta {
+    type Error = DeltaError;
+    const MAX_ENCODED_SIZE: Option<usize> = Some(N * S::MAX_VARINT);
+
+    fn encode(dst: &mut [u8], v: &[S; N]) -> Result<usize, Self::Error> {
+        encode_samples(dst, 0, v)
+    }
+
+    fn decode(src: &[u8]) -> Result<[S; N], Self::Error> {
+        let mut out = [S::default(); N];
+        let n = decode_samples(src, &mut out)?;
+        if n != src.len() {
+            return Err(DeltaError::TrailingBytes);
+        }
+        Ok(out)
+    }
+}
+
+impl<S: Sample, const N: usize> Codec<Vec<S, N>> for Delta {
+    type Error = DeltaError;
+    // The count in front is a varint too
+    const MAX_ENCODED_SIZE: Option<usize> = Some(MAX_VARINT + N * S::MAX_VARINT);
+
+    fn encode(dst: &mut [u8], v: &Vec<S, N>) -> Result<usize, Self::Error> {
+        let n = write_varint(dst, v.len() as u64)?;
+        encode_samples(dst, n, v)
+    }
+
+    fn decode(src: &[u8]) -> Result<Vec<S, N>, Self::Error> {
+        let (count, mut pos) = read_varint(src)?;
+        if count > N as u64 {
+            return Err(DeltaError::TooMany);
+        }
+        let mut out = Vec::new();
+        // count <= N, the resize can't fail
+        let _ = out.resize_default(count as usize);
+        pos += decode_samples(&src[pos..], &mut out)?;
+        if pos != src.len() {
+            return Err(DeltaError::TrailingBytes);
+        }
+        Ok(out)
+    }
+}
+
+// Write samples at dst[pos..], returns the new end
+fn encode_samples<S: Sample>(
+    dst: &mut [u8],
+    mut pos: usize,
+    samples: &[S],
+) -> Result<usize, DeltaError> {
+    let mut prev = 0i64;
+    for sample in samples {
+        let bits = sample.to_bits();
+        let out = dst.get_mut(pos..).ok_or(DeltaError::BufferFull)?;
+        pos += write_varint(out, zigzag(bits.wrapping_sub(prev)))?;
+        prev = bits;
+    }
+    Ok(pos)
+}
+
+// Fill out from src, returns how many bytes that took
+fn decode_samples<S: Sample>(src: &[u8], out: &mut [S]) -> Result<usize, DeltaError> {
+    let mut prev = 0i64;
+    let mut pos = 0;
+    for sample in out.iter_mut() {
+        let (delta, n) = read_varint(&src[pos..])?;
+        pos += n;
+        prev = prev.wrapping_add(unzigzag(delta));
+        *sample = S::from_bits(prev).ok_or(DeltaError::OutOfRange)?;
+    }
+    Ok(pos)
+}
+
+fn zigzag(v: i64) -> u64 {
+    ((v << 1) ^ (v >> 63)) as u64
+}
+
+fn unzigzag(v: u64) -> i64 {
+    (v >> 1) as i64 ^ -((v & 1) as i64)
+}
+
+fn write_varint(dst: &mut [u8], mut v: u64) -> Result<usize, DeltaError> {
+    let mut n = 0;
+    loop {
+        let byte = (v & 0x7F) as u8;
+        v >>= 7;
+        let slot = dst.get_mut(n).ok_or(DeltaError::BufferFull)?;
+        n += 1;
+        if v == 0 {
+            *slot = byte;
+            return Ok(n);
+        }
+        *slot = byte | 0x80;
+    }
+}
+
+// The value and how many bytes it took
+fn read_varint(src: &[u8]) -> Result<(u64, usize), DeltaError> {
+    let mut v = 0u64;
+    for (n, byte) in src.iter().take(MAX_VARINT).enumerate() {
+        v |= ((byte & 0x7F) as u64) << (7 * n);
+        if byte & 0x80 == 0 {
+            return Ok((v, n + 1));
+        }
+    }
+    Err(DeltaError::Truncated)
+}
@@ -0,0 +1,157 @@
+// Writing from fault handlers
+// When a HardFault or panic hits, the Database may be in the middle of a put
+// and its RAM can't be trusted. emergency_put_bytes appends a standalone
+// record to a partition that was erased beforehand, without a Database,
+// without erasing (too slow and too risky in a fault) and without touching
+// anything but the stack.
+//
+// // At boot, once the last breadcrumbs were read
+// emergency::for_each(&mut flash, CRASH_LOG, |key, bytes| { ... })?;
+// emergency::clear(&mut flash, CRASH_LOG)?;
+//
+// // In the fault handler
+// let _ = emergency_put_bytes(&mut flash, CRASH_LOG, b"pc", &pc.to_le_bytes());
+//
+// Records are appended one after the other (little endian):
+// [magic: u16][key_len: u16][val_len: u16][reserved: u16][key][val][crc32: u32]
+// padded to a whole word. The CRC covers everything before it, so a record
+// torn by a reset is skipped when reading back.
+
+use crate::db::FlashError;
+use crate::image::CRC32;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+/// Largest record (header, key, value and CRC) emergency_put_bytes writes
+pub const MAX_RECORD: usize = 256;
+
+const MAGIC: u16 = 0xED0C;
+const RECORD_HEADER: usize = 8;
+
+/// Flash region reserved for emergency records
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Partition {
+    pub offset: u32,
+    pub len: u32,
+}
+
+impl Partition {
+    pub const fn new(offset: u32, len: u32) -> Self {
+        Self { offset, len }
+    }
+}
+
+/// Append key/bytes to partition, safe to call from a fault handler
+/// Fails with DatabaseFull once the partition has no room left, and with
+/// BufferTooSmall if the record would be bigger than MAX_RECORD.
+pub fn emergency_put_bytes<F: NorFlash>(
+    flash: &mut F,
+    partition: Partition,
+    key: &[u8],
+    bytes: &[u8],
+) -> Result<(), FlashError> {
+    let len = RECORD_HEADER + key.len() + bytes.len() + 4;
+    if len > MAX_RECORD {
+        return Err(FlashError::BufferTooSmall);
+    }
+    let padded = len.next_multiple_of(4).next_multiple_of(F::WRITE_SIZE);
+    if padded > MAX_RECORD {
+        return Err(FlashError::BufferTooSmall);
+    }
+
+    let pos = free_space(flash, partition)?;
+    if pos + padded as u32 > partition.len {
+        return Err(FlashError::DatabaseFull);
+    }
+
+    let mut record = [0xFFu8; MAX_RECORD];
+    record[0..2].copy_from_slice(&MAGIC.to_le_bytes());
+    record[2..4].copy_from_slice(&(key.len() as u16).to_le_bytes());
+    record[4..6].copy_from_slice(&(bytes.len() as u16).to_le_bytes());
+    record[6..8].copy_from_slice(&0u16.to_le_bytes());
+    let key_end = RECORD_HEADER + key.len();
+    record[RECORD_HEADER..key_end].copy_from_slice(key);
+    record[key_end..key_end + bytes.len()].copy_from_slice(bytes);
+    let crc = CRC32.checksum(&record[..len - 4]);
+    record[len - 4..len].copy_from_slice(&crc.to_le_bytes());
+
+    flash
+        .write(partition.offset + pos, &record[..padded])
+        .map_err(|_| FlashError::WriteError)
+}
+
+/// Call f with every intact record in partition, oldest first
+pub fn for_each<F, G>(flash: &mut F, partition: Partition, mut f: G) -> Result<(), FlashError>
+where
+    F: NorFlash,
+    G: FnMut(&[u8], &[u8]),
+{
+    let mut record = [0u8; MAX_RECORD];
+    let mut pos = 0;
+    while let Some(len) = record_at(flash, partition, pos, &mut record)? {
+        let key_len = u16::from_le_bytes([record[2], record[3]]) as usize;
+        let stored = u32::from_le_bytes([
+            record[len - 4],
+            record[len - 3],
+            record[len - 2],
+            record[len - 1],
+        ]);
+        if CRC32.checksum(&record[..len - 4]) == stored {
+            let key_end = RECORD_HEADER + key_len;
+            f(&record[RECORD_HEADER..key_end], &record[key_end..len - 4]);
+        }
+        pos += len.next_multiple_of(4).next_multiple_of(F::WRITE_SIZE) as u32;
+    }
+    Ok(())
+}
+
+/// Erase partition so it can take new records, not for fault context
+pub fn clear<F: NorFlash>(flash: &mut F, partition: Partition) -> Result<(), FlashError> {
+    flash
+        .erase(partition.offset, partition.offset + partition.len)
+        .map_err(|_| FlashError::EraseError)
+}
+
+// Offset (in the partition) right behind the last record
+fn free_space<F: NorFlash>(flash: &mut F, partition: Partition) -> Result<u32, FlashError> {
+    let mut record = [0u8; MAX_RECORD];
+    let mut pos = 0;
+    while let Some(len) = record_at(flash, partition, pos, &mut record)? {
+        pos += len.next_multiple_of(4).next_multiple_of(F::WRITE_SIZE) as u32;
+    }
+    Ok(pos)
+}
+
+// Read the record at pos into buf, returns its length (unpadded)
+// None at the end of the records: erased flash, the end of the partition
+// or a header that makes no sense.
+fn record_at<F: ReadNorFlash>(
+    flash: &mut F,
+    partition: Partition,
+    pos: u32,
+    buf: &mut [u8; MAX_RECORD],
+) -> Result<Option<usize>, FlashError> {
+    if pos + RECORD_HEADER as u32 > partition.len {
+        return Ok(None);
+    }
+    flash
+        .read(partition.offset + pos, &mut buf[..RECORD_HEADER])
+        .map_err(|_| FlashError::ReadError)?;
+    // Erased flash reads as 0xFFFF
+    if u16::from_le_bytes([buf[0], buf[1]]) != MAGIC {
+        return Ok(None);
+    }
+
+    let key_len = u16::from_le_bytes([buf[2], buf[3]]) as usize;
+    let val_len = u16::from_le_bytes([buf[4], buf[5]]) as usize;
+    let len = RECORD_HEADER + key_len + val_len + 4;
+    if len > MAX_RECORD || pos + len as u32 > partition.len {
+        return Ok(None);
+    }
+    flash
+        .read(
+            partition.offset + pos + RECORD_HEADER as u32,
+            &mut buf[RECORD_HEADER..len],
+        )
+        .map_err(|_| FlashError::ReadError)?;
+    Ok(Some(len))
+}
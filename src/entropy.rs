@@ -0,0 +1,82 @@
+// Source of randomness for the features that need it
+// (nonces for encryption, decoy padding, wear-leveling randomization)
+// Pass an Entropy implementation in when building the thing that needs it,
+// instead of each function taking its own ad-hoc RNG parameter.
+
+use nrf52840_hal::pac::RNG;
+
+pub trait Entropy {
+    /// Fill dst with random bytes
+    fn fill_bytes(&mut self, dst: &mut [u8]);
+
+    fn next_u32(&mut self) -> u32 {
+        let mut bytes = [0u8; 4];
+        self.fill_bytes(&mut bytes);
+        u32::from_le_bytes(bytes)
+    }
+}
+
+impl<E: Entropy + ?Sized> Entropy for &mut E {
+    fn fill_bytes(&mut self, dst: &mut [u8]) {
+        (**self).fill_bytes(dst)
+    }
+}
+
+/// Entropy from the nRF52840 RNG peripheral
+/// The HAL turns on bias correction, so the output is suitable for nonces.
+pub struct HardwareRng {
+    rng: nrf52840_hal::Rng,
+}
+
+impl HardwareRng {
+    pub fn new(rng: RNG) -> Self {
+        Self {
+            rng: nrf52840_hal::Rng::new(rng),
+        }
+    }
+}
+
+impl Entropy for HardwareRng {
+    fn fill_bytes(&mut self, dst: &mut [u8]) {
+        self.rng.random(dst)
+    }
+}
+
+/// Entropy for host builds (tools and tests on the desktop)
+/// Uses the randomly keyed SipHash behind std's HashMap over a counter.
+/// Good enough for tests and padding, don't use it for real keys.
+#[cfg(feature = "std")]
+pub struct StdEntropy {
+    state: std::collections::hash_map::RandomState,
+    counter: u64,
+}
+
+#[cfg(feature = "std")]
+impl StdEntropy {
+    pub fn new() -> Self {
+        Self {
+            state: std::collections::hash_map::RandomState::new(),
+            counter: 0,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl Default for StdEntropy {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(feature = "std")]
+impl Entropy for StdEntropy {
+    fn fill_bytes(&mut self, dst: &mut [u8]) {
+        use core::hash::BuildHasher;
+
+        for chunk in dst.chunks_mut(8) {
+            self.counter = self.counter.wrapping_add(1);
+            let word = self.state.hash_one(self.counter).to_le_bytes();
+            chunk.copy_from_slice(&word[..chunk.len()]);
+        }
+    }
+}
@@ -0,0 +1,715 @@
+// Stable numeric codes for the crate's errors
+// For reporting errors over a link where every byte counts (LoRa, NB-IoT
+// status frames): every error has code() -> u16 and from_code(u16), so the
+// backend can decode a report with this table instead of the defmt tables
+// of the exact firmware build.
+//
+// uplink.push_u16(e.code());
+// ...
+// match db::FlashError::from_code(code) { ... }   // same crate on the server
+//
+// The high byte is the error type (GROUP_* below), the low byte the variant.
+// Codes are never reused or renumbered: a new variant gets the next free
+// number, a removed one leaves a hole. Variants that wrap another error of
+// the crate (TxnError::Flash, L10nError::Key, ...) report the inner code, so
+// the backend sees what actually failed. Variants that carry data report
+// just the variant, and from_code() gives None for them since the data
+// can't be rebuilt from the code.
+
+use crate::cal::CalError;
+#[cfg(feature = "persistence")]
+use crate::cli::CliError;
+use crate::codec::{CheckedError, CodecErrorKind, FieldError, JsonError, MultiError, StreamError};
+use crate::compress::CompressError;
+use crate::crypto::CryptoError;
+#[cfg(feature = "crypto")]
+use crate::crypto::EncryptedError;
+use crate::db::{DbError, FlashError, TxnError};
+use crate::delta::DeltaError;
+use crate::fixed::FixedError;
+use crate::flags::FlagError;
+#[cfg(feature = "persistence")]
+use crate::flash::{self, LayoutError};
+use crate::geo::GeoError;
+use crate::keys::KeyError;
+use crate::kv::StoreError;
+use crate::l10n::L10nError;
+use crate::modbus::ModbusError;
+#[cfg(feature = "sync")]
+use crate::mqtt::DiscoveryError;
+use crate::msgpack::MsgPackError;
+use crate::namespace::NamespaceError;
+use crate::schedule::ScheduleError;
+#[cfg(feature = "persistence")]
+use crate::timeseries::TimeSeriesError;
+use crate::tlv::TlvError;
+#[cfg(feature = "sync")]
+use crate::transfer::TransferError;
+use crate::units::UnitError;
+use crate::versioned::VersionedError;
+
+pub const GROUP_FLASH: u16 = 0x01;
+pub const GROUP_DB: u16 = 0x02;
+pub const GROUP_TXN: u16 = 0x03;
+pub const GROUP_STORE: u16 = 0x04;
+pub const GROUP_FLASH_DRIVER: u16 = 0x05;
+pub const GROUP_LAYOUT: u16 = 0x06;
+pub const GROUP_NAMESPACE: u16 = 0x07;
+pub const GROUP_JSON: u16 = 0x08;
+pub const GROUP_MULTI: u16 = 0x09;
+pub const GROUP_CRYPTO: u16 = 0x0A;
+pub const GROUP_KEY: u16 = 0x0B;
+pub const GROUP_CAL: u16 = 0x0C;
+pub const GROUP_UNIT: u16 = 0x0D;
+pub const GROUP_FLAG: u16 = 0x0E;
+pub const GROUP_L10N: u16 = 0x0F;
+pub const GROUP_GEO: u16 = 0x10;
+pub const GROUP_SCHEDULE: u16 = 0x11;
+pub const GROUP_MODBUS: u16 = 0x12;
+pub const GROUP_CLI: u16 = 0x13;
+pub const GROUP_DISCOVERY: u16 = 0x14;
+pub const GROUP_TRANSFER: u16 = 0x15;
+pub const GROUP_FACTORY: u16 = 0x16;
+pub const GROUP_TIMESERIES: u16 = 0x17;
+pub const GROUP_COMPRESS: u16 = 0x18;
+pub const GROUP_FIELD: u16 = 0x19;
+pub const GROUP_MSGPACK: u16 = 0x1A;
+pub const GROUP_TLV: u16 = 0x1B;
+pub const GROUP_DELTA: u16 = 0x1C;
+pub const GROUP_ENCRYPTED: u16 = 0x1D;
+pub const GROUP_CHECKED: u16 = 0x1E;
+pub const GROUP_VERSIONED: u16 = 0x1F;
+pub const GROUP_STREAM: u16 = 0x20;
+pub const GROUP_FIXED: u16 = 0x21;
+pub const GROUP_CODEC_KIND: u16 = 0x22;
+
+/// The error type a code belongs to, one of the GROUP_* constants
+pub fn group(code: u16) -> u16 {
+    code >> 8
+}
+
+const fn code(group: u16, variant: u16) -> u16 {
+    (group << 8) | variant
+}
+
+// code() and from_code() for errors whose variants are all plain
+macro_rules! plain_codes {
+    ($ty:ty, $group:expr, { $($variant:ident = $code:literal,)* }) => {
+        impl $ty {
+            /// Stable code of the error, see errcode.rs
+            pub fn code(&self) -> u16 {
+                match self {
+                    $(Self::$variant => code($group, $code),)*
+                }
+            }
+
+            /// The error with this code, None if it isn't one of this type's
+            pub fn from_code(code: u16) -> Option<Self> {
+                if group(code) != $group {
+                    return None;
+                }
+                match code & 0xFF {
+                    $($code => Some(Self::$variant),)*
+                    _ => None,
+                }
+            }
+        }
+    };
+}
+
+impl FlashError {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            FlashError::SerializationError => code(GROUP_FLASH, 0x01),
+            FlashError::DeserializationError => code(GROUP_FLASH, 0x02),
+            FlashError::BufferTooSmall => code(GROUP_FLASH, 0x03),
+            FlashError::EraseError => code(GROUP_FLASH, 0x04),
+            FlashError::WriteError => code(GROUP_FLASH, 0x05),
+            FlashError::ReadError => code(GROUP_FLASH, 0x06),
+            FlashError::DatabaseFull => code(GROUP_FLASH, 0x07),
+            FlashError::BadHeader => code(GROUP_FLASH, 0x08),
+            FlashError::UnsupportedVersion => code(GROUP_FLASH, 0x09),
+            FlashError::CrcMismatch => code(GROUP_FLASH, 0x0A),
+            FlashError::Sealed => code(GROUP_FLASH, 0x0B),
+            FlashError::AuthenticationFailed => code(GROUP_FLASH, 0x0C),
+            FlashError::LowVoltage => code(GROUP_FLASH, 0x0D),
+            FlashError::NotPersisted => code(GROUP_FLASH, 0x0E),
+            FlashError::VerifyFailed => code(GROUP_FLASH, 0x0F),
+            FlashError::RegionOverlap => code(GROUP_FLASH, 0x10),
+            FlashError::Codec(kind) => kind.code(),
+        }
+    }
+
+    /// The error with this code, a CodecErrorKind code gives Codec
+    pub fn from_code(code: u16) -> Option<Self> {
+        if let Some(kind) = CodecErrorKind::from_code(code) {
+            return Some(FlashError::Codec(kind));
+        }
+        if group(code) != GROUP_FLASH {
+            return None;
+        }
+        match code & 0xFF {
+            0x01 => Some(FlashError::SerializationError),
+            0x02 => Some(FlashError::DeserializationError),
+            0x03 => Some(FlashError::BufferTooSmall),
+            0x04 => Some(FlashError::EraseError),
+            0x05 => Some(FlashError::WriteError),
+            0x06 => Some(FlashError::ReadError),
+            0x07 => Some(FlashError::DatabaseFull),
+            0x08 => Some(FlashError::BadHeader),
+            0x09 => Some(FlashError::UnsupportedVersion),
+            0x0A => Some(FlashError::CrcMismatch),
+            0x0B => Some(FlashError::Sealed),
+            0x0C => Some(FlashError::AuthenticationFailed),
+            0x0D => Some(FlashError::LowVoltage),
+            0x0E => Some(FlashError::NotPersisted),
+            0x0F => Some(FlashError::VerifyFailed),
+            0x10 => Some(FlashError::RegionOverlap),
+            _ => None,
+        }
+    }
+}
+
+plain_codes!(StoreError, GROUP_STORE, {
+    Full = 0x01,
+    TooLarge = 0x02,
+});
+
+#[cfg(feature = "persistence")]
+plain_codes!(flash::FlashError, GROUP_FLASH_DRIVER, {
+    OutOfBounds = 0x01,
+    Unaligned = 0x02,
+    Other = 0x03,
+});
+
+plain_codes!(NamespaceError, GROUP_NAMESPACE, {
+    TooLong = 0x01,
+    BadNamespace = 0x02,
+    Storage = 0x03,
+});
+
+plain_codes!(FieldError, GROUP_FIELD, {
+    NoField = 0x01,
+    Malformed = 0x02,
+    TooLarge = 0x03,
+    Full = 0x04,
+    Reserved = 0x05,
+    Encode = 0x06,
+});
+
+plain_codes!(MsgPackError, GROUP_MSGPACK, {
+    BufferFull = 0x01,
+    Eof = 0x02,
+    UnexpectedType = 0x03,
+    BadUtf8 = 0x04,
+    UnknownLength = 0x05,
+    TrailingBytes = 0x06,
+    Custom = 0x07,
+});
+
+plain_codes!(TlvError, GROUP_TLV, {
+    BufferFull = 0x01,
+    TooLarge = 0x02,
+    Eof = 0x03,
+    BadLength = 0x04,
+    BadUtf8 = 0x05,
+    BadTag = 0x06,
+    Unsupported = 0x07,
+    Custom = 0x08,
+});
+
+plain_codes!(DeltaError, GROUP_DELTA, {
+    BufferFull = 0x01,
+    Truncated = 0x02,
+    OutOfRange = 0x03,
+    TooMany = 0x04,
+    TrailingBytes = 0x05,
+});
+
+plain_codes!(FixedError, GROUP_FIXED, {
+    BufferTooSmall = 0x01,
+    BadLength = 0x02,
+});
+
+plain_codes!(CodecErrorKind, GROUP_CODEC_KIND, {
+    BufferTooSmall = 0x01,
+    Malformed = 0x02,
+    UnsupportedVersion = 0x03,
+    Integrity = 0x04,
+    Unsupported = 0x05,
+    Other = 0x06,
+});
+
+plain_codes!(CryptoError, GROUP_CRYPTO, {
+    BufferTooSmall = 0x01,
+    AuthenticationFailed = 0x02,
+});
+
+plain_codes!(CalError, GROUP_CAL, {
+    NotMonotonic = 0x01,
+    BadLength = 0x02,
+    BufferTooSmall = 0x03,
+    NotFound = 0x04,
+    Storage = 0x05,
+});
+
+plain_codes!(GeoError, GROUP_GEO, {
+    OutOfRange = 0x01,
+    BadLength = 0x02,
+    BufferTooSmall = 0x03,
+});
+
+plain_codes!(ScheduleError, GROUP_SCHEDULE, {
+    Unaligned = 0x01,
+    BadWindow = 0x02,
+});
+
+plain_codes!(ModbusError, GROUP_MODBUS, {
+    IllegalDataAddress = 0x01,
+    IllegalDataValue = 0x02,
+    DeviceFailure = 0x03,
+});
+
+#[cfg(feature = "sync")]
+plain_codes!(DiscoveryError, GROUP_DISCOVERY, {
+    TooLong = 0x01,
+    BadValue = 0x02,
+});
+
+impl<E> DbError<E> {
+    /// Stable code of the error, see errcode.rs
+    /// The codec's own error isn't part of it.
+    pub fn code(&self) -> u16 {
+        match self {
+            DbError::Encode(_) => code(GROUP_DB, 0x01),
+            DbError::Decode(_) => code(GROUP_DB, 0x02),
+            DbError::Full => code(GROUP_DB, 0x03),
+            DbError::TooLarge => code(GROUP_DB, 0x04),
+            DbError::Reserved => code(GROUP_DB, 0x05),
+            DbError::TooManyComputed => code(GROUP_DB, 0x06),
+            DbError::VersionMismatch => code(GROUP_DB, 0x07),
+            DbError::Exists => code(GROUP_DB, 0x08),
+        }
+    }
+
+    /// The error with this code, None for Encode and Decode
+    pub fn from_code(code: u16) -> Option<Self> {
+        if group(code) != GROUP_DB {
+            return None;
+        }
+        match code & 0xFF {
+            0x03 => Some(DbError::Full),
+            0x04 => Some(DbError::TooLarge),
+            0x05 => Some(DbError::Reserved),
+            0x06 => Some(DbError::TooManyComputed),
+            0x07 => Some(DbError::VersionMismatch),
+            0x08 => Some(DbError::Exists),
+            _ => None,
+        }
+    }
+}
+
+impl TxnError {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            TxnError::TooManyOps => code(GROUP_TXN, 0x01),
+            TxnError::Encode(kind) => kind.code(),
+            TxnError::Full => code(GROUP_TXN, 0x03),
+            TxnError::Aborted => code(GROUP_TXN, 0x04),
+            TxnError::Reserved => code(GROUP_TXN, 0x05),
+            TxnError::Flash(e) => e.code(),
+        }
+    }
+
+    /// The error with this code, a FlashError code gives TxnError::Flash
+    /// and a CodecErrorKind code TxnError::Encode
+    pub fn from_code(code: u16) -> Option<Self> {
+        if let Some(kind) = CodecErrorKind::from_code(code) {
+            return Some(TxnError::Encode(kind));
+        }
+        if let Some(e) = FlashError::from_code(code) {
+            return Some(TxnError::Flash(e));
+        }
+        if group(code) != GROUP_TXN {
+            return None;
+        }
+        match code & 0xFF {
+            0x01 => Some(TxnError::TooManyOps),
+            0x03 => Some(TxnError::Full),
+            0x04 => Some(TxnError::Aborted),
+            0x05 => Some(TxnError::Reserved),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "persistence")]
+impl LayoutError {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            LayoutError::OutOfBounds(_) => code(GROUP_LAYOUT, 0x01),
+            LayoutError::Misaligned(_) => code(GROUP_LAYOUT, 0x02),
+            LayoutError::Overlap(_, _) => code(GROUP_LAYOUT, 0x03),
+            LayoutError::OverlapsFirmware(_) => code(GROUP_LAYOUT, 0x04),
+        }
+    }
+
+    /// Always None, every variant carries the partition index
+    pub fn from_code(_code: u16) -> Option<Self> {
+        None
+    }
+}
+
+impl JsonError {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            JsonError::Ser(_) => code(GROUP_JSON, 0x01),
+            JsonError::De(_) => code(GROUP_JSON, 0x02),
+        }
+    }
+
+    /// Always None, both variants carry the serde_json_core error
+    pub fn from_code(_code: u16) -> Option<Self> {
+        None
+    }
+}
+
+impl MultiError {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            MultiError::Empty => code(GROUP_MULTI, 0x01),
+            MultiError::UnknownFormat(_) => code(GROUP_MULTI, 0x02),
+            MultiError::Postcard(_) => code(GROUP_MULTI, 0x03),
+            MultiError::Json(e) => e.code(),
+        }
+    }
+
+    /// The error with this code, only Empty has no data
+    pub fn from_code(code: u16) -> Option<Self> {
+        (code == MultiError::Empty.code()).then_some(MultiError::Empty)
+    }
+}
+
+#[cfg(feature = "crypto")]
+impl<E> EncryptedError<E> {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            EncryptedError::Inner(_) => code(GROUP_ENCRYPTED, 0x01),
+            EncryptedError::Crypto(e) => e.code(),
+        }
+    }
+
+    /// The error with this code, a CryptoError code gives Crypto
+    pub fn from_code(code: u16) -> Option<Self> {
+        CryptoError::from_code(code).map(EncryptedError::Crypto)
+    }
+}
+
+impl<E> CheckedError<E> {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            CheckedError::Inner(_) => code(GROUP_CHECKED, 0x01),
+            CheckedError::TooSmall => code(GROUP_CHECKED, 0x02),
+            CheckedError::CrcMismatch => code(GROUP_CHECKED, 0x03),
+        }
+    }
+
+    /// The error with this code, None for Inner
+    pub fn from_code(code: u16) -> Option<Self> {
+        if group(code) != GROUP_CHECKED {
+            return None;
+        }
+        match code & 0xFF {
+            0x02 => Some(CheckedError::TooSmall),
+            0x03 => Some(CheckedError::CrcMismatch),
+            _ => None,
+        }
+    }
+}
+
+impl<E> StreamError<E> {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            StreamError::Codec(_) => code(GROUP_STREAM, 0x01),
+            StreamError::TooLarge => code(GROUP_STREAM, 0x02),
+            StreamError::Io => code(GROUP_STREAM, 0x03),
+        }
+    }
+
+    /// The error with this code, None for Codec
+    pub fn from_code(code: u16) -> Option<Self> {
+        if group(code) != GROUP_STREAM {
+            return None;
+        }
+        match code & 0xFF {
+            0x02 => Some(StreamError::TooLarge),
+            0x03 => Some(StreamError::Io),
+            _ => None,
+        }
+    }
+}
+
+impl<E> VersionedError<E> {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            VersionedError::Inner(_) => code(GROUP_VERSIONED, 0x01),
+            VersionedError::Empty => code(GROUP_VERSIONED, 0x02),
+            VersionedError::TooNew(_) => code(GROUP_VERSIONED, 0x03),
+            VersionedError::Upgrade(_) => code(GROUP_VERSIONED, 0x04),
+        }
+    }
+
+    /// The error with this code, only Empty has no data
+    pub fn from_code(code: u16) -> Option<Self> {
+        (group(code) == GROUP_VERSIONED && code & 0xFF == 0x02).then_some(VersionedError::Empty)
+    }
+}
+
+impl<E> CompressError<E> {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            CompressError::Inner(_) => code(GROUP_COMPRESS, 0x01),
+            CompressError::TooLarge => code(GROUP_COMPRESS, 0x02),
+            CompressError::WrongDictionary(_) => code(GROUP_COMPRESS, 0x03),
+            CompressError::Corrupt => code(GROUP_COMPRESS, 0x04),
+        }
+    }
+
+    /// The error with this code, None for the variants with data
+    pub fn from_code(code: u16) -> Option<Self> {
+        if group(code) != GROUP_COMPRESS {
+            return None;
+        }
+        match code & 0xFF {
+            0x02 => Some(CompressError::TooLarge),
+            0x04 => Some(CompressError::Corrupt),
+            _ => None,
+        }
+    }
+}
+
+impl KeyError {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            KeyError::Empty => code(GROUP_KEY, 0x01),
+            KeyError::Reserved(_) => code(GROUP_KEY, 0x02),
+            KeyError::TooLong => code(GROUP_KEY, 0x03),
+        }
+    }
+
+    /// The error with this code, None for Reserved
+    pub fn from_code(code: u16) -> Option<Self> {
+        if group(code) != GROUP_KEY {
+            return None;
+        }
+        match code & 0xFF {
+            0x01 => Some(KeyError::Empty),
+            0x03 => Some(KeyError::TooLong),
+            _ => None,
+        }
+    }
+}
+
+impl UnitError {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            UnitError::UnknownKey => code(GROUP_UNIT, 0x01),
+            UnitError::WrongUnit { .. } => code(GROUP_UNIT, 0x02),
+            UnitError::Incompatible => code(GROUP_UNIT, 0x03),
+            UnitError::Storage => code(GROUP_UNIT, 0x04),
+        }
+    }
+
+    /// The error with this code, None for WrongUnit
+    pub fn from_code(code: u16) -> Option<Self> {
+        if group(code) != GROUP_UNIT {
+            return None;
+        }
+        match code & 0xFF {
+            0x01 => Some(UnitError::UnknownKey),
+            0x03 => Some(UnitError::Incompatible),
+            0x04 => Some(UnitError::Storage),
+            _ => None,
+        }
+    }
+}
+
+impl FlagError {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            FlagError::Key(e) => e.code(),
+            FlagError::BadPercentage => code(GROUP_FLAG, 0x02),
+            FlagError::NoArms => code(GROUP_FLAG, 0x03),
+            FlagError::Storage => code(GROUP_FLAG, 0x04),
+        }
+    }
+
+    /// The error with this code, a NamespaceError code gives FlagError::Key
+    pub fn from_code(code: u16) -> Option<Self> {
+        if let Some(e) = NamespaceError::from_code(code) {
+            return Some(FlagError::Key(e));
+        }
+        if group(code) != GROUP_FLAG {
+            return None;
+        }
+        match code & 0xFF {
+            0x02 => Some(FlagError::BadPercentage),
+            0x03 => Some(FlagError::NoArms),
+            0x04 => Some(FlagError::Storage),
+            _ => None,
+        }
+    }
+}
+
+impl L10nError {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            L10nError::Key(e) => e.code(),
+            L10nError::Storage => code(GROUP_L10N, 0x02),
+        }
+    }
+
+    /// The error with this code, a NamespaceError code gives L10nError::Key
+    pub fn from_code(code: u16) -> Option<Self> {
+        if let Some(e) = NamespaceError::from_code(code) {
+            return Some(L10nError::Key(e));
+        }
+        (code == L10nError::Storage.code()).then_some(L10nError::Storage)
+    }
+}
+
+#[cfg(feature = "persistence")]
+impl CliError {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            CliError::UnknownCommand => code(GROUP_CLI, 0x01),
+            CliError::MissingArgument => code(GROUP_CLI, 0x02),
+            CliError::BadKey => code(GROUP_CLI, 0x03),
+            CliError::BadValue => code(GROUP_CLI, 0x04),
+            CliError::NotFound => code(GROUP_CLI, 0x05),
+            CliError::Store => code(GROUP_CLI, 0x06),
+            CliError::Flash(e) => e.code(),
+            CliError::Output => code(GROUP_CLI, 0x08),
+        }
+    }
+
+    /// The error with this code, a FlashError code gives CliError::Flash
+    pub fn from_code(code: u16) -> Option<Self> {
+        if let Some(e) = FlashError::from_code(code) {
+            return Some(CliError::Flash(e));
+        }
+        if group(code) != GROUP_CLI {
+            return None;
+        }
+        match code & 0xFF {
+            0x01 => Some(CliError::UnknownCommand),
+            0x02 => Some(CliError::MissingArgument),
+            0x03 => Some(CliError::BadKey),
+            0x04 => Some(CliError::BadValue),
+            0x05 => Some(CliError::NotFound),
+            0x06 => Some(CliError::Store),
+            0x08 => Some(CliError::Output),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "sync")]
+impl TransferError {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            TransferError::Transport => code(GROUP_TRANSFER, 0x01),
+            TransferError::Flash(e) => e.code(),
+            TransferError::BadFrame => code(GROUP_TRANSFER, 0x03),
+            TransferError::TooLarge => code(GROUP_TRANSFER, 0x04),
+            TransferError::CrcMismatch => code(GROUP_TRANSFER, 0x05),
+            TransferError::NoImage => code(GROUP_TRANSFER, 0x06),
+        }
+    }
+
+    /// The error with this code, a FlashError code gives TransferError::Flash
+    pub fn from_code(code: u16) -> Option<Self> {
+        if let Some(e) = FlashError::from_code(code) {
+            return Some(TransferError::Flash(e));
+        }
+        if group(code) != GROUP_TRANSFER {
+            return None;
+        }
+        match code & 0xFF {
+            0x01 => Some(TransferError::Transport),
+            0x03 => Some(TransferError::BadFrame),
+            0x04 => Some(TransferError::TooLarge),
+            0x05 => Some(TransferError::CrcMismatch),
+            0x06 => Some(TransferError::NoImage),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "persistence")]
+impl<E> TimeSeriesError<E> {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        match self {
+            TimeSeriesError::Encode(_) => code(GROUP_TIMESERIES, 0x01),
+            TimeSeriesError::Decode(_) => code(GROUP_TIMESERIES, 0x02),
+            TimeSeriesError::TooLarge => code(GROUP_TIMESERIES, 0x03),
+            TimeSeriesError::OutOfOrder => code(GROUP_TIMESERIES, 0x04),
+            TimeSeriesError::Flash(e) => e.code(),
+        }
+    }
+
+    /// The error with this code, a FlashError code gives TimeSeriesError::Flash
+    pub fn from_code(code: u16) -> Option<Self> {
+        if let Some(e) = FlashError::from_code(code) {
+            return Some(TimeSeriesError::Flash(e));
+        }
+        if group(code) != GROUP_TIMESERIES {
+            return None;
+        }
+        match code & 0xFF {
+            0x03 => Some(TimeSeriesError::TooLarge),
+            0x04 => Some(TimeSeriesError::OutOfOrder),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<E> crate::factory::FactoryError<E> {
+    /// Stable code of the error, see errcode.rs
+    pub fn code(&self) -> u16 {
+        use crate::factory::FactoryError;
+        match self {
+            FactoryError::Db(e) => e.code(),
+            FactoryError::Flash(e) => e.code(),
+            FactoryError::Device(_) => code(GROUP_FACTORY, 0x03),
+        }
+    }
+
+    /// The error with this code, a DbError or FlashError code gives the
+    /// matching variant
+    pub fn from_code(code: u16) -> Option<Self> {
+        use crate::factory::FactoryError;
+        if let Some(e) = DbError::from_code(code) {
+            return Some(FactoryError::Db(e));
+        }
+        FlashError::from_code(code).map(FactoryError::Flash)
+    }
+}
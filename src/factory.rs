@@ -0,0 +1,145 @@
+// Factory images, built on the host (std feature)
+// Provisioning data (serial numbers, calibration defaults, keys) can be
+// baked into a flash image on the desktop and programmed together with the
+// firmware, instead of being written by first-boot code. The image comes
+// out of the same Database, codec and save code as on the device, so the
+// board opens it like any image it wrote itself.
+//
+// let image = factory::build::<_, _, Postcard, 32, 64>(
+//     [(KEY_SERIAL, Setting::Serial(1234)), (KEY_GAIN, Setting::Gain(12))],
+//     APP_VERSION,
+//     device_id,
+//     FLASH_STORAGE_ADDR,
+//     4096,
+// )?;
+// std::fs::write("db.bin", &image.bytes)?;   // program at image.offset
+//
+// N and B have to match the firmware's Database.
+//
+// For a production batch, a Template holds what all units share and a
+// callback adds (or replaces) the per-unit entries, e.g. the serial number
+// and a key fetched from the HSM:
+//
+// let template = Template {
+//     entries,
+//     app_version: APP_VERSION,
+//     flash_offset: FLASH_STORAGE_ADDR,
+//     erase_size: 4096,
+// };
+// for image in template.build_batch::<Postcard, _, _, _, 32, 64>(device_ids, |device_id, entries| {
+//     entries.push((KEY_SERIAL, Setting::Serial(next_serial())));
+//     entries.push((KEY_SECRET, Setting::Key(hsm.derive(device_id)?)));
+//     Ok::<_, HsmError>(())
+// }) {
+//     let image = image?;
+//     std::fs::write(format!("{:016x}.bin", image.device_id), &image.bytes)?;
+// }
+
+use crate::codec::Codec;
+use crate::db::{Database, DbError, FlashError};
+use crate::image;
+
+#[derive(Debug, Clone, Copy)]
+pub enum FactoryError<E> {
+    // An entry couldn't be encoded or doesn't fit
+    Db(DbError<E>),
+    Flash(FlashError),
+    // The personalize callback of a batch failed for this device ID
+    Device(u64),
+}
+
+impl<E> From<DbError<E>> for FactoryError<E> {
+    fn from(e: DbError<E>) -> Self {
+        FactoryError::Db(e)
+    }
+}
+
+impl<E> From<FlashError> for FactoryError<E> {
+    fn from(e: FlashError) -> Self {
+        FactoryError::Flash(e)
+    }
+}
+
+/// A padded image (footer included) and where to program it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FactoryImage {
+    pub device_id: u64,
+    pub offset: u32,
+    pub bytes: std::vec::Vec<u8>,
+}
+
+/// Build the image a Database holding entries would save at flash_offset
+/// app_version and device_id go into the header as with
+/// Database::set_version_stamp. erase_size is the page size of the target
+/// flash (4096 on the nRF52840).
+pub fn build<K, V, C, I, const N: usize, const B: usize>(
+    entries: I,
+    app_version: u32,
+    device_id: u64,
+    flash_offset: u32,
+    erase_size: usize,
+) -> Result<FactoryImage, FactoryError<C::Error>>
+where
+    C: Codec<V>,
+    K: Eq + core::hash::Hash + Clone + serde::Serialize,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    I: IntoIterator<Item = (K, V)>,
+{
+    // The cache doesn't end up in the image, one slot is enough
+    let mut db: Database<K, V, C, N, B, 1> = Database::new();
+    db.set_version_stamp(app_version, device_id);
+    for (key, val) in entries {
+        db.put(key, val)?;
+    }
+
+    let mut out = std::vec![0u8; image::region_len(erase_size)];
+    let artifact = db.to_artifact(flash_offset, erase_size, &mut out)?;
+    Ok(FactoryImage {
+        device_id,
+        offset: artifact.offset,
+        bytes: artifact.bytes.to_vec(),
+    })
+}
+
+/// What every image of a batch has in common, see the top of the file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Template<K, V> {
+    pub entries: std::vec::Vec<(K, V)>,
+    pub app_version: u32,
+    pub flash_offset: u32,
+    pub erase_size: usize,
+}
+
+impl<K, V> Template<K, V>
+where
+    K: Eq + core::hash::Hash + Clone + serde::Serialize,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    /// One image per device ID, built as they are pulled from the iterator
+    /// personalize gets the device ID and an empty list for the entries of
+    /// that unit, they go on top of the template entries (same key = the
+    /// unit's value wins). If it fails, that image is a FactoryError::Device
+    /// and the batch goes on with the next device.
+    pub fn build_batch<'a, C, D, F, E, const N: usize, const B: usize>(
+        &'a self,
+        devices: D,
+        mut personalize: F,
+    ) -> impl Iterator<Item = Result<FactoryImage, FactoryError<C::Error>>> + 'a
+    where
+        C: Codec<V> + 'a,
+        D: IntoIterator<Item = u64> + 'a,
+        F: FnMut(u64, &mut std::vec::Vec<(K, V)>) -> Result<(), E> + 'a,
+    {
+        devices.into_iter().map(move |device_id| {
+            let mut own = std::vec::Vec::new();
+            personalize(device_id, &mut own).map_err(|_| FactoryError::Device(device_id))?;
+            build::<K, V, C, _, N, B>(
+                self.entries.iter().cloned().chain(own),
+                self.app_version,
+                device_id,
+                self.flash_offset,
+                self.erase_size,
+            )
+        })
+    }
+}
@@ -0,0 +1,140 @@
+// Fixed point numbers
+// An f32 field costs 4 bytes in Postcard whatever its value, and its JSON
+// text depends on the float formatting of the build. Milli (thousandths) and
+// Q16 (Q15.16, 16 fraction bits) keep the number as an i32 instead, which
+// Postcard writes as a zig-zag varint: 21.5 °C as Milli is 21500, 3 bytes.
+//
+// #[derive(Serialize, Deserialize)]
+// struct Sensor {
+//     #[serde(with = "fixed::milli")]
+//     temp_c: f32,                  // the struct keeps its f32
+//     gain: Q16,                    // or the type itself, Q16::from(1.25)
+// }
+//
+// A bare f32 value goes through the Fixed codec, which writes the i32 little
+// endian: Database<u8, f32, Fixed<Milli>, 8, 4, 2>.
+//
+// Conversions from f32 round to the nearest step and saturate at the ends of
+// the i32 range (NaN is 0). Milli covers ±2147483.647 in steps of 0.001, Q16
+// ±32768 in steps of 1/65536.
+
+use crate::codec::Codec;
+use core::marker::PhantomData;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FixedError {
+    BufferTooSmall,
+    BadLength,
+}
+
+/// A number stored as an i32 number of steps
+pub trait FixedPoint: Copy + From<f32> + Into<f32> {
+    fn raw(self) -> i32;
+    fn from_raw(raw: i32) -> Self;
+}
+
+macro_rules! fixed_point {
+    ($ty:ident, $scale:expr) => {
+        impl FixedPoint for $ty {
+            fn raw(self) -> i32 {
+                self.0
+            }
+            fn from_raw(raw: i32) -> Self {
+                Self(raw)
+            }
+        }
+
+        impl From<f32> for $ty {
+            fn from(v: f32) -> Self {
+                // `as` saturates and maps NaN to 0
+                Self(libm::roundf(v * $scale) as i32)
+            }
+        }
+
+        impl From<$ty> for f32 {
+            fn from(v: $ty) -> f32 {
+                v.0 as f32 / $scale
+            }
+        }
+    };
+}
+
+/// Thousandths, 21.5 is Milli(21500)
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    defmt::Format,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct Milli(pub i32);
+
+/// Q15.16, 1.25 is Q16(81920)
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    defmt::Format,
+    serde::Serialize,
+    serde::Deserialize,
+)]
+#[serde(transparent)]
+pub struct Q16(pub i32);
+
+fixed_point!(Milli, 1000.0);
+fixed_point!(Q16, 65536.0);
+
+// For #[serde(with = "fixed::milli")] on f32 fields
+macro_rules! serde_with {
+    ($module:ident, $ty:ident) => {
+        #[doc = concat!("Serde adapter that stores an f32 field as ", stringify!($ty))]
+        pub mod $module {
+            use super::$ty;
+            use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+            pub fn serialize<S: Serializer>(v: &f32, s: S) -> Result<S::Ok, S::Error> {
+                $ty::from(*v).serialize(s)
+            }
+
+            pub fn deserialize<'de, D: Deserializer<'de>>(d: D) -> Result<f32, D::Error> {
+                $ty::deserialize(d).map(f32::from)
+            }
+        }
+    };
+}
+
+serde_with!(milli, Milli);
+serde_with!(q16, Q16);
+
+/// f32 values as F's i32, 4 bytes little endian
+pub struct Fixed<F>(PhantomData<F>);
+
+impl<F: FixedPoint> Codec<f32> for Fixed<F> {
+    type Error = FixedError;
+    const MAX_ENCODED_SIZE: Option<usize> = Some(4);
+
+    fn encode(dst: &mut [u8], v: &f32) -> Result<usize, Self::Error> {
+        let out = dst.get_mut(..4).ok_or(FixedError::BufferTooSmall)?;
+        out.copy_from_slice(&F::from(*v).raw().to_le_bytes());
+        Ok(4)
+    }
+
+    fn decode(src: &[u8]) -> Result<f32, Self::Error> {
+        let bytes: [u8; 4] = src.try_into().map_err(|_| FixedError::BadLength)?;
+        Ok(F::from_raw(i32::from_le_bytes(bytes)).into())
+    }
+}
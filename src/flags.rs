@@ -0,0 +1,132 @@
+// Feature flags
+// Flags live in the "flag" namespace of a String keyed database, so a staged
+// rollout is just a small config delta pushed to the fleet:
+//
+// flags::set(&mut db, "new_ui", Flag::Rollout(10))?;   // 10% of devices
+// if flags::is_enabled(&mut db, "new_ui", flash::device_id(&p.FICR)) { ... }
+//
+// Every device lands in a bucket 0..100 per flag, derived from the flag name
+// and device_entropy (anything stable per device, e.g. the FICR device ID).
+// Raising the percentage only ever adds devices, nobody flips back off.
+//
+// Experiments (A/B tests) go next to the flags in the "exp" namespace. The
+// arm is worked out from the device ID and a salt the first time and then
+// stored, so a device stays in its cohort across reboots and firmware updates:
+//
+// let arm = flags::cohort(&mut db, "checkout_v2", 2, 0x5eed, device_id)?;
+
+use crate::codec::Codec;
+use crate::crc32::Crc32;
+use crate::db::Database;
+use crate::namespace::{self, NamespaceError};
+use heapless::String;
+
+pub const NAMESPACE: &str = "flag";
+pub const EXPERIMENT_NAMESPACE: &str = "exp";
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, defmt::Format, serde::Serialize, serde::Deserialize,
+)]
+pub enum Flag {
+    Off,
+    On,
+    /// On for this percentage of devices (0..=100)
+    Rollout(u8),
+    /// Persisted experiment arm, see cohort()
+    Cohort(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum FlagError {
+    Key(NamespaceError),
+    // Rollout percentages go up to 100
+    BadPercentage,
+    // An experiment needs at least one arm
+    NoArms,
+    Storage,
+}
+
+pub fn set<C, const L: usize, const N: usize, const B: usize, const CACH: usize>(
+    db: &mut Database<String<L>, Flag, C, N, B, CACH>,
+    name: &str,
+    flag: Flag,
+) -> Result<(), FlagError>
+where
+    C: Codec<Flag>,
+{
+    if let Flag::Rollout(pct) = flag {
+        if pct > 100 {
+            return Err(FlagError::BadPercentage);
+        }
+    }
+    let key = namespace::key(NAMESPACE, name).map_err(FlagError::Key)?;
+    db.put(key, flag).map_err(|_| FlagError::Storage)
+}
+
+pub fn get<C, const L: usize, const N: usize, const B: usize, const CACH: usize>(
+    db: &mut Database<String<L>, Flag, C, N, B, CACH>,
+    name: &str,
+) -> Result<Option<Flag>, FlagError>
+where
+    C: Codec<Flag>,
+{
+    let key = namespace::key(NAMESPACE, name).map_err(FlagError::Key)?;
+    db.get(&key).map_err(|_| FlagError::Storage)
+}
+
+/// Is the flag on for this device
+/// Unknown flags and flags that can't be read count as off.
+pub fn is_enabled<C, const L: usize, const N: usize, const B: usize, const CACH: usize>(
+    db: &mut Database<String<L>, Flag, C, N, B, CACH>,
+    name: &str,
+    device_entropy: u64,
+) -> bool
+where
+    C: Codec<Flag>,
+{
+    match get(db, name) {
+        Ok(Some(Flag::On)) => true,
+        Ok(Some(Flag::Rollout(pct))) => bucket(name, device_entropy) < pct as u32,
+        _ => false,
+    }
+}
+
+/// Rollout bucket (0..100) of a device for one flag
+pub fn bucket(name: &str, device_entropy: u64) -> u32 {
+    let mut digest = Crc32.digest();
+    digest.update(name.as_bytes());
+    digest.update(&device_entropy.to_le_bytes());
+    digest.finalize() % 100
+}
+
+/// Experiment arm (0..arms) of this device, assigned and stored on first use
+/// A stored arm is kept as long as it is still < arms, changing the salt
+/// only affects devices that haven't been assigned yet.
+pub fn cohort<C, const L: usize, const N: usize, const B: usize, const CACH: usize>(
+    db: &mut Database<String<L>, Flag, C, N, B, CACH>,
+    experiment: &str,
+    arms: u8,
+    salt: u32,
+    device_id: u64,
+) -> Result<u8, FlagError>
+where
+    C: Codec<Flag>,
+{
+    if arms == 0 {
+        return Err(FlagError::NoArms);
+    }
+    let key = namespace::key(EXPERIMENT_NAMESPACE, experiment).map_err(FlagError::Key)?;
+    if let Some(Flag::Cohort(arm)) = db.get(&key).map_err(|_| FlagError::Storage)? {
+        if arm < arms {
+            return Ok(arm);
+        }
+    }
+
+    let mut digest = Crc32.digest();
+    digest.update(&salt.to_le_bytes());
+    digest.update(&device_id.to_le_bytes());
+    let arm = (digest.finalize() % arms as u32) as u8;
+    db.put(key, Flag::Cohort(arm))
+        .map_err(|_| FlagError::Storage)?;
+    Ok(arm)
+}
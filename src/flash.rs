@@ -8,15 +8,138 @@ use embedded_storage::nor_flash::{
 
 // I believe for other chips there are other hal crates (stm32-hal, esp-hal, etc.)
 // Need to do more research on this.
-use nrf52840_hal::pac::NVMC;
+use nrf52840_hal::pac::power::pofcon::THRESHOLD_A;
+use nrf52840_hal::pac::{FICR, NVMC, POWER};
+
+use crate::emergency::Partition;
 
 /// Size of a flash page on nRF52840 (4KB)
 /// https://docs.nordicsemi.com/bundle/ps_nrf52840/page/memory.html
 /// Pages go from 0 - 255 (256 pages * 4KB = 1MB)
 pub const PAGE_SIZE: usize = 4096;
 pub const WRITE_ALIGNMENT: u32 = 4;
+/// Size of the whole internal flash (1MB)
+pub const FLASH_SIZE: u32 = 256 * PAGE_SIZE as u32;
+
+/// Read the 64-bit device ID that Nordic programs into FICR at the factory
+/// Used to stamp flash images so we know which board wrote them.
+pub fn device_id(ficr: &FICR) -> u64 {
+    let lo = ficr.deviceid[0].read().bits() as u64;
+    let hi = ficr.deviceid[1].read().bits() as u64;
+    (hi << 32) | lo
+}
+
+/// Turn on the power-fail comparator so supply_ok() can see a brown-out coming
+/// Pick a threshold comfortably above the BOR level, e.g. V28 for a 3V cell.
+pub fn enable_pof_warning(power: &POWER, threshold: THRESHOLD_A) {
+    power.events_pofwarn.reset();
+    power
+        .pofcon
+        .write(|w| w.pof().enabled().threshold().variant(threshold));
+}
+
+/// False once VDD has dropped below the POFCON threshold
+/// The comparator only reports the crossing (the POFWARN event), there is no
+/// live status bit, so this stays false until clear_pof_warning().
+/// Has the fn() -> bool shape Database::set_supply_check wants.
+pub fn supply_ok() -> bool {
+    // Only reads the event register, doesn't need the POWER singleton
+    let power = unsafe { &*POWER::ptr() };
+    power.events_pofwarn.read().bits() == 0
+}
+
+/// Forget an earlier POFWARN, e.g. once the supply is back (charger plugged in)
+pub fn clear_pof_warning() {
+    let power = unsafe { &*POWER::ptr() };
+    power.events_pofwarn.reset();
+}
+
+/// What is wrong with a flash layout, the numbers are indices into the
+/// partitions given to assert_layout
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum LayoutError {
+    // Ends past the end of the flash (or is empty)
+    OutOfBounds(usize),
+    // Doesn't start and end on a page boundary
+    Misaligned(usize),
+    Overlap(usize, usize),
+    // Starts before the end of the firmware image
+    OverlapsFirmware(usize),
+}
+
+/// Check the partitions (database, backup, emergency log...) once at init
+/// so a bad offset fails right away instead of erasing code later on.
+///
+/// flash::assert_layout(&[DB, DB_BACKUP, CRASH_LOG])?;
+pub fn assert_layout(partitions: &[Partition]) -> Result<(), LayoutError> {
+    check_layout(partitions, FLASH_SIZE, PAGE_SIZE as u32, firmware_end())
+}
+
+/// assert_layout for any flash, firmware_end is the first address the
+/// partitions may use
+pub fn check_layout(
+    partitions: &[Partition],
+    capacity: u32,
+    erase_size: u32,
+    firmware_end: u32,
+) -> Result<(), LayoutError> {
+    // Bounds first, so the overlap check below can't overflow
+    for (i, p) in partitions.iter().enumerate() {
+        let end = p.offset.checked_add(p.len);
+        if p.len == 0 || end.is_none_or(|end| end > capacity) {
+            return Err(LayoutError::OutOfBounds(i));
+        }
+        // checked_rem also turns a zero erase_size into Misaligned
+        if p.offset.checked_rem(erase_size) != Some(0) || p.len.checked_rem(erase_size) != Some(0) {
+            return Err(LayoutError::Misaligned(i));
+        }
+        if p.offset < firmware_end {
+            return Err(LayoutError::OverlapsFirmware(i));
+        }
+    }
+    for (i, p) in partitions.iter().enumerate() {
+        for (j, q) in partitions.iter().enumerate().skip(i + 1) {
+            if p.offset < q.offset + q.len && q.offset < p.offset + p.len {
+                return Err(LayoutError::Overlap(i, j));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// First flash address after the firmware, from the cortex-m-rt linker
+/// symbols: .text and .rodata, then the initial values of .data
+pub fn firmware_end() -> u32 {
+    extern "C" {
+        static __sidata: u8;
+        static __sdata: u8;
+        static __edata: u8;
+    }
+    // Only the addresses are used, nothing is read
+    let data_len = core::ptr::addr_of!(__edata) as u32 - core::ptr::addr_of!(__sdata) as u32;
+    core::ptr::addr_of!(__sidata) as u32 + data_len
+}
+
+/// Time it takes to fully erase one page (tERASEPAGE from the datasheet)
+/// Partial erases have to add up to at least this much for the page to be erased.
+pub const PAGE_ERASE_TIME_MS: u32 = 85;
+
+/// Default length of one partial erase step
+pub const DEFAULT_ERASE_SLICE_MS: u8 = 10;
+
 pub struct FlashStorage {
     nvmc: NVMC,
+    // Length of each ERASEPAGEPARTIAL step in ms (max 127)
+    erase_slice_ms: u8,
+    partial: Option<PartialErase>,
+}
+
+// Progress of an erase that is being done in slices
+#[derive(Debug, Clone, Copy)]
+struct PartialErase {
+    page_addr: u32,
+    end: u32,
+    elapsed_ms: u32,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -38,7 +161,85 @@ impl NorFlashError for FlashError {
 
 impl FlashStorage {
     pub fn new(nvmc: NVMC) -> Self {
-        Self { nvmc }
+        Self {
+            nvmc,
+            erase_slice_ms: DEFAULT_ERASE_SLICE_MS,
+            partial: None,
+        }
+    }
+
+    /// Set how long each erase_partial_step() keeps the CPU stalled
+    /// Values are clamped to 1..=127ms (the size of the DURATION field)
+    pub fn set_erase_slice_ms(&mut self, ms: u8) {
+        self.erase_slice_ms = ms.clamp(1, 127);
+    }
+
+    /// Start erasing the pages in from..to in small time slices
+    /// A full page erase blocks the CPU for ~85ms, which is long enough to
+    /// miss BLE connection events. Call erase_partial_step() from the main
+    /// loop (in between radio activity) until it returns Ok(true).
+    pub fn begin_partial_erase(&mut self, from: u32, to: u32) -> Result<(), FlashError> {
+        let page = PAGE_SIZE as u32;
+        if !from.is_multiple_of(page) || !to.is_multiple_of(page) || to < from {
+            return Err(FlashError::Unaligned);
+        }
+        self.partial = Some(PartialErase {
+            page_addr: from,
+            end: to,
+            elapsed_ms: 0,
+        });
+        Ok(())
+    }
+
+    /// Run one partial erase slice
+    /// Returns Ok(true) once every page from begin_partial_erase() is erased.
+    /// Returns Ok(true) straight away if there is nothing to erase.
+    pub fn erase_partial_step(&mut self) -> Result<bool, FlashError> {
+        let mut state = match self.partial {
+            Some(state) => state,
+            None => return Ok(true),
+        };
+
+        if state.page_addr >= state.end {
+            self.partial = None;
+            return Ok(true);
+        }
+
+        self.nvmc
+            .erasepagepartialcfg
+            .write(|w| unsafe { w.duration().bits(self.erase_slice_ms) });
+
+        self.nvmc.config.write(|w| w.wen().een());
+        while self.nvmc.ready.read().ready().is_busy() {}
+
+        self.nvmc
+            .erasepagepartial
+            .write(|w| unsafe { w.bits(state.page_addr) });
+
+        // Blocks for erase_slice_ms
+        while self.nvmc.ready.read().ready().is_busy() {}
+        self.nvmc.config.write(|w| w.wen().ren());
+
+        state.elapsed_ms += self.erase_slice_ms as u32;
+        if state.elapsed_ms >= PAGE_ERASE_TIME_MS {
+            // This page is done, move on to the next one
+            state.page_addr += PAGE_SIZE as u32;
+            state.elapsed_ms = 0;
+        }
+
+        if state.page_addr >= state.end {
+            self.partial = None;
+            Ok(true)
+        } else {
+            self.partial = Some(state);
+            Ok(false)
+        }
+    }
+
+    /// True while a partial erase has been started and not finished
+    /// Writing to the pages being erased before this is false will fail.
+    pub fn partial_erase_pending(&self) -> bool {
+        self.partial.is_some()
     }
 
     /// Erase a page of flash memory
@@ -187,3 +388,139 @@ impl NorFlash for FlashStorage {
         self.write_bytes(offset, bytes)
     }
 }
+
+// With the SoftDevice enabled the NVMC belongs to it, touching it directly
+// hard faults. Flash has to go through sd_flash_write/sd_flash_page_erase,
+// which are asynchronous and report back with a SoC event. This crate doesn't
+// depend on a SoftDevice binding, the application bridges the three calls:
+//
+// impl SoftDeviceFlashOps for Bridge {
+//     fn flash_write(&mut self, addr: u32, words: &[u32]) -> u32 {
+//         unsafe { raw::sd_flash_write(addr as *mut u32, words.as_ptr(), words.len() as u32) }
+//     }
+//     fn flash_page_erase(&mut self, page: u32) -> u32 {
+//         unsafe { raw::sd_flash_page_erase(page) }
+//     }
+//     fn wait_flash_event(&mut self) -> bool {
+//         // block until NRF_EVT_FLASH_OPERATION_SUCCESS / _ERROR
+//     }
+// }
+// let mut flash = SoftDeviceFlash::new(Bridge);
+// db.save_to_flash(&mut flash, 4, FLASH_STORAGE_ADDR)?;
+
+/// NRF_SUCCESS
+pub const NRF_SUCCESS: u32 = 0;
+/// NRF_ERROR_BUSY, another flash operation is still pending
+pub const NRF_ERROR_BUSY: u32 = 17;
+
+/// How often a flash operation is retried when the SoftDevice is busy or
+/// reports NRF_EVT_FLASH_OPERATION_ERROR (it couldn't find a gap in the radio schedule)
+pub const SD_FLASH_RETRIES: u8 = 3;
+
+// Words handed to one sd_flash_write call
+const SD_WRITE_WORDS: usize = 64;
+
+/// The SoftDevice calls SoftDeviceFlash needs, implemented by the application
+pub trait SoftDeviceFlashOps {
+    /// Start sd_flash_write, return its error code
+    fn flash_write(&mut self, addr: u32, words: &[u32]) -> u32;
+
+    /// Start sd_flash_page_erase (page number, not address), return its error code
+    fn flash_page_erase(&mut self, page: u32) -> u32;
+
+    /// Wait for the flash SoC event of the operation that was started
+    /// Returns true for NRF_EVT_FLASH_OPERATION_SUCCESS.
+    fn wait_flash_event(&mut self) -> bool;
+}
+
+/// NorFlash on top of the SoftDevice flash API, for BLE products
+pub struct SoftDeviceFlash<S: SoftDeviceFlashOps> {
+    sd: S,
+}
+
+impl<S: SoftDeviceFlashOps> SoftDeviceFlash<S> {
+    pub fn new(sd: S) -> Self {
+        Self { sd }
+    }
+
+    // Start an operation and wait for its event, retrying while the
+    // SoftDevice is busy or couldn't schedule it
+    fn run(&mut self, mut start: impl FnMut(&mut S) -> u32) -> Result<(), FlashError> {
+        let mut attempts = 0;
+        loop {
+            match start(&mut self.sd) {
+                NRF_SUCCESS => {
+                    if self.sd.wait_flash_event() {
+                        return Ok(());
+                    }
+                }
+                NRF_ERROR_BUSY => {}
+                _ => return Err(FlashError::Other),
+            }
+            // A SoftDevice that stays busy mustn't hang the caller
+            attempts += 1;
+            if attempts > SD_FLASH_RETRIES {
+                return Err(FlashError::Other);
+            }
+        }
+    }
+}
+
+impl<S: SoftDeviceFlashOps> ErrorType for SoftDeviceFlash<S> {
+    type Error = FlashError;
+}
+
+impl<S: SoftDeviceFlashOps> ReadNorFlash for SoftDeviceFlash<S> {
+    const READ_SIZE: usize = 1;
+
+    // Reading is plain memory access, the SoftDevice doesn't mind that
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), Self::Error> {
+        unsafe {
+            core::ptr::copy_nonoverlapping(offset as *const u8, bytes.as_mut_ptr(), bytes.len());
+        }
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        // Same region as FlashStorage
+        64 * 1024
+    }
+}
+
+impl<S: SoftDeviceFlashOps> NorFlash for SoftDeviceFlash<S> {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = PAGE_SIZE;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), Self::Error> {
+        let page = PAGE_SIZE as u32;
+        if !from.is_multiple_of(page) || !to.is_multiple_of(page) {
+            return Err(FlashError::Unaligned);
+        }
+        for page_addr in (from..to).step_by(PAGE_SIZE) {
+            self.run(|sd| sd.flash_page_erase(page_addr / page))?;
+        }
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), Self::Error> {
+        if !offset.is_multiple_of(WRITE_ALIGNMENT) {
+            return Err(FlashError::Unaligned);
+        }
+
+        // The SoftDevice reads the source buffer until the event comes in,
+        // so the words have to stay put in this buffer until run() returns
+        let mut words = [0u32; SD_WRITE_WORDS];
+        for (i, chunk) in bytes.chunks(SD_WRITE_WORDS * 4).enumerate() {
+            let n = chunk.len().div_ceil(4);
+            for (word, b) in words.iter_mut().zip(chunk.chunks(4)) {
+                // Pad a trailing partial word with 0xFF like FlashStorage does
+                let mut raw = [0xFF; 4];
+                raw[..b.len()].copy_from_slice(b);
+                *word = u32::from_le_bytes(raw);
+            }
+            let addr = offset + (i * SD_WRITE_WORDS * 4) as u32;
+            self.run(|sd| sd.flash_write(addr, &words[..n]))?;
+        }
+        Ok(())
+    }
+}
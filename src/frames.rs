@@ -0,0 +1,219 @@
+// Sending the database over a small-MTU link
+// export() hands its stream to a sink in pieces of whatever size, which is
+// fine for RTT or a UART but not for a radio with a fixed packet size.
+// frames() cuts the same stream into frames of at most mtu bytes, built one
+// at a time as the iterator is pulled, so nothing but the frame is buffered:
+//
+// for frame in db.frames(200)? {
+//     radio.send(&frame);
+// }
+//
+// Every frame is [len: u16][seq: u16][payload][crc32: u32], the CRC covers
+// the len, seq and payload. The payloads put back together in seq order are
+// exactly an export() stream, so the receiver checks each frame with
+// unframe() and feeds the payloads to import().
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::crc32;
+use crate::db::{Database, FlashError};
+use crate::image;
+use crate::kv::BlobStore;
+use heapless::Vec;
+
+/// Largest frame frames() builds, a larger mtu is clamped to this
+pub const MAX_FRAME_LEN: usize = 256;
+
+/// Bytes of every frame that aren't payload (len, seq and crc)
+pub const FRAME_OVERHEAD: usize = 8;
+
+pub type Frame = Vec<u8, MAX_FRAME_LEN>;
+
+/// Check a frame from frames(), returns its seq and payload
+pub fn unframe(frame: &[u8]) -> Result<(u16, &[u8]), FlashError> {
+    if frame.len() < FRAME_OVERHEAD {
+        return Err(FlashError::BadHeader);
+    }
+    let len = u16::from_le_bytes([frame[0], frame[1]]) as usize;
+    if frame.len() != len + FRAME_OVERHEAD {
+        return Err(FlashError::BadHeader);
+    }
+    let crc_at = frame.len() - 4;
+    let crc = u32::from_le_bytes([
+        frame[crc_at],
+        frame[crc_at + 1],
+        frame[crc_at + 2],
+        frame[crc_at + 3],
+    ]);
+    if image::CRC32.checksum(&frame[..crc_at]) != crc {
+        return Err(FlashError::CrcMismatch);
+    }
+    let seq = u16::from_le_bytes([frame[2], frame[3]]);
+    Ok((seq, &frame[4..crc_at]))
+}
+
+impl<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>
+    Database<K, V, C, N, B, CACH, S, CP>
+where
+    C: Codec<V>,
+    K: Eq + Clone + serde::Serialize,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    /// The export() stream in frames of at most mtu bytes, see frames.rs
+    /// Fails up front if a key doesn't serialize, the frames themselves
+    /// can't fail.
+    pub fn frames(&self, mtu: usize) -> Result<impl Iterator<Item = Frame> + '_, FlashError> {
+        let mut key_buf = [0u8; B];
+        let mut num_entries = 0u32;
+        for (key, _) in self.blobs() {
+            postcard::to_slice(key, &mut key_buf).map_err(|_| FlashError::SerializationError)?;
+            num_entries += 1;
+        }
+
+        let mut small = [0u8; 12];
+        small[..4].copy_from_slice(&image::SNAPSHOT_MAGIC.to_le_bytes());
+        small[4..6].copy_from_slice(&image::SNAPSHOT_VERSION.to_le_bytes());
+        small[8..].copy_from_slice(&num_entries.to_le_bytes());
+
+        Ok(Frames {
+            entries: self.blobs(),
+            payload: mtu.clamp(FRAME_OVERHEAD + 1, MAX_FRAME_LEN) - FRAME_OVERHEAD,
+            seq: 0,
+            digest: image::CRC32.digest(),
+            stage: Stage::Prelude,
+            pos: 0,
+            small,
+            small_len: 12,
+            key_buf,
+            key_len: 0,
+            blob: &[],
+        })
+    }
+}
+
+// Which part of the export() stream is being cut into frames
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Stage {
+    Prelude,
+    KeyLen,
+    Key,
+    ValLen,
+    Val,
+    Crc,
+    Done,
+}
+
+struct Frames<'a, K, I, const B: usize>
+where
+    I: Iterator<Item = (&'a K, &'a [u8])>,
+    K: 'a,
+{
+    entries: I,
+    // Payload bytes per frame
+    payload: usize,
+    seq: u16,
+    // Over the stream, for its trailing CRC
+    digest: crc32::Digest,
+    stage: Stage,
+    // How much of the current part is in frames already
+    pos: usize,
+    // The prelude, a length or the CRC
+    small: [u8; 12],
+    small_len: usize,
+    key_buf: [u8; B],
+    key_len: usize,
+    blob: &'a [u8],
+}
+
+impl<'a, K, I, const B: usize> Frames<'a, K, I, B>
+where
+    I: Iterator<Item = (&'a K, &'a [u8])>,
+    K: serde::Serialize + 'a,
+{
+    fn part(&self) -> &[u8] {
+        match self.stage {
+            Stage::Key => &self.key_buf[..self.key_len],
+            Stage::Val => self.blob,
+            Stage::Done => &[],
+            _ => &self.small[..self.small_len],
+        }
+    }
+
+    fn set_small(&mut self, stage: Stage, bytes: [u8; 4]) {
+        self.stage = stage;
+        self.small[..4].copy_from_slice(&bytes);
+        self.small_len = 4;
+    }
+
+    // Move on to the part after the current one
+    fn advance(&mut self) {
+        self.pos = 0;
+        match self.stage {
+            Stage::Prelude | Stage::Val => self.next_entry(),
+            Stage::KeyLen => self.stage = Stage::Key,
+            Stage::Key => self.set_small(Stage::ValLen, (self.blob.len() as u32).to_le_bytes()),
+            Stage::ValLen => self.stage = Stage::Val,
+            Stage::Crc | Stage::Done => self.stage = Stage::Done,
+        }
+    }
+
+    fn next_entry(&mut self) {
+        match self.entries.next() {
+            Some((key, blob)) => {
+                // Checked by frames() already
+                self.key_len = postcard::to_slice(key, &mut self.key_buf)
+                    .map(|bytes| bytes.len())
+                    .unwrap_or(0);
+                self.blob = blob;
+                self.set_small(Stage::KeyLen, (self.key_len as u32).to_le_bytes());
+            }
+            None => {
+                let crc = self.digest.finalize();
+                self.set_small(Stage::Crc, crc.to_le_bytes());
+            }
+        }
+    }
+}
+
+impl<'a, K, I, const B: usize> Iterator for Frames<'a, K, I, B>
+where
+    I: Iterator<Item = (&'a K, &'a [u8])>,
+    K: serde::Serialize + 'a,
+{
+    type Item = Frame;
+
+    fn next(&mut self) -> Option<Frame> {
+        if self.stage == Stage::Done {
+            return None;
+        }
+
+        // Room for the header, filled in once the length is known
+        let mut frame = Frame::new();
+        let _ = frame.extend_from_slice(&[0; 4]);
+        while frame.len() - 4 < self.payload && self.stage != Stage::Done {
+            let room = self.payload - (frame.len() - 4);
+            let start = frame.len();
+            let part = self.part();
+            let n = room.min(part.len() - self.pos);
+            // Can't fail, n is limited to what is left of the payload
+            let _ = frame.extend_from_slice(&part[self.pos..self.pos + n]);
+            if self.stage != Stage::Crc {
+                self.digest.update(&frame[start..]);
+            }
+            self.pos += n;
+            if self.pos == self.part().len() {
+                self.advance();
+            }
+        }
+
+        let len = (frame.len() - 4) as u16;
+        frame[..2].copy_from_slice(&len.to_le_bytes());
+        frame[2..4].copy_from_slice(&self.seq.to_le_bytes());
+        let crc = image::CRC32.checksum(&frame);
+        let _ = frame.extend_from_slice(&crc.to_le_bytes());
+        self.seq = self.seq.wrapping_add(1);
+        Some(frame)
+    }
+}
@@ -0,0 +1,164 @@
+// Coordinates and geofences
+// GeoPoint stores latitude/longitude as fixed point degrees * 1e7 (about
+// 1 cm resolution), 8 bytes per point instead of two f64s or JSON text.
+//
+// let home = GeoPoint::from_degrees(52.370_216, 4.895_168)?;
+// db.put(KEY_HOME, home)?;
+// let fence = Geofence::new(home, 250);
+// if !fence.contains(&current_fix) { ... }
+//
+// GeoCodec packs GeoPoint as [lat i32][lon i32] and Geofence as
+// [lat i32][lon i32][radius_m u32], little endian.
+
+use crate::codec::Codec;
+
+/// Fixed point scale, units are 1e-7 degrees
+pub const SCALE: f64 = 10_000_000.0;
+/// Mean earth radius used by the distance helpers
+pub const EARTH_RADIUS_M: f64 = 6_371_000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum GeoError {
+    // Latitude outside ±90 or longitude outside ±180 degrees
+    OutOfRange,
+    BadLength,
+    BufferTooSmall,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, defmt::Format, serde::Serialize, serde::Deserialize,
+)]
+pub struct GeoPoint {
+    lat_e7: i32,
+    lon_e7: i32,
+}
+
+impl GeoPoint {
+    pub fn new(lat_e7: i32, lon_e7: i32) -> Result<Self, GeoError> {
+        if !(-900_000_000..=900_000_000).contains(&lat_e7)
+            || !(-1_800_000_000..=1_800_000_000).contains(&lon_e7)
+        {
+            return Err(GeoError::OutOfRange);
+        }
+        Ok(Self { lat_e7, lon_e7 })
+    }
+
+    pub fn from_degrees(lat: f64, lon: f64) -> Result<Self, GeoError> {
+        // Range check before the cast, `as` would saturate silently
+        if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+            return Err(GeoError::OutOfRange);
+        }
+        Self::new(
+            libm::round(lat * SCALE) as i32,
+            libm::round(lon * SCALE) as i32,
+        )
+    }
+
+    pub fn lat_e7(&self) -> i32 {
+        self.lat_e7
+    }
+
+    pub fn lon_e7(&self) -> i32 {
+        self.lon_e7
+    }
+
+    pub fn lat(&self) -> f64 {
+        self.lat_e7 as f64 / SCALE
+    }
+
+    pub fn lon(&self) -> f64 {
+        self.lon_e7 as f64 / SCALE
+    }
+
+    /// Great circle distance in meters (haversine)
+    pub fn distance_m(&self, other: &GeoPoint) -> f64 {
+        let (lat1, lat2) = (self.lat().to_radians(), other.lat().to_radians());
+        let dlat = lat2 - lat1;
+        let dlon = (other.lon() - self.lon()).to_radians();
+
+        let a = libm::sin(dlat / 2.0) * libm::sin(dlat / 2.0)
+            + libm::cos(lat1) * libm::cos(lat2) * libm::sin(dlon / 2.0) * libm::sin(dlon / 2.0);
+        2.0 * EARTH_RADIUS_M * libm::atan2(libm::sqrt(a), libm::sqrt(1.0 - a))
+    }
+
+    fn to_bytes(self) -> [u8; 8] {
+        let mut out = [0u8; 8];
+        out[..4].copy_from_slice(&self.lat_e7.to_le_bytes());
+        out[4..].copy_from_slice(&self.lon_e7.to_le_bytes());
+        out
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self, GeoError> {
+        Self::new(
+            i32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]),
+            i32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+        )
+    }
+}
+
+/// Circle around a point
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Hash, defmt::Format, serde::Serialize, serde::Deserialize,
+)]
+pub struct Geofence {
+    pub center: GeoPoint,
+    pub radius_m: u32,
+}
+
+impl Geofence {
+    pub const fn new(center: GeoPoint, radius_m: u32) -> Self {
+        Self { center, radius_m }
+    }
+
+    pub fn contains(&self, point: &GeoPoint) -> bool {
+        self.center.distance_m(point) <= self.radius_m as f64
+    }
+
+    /// Meters from the edge of the fence, negative inside
+    pub fn distance_to_edge_m(&self, point: &GeoPoint) -> f64 {
+        self.center.distance_m(point) - self.radius_m as f64
+    }
+}
+
+/// Packed codec for GeoPoint (8 bytes) and Geofence (12 bytes)
+pub struct GeoCodec;
+
+impl Codec<GeoPoint> for GeoCodec {
+    type Error = GeoError;
+    const MAX_ENCODED_SIZE: Option<usize> = Some(8);
+
+    fn encode(dst: &mut [u8], v: &GeoPoint) -> Result<usize, Self::Error> {
+        let dst = dst.get_mut(..8).ok_or(GeoError::BufferTooSmall)?;
+        dst.copy_from_slice(&v.to_bytes());
+        Ok(8)
+    }
+
+    fn decode(src: &[u8]) -> Result<GeoPoint, Self::Error> {
+        if src.len() != 8 {
+            return Err(GeoError::BadLength);
+        }
+        GeoPoint::from_bytes(src)
+    }
+}
+
+impl Codec<Geofence> for GeoCodec {
+    type Error = GeoError;
+    const MAX_ENCODED_SIZE: Option<usize> = Some(12);
+
+    fn encode(dst: &mut [u8], v: &Geofence) -> Result<usize, Self::Error> {
+        let dst = dst.get_mut(..12).ok_or(GeoError::BufferTooSmall)?;
+        dst[..8].copy_from_slice(&v.center.to_bytes());
+        dst[8..].copy_from_slice(&v.radius_m.to_le_bytes());
+        Ok(12)
+    }
+
+    fn decode(src: &[u8]) -> Result<Geofence, Self::Error> {
+        if src.len() != 12 {
+            return Err(GeoError::BadLength);
+        }
+        Ok(Geofence::new(
+            GeoPoint::from_bytes(&src[..8])?,
+            u32::from_le_bytes([src[8], src[9], src[10], src[11]]),
+        ))
+    }
+}
@@ -0,0 +1,53 @@
+// Bounded value history
+// Keys whose recent values matter for diagnostics ("what was battery_mv
+// before the reset") can keep the last H values instead of just one. The
+// history is a heapless::HistoryBuf stored as the value, so it is saved and
+// loaded with the rest of the database. Keep watched keys in their own
+// Database, every value of it is a History.
+//
+// let mut watched: Database<u8, History<u16, 8>, Postcard, 4, 64, 2> = Database::new();
+// watched.record(KEY_BATTERY_MV, 3712)?;
+// let last = watched.history(&KEY_BATTERY_MV, 5)?;   // newest first
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::{Database, DbError};
+use crate::kv::BlobStore;
+use heapless::{HistoryBuf, Vec};
+
+/// The last H values of a key, the oldest is dropped first
+pub type History<T, const H: usize> = HistoryBuf<T, H>;
+
+impl<K, T, C, S, CP, const H: usize, const N: usize, const B: usize, const CACH: usize>
+    Database<K, History<T, H>, C, N, B, CACH, S, CP>
+where
+    C: Codec<History<T, H>>,
+    K: Eq + Clone,
+    T: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    /// Append val to the history of key
+    pub fn record(&mut self, key: K, val: T) -> Result<(), DbError<C::Error>> {
+        let mut history = self.get(&key)?.unwrap_or_default();
+        history.write(val);
+        self.put(key, history)
+    }
+
+    /// Up to n of the most recent values of key, newest first
+    pub fn history(&mut self, key: &K, n: usize) -> Result<Vec<T, H>, DbError<C::Error>> {
+        let mut out = Vec::new();
+        if let Some(history) = self.get(key)? {
+            for val in history.oldest_ordered().rev().take(n) {
+                // Can't fail, there are never more than H values
+                let _ = out.push(val.clone());
+            }
+        }
+        Ok(out)
+    }
+
+    /// The value recorded last
+    pub fn latest(&mut self, key: &K) -> Result<Option<T>, DbError<C::Error>> {
+        Ok(self.get(key)?.and_then(|history| history.recent().cloned()))
+    }
+}
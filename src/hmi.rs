@@ -0,0 +1,134 @@
+// Display helper for devices with character LCDs or e-paper screens
+// Formats database entries into fixed width "key:value" lines and keeps
+// track of which page of entries is being shown.
+//
+// let mut pager: Pager<20, 4> = Pager::new();
+// for (row, line) in pager.lines(&db).iter().enumerate() {
+//     lcd.write_line(row, line);
+// }
+// pager.page_down(&db);
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::Database;
+use crate::kv::BlobStore;
+use core::fmt::Write;
+use heapless::{String, Vec};
+
+/// COLS is the width of a line in characters, ROWS the number of lines per page
+pub struct Pager<const COLS: usize, const ROWS: usize> {
+    page: usize,
+}
+
+impl<const COLS: usize, const ROWS: usize> Default for Pager<COLS, ROWS> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const COLS: usize, const ROWS: usize> Pager<COLS, ROWS> {
+    /// Keys longer than this are cut off so there is room for the value
+    pub const KEY_WIDTH: usize = COLS / 2;
+
+    pub const fn new() -> Self {
+        Self { page: 0 }
+    }
+
+    pub fn page(&self) -> usize {
+        self.page
+    }
+
+    /// Number of pages needed to show every entry (at least 1)
+    pub fn page_count<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &self,
+        db: &Database<K, V, C, N, B, CACH, S, CP>,
+    ) -> usize
+    where
+        C: Codec<V>,
+        K: Eq + Clone,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+    {
+        db.len().div_ceil(ROWS).max(1)
+    }
+
+    /// Move to the next page, stays on the last page
+    pub fn page_down<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &mut self,
+        db: &Database<K, V, C, N, B, CACH, S, CP>,
+    ) where
+        C: Codec<V>,
+        K: Eq + Clone,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+    {
+        if self.page + 1 < self.page_count(db) {
+            self.page += 1;
+        }
+    }
+
+    /// Move to the previous page, stays on the first page
+    pub fn page_up(&mut self) {
+        self.page = self.page.saturating_sub(1);
+    }
+
+    /// Render the current page
+    /// Entries can be deleted while a page is shown, so the page is
+    /// pulled back if it is now past the end.
+    pub fn lines<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &mut self,
+        db: &Database<K, V, C, N, B, CACH, S, CP>,
+    ) -> Vec<String<COLS>, ROWS>
+    where
+        C: Codec<V>,
+        K: Eq + Clone + core::fmt::Display,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+    {
+        self.page = self.page.min(self.page_count(db) - 1);
+
+        let mut lines = Vec::new();
+        for (key, blob) in db.blobs().skip(self.page * ROWS).take(ROWS) {
+            let mut line = Truncate::<COLS>::new(COLS);
+
+            let mut key_part = Truncate::<COLS>::new(Self::KEY_WIDTH);
+            let _ = write!(key_part, "{}", key);
+            let _ = line.write_str(&key_part.text);
+            let _ = line.write_char(':');
+            let _ = C::preview(blob, &mut line);
+
+            // lines has room for ROWS entries and we take at most ROWS
+            let _ = lines.push(line.text);
+        }
+        lines
+    }
+}
+
+// fmt::Write that silently drops whatever doesn't fit in limit characters
+struct Truncate<const COLS: usize> {
+    text: String<COLS>,
+    limit: usize,
+}
+
+impl<const COLS: usize> Truncate<COLS> {
+    fn new(limit: usize) -> Self {
+        Self {
+            text: String::new(),
+            limit: limit.min(COLS),
+        }
+    }
+}
+
+impl<const COLS: usize> Write for Truncate<COLS> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        for c in s.chars() {
+            if self.text.chars().count() >= self.limit || self.text.push(c).is_err() {
+                break;
+            }
+        }
+        Ok(())
+    }
+}
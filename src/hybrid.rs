@@ -0,0 +1,42 @@
+// Timestamps without an RTC
+// Boards without a real-time clock restart their timers at 0 on every
+// reset, so "uptime at the time" alone can't order events across reboots.
+// A HybridTime adds the boot count the Database keeps in its image:
+//
+// db.load_from_flash(&mut flash, DB_ADDR)?;
+// db.set_clock(timer_us);
+// db.start_boot();                           // once per reset, after the load
+// db.save_to_flash(&mut flash, 4, DB_ADDR)?;  // so the next boot counts on
+// ...
+// let now = db.hybrid_now();
+// events.put_stamped(KEY_FAULT, fault, now.to_u64())?;   // see meta.rs
+//
+// HybridTimes of different boots compare by boot first, so sorting the
+// records of a post-mortem dump puts them back in order. If the device
+// resets before the boot count was saved, the next boot reuses the number
+// and the order between those two boots is lost.
+
+/// (boot count, microseconds since that boot)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, defmt::Format)]
+pub struct HybridTime {
+    pub boot: u32,
+    pub ticks: u64,
+}
+
+// ticks gets the low 48 bits of to_u64(), that is 8.9 years of microseconds
+const TICK_BITS: u32 = 48;
+
+impl HybridTime {
+    /// Packed into a u64 that orders the same way (e.g. for Meta::timestamp)
+    /// Only the low 16 bits of boot and 48 bits of ticks fit.
+    pub fn to_u64(self) -> u64 {
+        ((self.boot as u64) << TICK_BITS) | (self.ticks & ((1 << TICK_BITS) - 1))
+    }
+
+    pub fn from_u64(packed: u64) -> Self {
+        Self {
+            boot: (packed >> TICK_BITS) as u32,
+            ticks: packed & ((1 << TICK_BITS) - 1),
+        }
+    }
+}
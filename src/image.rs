@@ -0,0 +1,418 @@
+// Flash image header
+// Every image written by Database::save_to_flash starts with this header
+// so we can tell which firmware (and which device) wrote a flash dump
+// and whether the payload behind it is still intact.
+//
+// Layout (all little endian):
+// [magic: u32][format_version: u16][header_len: u16][app_version: u32]
+// [device_id: u64][payload_len: u32][payload_crc: u32][payload...]
+//
+// The payload is the entries, [num_entries: u32][key_width: u8] and then
+// every key and value. Since format 2 integer keys are stored fixed-width,
+// see keycodec.rs for the record layout. Format 1 images have no key_width
+// byte, their keys are all [key_len: u32][postcard key]. Since format 3
+// the payload ends in [boot_count: u32] (hybrid.rs), after the entries.
+//
+// Every save takes up the same region, MAX_IMAGE_SIZE rounded up to whole
+// erase blocks (region_len(), 8KB on 4KB pages), and the last FOOTER_SIZE
+// bytes of the region hold a footer, wherever the image itself ends:
+// [footer_magic: u32][image_len: u32][image_crc: u32][sequence: u32]
+// image_len and image_crc cover header and payload. sequence counts the
+// saves of power::LowPowerSaver, it is 0xFFFFFFFF for any other save. OTA and factory tools
+// can check an image with just the footer, without knowing about
+// sealing or the payload format. Images are at most MAX_IMAGE_LEN so they
+// never reach the footer. A backup copy has to start region_len() away from
+// the primary or further. Loading doesn't need the footer, images written
+// before it existed still open.
+
+use crate::crc32::Crc32;
+use crate::db::{FlashError, MAX_IMAGE_SIZE};
+use crate::keycodec;
+use embedded_storage::nor_flash::ReadNorFlash;
+
+/// "EDB1" - marks the start of an image written by this crate
+pub const MAGIC: u32 = 0x4544_4231;
+/// "EDBE" - same, but the payload is encrypted (see crypto.rs)
+pub const MAGIC_ENCRYPTED: u32 = 0x4544_4245;
+/// "EDBA" - plain payload followed by an authentication tag
+pub const MAGIC_AUTHENTICATED: u32 = 0x4544_4241;
+/// Version of the on-flash format, bump this when the layout changes
+pub const FORMAT_VERSION: u16 = 3;
+pub const HEADER_SIZE: usize = 28;
+/// The part of the header that doesn't depend on the payload
+/// (magic through device_id), covered by the tag of a sealed image.
+pub const HEADER_AUTH_SIZE: usize = 20;
+
+/// "EDBS" - start of a snapshot stream from Database::export
+pub const SNAPSHOT_MAGIC: u32 = 0x4544_4253;
+pub const SNAPSHOT_VERSION: u16 = 1;
+
+/// "EDBF" - start of the footer at the end of the last erase block
+pub const FOOTER_MAGIC: u32 = 0x4544_4246;
+pub const FOOTER_SIZE: usize = 16;
+/// Longest image (header and payload), the rest of the region is the footer's
+pub const MAX_IMAGE_LEN: usize = MAX_IMAGE_SIZE - FOOTER_SIZE;
+
+/// CRC used over the payload (same polynomial as zlib/Ethernet)
+pub const CRC32: Crc32 = Crc32;
+
+/// How the payload is protected beyond the CRC
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Sealing {
+    None,
+    /// [nonce][ciphertext][tag]
+    Encrypted,
+    /// [records][tag]
+    Authenticated,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ImageHeader {
+    /// Format version of the crate that wrote the image
+    pub format_version: u16,
+    /// Application supplied firmware version
+    pub app_version: u32,
+    /// Device ID of the chip that wrote the image (FICR on nRF52840)
+    pub device_id: u64,
+    pub payload_len: u32,
+    pub payload_crc: u32,
+    pub sealing: Sealing,
+}
+
+impl ImageHeader {
+    pub fn to_bytes(&self) -> [u8; HEADER_SIZE] {
+        let mut out = [0u8; HEADER_SIZE];
+        let magic = match self.sealing {
+            Sealing::None => MAGIC,
+            Sealing::Encrypted => MAGIC_ENCRYPTED,
+            Sealing::Authenticated => MAGIC_AUTHENTICATED,
+        };
+        out[0..4].copy_from_slice(&magic.to_le_bytes());
+        out[4..6].copy_from_slice(&self.format_version.to_le_bytes());
+        out[6..8].copy_from_slice(&(HEADER_SIZE as u16).to_le_bytes());
+        out[8..12].copy_from_slice(&self.app_version.to_le_bytes());
+        out[12..20].copy_from_slice(&self.device_id.to_le_bytes());
+        out[20..24].copy_from_slice(&self.payload_len.to_le_bytes());
+        out[24..28].copy_from_slice(&self.payload_crc.to_le_bytes());
+        out
+    }
+
+    /// Header bytes that are bound to the payload by sealing
+    /// Changing the app version or device ID of a sealed image breaks it.
+    pub fn auth_bytes(&self) -> [u8; HEADER_AUTH_SIZE] {
+        let mut out = [0u8; HEADER_AUTH_SIZE];
+        out.copy_from_slice(&self.to_bytes()[..HEADER_AUTH_SIZE]);
+        out
+    }
+
+    /// Parse a header from the start of an image
+    /// Returns Ok(None) if the flash is erased (all 0xFF), so callers can
+    /// treat that as "nothing stored yet" instead of an error.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Option<Self>, FlashError> {
+        if bytes.len() < HEADER_SIZE {
+            return Err(FlashError::BufferTooSmall);
+        }
+
+        let magic = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+        if magic == 0xFFFF_FFFF {
+            return Ok(None);
+        }
+        let sealing = match magic {
+            MAGIC => Sealing::None,
+            MAGIC_ENCRYPTED => Sealing::Encrypted,
+            MAGIC_AUTHENTICATED => Sealing::Authenticated,
+            _ => return Err(FlashError::BadHeader),
+        };
+
+        let format_version = u16::from_le_bytes([bytes[4], bytes[5]]);
+        if format_version > FORMAT_VERSION {
+            return Err(FlashError::UnsupportedVersion);
+        }
+        let header_len = u16::from_le_bytes([bytes[6], bytes[7]]) as usize;
+        if header_len != HEADER_SIZE {
+            return Err(FlashError::BadHeader);
+        }
+
+        let mut device_id = [0u8; 8];
+        device_id.copy_from_slice(&bytes[12..20]);
+
+        Ok(Some(Self {
+            format_version,
+            app_version: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            device_id: u64::from_le_bytes(device_id),
+            payload_len: u32::from_le_bytes([bytes[20], bytes[21], bytes[22], bytes[23]]),
+            payload_crc: u32::from_le_bytes([bytes[24], bytes[25], bytes[26], bytes[27]]),
+            sealing,
+        }))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct Footer {
+    /// Header plus payload, without the padding
+    pub image_len: u32,
+    pub image_crc: u32,
+    /// See the top of the file, NO_SEQUENCE for plain saves
+    pub sequence: u32,
+}
+
+pub const NO_SEQUENCE: u32 = u32::MAX;
+
+impl Footer {
+    pub fn to_bytes(&self) -> [u8; FOOTER_SIZE] {
+        let mut out = [0xFFu8; FOOTER_SIZE];
+        out[0..4].copy_from_slice(&FOOTER_MAGIC.to_le_bytes());
+        out[4..8].copy_from_slice(&self.image_len.to_le_bytes());
+        out[8..12].copy_from_slice(&self.image_crc.to_le_bytes());
+        out[12..16].copy_from_slice(&self.sequence.to_le_bytes());
+        out
+    }
+
+    /// Returns Ok(None) if there is no footer (erased, or an older image)
+    pub fn from_bytes(bytes: &[u8]) -> Result<Option<Self>, FlashError> {
+        if bytes.len() < FOOTER_SIZE {
+            return Err(FlashError::BufferTooSmall);
+        }
+        if u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]) != FOOTER_MAGIC {
+            return Ok(None);
+        }
+        Ok(Some(Self {
+            image_len: u32::from_le_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]),
+            image_crc: u32::from_le_bytes([bytes[8], bytes[9], bytes[10], bytes[11]]),
+            sequence: u32::from_le_bytes([bytes[12], bytes[13], bytes[14], bytes[15]]),
+        }))
+    }
+}
+
+/// Bytes a saved image takes up in flash whatever its size, footer and
+/// padding included, see the top of the file
+pub fn region_len(erase_size: usize) -> usize {
+    MAX_IMAGE_SIZE.div_ceil(erase_size) * erase_size
+}
+
+/// Whether the image regions at offsets a and b share an erase block
+pub fn regions_overlap(a: u32, b: u32, erase_size: usize) -> bool {
+    a.abs_diff(b) < region_len(erase_size) as u32
+}
+
+/// A padded image and where it goes, from Database::to_artifact
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Artifact<'a> {
+    pub offset: u32,
+    pub bytes: &'a [u8],
+}
+
+/// Check the image at flash_offset without loading it into a Database
+/// The payload is read in small chunks so this doesn't need the 8KB load buffer.
+/// Returns Ok(None) if the flash is erased.
+pub fn verify<F>(flash: &mut F, flash_offset: u32) -> Result<Option<ImageHeader>, FlashError>
+where
+    F: ReadNorFlash,
+{
+    let mut header_bytes = [0u8; HEADER_SIZE];
+    flash
+        .read(flash_offset, &mut header_bytes)
+        .map_err(|_| FlashError::ReadError)?;
+
+    let header = match ImageHeader::from_bytes(&header_bytes)? {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+
+    // A corrupt length would have us read whatever flash comes after
+    if header.payload_len as usize > MAX_IMAGE_LEN - HEADER_SIZE {
+        return Err(FlashError::BadHeader);
+    }
+
+    let mut digest = CRC32.digest();
+    let mut chunk = [0u8; 64];
+    let mut offset = flash_offset
+        .checked_add(HEADER_SIZE as u32)
+        .ok_or(FlashError::ReadError)?;
+    let mut remaining = header.payload_len as usize;
+    while remaining > 0 {
+        let n = remaining.min(chunk.len());
+        flash
+            .read(offset, &mut chunk[..n])
+            .map_err(|_| FlashError::ReadError)?;
+        digest.update(&chunk[..n]);
+        offset = offset.checked_add(n as u32).ok_or(FlashError::ReadError)?;
+        remaining -= n;
+    }
+
+    if digest.finalize() != header.payload_crc {
+        return Err(FlashError::CrcMismatch);
+    }
+    Ok(Some(header))
+}
+
+/// Key and value bytes of every entry of a complete, unsealed image in memory
+/// (a flash dump on the host, memory-mapped flash on the device). Keys are
+/// left serialized, so tools that don't know the key type can still list
+/// them and decide themselves whether to decode. Integer keys are stored
+/// fixed-width (see key_width() and keycodec.rs).
+/// The CRC is not checked here, see verify().
+pub fn records(image: &[u8]) -> Result<Records<'_>, FlashError> {
+    let header = ImageHeader::from_bytes(image)?.ok_or(FlashError::BadHeader)?;
+    if header.sealing != Sealing::None {
+        return Err(FlashError::Sealed);
+    }
+    let payload = HEADER_SIZE
+        .checked_add(header.payload_len as usize)
+        .and_then(|end| image.get(HEADER_SIZE..end))
+        .ok_or(FlashError::BufferTooSmall)?;
+
+    let mut pos = 0;
+    let remaining = read_u32(payload, &mut pos).ok_or(FlashError::BufferTooSmall)?;
+    let mut key_width = 0;
+    if header.format_version >= 2 {
+        key_width = *payload.get(pos).ok_or(FlashError::BufferTooSmall)?;
+        pos += 1;
+    }
+    let key_width = check_key_width(key_width)?;
+    Ok(Records {
+        payload,
+        pos,
+        remaining,
+        key_width,
+    })
+}
+
+// The key_width byte of a format 2 payload, see keycodec.rs
+pub(crate) fn check_key_width(width: u8) -> Result<u8, FlashError> {
+    match width {
+        0 | 1 | 2 | 4 => Ok(width),
+        _ => Err(FlashError::BadHeader),
+    }
+}
+
+/// Iterator returned by records()
+/// Stops early if the payload is cut short.
+pub struct Records<'a> {
+    payload: &'a [u8],
+    pos: usize,
+    remaining: u32,
+    key_width: u8,
+}
+
+impl Records<'_> {
+    /// Width of the fixed-width integer keys, 0 if keys are postcard
+    /// keycodec::decode_fixed decodes the former.
+    pub fn key_width(&self) -> u8 {
+        self.key_width
+    }
+}
+
+impl<'a> Iterator for Records<'a> {
+    type Item = (&'a [u8], &'a [u8]);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let record = read_record(self.payload, &mut self.pos, self.key_width);
+        if record.is_none() {
+            self.remaining = 0;
+        }
+        record
+    }
+}
+
+/// records() for an image in flash, read one entry at a time into buf
+/// buf has to fit the biggest key plus its value. The CRC is checked first.
+/// Keys are always handed to f in the postcard form, fixed-width integer
+/// keys are converted.
+/// Returns Ok(None) if the flash is erased.
+pub fn scan<F, G>(
+    flash: &mut F,
+    flash_offset: u32,
+    buf: &mut [u8],
+    mut f: G,
+) -> Result<Option<ImageHeader>, FlashError>
+where
+    F: ReadNorFlash,
+    G: FnMut(&[u8], &[u8]),
+{
+    let header = match verify(flash, flash_offset)? {
+        Some(h) => h,
+        None => return Ok(None),
+    };
+    if header.sealing != Sealing::None {
+        return Err(FlashError::Sealed);
+    }
+
+    let mut read = |pos: usize, out: &mut [u8]| {
+        flash
+            .read(flash_offset + (HEADER_SIZE + pos) as u32, out)
+            .map_err(|_| FlashError::ReadError)
+    };
+    let mut word = [0u8; 4];
+    let mut pos = 0;
+    read(pos, &mut word)?;
+    pos += 4;
+    let num_entries = u32::from_le_bytes(word);
+    let mut key_width = [0u8; 1];
+    if header.format_version >= 2 {
+        read(pos, &mut key_width)?;
+        pos += 1;
+    }
+    let key_width = check_key_width(key_width[0])? as usize;
+
+    for _ in 0..num_entries {
+        let key_len = if key_width == 0 {
+            read(pos, &mut word)?;
+            pos += 4;
+            let key_len = u32::from_le_bytes(word) as usize;
+            if key_len > buf.len() {
+                return Err(FlashError::BufferTooSmall);
+            }
+            read(pos, &mut buf[..key_len])?;
+            pos += key_len;
+            key_len
+        } else {
+            let mut key = [0u8; 4];
+            read(pos, &mut key[..key_width])?;
+            pos += key_width;
+            keycodec::to_postcard(&key[..key_width], buf).ok_or(FlashError::BufferTooSmall)?
+        };
+
+        read(pos, &mut word)?;
+        pos += 4;
+        let val_len = u32::from_le_bytes(word) as usize;
+        let val_end = key_len
+            .checked_add(val_len)
+            .filter(|end| *end <= buf.len())
+            .ok_or(FlashError::BufferTooSmall)?;
+        if pos + val_len > header.payload_len as usize {
+            return Err(FlashError::BufferTooSmall);
+        }
+        read(pos, &mut buf[key_len..val_end])?;
+        f(&buf[..key_len], &buf[key_len..val_end]);
+        pos += val_len;
+    }
+    Ok(Some(header))
+}
+
+fn read_record<'a>(
+    payload: &'a [u8],
+    pos: &mut usize,
+    key_width: u8,
+) -> Option<(&'a [u8], &'a [u8])> {
+    let key_len = match key_width {
+        0 => read_u32(payload, pos)? as usize,
+        width => width as usize,
+    };
+    let key = payload.get(*pos..pos.checked_add(key_len)?)?;
+    *pos += key_len;
+    let val_len = read_u32(payload, pos)? as usize;
+    let value = payload.get(*pos..pos.checked_add(val_len)?)?;
+    *pos += val_len;
+    Some((key, value))
+}
+
+fn read_u32(bytes: &[u8], pos: &mut usize) -> Option<u32> {
+    let word = bytes.get(*pos..*pos + 4)?;
+    *pos += 4;
+    Some(u32::from_le_bytes([word[0], word[1], word[2], word[3]]))
+}
@@ -0,0 +1,173 @@
+// Bring-up checks at init
+// A wrong partition or a flash that doesn't take writes otherwise shows up
+// as a WriteError on the first save, long after boot. with_capacity_check()
+// builds the Database and looks at the partition right away:
+//
+// let (mut db, report) = Db::with_capacity_check(&mut flash, DB_PARTITION, Some(SCRATCH_PAGE));
+// defmt::info!("{}", report);
+// if !report.is_ok() {
+//     defmt::panic!("flash bring-up failed");
+// }
+// db.load_from_flash(&mut flash, DB_PARTITION.offset)?;
+//
+// The write test needs a page of its own, set aside in the flash layout for
+// nothing else: its first word is programmed, read back and the page erased
+// again. Pass None to skip it. The page has to be erase aligned, outside the
+// partition and blank all the way through, otherwise the test is skipped
+// and nothing is written. It runs on every boot it's called on, so the page
+// wears like any other.
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::{Database, FlashError};
+use crate::emergency::Partition;
+use crate::flash::{self, LayoutError};
+use crate::image::{self, ImageHeader};
+use crate::kv::BlobStore;
+use embedded_storage::nor_flash::NorFlash;
+
+// Longest scratch word we test with
+const MAX_SCRATCH: usize = 16;
+const PATTERN: u8 = 0x5A;
+
+/// Outcome of the scratch word write test
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ScratchTest {
+    // Programmed, read back and erased again
+    Passed,
+    // No scratch page, a bad layout or the page isn't blank, nothing written
+    Skipped,
+    WriteFailed,
+    // Programmed without an error, but reads back different
+    ReadBackMismatch,
+    EraseFailed,
+}
+
+/// What with_capacity_check() found, see the top of the file
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct InitReport {
+    pub partition: Partition,
+    pub capacity: usize,
+    pub erase_size: usize,
+    pub write_size: usize,
+    // In bounds and on erase block boundaries
+    pub layout: Result<(), LayoutError>,
+    // The partition holds the image region (MAX_IMAGE_SIZE in whole erase
+    // blocks, footer included)
+    pub room_for_max_image: bool,
+    // The image at the start of the partition, Ok(None) if erased (or the
+    // layout is bad and the flash wasn't read)
+    pub image: Result<Option<ImageHeader>, FlashError>,
+    pub scratch: ScratchTest,
+}
+
+impl InitReport {
+    /// Nothing points at a bad layout or broken flash
+    /// A missing or corrupt image is fine here, that's what load reports.
+    pub fn is_ok(&self) -> bool {
+        self.layout.is_ok()
+            && matches!(self.scratch, ScratchTest::Passed | ScratchTest::Skipped)
+            && !matches!(self.image, Err(FlashError::ReadError))
+    }
+}
+
+impl<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>
+    Database<K, V, C, N, B, CACH, S, CP>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    /// new() (or a default store of another kind), plus a check of the
+    /// partition the database is going to use
+    /// scratch is the offset of a page reserved for the write test.
+    pub fn with_capacity_check<F: NorFlash>(
+        flash: &mut F,
+        partition: Partition,
+        scratch: Option<u32>,
+    ) -> (Self, InitReport)
+    where
+        S: Default,
+        CP: Default,
+    {
+        let db = Self::with_store_and_policy(S::default(), CP::default());
+        (db, check_partition(flash, partition, scratch))
+    }
+}
+
+/// The checks of with_capacity_check, for a partition used some other way
+pub fn check_partition<F: NorFlash>(
+    flash: &mut F,
+    partition: Partition,
+    scratch: Option<u32>,
+) -> InitReport {
+    let erase_size = F::ERASE_SIZE;
+    let layout = flash::check_layout(&[partition], flash.capacity() as u32, erase_size as u32, 0);
+    let mut report = InitReport {
+        partition,
+        capacity: flash.capacity(),
+        erase_size,
+        write_size: F::WRITE_SIZE,
+        layout,
+        room_for_max_image: partition.len as usize >= image::region_len(erase_size),
+        image: Ok(None),
+        scratch: ScratchTest::Skipped,
+    };
+    if report.layout.is_err() {
+        return report;
+    }
+
+    report.image = image::verify(flash, partition.offset);
+    if let Some(page) = scratch {
+        let page = Partition::new(page, erase_size as u32);
+        // The scratch page can't be in use by anything the layout knows of
+        if flash::check_layout(
+            &[partition, page],
+            report.capacity as u32,
+            erase_size as u32,
+            0,
+        )
+        .is_ok()
+        {
+            report.scratch = scratch_test(flash, page);
+        }
+    }
+    report
+}
+
+// Whether all of the page reads back erased
+fn blank<F: NorFlash>(flash: &mut F, page: Partition) -> bool {
+    let mut buf = [0u8; MAX_SCRATCH];
+    (page.offset..page.offset + page.len)
+        .step_by(MAX_SCRATCH)
+        .all(|offset| {
+            let n = MAX_SCRATCH.min((page.offset + page.len - offset) as usize);
+            flash.read(offset, &mut buf[..n]).is_ok() && buf[..n].iter().all(|b| *b == 0xFF)
+        })
+}
+
+// Program, read back and erase the first word of the scratch page
+fn scratch_test<F: NorFlash>(flash: &mut F, page: Partition) -> ScratchTest {
+    let len = F::WRITE_SIZE.max(4);
+    if len > MAX_SCRATCH || !len.is_multiple_of(F::WRITE_SIZE) || !blank(flash, page) {
+        return ScratchTest::Skipped;
+    }
+
+    let mut buf = [0u8; MAX_SCRATCH];
+    if flash
+        .write(page.offset, &[PATTERN; MAX_SCRATCH][..len])
+        .is_err()
+    {
+        return ScratchTest::WriteFailed;
+    }
+    if flash.read(page.offset, &mut buf[..len]).is_err() || buf[..len].iter().any(|b| *b != PATTERN)
+    {
+        return ScratchTest::ReadBackMismatch;
+    }
+    if flash.erase(page.offset, page.offset + page.len).is_err() || !blank(flash, page) {
+        return ScratchTest::EraseFailed;
+    }
+    ScratchTest::Passed
+}
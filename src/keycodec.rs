@@ -0,0 +1,264 @@
+// Fixed-width integer keys in the flash image
+// Keys are stored as [key_len: u32][postcard key] in general. For u8, u16
+// and u32 keys (and newtypes around them, struct SensorId(u16)) that is 5-9
+// bytes for 1-4 bytes of key, so save_to_flash writes those as the plain
+// little endian integer instead, with the width once in the payload:
+//
+// [num_entries: u32][key_width: u8][key: key_width bytes][val_len: u32][val]...
+//
+// key_width 0 is the general layout. Whether a key type qualifies is found
+// out by serializing it with a probe that only accepts those integers, so
+// it doesn't need a trait on K and every existing key type still works.
+
+use serde::de::{self, Visitor};
+use serde::ser::{self, Impossible, Serialize};
+
+/// Width the keys of an image are stored with, 0 for postcard
+/// All keys have to agree, else it is 0 as well.
+pub fn image_width<'a, K, I>(keys: I) -> u8
+where
+    K: Serialize + 'a,
+    I: IntoIterator<Item = &'a K>,
+{
+    let mut width = None;
+    for key in keys {
+        match (fixed(key), width) {
+            (Some((_, w)), None) => width = Some(w),
+            (Some((_, w)), Some(seen)) if w == seen => {}
+            _ => return 0,
+        }
+    }
+    width.unwrap_or(0)
+}
+
+/// Write key as a width byte integer, returns how many bytes that is
+/// None if key isn't an integer of that width, or out is too small.
+pub fn encode_fixed<K: Serialize>(key: &K, width: u8, out: &mut [u8]) -> Option<usize> {
+    let width = width as usize;
+    match fixed(key) {
+        Some((value, w)) if w as usize == width && out.len() >= width => {
+            out[..width].copy_from_slice(&value.to_le_bytes()[..width]);
+            Some(width)
+        }
+        _ => None,
+    }
+}
+
+/// A key written by encode_fixed, bytes is exactly the key
+pub fn decode_fixed<K: de::DeserializeOwned>(bytes: &[u8]) -> Option<K> {
+    if bytes.is_empty() || bytes.len() > 4 {
+        return None;
+    }
+    let mut word = [0u8; 4];
+    word[..bytes.len()].copy_from_slice(bytes);
+    K::deserialize(IntDeserializer(u32::from_le_bytes(word))).ok()
+}
+
+/// A key stored with any width, in the postcard form
+/// For tools that expect postcard keys from images with fixed-width keys,
+/// u8 is the same byte and u16/u32 become a varint. Returns the length.
+pub fn to_postcard(bytes: &[u8], out: &mut [u8]) -> Option<usize> {
+    if bytes.len() == 1 {
+        *out.first_mut()? = bytes[0];
+        return Some(1);
+    }
+    if bytes.len() > 4 {
+        return None;
+    }
+    let mut word = [0u8; 4];
+    word[..bytes.len()].copy_from_slice(bytes);
+    let mut value = u32::from_le_bytes(word);
+    let mut len = 0;
+    loop {
+        let byte = (value & 0x7F) as u8;
+        value >>= 7;
+        let slot = out.get_mut(len)?;
+        len += 1;
+        if value == 0 {
+            *slot = byte;
+            return Some(len);
+        }
+        *slot = byte | 0x80;
+    }
+}
+
+// The integer and its width in bytes, if key is one of the fixed-width types
+fn fixed<K: Serialize>(key: &K) -> Option<(u32, u8)> {
+    key.serialize(Probe).ok()
+}
+
+#[derive(Debug)]
+struct NotFixed;
+
+impl core::fmt::Display for NotFixed {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        f.write_str("not a fixed-width key")
+    }
+}
+
+impl ser::StdError for NotFixed {}
+
+impl ser::Error for NotFixed {
+    fn custom<T: core::fmt::Display>(_msg: T) -> Self {
+        NotFixed
+    }
+}
+
+// Serializer that only gets through u8, u16, u32 and newtypes around them
+struct Probe;
+
+type Reject = Impossible<(u32, u8), NotFixed>;
+
+impl ser::Serializer for Probe {
+    type Ok = (u32, u8);
+    type Error = NotFixed;
+    type SerializeSeq = Reject;
+    type SerializeTuple = Reject;
+    type SerializeTupleStruct = Reject;
+    type SerializeTupleVariant = Reject;
+    type SerializeMap = Reject;
+    type SerializeStruct = Reject;
+    type SerializeStructVariant = Reject;
+
+    fn serialize_u8(self, v: u8) -> Result<Self::Ok, NotFixed> {
+        Ok((v as u32, 1))
+    }
+    fn serialize_u16(self, v: u16) -> Result<Self::Ok, NotFixed> {
+        Ok((v as u32, 2))
+    }
+    fn serialize_u32(self, v: u32) -> Result<Self::Ok, NotFixed> {
+        Ok((v, 4))
+    }
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Self::Ok, NotFixed> {
+        value.serialize(self)
+    }
+
+    fn serialize_bool(self, _: bool) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_i8(self, _: i8) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_i16(self, _: i16) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_i32(self, _: i32) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_i64(self, _: i64) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_u64(self, _: u64) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_f32(self, _: f32) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_f64(self, _: f64) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_char(self, _: char) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_str(self, _: &str) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_bytes(self, _: &[u8]) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_none(self) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_some<T: ?Sized + Serialize>(self, _: &T) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_unit(self) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+    ) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: &T,
+    ) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<Reject, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_tuple(self, _: usize) -> Result<Reject, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_tuple_struct(self, _: &'static str, _: usize) -> Result<Reject, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Reject, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_map(self, _: Option<usize>) -> Result<Reject, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_struct(self, _: &'static str, _: usize) -> Result<Reject, NotFixed> {
+        Err(NotFixed)
+    }
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Reject, NotFixed> {
+        Err(NotFixed)
+    }
+    fn collect_str<T: ?Sized + core::fmt::Display>(self, _: &T) -> Result<Self::Ok, NotFixed> {
+        Err(NotFixed)
+    }
+}
+
+// Hands a stored integer to the key's Deserialize, the visitors of u8/u16
+// range check it themselves
+struct IntDeserializer(u32);
+
+impl<'de> de::Deserializer<'de> for IntDeserializer {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_u32(self.0)
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct seq tuple
+        tuple_struct map struct enum identifier ignored_any
+    }
+}
@@ -0,0 +1,68 @@
+// Key normalization for string keys
+// Keys typed into a serial console or sent over BLE provisioning often only
+// differ by case or stray whitespace ("WiFi_SSID " vs "wifi_ssid"), and each
+// spelling silently becomes its own entry. A KeyPolicy cleans keys up where
+// they enter the system (cli::DbCommand::run_with_policy, provisioning code)
+// before they are used with the database.
+//
+// const POLICY: KeyPolicy = KeyPolicy::STRICT;
+// let key: String<32> = POLICY.normalize(" WiFi_SSID ")?;   // "wifi_ssid"
+
+use heapless::String;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum KeyError {
+    // Nothing left after trimming
+    Empty,
+    // The key contains one of the policy's reserved characters
+    Reserved(char),
+    TooLong,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct KeyPolicy {
+    /// ASCII lowercase the key
+    pub lowercase: bool,
+    /// Drop leading/trailing whitespace
+    pub trim: bool,
+    /// Characters that are rejected
+    pub reserved: &'static [char],
+}
+
+impl KeyPolicy {
+    /// Keys are used exactly as given
+    pub const NONE: KeyPolicy = KeyPolicy {
+        lowercase: false,
+        trim: false,
+        reserved: &[],
+    };
+
+    /// Trimmed, lowercase, no whitespace, quotes or control characters
+    /// ':' is allowed so namespaced keys pass.
+    pub const STRICT: KeyPolicy = KeyPolicy {
+        lowercase: true,
+        trim: true,
+        reserved: &[' ', '\t', '"', '\'', '\\', '\r', '\n', '\0'],
+    };
+
+    pub fn normalize<const L: usize>(&self, raw: &str) -> Result<String<L>, KeyError> {
+        let raw = if self.trim { raw.trim() } else { raw };
+        if raw.is_empty() {
+            return Err(KeyError::Empty);
+        }
+
+        let mut key = String::new();
+        for c in raw.chars() {
+            if self.reserved.contains(&c) {
+                return Err(KeyError::Reserved(c));
+            }
+            let c = if self.lowercase {
+                c.to_ascii_lowercase()
+            } else {
+                c
+            };
+            key.push(c).map_err(|_| KeyError::TooLong)?;
+        }
+        Ok(key)
+    }
+}
@@ -1,6 +1,8 @@
 use core::borrow::Borrow;
 use core::hash::Hash;
+use core::ops::{Bound, RangeBounds};
 use heapless::index_map::FnvIndexMap;
+use heapless::Vec;
 
 pub struct KvStore<K, V, const N: usize>
 where
@@ -66,4 +68,407 @@ where
     pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
         self.map.iter()
     }
+
+    pub fn iter_mut(&mut self) -> impl Iterator<Item = (&K, &mut V)> {
+        self.map.iter_mut()
+    }
+
+    /// Keep the entries f returns true for, remove the rest
+    /// f can change the values it keeps. The order of iter() stays the same.
+    pub fn retain<F>(&mut self, f: F)
+    where
+        F: FnMut(&K, &mut V) -> bool,
+    {
+        self.map.retain(f)
+    }
+}
+
+impl<K, V, const N: usize> Default for KvStore<K, V, N>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+// Storage backends for Database
+// Database keeps encoded values as byte blobs in a BlobStore. KvStore (hash
+// map, no ordering) is the default, SortedStore keeps keys in order so
+// Database::range works on it. SortedStore only needs K: Ord, not Hash, and
+// for a handful of keys a binary search beats hashing anyway.
+//
+// Both reserve B bytes for every one of the N entries, which adds up when
+// most values are a few bytes and only some get close to B. ArenaStore puts
+// all values in one byte arena of A bytes instead, so value RAM is a single
+// number to tune:
+//
+// let db: Database<u16, Config, Postcard, 64, 128, 4, ArenaStore<u16, 64, 2048>> =
+//     Database::with_store(ArenaStore::new());
+//
+// B is then only the largest value a put() takes. Replaced and removed
+// values leave holes in the arena, they are compacted away when a value
+// doesn't fit at the end any more (or by calling compact() when idle).
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum StoreError {
+    // No room for another key (or its value, in an ArenaStore)
+    Full,
+    // The value is bigger than a blob can hold
+    TooLarge,
+}
+
+/// Where Database keeps the encoded values
+/// insert() must succeed as long as the key exists or len() < capacity(),
+/// and the values together stay within byte_capacity(). Batches (imports,
+/// transactions) check that up front and rely on it.
+pub trait BlobStore<K> {
+    fn capacity(&self) -> usize;
+    /// Encoded bytes the store holds when full
+    fn byte_capacity(&self) -> usize;
+    fn len(&self) -> usize;
+    fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+    fn clear(&mut self);
+    /// Insert or replace the value stored under key
+    fn insert(&mut self, key: K, value: &[u8]) -> Result<(), StoreError>;
+    fn get(&self, key: &K) -> Option<&[u8]>;
+    /// The stored key together with its value
+    fn get_key_value<'a>(&'a self, key: &K) -> Option<(&'a K, &'a [u8])>
+    where
+        K: 'a;
+    /// Returns true if the key was there
+    fn remove(&mut self, key: &K) -> bool;
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a [u8])>
+    where
+        K: 'a;
+}
+
+impl<K, const N: usize, const B: usize> BlobStore<K> for KvStore<K, Vec<u8, B>, N>
+where
+    K: Eq + Hash,
+{
+    fn capacity(&self) -> usize {
+        N
+    }
+    fn byte_capacity(&self) -> usize {
+        N * B
+    }
+    fn len(&self) -> usize {
+        self.map.len()
+    }
+    fn clear(&mut self) {
+        self.map.clear()
+    }
+
+    fn insert(&mut self, key: K, value: &[u8]) -> Result<(), StoreError> {
+        let blob = Vec::from_slice(value).map_err(|_| StoreError::TooLarge)?;
+        self.put(key, blob).map_err(|_| StoreError::Full)?;
+        Ok(())
+    }
+
+    fn get(&self, key: &K) -> Option<&[u8]> {
+        self.map.get(key).map(|v| v.as_slice())
+    }
+
+    fn get_key_value<'a>(&'a self, key: &K) -> Option<(&'a K, &'a [u8])>
+    where
+        K: 'a,
+    {
+        let i = self.map.get_index_of(key)?;
+        self.map.get_index(i).map(|(k, v)| (k, v.as_slice()))
+    }
+
+    fn remove(&mut self, key: &K) -> bool {
+        self.map.remove(key).is_some()
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a [u8])>
+    where
+        K: 'a,
+    {
+        self.map.iter().map(|(k, v)| (k, v.as_slice()))
+    }
+}
+
+/// Store that keeps entries sorted by key (a sorted array, binary search)
+/// Inserts and removes shift the entries after them, fine for the few
+/// hundred entries this crate is meant for.
+pub struct SortedStore<K, const N: usize, const B: usize> {
+    entries: Vec<(K, Vec<u8, B>), N>,
+}
+
+impl<K: Ord, const N: usize, const B: usize> SortedStore<K, N, B> {
+    pub const fn new() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+
+    fn find(&self, key: &K) -> Result<usize, usize> {
+        self.entries.binary_search_by(|(k, _)| k.cmp(key))
+    }
+
+    /// Entries with keys in range, in key order
+    pub fn range<R: RangeBounds<K>>(&self, range: R) -> impl Iterator<Item = (&K, &[u8])> {
+        let start = match range.start_bound() {
+            Bound::Included(s) => self.entries.partition_point(|(k, _)| k < s),
+            Bound::Excluded(s) => self.entries.partition_point(|(k, _)| k <= s),
+            Bound::Unbounded => 0,
+        };
+        let end = match range.end_bound() {
+            Bound::Included(e) => self.entries.partition_point(|(k, _)| k <= e),
+            Bound::Excluded(e) => self.entries.partition_point(|(k, _)| k < e),
+            Bound::Unbounded => self.entries.len(),
+        };
+        self.entries[start..end.max(start)]
+            .iter()
+            .map(|(k, v)| (k, v.as_slice()))
+    }
+}
+
+impl<K: Ord, const N: usize, const B: usize> Default for SortedStore<K, N, B> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K: Ord, const N: usize, const B: usize> BlobStore<K> for SortedStore<K, N, B> {
+    fn capacity(&self) -> usize {
+        N
+    }
+    fn byte_capacity(&self) -> usize {
+        N * B
+    }
+    fn len(&self) -> usize {
+        self.entries.len()
+    }
+    fn clear(&mut self) {
+        self.entries.clear()
+    }
+
+    fn insert(&mut self, key: K, value: &[u8]) -> Result<(), StoreError> {
+        let blob = Vec::from_slice(value).map_err(|_| StoreError::TooLarge)?;
+        match self.find(&key) {
+            Ok(i) => self.entries[i].1 = blob,
+            Err(i) => self
+                .entries
+                .insert(i, (key, blob))
+                .map_err(|_| StoreError::Full)?,
+        }
+        Ok(())
+    }
+
+    fn get(&self, key: &K) -> Option<&[u8]> {
+        let i = self.find(key).ok()?;
+        Some(self.entries[i].1.as_slice())
+    }
+
+    fn get_key_value<'a>(&'a self, key: &K) -> Option<(&'a K, &'a [u8])>
+    where
+        K: 'a,
+    {
+        let i = self.find(key).ok()?;
+        let (k, v) = &self.entries[i];
+        Some((k, v.as_slice()))
+    }
+
+    fn remove(&mut self, key: &K) -> bool {
+        match self.find(key) {
+            Ok(i) => {
+                self.entries.remove(i);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a [u8])>
+    where
+        K: 'a,
+    {
+        self.entries.iter().map(|(k, v)| (k, v.as_slice()))
+    }
+}
+
+/// Store with the values of all N entries in one arena of A bytes
+/// See the top of the section.
+pub struct ArenaStore<K, const N: usize, const A: usize>
+where
+    K: Eq + Hash,
+{
+    spans: FnvIndexMap<K, Span, N>,
+    arena: [u8; A],
+    // End of the last value written, the arena is free from here on
+    top: usize,
+    // Bytes of the values, top minus the holes
+    live: usize,
+}
+
+// Where a value is in the arena
+#[derive(Clone, Copy)]
+struct Span {
+    offset: usize,
+    len: usize,
+}
+
+impl<K, const N: usize, const A: usize> ArenaStore<K, N, A>
+where
+    K: Eq + Hash,
+{
+    pub const fn new() -> Self {
+        Self {
+            spans: FnvIndexMap::new(),
+            arena: [0; A],
+            top: 0,
+            live: 0,
+        }
+    }
+
+    /// Bytes still free for values, holes included
+    pub fn free(&self) -> usize {
+        A - self.live
+    }
+
+    /// Bytes taken up by holes, compact() gets them back
+    pub fn fragmented(&self) -> usize {
+        self.top - self.live
+    }
+
+    /// Move the values to the start of the arena, closing the holes
+    /// Takes a pass over the entries per value, call it when idle rather
+    /// than leaving it to an insert on a hot path.
+    pub fn compact(&mut self) {
+        let mut cursor = 0;
+        // The values not moved yet all lie at or after cursor, take them in
+        // order of offset
+        while let Some(span) = self
+            .spans
+            .values_mut()
+            .filter(|span| span.len > 0 && span.offset >= cursor)
+            .min_by_key(|span| span.offset)
+        {
+            let from = span.offset;
+            span.offset = cursor;
+            self.arena.copy_within(from..from + span.len, cursor);
+            cursor += span.len;
+        }
+        self.top = cursor;
+    }
+}
+
+impl<K, const N: usize, const A: usize> Default for ArenaStore<K, N, A>
+where
+    K: Eq + Hash,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, const N: usize, const A: usize> BlobStore<K> for ArenaStore<K, N, A>
+where
+    K: Eq + Hash,
+{
+    fn capacity(&self) -> usize {
+        N
+    }
+    fn byte_capacity(&self) -> usize {
+        A
+    }
+    fn len(&self) -> usize {
+        self.spans.len()
+    }
+    fn clear(&mut self) {
+        self.spans.clear();
+        self.top = 0;
+        self.live = 0;
+    }
+
+    fn insert(&mut self, key: K, value: &[u8]) -> Result<(), StoreError> {
+        let old = self.spans.get(&key).copied();
+        if old.is_none() && self.spans.is_full() {
+            return Err(StoreError::Full);
+        }
+        let old_len = old.map_or(0, |span| span.len);
+        if value.len() > A - (self.live - old_len) {
+            return Err(StoreError::Full);
+        }
+
+        // Shrinking or same size, the value stays where it is
+        if let Some(span) = old.filter(|span| value.len() <= span.len) {
+            self.arena[span.offset..span.offset + value.len()].copy_from_slice(value);
+            self.spans
+                .insert(
+                    key,
+                    Span {
+                        offset: span.offset,
+                        len: value.len(),
+                    },
+                )
+                .map_err(|_| StoreError::Full)?;
+            self.live -= old_len - value.len();
+            return Ok(());
+        }
+
+        // The old value becomes a hole
+        if let Some(span) = self.spans.get_mut(&key) {
+            span.len = 0;
+        }
+        self.live -= old_len;
+        if self.top + value.len() > A {
+            self.compact();
+        }
+        let offset = self.top;
+        self.arena[offset..offset + value.len()].copy_from_slice(value);
+        self.spans
+            .insert(
+                key,
+                Span {
+                    offset,
+                    len: value.len(),
+                },
+            )
+            .map_err(|_| StoreError::Full)?;
+        self.top += value.len();
+        self.live += value.len();
+        Ok(())
+    }
+
+    fn get(&self, key: &K) -> Option<&[u8]> {
+        let span = self.spans.get(key)?;
+        Some(&self.arena[span.offset..span.offset + span.len])
+    }
+
+    fn get_key_value<'a>(&'a self, key: &K) -> Option<(&'a K, &'a [u8])>
+    where
+        K: 'a,
+    {
+        let i = self.spans.get_index_of(key)?;
+        let (k, span) = self.spans.get_index(i)?;
+        Some((k, &self.arena[span.offset..span.offset + span.len]))
+    }
+
+    fn remove(&mut self, key: &K) -> bool {
+        match self.spans.remove(key) {
+            Some(span) => {
+                self.live -= span.len;
+                if span.offset + span.len == self.top {
+                    self.top = span.offset;
+                }
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn iter<'a>(&'a self) -> impl Iterator<Item = (&'a K, &'a [u8])>
+    where
+        K: 'a,
+    {
+        self.spans
+            .iter()
+            .map(|(k, span)| (k, &self.arena[span.offset..span.offset + span.len]))
+    }
 }
@@ -0,0 +1,85 @@
+// Localized strings
+// Translations live in the "l10n" namespace as "l10n:<locale>:<message id>",
+// so they can be pushed through the normal provisioning path instead of
+// being baked into the firmware image.
+//
+// const CHAIN: &[&str] = &["en"];
+// let l10n = Translations::new(CHAIN);
+// l10n.put(&mut db, "de", "greeting", String::try_from("Hallo").unwrap())?;
+// let text = l10n.get(&mut db, "de-AT", "greeting")?;
+//
+// get() tries the locale, then its language ("de-AT" -> "de"), then every
+// locale in the fallback chain in order.
+
+use crate::codec::Codec;
+use crate::db::Database;
+use crate::namespace::{self, NamespaceError};
+use core::fmt::Write;
+use heapless::String;
+
+pub const NAMESPACE: &str = "l10n";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum L10nError {
+    Key(NamespaceError),
+    // The translation couldn't be stored or read back
+    Storage,
+}
+
+pub struct Translations<'a> {
+    fallback: &'a [&'a str],
+}
+
+impl<'a> Translations<'a> {
+    pub const fn new(fallback: &'a [&'a str]) -> Self {
+        Self { fallback }
+    }
+
+    /// Database key of msg_id in locale
+    pub fn key<const L: usize>(locale: &str, msg_id: &str) -> Result<String<L>, L10nError> {
+        let mut name: String<L> = String::new();
+        write!(name, "{}{}{}", locale, namespace::SEPARATOR, msg_id)
+            .map_err(|_| L10nError::Key(NamespaceError::TooLong))?;
+        namespace::key(NAMESPACE, &name).map_err(L10nError::Key)
+    }
+
+    pub fn put<V, C, const L: usize, const N: usize, const B: usize, const CACH: usize>(
+        &self,
+        db: &mut Database<String<L>, V, C, N, B, CACH>,
+        locale: &str,
+        msg_id: &str,
+        text: V,
+    ) -> Result<(), L10nError>
+    where
+        C: Codec<V>,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    {
+        let key = Self::key::<L>(locale, msg_id)?;
+        db.put(key, text).map_err(|_| L10nError::Storage)
+    }
+
+    /// Look up msg_id for locale, falling back as described at the top
+    pub fn get<V, C, const L: usize, const N: usize, const B: usize, const CACH: usize>(
+        &self,
+        db: &mut Database<String<L>, V, C, N, B, CACH>,
+        locale: &str,
+        msg_id: &str,
+    ) -> Result<Option<V>, L10nError>
+    where
+        C: Codec<V>,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    {
+        let language = locale.split_once('-').map(|(lang, _)| lang);
+        let chain = core::iter::once(locale)
+            .chain(language)
+            .chain(self.fallback.iter().copied());
+
+        for loc in chain {
+            let key = Self::key::<L>(loc, msg_id)?;
+            if let Some(text) = db.get(&key).map_err(|_| L10nError::Storage)? {
+                return Ok(Some(text));
+            }
+        }
+        Ok(None)
+    }
+}
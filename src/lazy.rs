@@ -0,0 +1,203 @@
+// Lazy loading Database
+// load_from_flash only builds an index of key -> (offset, len) and get()
+// reads and decodes the value straight from flash into the cache.
+// RAM usage grows with the number of keys instead of the size of the values,
+// so this works when the stored values are much bigger than the RAM we have.
+//
+// This is read-only, the image is still written with Database::save_to_flash.
+
+use crate::codec::Codec;
+use crate::db::FlashError;
+use crate::image::{self, ImageHeader, Sealing, HEADER_SIZE};
+use crate::keycodec;
+use crate::kv::KvStore;
+use embedded_storage::nor_flash::ReadNorFlash;
+use heapless::LinearMap;
+
+/// Where a value lives in flash
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct ValueRef {
+    pub offset: u32,
+    pub len: u32,
+}
+
+/// B is the largest value we can read back (and the largest key we can index)
+pub struct LazyDatabase<K, V, C, const N: usize, const B: usize, const CACH: usize>
+where
+    C: Codec<V>,
+    K: Eq + core::hash::Hash + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    index: KvStore<K, ValueRef, N>,
+    cache: LinearMap<K, V, CACH>,
+    _c: core::marker::PhantomData<C>,
+}
+
+impl<K, V, C, const N: usize, const B: usize, const CACH: usize> Default
+    for LazyDatabase<K, V, C, N, B, CACH>
+where
+    C: Codec<V>,
+    K: Eq + core::hash::Hash + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, C, const N: usize, const B: usize, const CACH: usize> LazyDatabase<K, V, C, N, B, CACH>
+where
+    C: Codec<V>,
+    K: Eq + core::hash::Hash + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    pub const fn new() -> Self {
+        Self {
+            index: KvStore::new(),
+            cache: LinearMap::new(),
+            _c: core::marker::PhantomData,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.index.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.index.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.index.capacity()
+    }
+
+    /// Where the value for key is stored in flash, if it exists
+    pub fn value_ref(&self, key: &K) -> Option<ValueRef> {
+        self.index.get(key).copied()
+    }
+
+    /// Build the key index from an image written by Database::save_to_flash
+    /// The payload CRC is checked first, then the records are walked with
+    /// small reads so only one key at a time is ever in RAM.
+    pub fn load_from_flash<F>(
+        &mut self,
+        flash: &mut F,
+        flash_offset: u32,
+    ) -> Result<Option<ImageHeader>, FlashError>
+    where
+        F: ReadNorFlash,
+        K: serde::de::DeserializeOwned,
+    {
+        let header = match image::verify(flash, flash_offset)? {
+            Some(h) => h,
+            None => return Ok(None),
+        };
+        // Values are read straight from flash, which skips the tag check
+        if header.sealing != Sealing::None {
+            return Err(FlashError::Sealed);
+        }
+
+        self.index.clear();
+        self.cache.clear();
+
+        let end = flash_offset + HEADER_SIZE as u32 + header.payload_len;
+        let mut pos = flash_offset + HEADER_SIZE as u32;
+        let num_entries = read_u32(flash, &mut pos, end)?;
+        let mut key_width = [0u8; 1];
+        if header.format_version >= 2 {
+            if pos + 1 > end {
+                return Err(FlashError::BufferTooSmall);
+            }
+            flash
+                .read(pos, &mut key_width)
+                .map_err(|_| FlashError::ReadError)?;
+            pos += 1;
+        }
+        let key_width = image::check_key_width(key_width[0])? as usize;
+
+        let mut key_buf = [0u8; B];
+        for _ in 0..num_entries {
+            let key_len = match key_width {
+                0 => read_u32(flash, &mut pos, end)? as usize,
+                width => width,
+            };
+            if key_len > B || pos + key_len as u32 > end {
+                return Err(FlashError::BufferTooSmall);
+            }
+            flash
+                .read(pos, &mut key_buf[..key_len])
+                .map_err(|_| FlashError::ReadError)?;
+            let key: K = if key_width == 0 {
+                postcard::from_bytes(&key_buf[..key_len])
+                    .map_err(|_| FlashError::DeserializationError)?
+            } else {
+                keycodec::decode_fixed(&key_buf[..key_len])
+                    .ok_or(FlashError::DeserializationError)?
+            };
+            pos += key_len as u32;
+
+            let val_len = read_u32(flash, &mut pos, end)?;
+            if pos + val_len > end {
+                return Err(FlashError::BufferTooSmall);
+            }
+            let value = ValueRef {
+                offset: pos,
+                len: val_len,
+            };
+            pos += val_len;
+
+            self.index
+                .put(key, value)
+                .map_err(|_| FlashError::DatabaseFull)?;
+        }
+
+        Ok(Some(header))
+    }
+
+    /// Get a value, reading it from flash if it isn't cached
+    /// flash must be the same device load_from_flash indexed.
+    pub fn get<F>(&mut self, flash: &mut F, key: &K) -> Result<Option<V>, FlashError>
+    where
+        F: ReadNorFlash,
+    {
+        if let Some(v) = self.cache.get(key).cloned() {
+            return Ok(Some(v));
+        }
+        let value = match self.index.get(key) {
+            Some(v) => *v,
+            None => return Ok(None),
+        };
+
+        let len = value.len as usize;
+        if len > B {
+            return Err(FlashError::BufferTooSmall);
+        }
+        let mut buf = [0u8; B];
+        flash
+            .read(value.offset, &mut buf[..len])
+            .map_err(|_| FlashError::ReadError)?;
+        let val = C::decode(&buf[..len]).map_err(|e| FlashError::Codec(e.into()))?;
+
+        if self.cache.is_full() {
+            if let Some((k0, _)) = self.cache.iter().next() {
+                let victim = k0.clone();
+                let _ = self.cache.remove(&victim);
+            }
+        }
+        let _ = self.cache.insert(key.clone(), val.clone());
+
+        Ok(Some(val))
+    }
+}
+
+fn read_u32<F: ReadNorFlash>(flash: &mut F, pos: &mut u32, end: u32) -> Result<u32, FlashError> {
+    if *pos + 4 > end {
+        return Err(FlashError::BufferTooSmall);
+    }
+    let mut bytes = [0u8; 4];
+    flash
+        .read(*pos, &mut bytes)
+        .map_err(|_| FlashError::ReadError)?;
+    *pos += 4;
+    Ok(u32::from_le_bytes(bytes))
+}
@@ -1,23 +1,120 @@
 #![no_main]
-#![no_std]
+#![cfg_attr(not(feature = "std"), no_std)]
 
+pub mod cache;
+pub mod cal;
+pub mod canopen;
+#[cfg(feature = "persistence")]
+pub mod cli;
+pub mod clock;
 pub mod codec;
+pub mod compress;
+pub mod crc16;
+pub mod crc32;
+pub mod crypto;
 pub mod db;
+pub mod delta;
+#[cfg(feature = "persistence")]
+pub mod emergency;
+pub mod entropy;
+pub mod errcode;
+#[cfg(feature = "std")]
+pub mod factory;
+pub mod fixed;
+pub mod flags;
+#[cfg(feature = "persistence")]
 pub mod flash;
+#[cfg(feature = "sync")]
+pub mod frames;
+pub mod geo;
+pub mod history;
+pub mod hmi;
+#[cfg(feature = "persistence")]
+pub mod hybrid;
+#[cfg(feature = "persistence")]
+pub mod image;
+#[cfg(feature = "integrity")]
+pub mod init;
+pub mod keycodec;
+pub mod keys;
 pub mod kv;
+pub mod l10n;
+#[cfg(feature = "persistence")]
+pub mod lazy;
+pub mod list;
+#[cfg(feature = "persistence")]
+pub mod maintenance;
+pub mod meta;
+pub mod modbus;
+#[cfg(feature = "sync")]
+pub mod mqtt;
+pub mod msgpack;
+pub mod namespace;
+#[cfg(feature = "persistence")]
+pub mod power;
+pub mod queue;
+pub mod ram;
+pub mod schedule;
+#[cfg(feature = "persistence")]
+pub mod timeseries;
+pub mod tlv;
+#[cfg(feature = "sync")]
+pub mod transfer;
+pub mod units;
+pub mod versioned;
 
 use defmt_rtt as _;
 
+#[cfg(feature = "persistence")]
+use cache::CachePolicy;
+#[cfg(feature = "persistence")]
+use codec::Codec;
+#[cfg(feature = "persistence")]
+use db::Database;
+#[cfg(feature = "persistence")]
+use embedded_storage::nor_flash::NorFlash;
+#[cfg(feature = "persistence")]
+use kv::BlobStore;
+#[cfg(feature = "persistence")]
+use maintenance::{Maintenance, MaintenancePolicy};
+
 // I'm building this for the nRF52840 board - similar to the nRF52840 DK
 // https://docs.nordicsemi.com/bundle/ncs-latest/page/zephyr/boards/nordic/nrf52840dk/doc/index.html
 
 use nrf52840_hal as _;
+#[cfg(not(feature = "panic-handler"))]
 use panic_probe as _;
 
-// Panic handler - just trigger a UDF so it won't print a panic message
-// We are using no_std, so we can't use the default panic handler
-#[defmt::panic_handler]
-fn panic() -> ! {
+// Panic handler - logs the panic, runs the hook (if any) and then triggers a
+// UDF. Only with the "panic-handler" feature, which takes the place of
+// panic-probe. Every panic ends up here: unwrap, indexing, overflow, and
+// defmt::panic! through core::panic!.
+#[cfg(feature = "panic-handler")]
+type PanicHook = cortex_m::interrupt::Mutex<core::cell::Cell<Option<fn()>>>;
+#[cfg(feature = "panic-handler")]
+static PANIC_HOOK: PanicHook = cortex_m::interrupt::Mutex::new(core::cell::Cell::new(None));
+
+// Register what to do before the core stops on a panic, e.g. save the
+// database and reset:
+//
+// embedded_db::set_panic_hook(|| {
+//     // flush, then
+//     cortex_m::peripheral::SCB::sys_reset();
+// });
+//
+// The hook runs at most once, a panic inside it goes straight to the UDF.
+#[cfg(feature = "panic-handler")]
+pub fn set_panic_hook(hook: fn()) {
+    cortex_m::interrupt::free(|cs| PANIC_HOOK.borrow(cs).set(Some(hook)));
+}
+
+#[cfg(all(feature = "panic-handler", not(feature = "std")))]
+#[panic_handler]
+fn panic(info: &core::panic::PanicInfo) -> ! {
+    defmt::error!("{}", defmt::Display2Format(info));
+    if let Some(hook) = cortex_m::interrupt::free(|cs| PANIC_HOOK.borrow(cs).take()) {
+        hook();
+    }
     cortex_m::asm::udf()
 }
 
@@ -40,3 +137,29 @@ pub fn idle_forever() -> ! {
         cortex_m::asm::wfi()
     }
 }
+
+// Same as idle_forever, but runs database housekeeping (scrub, autosave)
+// between WFIs. Each wake up does at most one bounded step, so simple
+// super-loop applications get background maintenance without an executor.
+#[cfg(feature = "persistence")]
+pub fn idle_with_maintenance<K, V, C, F, S, CP, const N: usize, const B: usize, const CACH: usize>(
+    db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+    flash: &mut F,
+    policy: MaintenancePolicy,
+) -> !
+where
+    C: Codec<V>,
+    K: Eq + Clone + serde::Serialize,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+    F: NorFlash,
+{
+    let mut maintenance = Maintenance::new(policy);
+    loop {
+        cortex_m::asm::wfi();
+        if let Err(e) = maintenance.step(db, flash) {
+            defmt::warn!("maintenance step failed: {:?}", e);
+        }
+    }
+}
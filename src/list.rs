@@ -0,0 +1,74 @@
+// Bounded lists under one key
+// For things like "the last error codes" that belong together: the value is
+// a List of up to L items, push() appends and drops the oldest item once
+// the list is full, pop() takes the newest off again. The list goes through
+// the codec like any other value, so it is saved and loaded with the rest
+// of the database. Every value of the database is a List, keep lists in
+// their own Database.
+//
+// let mut lists: Database<u8, List<u16, 16>, Postcard, 4, 64, 2> = Database::new();
+// lists.push(KEY_ERRORS, code)?;
+// for code in lists.list_iter(&KEY_ERRORS)? { ... }   // oldest first
+//
+// Unlike History (history.rs) the items can be taken off again, and the
+// list order is kept as is in the encoded value.
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::{Database, DbError};
+use crate::kv::BlobStore;
+use heapless::Vec;
+
+/// Up to L items, oldest first
+pub type List<T, const L: usize> = Vec<T, L>;
+
+impl<K, T, C, S, CP, const L: usize, const N: usize, const B: usize, const CACH: usize>
+    Database<K, List<T, L>, C, N, B, CACH, S, CP>
+where
+    C: Codec<List<T, L>>,
+    K: Eq + Clone,
+    T: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    /// Append item to the list of key, a new key starts an empty list
+    /// Returns the oldest item if it had to go to make room.
+    pub fn push(&mut self, key: K, item: T) -> Result<Option<T>, DbError<C::Error>> {
+        let mut list = self.get(&key)?.unwrap_or_default();
+        let dropped = if list.is_full() && !list.is_empty() {
+            Some(list.remove(0))
+        } else {
+            None
+        };
+        // Can't fail, there is room now (or L is 0 and nothing is kept)
+        let _ = list.push(item);
+        self.put(key, list)?;
+        Ok(dropped)
+    }
+
+    /// Take the newest item off the list of key
+    /// The key is deleted along with the last item.
+    pub fn pop(&mut self, key: &K) -> Result<Option<T>, DbError<C::Error>> {
+        let mut list = match self.get(key)? {
+            Some(list) => list,
+            None => return Ok(None),
+        };
+        let item = list.pop();
+        if list.is_empty() {
+            self.delete(key);
+        } else {
+            self.put(key.clone(), list)?;
+        }
+        Ok(item)
+    }
+
+    /// The items of key, oldest first (none if the key doesn't exist)
+    pub fn list_iter(&mut self, key: &K) -> Result<impl Iterator<Item = T>, DbError<C::Error>> {
+        Ok(self.get(key)?.unwrap_or_default().into_iter())
+    }
+
+    /// How many items the list of key has
+    pub fn list_len(&mut self, key: &K) -> Result<usize, DbError<C::Error>> {
+        Ok(self.get(key)?.map_or(0, |list| list.len()))
+    }
+}
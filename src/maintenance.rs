@@ -0,0 +1,176 @@
+// Background housekeeping for simple super-loop applications
+// Each wake up from WFI runs at most one bounded step (scrub or autosave)
+// so an interrupt handler never waits long for the main loop.
+//
+// There is no compaction step: every save rewrites the whole image,
+// so there are no stale records to reclaim.
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::crypto::ImageCipher;
+use crate::db::{Database, FlashError};
+use crate::image;
+use crate::kv::BlobStore;
+use embedded_storage::nor_flash::NorFlash;
+
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct MaintenancePolicy {
+    /// Where the database image lives in flash
+    pub flash_offset: u32,
+    /// Verify the stored image every this many wake ups (0 = never)
+    /// A corrupt image is rewritten from the copy in RAM if the database
+    /// was loaded from or saved to flash_offset. Otherwise RAM may not hold
+    /// it (a failed boot load), the step returns the error and leaves the
+    /// image for the application to recover, e.g. from its backup.
+    pub scrub_every: u32,
+    /// Save the database every this many wake ups (0 = never)
+    /// Only if something changed (needs_persist), every save erases flash.
+    pub autosave_every: u32,
+}
+
+/// When Database::maybe_persist saves, whichever limit is hit first
+/// Nothing is saved while nothing changed, whatever the limits say.
+///
+/// db.set_persist_policy(PersistPolicy {
+///     flash_offset: FLASH_STORAGE_ADDR,
+///     after_changes: 50,
+///     after_bytes: 0,
+///     should_persist: Some(|elapsed_us| elapsed_us > 10 * 60 * 1_000_000),
+/// });
+/// loop { ...; db.maybe_persist(&mut flash, None)?; }
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub struct PersistPolicy {
+    /// Where the database image lives in flash
+    pub flash_offset: u32,
+    /// Save after this many puts/deletes (0 = don't count)
+    pub after_changes: u32,
+    /// Save after this many encoded value bytes were written (0 = don't count)
+    pub after_bytes: usize,
+    /// Asked with the microseconds since the last save (needs set_clock)
+    pub should_persist: Option<fn(u64) -> bool>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MaintenanceStep {
+    Idle,
+    Scrubbed,
+    Repaired,
+    Saved,
+}
+
+/// Keeps track of wake ups between maintenance steps
+pub struct Maintenance {
+    policy: MaintenancePolicy,
+    wakeups: u32,
+}
+
+impl Maintenance {
+    pub const fn new(policy: MaintenancePolicy) -> Self {
+        Self { policy, wakeups: 0 }
+    }
+
+    /// Run the maintenance that is due after one more wake up
+    /// Applications with their own main loop can call this directly
+    /// instead of using idle_with_maintenance().
+    pub fn step<K, V, C, F, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &mut self,
+        db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+        flash: &mut F,
+    ) -> Result<MaintenanceStep, FlashError>
+    where
+        C: Codec<V>,
+        K: Eq + Clone + serde::Serialize,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+        F: NorFlash,
+    {
+        self.step_inner(db, flash, None)
+    }
+
+    /// Same as step, for databases kept with save_to_flash_sealed
+    /// Autosaves and repairs are sealed too, a plain save would lock the
+    /// device out of its own image.
+    pub fn step_sealed<K, V, C, F, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &mut self,
+        db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+        flash: &mut F,
+        cipher: &mut dyn ImageCipher,
+    ) -> Result<MaintenanceStep, FlashError>
+    where
+        C: Codec<V>,
+        K: Eq + Clone + serde::Serialize,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+        F: NorFlash,
+    {
+        self.step_inner(db, flash, Some(cipher))
+    }
+
+    fn step_inner<K, V, C, F, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &mut self,
+        db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+        flash: &mut F,
+        cipher: Option<&mut dyn ImageCipher>,
+    ) -> Result<MaintenanceStep, FlashError>
+    where
+        C: Codec<V>,
+        K: Eq + Clone + serde::Serialize,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+        F: NorFlash,
+    {
+        self.wakeups = self.wakeups.wrapping_add(1);
+        let offset = self.policy.flash_offset;
+
+        // Autosave wins if both are due, a fresh save is also a fresh scrub
+        if due(self.wakeups, self.policy.autosave_every) && db.needs_persist() {
+            save(db, flash, offset, cipher)?;
+            return Ok(MaintenanceStep::Saved);
+        }
+
+        if due(self.wakeups, self.policy.scrub_every) {
+            return match image::verify(flash, offset) {
+                // An erased header where we saved is as lost as a bad CRC
+                Ok(None) | Err(FlashError::CrcMismatch) | Err(FlashError::BadHeader)
+                    if db.persisted_at() == Some(offset) =>
+                {
+                    defmt::warn!("stored image is missing or corrupt, rewriting it from RAM");
+                    save(db, flash, offset, cipher)?;
+                    Ok(MaintenanceStep::Repaired)
+                }
+                Ok(_) => Ok(MaintenanceStep::Scrubbed),
+                Err(e) => Err(e),
+            };
+        }
+
+        Ok(MaintenanceStep::Idle)
+    }
+}
+
+fn due(wakeups: u32, every: u32) -> bool {
+    every != 0 && wakeups.is_multiple_of(every)
+}
+
+fn save<K, V, C, F, S, CP, const N: usize, const B: usize, const CACH: usize>(
+    db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+    flash: &mut F,
+    offset: u32,
+    cipher: Option<&mut dyn ImageCipher>,
+) -> Result<(), FlashError>
+where
+    C: Codec<V>,
+    K: Eq + Clone + serde::Serialize,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+    F: NorFlash,
+{
+    let size = core::mem::size_of::<u32>();
+    match cipher {
+        Some(cipher) => db.save_to_flash_sealed(flash, size, offset, cipher),
+        None => db.save_to_flash(flash, size, offset),
+    }
+}
@@ -0,0 +1,97 @@
+// Per-entry metadata
+// For readings where it matters how fresh the stored value is. Every value
+// of the database is a Stamped, the value plus a Meta with a version that
+// goes up on each write of the key and a timestamp from the caller (RTC
+// seconds, uptime, whatever the application counts in). It is part of the
+// value, so it is saved and loaded with the rest of the database.
+//
+// let mut readings: Database<u8, Stamped<i16>, Postcard, 16, 32, 4> = Database::new();
+// readings.put_stamped(KEY_TEMP, 215, rtc.seconds())?;
+// if let Some((temp, meta)) = readings.get_with_meta(&KEY_TEMP)? {
+//     let age = rtc.seconds() - meta.timestamp;
+// }
+//
+// Two contexts updating the same key (a task and a command handler) can use
+// the version to notice each other instead of overwriting blindly:
+//
+// let (count, meta) = readings.get_with_meta(&KEY_COUNT)?.unwrap_or((0, Meta::default()));
+// match readings.put_if_version(KEY_COUNT, count + 1, meta.version, now) {
+//     Err(DbError::VersionMismatch) => { /* changed since the read, start over */ }
+//     result => { result?; }
+// }
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::{Database, DbError};
+use crate::kv::BlobStore;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, defmt::Format, serde::Serialize, serde::Deserialize,
+)]
+pub struct Meta {
+    // 1 for the first write of a key, +1 for every write after it
+    pub version: u32,
+    pub timestamp: u64,
+}
+
+/// A value with its Meta, see the top of the file
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct Stamped<T> {
+    pub meta: Meta,
+    pub value: T,
+}
+
+impl<K, T, C, S, CP, const N: usize, const B: usize, const CACH: usize>
+    Database<K, Stamped<T>, C, N, B, CACH, S, CP>
+where
+    C: Codec<Stamped<T>>,
+    K: Eq + Clone,
+    T: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    /// Store val, written at timestamp, and bump the version of key
+    /// Returns the new version.
+    pub fn put_stamped(
+        &mut self,
+        key: K,
+        val: T,
+        timestamp: u64,
+    ) -> Result<u32, DbError<C::Error>> {
+        let version = match self.get(&key)? {
+            Some(old) => old.meta.version.wrapping_add(1),
+            None => 1,
+        };
+        let meta = Meta { version, timestamp };
+        self.put(key, Stamped { meta, value: val })?;
+        Ok(version)
+    }
+
+    /// put_stamped, but only if key is still at expected_version
+    /// expected_version is the version from the last read, 0 if the key
+    /// didn't exist. If another writer got in between, nothing is written
+    /// and the result is DbError::VersionMismatch: read again and retry.
+    pub fn put_if_version(
+        &mut self,
+        key: K,
+        val: T,
+        expected_version: u32,
+        timestamp: u64,
+    ) -> Result<u32, DbError<C::Error>> {
+        let current = self.meta(&key)?.map_or(0, |meta| meta.version);
+        if current != expected_version {
+            return Err(DbError::VersionMismatch);
+        }
+        self.put_stamped(key, val, timestamp)
+    }
+
+    /// The value of key and its Meta
+    pub fn get_with_meta(&mut self, key: &K) -> Result<Option<(T, Meta)>, DbError<C::Error>> {
+        Ok(self.get(key)?.map(|stamped| (stamped.value, stamped.meta)))
+    }
+
+    /// Just the Meta of key
+    pub fn meta(&mut self, key: &K) -> Result<Option<Meta>, DbError<C::Error>> {
+        Ok(self.get(key)?.map(|stamped| stamped.meta))
+    }
+}
@@ -0,0 +1,264 @@
+// Modbus register mapping
+// Exposes selected numeric keys as Modbus holding/input registers so a PLC
+// can read (and for holding registers, write) device configuration directly.
+// This only does the register <-> database part, the Modbus RTU/TCP framing
+// is left to whichever Modbus stack the product already uses.
+//
+// const REGISTERS: &[RegisterMapping<u32>] = &[
+//     RegisterMapping::holding(0, KEY_SETPOINT),   // u32 -> registers 0..2
+//     RegisterMapping::input(0, KEY_TEMPERATURE),
+// ];
+// let map = RegisterMap::new(REGISTERS);
+// map.read(&mut db, RegisterKind::Holding, start, &mut regs)?;
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::Database;
+use crate::kv::BlobStore;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum RegisterKind {
+    /// Read/write (function codes 3, 6, 16)
+    Holding,
+    /// Read only (function code 4)
+    Input,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct RegisterMapping<K> {
+    pub address: u16,
+    pub kind: RegisterKind,
+    pub key: K,
+}
+
+impl<K> RegisterMapping<K> {
+    pub const fn holding(address: u16, key: K) -> Self {
+        Self {
+            address,
+            kind: RegisterKind::Holding,
+            key,
+        }
+    }
+
+    pub const fn input(address: u16, key: K) -> Self {
+        Self {
+            address,
+            kind: RegisterKind::Input,
+            key,
+        }
+    }
+}
+
+/// Modbus exceptions we can return
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ModbusError {
+    IllegalDataAddress,
+    IllegalDataValue,
+    DeviceFailure,
+}
+
+impl ModbusError {
+    /// Exception code to put in the Modbus exception response
+    pub fn exception_code(&self) -> u8 {
+        match self {
+            ModbusError::IllegalDataAddress => 0x02,
+            ModbusError::IllegalDataValue => 0x03,
+            ModbusError::DeviceFailure => 0x04,
+        }
+    }
+}
+
+/// Largest value (in 16-bit registers) a RegisterValue can use
+pub const MAX_REGISTERS: usize = 4;
+
+/// Numeric values that can be split across 16-bit registers
+/// Multi-register values are big endian (high word first), which is what
+/// most PLCs expect.
+pub trait RegisterValue: Sized {
+    const REGISTERS: usize;
+    fn to_registers(&self, out: &mut [u16]);
+    fn from_registers(regs: &[u16]) -> Self;
+}
+
+impl RegisterValue for u16 {
+    const REGISTERS: usize = 1;
+    fn to_registers(&self, out: &mut [u16]) {
+        out[0] = *self;
+    }
+    fn from_registers(regs: &[u16]) -> Self {
+        regs[0]
+    }
+}
+
+impl RegisterValue for i16 {
+    const REGISTERS: usize = 1;
+    fn to_registers(&self, out: &mut [u16]) {
+        out[0] = *self as u16;
+    }
+    fn from_registers(regs: &[u16]) -> Self {
+        regs[0] as i16
+    }
+}
+
+impl RegisterValue for u32 {
+    const REGISTERS: usize = 2;
+    fn to_registers(&self, out: &mut [u16]) {
+        out[0] = (*self >> 16) as u16;
+        out[1] = *self as u16;
+    }
+    fn from_registers(regs: &[u16]) -> Self {
+        ((regs[0] as u32) << 16) | regs[1] as u32
+    }
+}
+
+impl RegisterValue for i32 {
+    const REGISTERS: usize = 2;
+    fn to_registers(&self, out: &mut [u16]) {
+        (*self as u32).to_registers(out)
+    }
+    fn from_registers(regs: &[u16]) -> Self {
+        u32::from_registers(regs) as i32
+    }
+}
+
+impl RegisterValue for f32 {
+    const REGISTERS: usize = 2;
+    fn to_registers(&self, out: &mut [u16]) {
+        self.to_bits().to_registers(out)
+    }
+    fn from_registers(regs: &[u16]) -> Self {
+        f32::from_bits(u32::from_registers(regs))
+    }
+}
+
+pub struct RegisterMap<'a, K> {
+    mappings: &'a [RegisterMapping<K>],
+}
+
+impl<'a, K> RegisterMap<'a, K>
+where
+    K: Eq + Clone,
+{
+    pub const fn new(mappings: &'a [RegisterMapping<K>]) -> Self {
+        Self { mappings }
+    }
+
+    /// Read registers start..start + out.len() of the given kind
+    /// Every register in the range has to be mapped (the Modbus spec says to
+    /// reject the whole request otherwise). Keys that were never stored read as 0.
+    pub fn read<V, C, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &self,
+        db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+        kind: RegisterKind,
+        start: u16,
+        out: &mut [u16],
+    ) -> Result<(), ModbusError>
+    where
+        C: Codec<V>,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone + RegisterValue,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+    {
+        self.check_covered::<V>(kind, start, out.len())?;
+
+        let end = start as usize + out.len();
+        for mapping in self.mappings.iter().filter(|m| m.kind == kind) {
+            let first = mapping.address as usize;
+            if first + V::REGISTERS <= start as usize || first >= end {
+                continue;
+            }
+
+            let mut regs = [0u16; MAX_REGISTERS];
+            if let Some(val) = db
+                .get(&mapping.key)
+                .map_err(|_| ModbusError::DeviceFailure)?
+            {
+                val.to_registers(&mut regs[..V::REGISTERS]);
+            }
+
+            for (i, reg) in regs[..V::REGISTERS].iter().enumerate() {
+                if let Some(slot) = (first + i)
+                    .checked_sub(start as usize)
+                    .and_then(|idx| out.get_mut(idx))
+                {
+                    *slot = *reg;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Write holding registers start..start + values.len()
+    /// Writing only part of a multi-register value keeps the other words.
+    pub fn write<V, C, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &self,
+        db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+        start: u16,
+        values: &[u16],
+    ) -> Result<(), ModbusError>
+    where
+        C: Codec<V>,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone + RegisterValue,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+    {
+        self.check_covered::<V>(RegisterKind::Holding, start, values.len())?;
+
+        let end = start as usize + values.len();
+        for mapping in self
+            .mappings
+            .iter()
+            .filter(|m| m.kind == RegisterKind::Holding)
+        {
+            let first = mapping.address as usize;
+            let last = first + V::REGISTERS;
+            if last <= start as usize || first >= end {
+                continue;
+            }
+
+            let mut regs = [0u16; MAX_REGISTERS];
+            if let Some(val) = db
+                .get(&mapping.key)
+                .map_err(|_| ModbusError::DeviceFailure)?
+            {
+                val.to_registers(&mut regs[..V::REGISTERS]);
+            }
+            for (i, reg) in regs[..V::REGISTERS].iter_mut().enumerate() {
+                if let Some(new) = (first + i)
+                    .checked_sub(start as usize)
+                    .and_then(|idx| values.get(idx))
+                {
+                    *reg = *new;
+                }
+            }
+
+            db.put(
+                mapping.key.clone(),
+                V::from_registers(&regs[..V::REGISTERS]),
+            )
+            .map_err(|_| ModbusError::DeviceFailure)?;
+        }
+        Ok(())
+    }
+
+    fn check_covered<V: RegisterValue>(
+        &self,
+        kind: RegisterKind,
+        start: u16,
+        count: usize,
+    ) -> Result<(), ModbusError> {
+        if count == 0 {
+            return Err(ModbusError::IllegalDataValue);
+        }
+        for address in start as usize..start as usize + count {
+            let mapped = self.mappings.iter().any(|m| {
+                m.kind == kind
+                    && (m.address as usize..m.address as usize + V::REGISTERS).contains(&address)
+            });
+            if !mapped {
+                return Err(ModbusError::IllegalDataAddress);
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,218 @@
+// MQTT discovery export (Home Assistant style)
+// Renders selected entries as discovery config documents plus their current
+// value, so firmware can publish its stored configuration in one loop:
+//
+// const EXPOSED: &[DiscoveryEntry<u32>] = &[
+//     DiscoveryEntry::new(KEY_SETPOINT, "number", "setpoint", "Setpoint"),
+//     DiscoveryEntry::new(KEY_TEMPERATURE, "sensor", "temp", "Temperature"),
+// ];
+// for msg in Discovery::new(&mut db, EXPOSED, "homeassistant", "boiler_01") {
+//     let msg = msg?;
+//     mqtt.publish(&msg.topic, msg.payload.as_bytes(), msg.retain)?;
+// }
+//
+// Each entry gives two messages:
+// <prefix>/<component>/<node>/<object>/config  {"name":..,"unique_id":..,"state_topic":..}
+// <node>/<object>/state                        the value as JSON
+// Entries that have no value yet only get the config message.
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::Database;
+use crate::kv::BlobStore;
+use core::fmt::Write;
+use heapless::String;
+
+/// Longest topic a message can have
+pub const MAX_TOPIC: usize = 128;
+/// Longest payload (config document or JSON value) a message can have
+pub const MAX_PAYLOAD: usize = 256;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct DiscoveryEntry<K> {
+    pub key: K,
+    /// Home Assistant component, e.g. "sensor", "number", "switch"
+    pub component: &'static str,
+    /// Used in the topics and unique_id, keep it to [a-zA-Z0-9_-]
+    pub object_id: &'static str,
+    /// Friendly name shown in the UI
+    pub name: &'static str,
+}
+
+impl<K> DiscoveryEntry<K> {
+    pub const fn new(
+        key: K,
+        component: &'static str,
+        object_id: &'static str,
+        name: &'static str,
+    ) -> Self {
+        Self {
+            key,
+            component,
+            object_id,
+            name,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum DiscoveryError {
+    // Topic or payload didn't fit in MAX_TOPIC/MAX_PAYLOAD
+    TooLong,
+    // The stored value couldn't be read or rendered as JSON
+    BadValue,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DiscoveryMessage {
+    pub topic: String<MAX_TOPIC>,
+    pub payload: String<MAX_PAYLOAD>,
+    /// Discovery configs and states should be retained so Home Assistant
+    /// picks them up after a restart
+    pub retain: bool,
+}
+
+#[derive(serde::Serialize)]
+struct ConfigDoc<'a> {
+    name: &'a str,
+    unique_id: &'a str,
+    state_topic: &'a str,
+}
+
+/// Iterator over the messages to publish for a table of DiscoveryEntry
+pub struct Discovery<'a, K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    db: &'a mut Database<K, V, C, N, B, CACH, S, CP>,
+    entries: &'a [DiscoveryEntry<K>],
+    prefix: &'a str,
+    node_id: &'a str,
+    next: usize,
+    state_pending: bool,
+}
+
+impl<'a, K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>
+    Discovery<'a, K, V, C, S, CP, N, B, CACH>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    pub fn new(
+        db: &'a mut Database<K, V, C, N, B, CACH, S, CP>,
+        entries: &'a [DiscoveryEntry<K>],
+        prefix: &'a str,
+        node_id: &'a str,
+    ) -> Self {
+        Self {
+            db,
+            entries,
+            prefix,
+            node_id,
+            next: 0,
+            state_pending: false,
+        }
+    }
+
+    fn state_topic(&self, entry: &DiscoveryEntry<K>) -> Result<String<MAX_TOPIC>, DiscoveryError> {
+        let mut topic = String::new();
+        write!(topic, "{}/{}/state", self.node_id, entry.object_id)
+            .map_err(|_| DiscoveryError::TooLong)?;
+        Ok(topic)
+    }
+
+    fn config(&self, entry: &DiscoveryEntry<K>) -> Result<DiscoveryMessage, DiscoveryError> {
+        let mut topic = String::new();
+        write!(
+            topic,
+            "{}/{}/{}/{}/config",
+            self.prefix, entry.component, self.node_id, entry.object_id
+        )
+        .map_err(|_| DiscoveryError::TooLong)?;
+
+        let mut unique_id: String<MAX_TOPIC> = String::new();
+        write!(unique_id, "{}_{}", self.node_id, entry.object_id)
+            .map_err(|_| DiscoveryError::TooLong)?;
+        let state_topic = self.state_topic(entry)?;
+
+        let doc = ConfigDoc {
+            name: entry.name,
+            unique_id: &unique_id,
+            state_topic: &state_topic,
+        };
+        let payload = to_json(&doc).ok_or(DiscoveryError::TooLong)?;
+
+        Ok(DiscoveryMessage {
+            topic,
+            payload,
+            retain: true,
+        })
+    }
+
+    fn state(
+        &mut self,
+        entry: &DiscoveryEntry<K>,
+    ) -> Result<Option<DiscoveryMessage>, DiscoveryError> {
+        let val = match self
+            .db
+            .get(&entry.key)
+            .map_err(|_| DiscoveryError::BadValue)?
+        {
+            Some(val) => val,
+            None => return Ok(None),
+        };
+
+        let payload = to_json(&val).ok_or(DiscoveryError::BadValue)?;
+        Ok(Some(DiscoveryMessage {
+            topic: self.state_topic(entry)?,
+            payload,
+            retain: true,
+        }))
+    }
+}
+
+impl<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize> Iterator
+    for Discovery<'_, K, V, C, S, CP, N, B, CACH>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    type Item = Result<DiscoveryMessage, DiscoveryError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let entries = self.entries;
+        let entry = entries.get(self.next)?;
+
+        if self.state_pending {
+            self.state_pending = false;
+            self.next += 1;
+            return match self.state(entry) {
+                Ok(Some(msg)) => Some(Ok(msg)),
+                Ok(None) => self.next(),
+                Err(e) => Some(Err(e)),
+            };
+        }
+
+        self.state_pending = true;
+        Some(self.config(entry))
+    }
+}
+
+// serde_json_core has its own heapless version, so go through a byte buffer
+fn to_json<T: serde::Serialize>(val: &T) -> Option<String<MAX_PAYLOAD>> {
+    let mut buf = [0u8; MAX_PAYLOAD];
+    let n = serde_json_core::to_slice(val, &mut buf).ok()?;
+    let mut out = String::new();
+    out.push_str(core::str::from_utf8(&buf[..n]).ok()?).ok()?;
+    Some(out)
+}
@@ -0,0 +1,640 @@
+// MessagePack for codec::MsgPack
+// A small serde serializer/deserializer for MessagePack into and out of a
+// byte slice, no allocation. The layout is what the usual host libraries
+// (msgpack for Python, rmp-serde's to_vec_named, msgpack-lite) read and
+// write, so values can be handed to the host tooling as is:
+//
+// - integers in the shortest form that holds the value (fixint, uint8..64,
+//   int8..64), floats as float32/float64
+// - structs as maps keyed by field name, tuples and sequences as arrays
+// - None and () as nil, Some(v) as v, newtypes as what they wrap
+// - unit enum variants as the variant name, the others as a map of one
+//   entry {variant name: value}
+// - byte slices (serde_bytes) as bin, char as a one char str
+//
+// Reading also takes structs written as arrays (rmp-serde's default to_vec)
+// and any integer width for any integer field that can hold the value.
+// Extension types aren't supported.
+
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum MsgPackError {
+    // Not enough room in the buffer for the encoding
+    BufferFull,
+    // The bytes end in the middle of a value
+    Eof,
+    // A marker that doesn't fit what is read, or an extension type
+    UnexpectedType,
+    // A str that isn't UTF-8
+    BadUtf8,
+    // A sequence or map whose length serde doesn't know up front
+    UnknownLength,
+    // Bytes left after the value
+    TrailingBytes,
+    // A Serialize or Deserialize impl rejected the value
+    Custom,
+}
+
+impl core::fmt::Display for MsgPackError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl ser::StdError for MsgPackError {}
+
+impl ser::Error for MsgPackError {
+    fn custom<T: core::fmt::Display>(_msg: T) -> Self {
+        MsgPackError::Custom
+    }
+}
+
+impl de::Error for MsgPackError {
+    fn custom<T: core::fmt::Display>(_msg: T) -> Self {
+        MsgPackError::Custom
+    }
+}
+
+/// Encode v into dst, returns the length
+pub fn to_slice<T: Serialize + ?Sized>(v: &T, dst: &mut [u8]) -> Result<usize, MsgPackError> {
+    let mut ser = Serializer { buf: dst, pos: 0 };
+    v.serialize(&mut ser)?;
+    Ok(ser.pos)
+}
+
+/// Decode a T that takes up all of src
+pub fn from_slice<'de, T: de::Deserialize<'de>>(src: &'de [u8]) -> Result<T, MsgPackError> {
+    let mut de = Deserializer { src, pos: 0 };
+    let v = T::deserialize(&mut de)?;
+    if de.pos != src.len() {
+        return Err(MsgPackError::TrailingBytes);
+    }
+    Ok(v)
+}
+
+struct Serializer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+}
+
+impl Serializer<'_> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), MsgPackError> {
+        let end = self.pos + bytes.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(MsgPackError::BufferFull)?
+            .copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    fn write_marker(&mut self, marker: u8, len: &[u8]) -> Result<(), MsgPackError> {
+        self.write(&[marker])?;
+        self.write(len)
+    }
+
+    fn write_uint(&mut self, v: u64) -> Result<(), MsgPackError> {
+        match v {
+            0..=0x7F => self.write(&[v as u8]),
+            0x80..=0xFF => self.write_marker(0xCC, &[v as u8]),
+            0x100..=0xFFFF => self.write_marker(0xCD, &(v as u16).to_be_bytes()),
+            0x1_0000..=0xFFFF_FFFF => self.write_marker(0xCE, &(v as u32).to_be_bytes()),
+            _ => self.write_marker(0xCF, &v.to_be_bytes()),
+        }
+    }
+
+    fn write_int(&mut self, v: i64) -> Result<(), MsgPackError> {
+        match v {
+            0.. => self.write_uint(v as u64),
+            -32..=-1 => self.write(&[v as u8]),
+            -0x80..=-33 => self.write_marker(0xD0, &[v as u8]),
+            -0x8000..=-0x81 => self.write_marker(0xD1, &(v as i16).to_be_bytes()),
+            -0x8000_0000..=-0x8001 => self.write_marker(0xD2, &(v as i32).to_be_bytes()),
+            _ => self.write_marker(0xD3, &v.to_be_bytes()),
+        }
+    }
+
+    // Header of a str, bin, array or map: the fix form (if there is one)
+    // and then the 8 (str, bin only), 16 and 32 bit length forms
+    fn write_len(
+        &mut self,
+        len: usize,
+        fix: Option<(u8, usize)>,
+        markers: [u8; 3],
+    ) -> Result<(), MsgPackError> {
+        match fix {
+            Some((base, max)) if len <= max => self.write(&[base | len as u8]),
+            _ if len <= 0xFF && markers[0] != 0 => self.write_marker(markers[0], &[len as u8]),
+            _ if len <= 0xFFFF => self.write_marker(markers[1], &(len as u16).to_be_bytes()),
+            _ => self.write_marker(markers[2], &(len as u32).to_be_bytes()),
+        }
+    }
+
+    fn write_str_len(&mut self, len: usize) -> Result<(), MsgPackError> {
+        self.write_len(len, Some((0xA0, 31)), [0xD9, 0xDA, 0xDB])
+    }
+
+    fn write_array_len(&mut self, len: usize) -> Result<(), MsgPackError> {
+        self.write_len(len, Some((0x90, 15)), [0, 0xDC, 0xDD])
+    }
+
+    fn write_map_len(&mut self, len: usize) -> Result<(), MsgPackError> {
+        self.write_len(len, Some((0x80, 15)), [0, 0xDE, 0xDF])
+    }
+}
+
+// Counts the bytes Display writes, for collect_str
+struct Counter(usize);
+
+impl core::fmt::Write for Counter {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.0 += s.len();
+        Ok(())
+    }
+}
+
+impl core::fmt::Write for Serializer<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write(s.as_bytes()).map_err(|_| core::fmt::Error)
+    }
+}
+
+impl<'a> ser::Serializer for &mut Serializer<'a> {
+    type Ok = ();
+    type Error = MsgPackError;
+    type SerializeSeq = Self;
+    type SerializeTuple = Self;
+    type SerializeTupleStruct = Self;
+    type SerializeTupleVariant = Self;
+    type SerializeMap = Self;
+    type SerializeStruct = Self;
+    type SerializeStructVariant = Self;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<(), MsgPackError> {
+        self.write(&[if v { 0xC3 } else { 0xC2 }])
+    }
+    fn serialize_i8(self, v: i8) -> Result<(), MsgPackError> {
+        self.write_int(v as i64)
+    }
+    fn serialize_i16(self, v: i16) -> Result<(), MsgPackError> {
+        self.write_int(v as i64)
+    }
+    fn serialize_i32(self, v: i32) -> Result<(), MsgPackError> {
+        self.write_int(v as i64)
+    }
+    fn serialize_i64(self, v: i64) -> Result<(), MsgPackError> {
+        self.write_int(v)
+    }
+    fn serialize_u8(self, v: u8) -> Result<(), MsgPackError> {
+        self.write_uint(v as u64)
+    }
+    fn serialize_u16(self, v: u16) -> Result<(), MsgPackError> {
+        self.write_uint(v as u64)
+    }
+    fn serialize_u32(self, v: u32) -> Result<(), MsgPackError> {
+        self.write_uint(v as u64)
+    }
+    fn serialize_u64(self, v: u64) -> Result<(), MsgPackError> {
+        self.write_uint(v)
+    }
+    fn serialize_f32(self, v: f32) -> Result<(), MsgPackError> {
+        self.write_marker(0xCA, &v.to_be_bytes())
+    }
+    fn serialize_f64(self, v: f64) -> Result<(), MsgPackError> {
+        self.write_marker(0xCB, &v.to_be_bytes())
+    }
+    fn serialize_char(self, v: char) -> Result<(), MsgPackError> {
+        self.serialize_str(v.encode_utf8(&mut [0u8; 4]))
+    }
+    fn serialize_str(self, v: &str) -> Result<(), MsgPackError> {
+        self.write_str_len(v.len())?;
+        self.write(v.as_bytes())
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), MsgPackError> {
+        self.write_len(v.len(), None, [0xC4, 0xC5, 0xC6])?;
+        self.write(v)
+    }
+    fn serialize_none(self) -> Result<(), MsgPackError> {
+        self.write(&[0xC0])
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, v: &T) -> Result<(), MsgPackError> {
+        v.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<(), MsgPackError> {
+        self.write(&[0xC0])
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), MsgPackError> {
+        self.serialize_unit()
+    }
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+    ) -> Result<(), MsgPackError> {
+        self.serialize_str(variant)
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        v: &T,
+    ) -> Result<(), MsgPackError> {
+        v.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        v: &T,
+    ) -> Result<(), MsgPackError> {
+        self.write_map_len(1)?;
+        self.serialize_str(variant)?;
+        v.serialize(self)
+    }
+    fn serialize_seq(self, len: Option<usize>) -> Result<Self, MsgPackError> {
+        self.write_array_len(len.ok_or(MsgPackError::UnknownLength)?)?;
+        Ok(self)
+    }
+    fn serialize_tuple(self, len: usize) -> Result<Self, MsgPackError> {
+        self.write_array_len(len)?;
+        Ok(self)
+    }
+    fn serialize_tuple_struct(self, _: &'static str, len: usize) -> Result<Self, MsgPackError> {
+        self.write_array_len(len)?;
+        Ok(self)
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self, MsgPackError> {
+        self.write_map_len(1)?;
+        self.serialize_str(variant)?;
+        self.write_array_len(len)?;
+        Ok(self)
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<Self, MsgPackError> {
+        self.write_map_len(len.ok_or(MsgPackError::UnknownLength)?)?;
+        Ok(self)
+    }
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<Self, MsgPackError> {
+        self.write_map_len(len)?;
+        Ok(self)
+    }
+    fn serialize_struct_variant(
+        self,
+        _: &'static str,
+        _: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Self, MsgPackError> {
+        self.write_map_len(1)?;
+        self.serialize_str(variant)?;
+        self.write_map_len(len)?;
+        Ok(self)
+    }
+    fn collect_str<T: core::fmt::Display + ?Sized>(self, v: &T) -> Result<(), MsgPackError> {
+        use core::fmt::Write;
+        let mut counter = Counter(0);
+        let _ = write!(counter, "{}", v);
+        self.write_str_len(counter.0)?;
+        write!(self, "{}", v).map_err(|_| MsgPackError::BufferFull)
+    }
+}
+
+impl ser::SerializeSeq for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = MsgPackError;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MsgPackError> {
+        v.serialize(&mut **self)
+    }
+    fn end(self) -> Result<(), MsgPackError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTuple for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = MsgPackError;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MsgPackError> {
+        v.serialize(&mut **self)
+    }
+    fn end(self) -> Result<(), MsgPackError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleStruct for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = MsgPackError;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MsgPackError> {
+        v.serialize(&mut **self)
+    }
+    fn end(self) -> Result<(), MsgPackError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeTupleVariant for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = MsgPackError;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MsgPackError> {
+        v.serialize(&mut **self)
+    }
+    fn end(self) -> Result<(), MsgPackError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeMap for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = MsgPackError;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, k: &T) -> Result<(), MsgPackError> {
+        k.serialize(&mut **self)
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), MsgPackError> {
+        v.serialize(&mut **self)
+    }
+    fn end(self) -> Result<(), MsgPackError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStruct for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = MsgPackError;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        v: &T,
+    ) -> Result<(), MsgPackError> {
+        ser::Serializer::serialize_str(&mut **self, key)?;
+        v.serialize(&mut **self)
+    }
+    fn end(self) -> Result<(), MsgPackError> {
+        Ok(())
+    }
+}
+
+impl ser::SerializeStructVariant for &mut Serializer<'_> {
+    type Ok = ();
+    type Error = MsgPackError;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        key: &'static str,
+        v: &T,
+    ) -> Result<(), MsgPackError> {
+        ser::Serializer::serialize_str(&mut **self, key)?;
+        v.serialize(&mut **self)
+    }
+    fn end(self) -> Result<(), MsgPackError> {
+        Ok(())
+    }
+}
+
+struct Deserializer<'de> {
+    src: &'de [u8],
+    pos: usize,
+}
+
+impl<'de> Deserializer<'de> {
+    fn peek(&self) -> Result<u8, MsgPackError> {
+        self.src.get(self.pos).copied().ok_or(MsgPackError::Eof)
+    }
+
+    fn take(&mut self, n: usize) -> Result<&'de [u8], MsgPackError> {
+        // n comes from the input, a bin32 length can overflow pos
+        let end = self.pos.checked_add(n).ok_or(MsgPackError::Eof)?;
+        let bytes = self.src.get(self.pos..end).ok_or(MsgPackError::Eof)?;
+        self.pos = end;
+        Ok(bytes)
+    }
+
+    fn take_array<const L: usize>(&mut self) -> Result<[u8; L], MsgPackError> {
+        let mut out = [0u8; L];
+        out.copy_from_slice(self.take(L)?);
+        Ok(out)
+    }
+
+    // Big endian length of 1, 2 or 4 bytes
+    fn take_len(&mut self, width: usize) -> Result<usize, MsgPackError> {
+        Ok(match width {
+            1 => self.take_array::<1>()?[0] as usize,
+            2 => u16::from_be_bytes(self.take_array()?) as usize,
+            _ => u32::from_be_bytes(self.take_array()?) as usize,
+        })
+    }
+
+    fn take_str(&mut self, len: usize) -> Result<&'de str, MsgPackError> {
+        core::str::from_utf8(self.take(len)?).map_err(|_| MsgPackError::BadUtf8)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for &mut Deserializer<'de> {
+    type Error = MsgPackError;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MsgPackError> {
+        let marker = self.take_array::<1>()?[0];
+        match marker {
+            0x00..=0x7F => visitor.visit_u64(marker as u64),
+            0x80..=0x8F => visitor.visit_map(Entries::new(self, (marker & 0x0F) as usize)),
+            0x90..=0x9F => visitor.visit_seq(Entries::new(self, (marker & 0x0F) as usize)),
+            0xA0..=0xBF => visitor.visit_borrowed_str(self.take_str((marker & 0x1F) as usize)?),
+            0xC0 => visitor.visit_unit(),
+            0xC2 => visitor.visit_bool(false),
+            0xC3 => visitor.visit_bool(true),
+            0xC4..=0xC6 => {
+                let len = self.take_len(1 << (marker - 0xC4))?;
+                visitor.visit_borrowed_bytes(self.take(len)?)
+            }
+            0xCA => visitor.visit_f32(f32::from_be_bytes(self.take_array()?)),
+            0xCB => visitor.visit_f64(f64::from_be_bytes(self.take_array()?)),
+            0xCC => visitor.visit_u64(self.take_array::<1>()?[0] as u64),
+            0xCD => visitor.visit_u64(u16::from_be_bytes(self.take_array()?) as u64),
+            0xCE => visitor.visit_u64(u32::from_be_bytes(self.take_array()?) as u64),
+            0xCF => visitor.visit_u64(u64::from_be_bytes(self.take_array()?)),
+            0xD0 => visitor.visit_i64(self.take_array::<1>()?[0] as i8 as i64),
+            0xD1 => visitor.visit_i64(i16::from_be_bytes(self.take_array()?) as i64),
+            0xD2 => visitor.visit_i64(i32::from_be_bytes(self.take_array()?) as i64),
+            0xD3 => visitor.visit_i64(i64::from_be_bytes(self.take_array()?)),
+            0xD9..=0xDB => {
+                let len = self.take_len(1 << (marker - 0xD9))?;
+                visitor.visit_borrowed_str(self.take_str(len)?)
+            }
+            0xDC | 0xDD => {
+                let len = self.take_len(2 << (marker - 0xDC))?;
+                visitor.visit_seq(Entries::new(self, len))
+            }
+            0xDE | 0xDF => {
+                let len = self.take_len(2 << (marker - 0xDE))?;
+                visitor.visit_map(Entries::new(self, len))
+            }
+            0xE0..=0xFF => visitor.visit_i64(marker as i8 as i64),
+            // 0xC1 is never used, the rest are extension types
+            _ => Err(MsgPackError::UnexpectedType),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, MsgPackError> {
+        if self.peek()? == 0xC0 {
+            self.pos += 1;
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, MsgPackError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MsgPackError> {
+        match self.peek()? {
+            // A unit variant, just the name
+            0xA0..=0xBF | 0xD9..=0xDB => {
+                let name: &'de str = de::Deserialize::deserialize(&mut *self)?;
+                visitor.visit_enum(name.into_deserializer())
+            }
+            // {variant: value}
+            0x81 => {
+                self.pos += 1;
+                visitor.visit_enum(Variant { de: self })
+            }
+            _ => Err(MsgPackError::UnexpectedType),
+        }
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct seq tuple tuple_struct map struct
+        identifier ignored_any
+    }
+}
+
+// The elements of an array or the entries of a map
+struct Entries<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+    left: usize,
+}
+
+impl<'a, 'de> Entries<'a, 'de> {
+    fn new(de: &'a mut Deserializer<'de>, left: usize) -> Self {
+        Self { de, left }
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for Entries<'_, 'de> {
+    type Error = MsgPackError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, MsgPackError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+impl<'de> de::MapAccess<'de> for Entries<'_, 'de> {
+    type Error = MsgPackError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, MsgPackError> {
+        if self.left == 0 {
+            return Ok(None);
+        }
+        self.left -= 1;
+        seed.deserialize(&mut *self.de).map(Some)
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<T::Value, MsgPackError> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.left)
+    }
+}
+
+// An enum variant written as {name: value}
+struct Variant<'a, 'de> {
+    de: &'a mut Deserializer<'de>,
+}
+
+impl<'a, 'de> de::EnumAccess<'de> for Variant<'a, 'de> {
+    type Error = MsgPackError;
+    type Variant = Self;
+
+    fn variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<(T::Value, Self), MsgPackError> {
+        let name = seed.deserialize(&mut *self.de)?;
+        Ok((name, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Variant<'_, 'de> {
+    type Error = MsgPackError;
+
+    fn unit_variant(self) -> Result<(), MsgPackError> {
+        de::Deserialize::deserialize(&mut *self.de)
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(
+        self,
+        seed: T,
+    ) -> Result<T::Value, MsgPackError> {
+        seed.deserialize(&mut *self.de)
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(
+        self,
+        _: usize,
+        visitor: V,
+    ) -> Result<V::Value, MsgPackError> {
+        de::Deserializer::deserialize_any(&mut *self.de, visitor)
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, MsgPackError> {
+        de::Deserializer::deserialize_any(&mut *self.de, visitor)
+    }
+}
@@ -0,0 +1,131 @@
+// Namespaced string keys
+// A namespace is just a key prefix: "<namespace>:<name>". Databases keyed by
+// heapless::String can keep unrelated subsystems (translations, flags, ...)
+// apart without a separate store for each one.
+//
+// let key: String<32> = namespace::key("l10n", "de:greeting")?;
+// assert_eq!(namespace::strip(&key, "l10n"), Some("de:greeting"));
+//
+// db.namespace("config") gives a handle that does this for every call.
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::Database;
+use crate::kv::BlobStore;
+use core::fmt::Write;
+use heapless::String;
+
+pub const SEPARATOR: char = ':';
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum NamespaceError {
+    // Namespace plus name don't fit in the key type
+    TooLong,
+    // Namespaces can't be empty or contain the separator
+    BadNamespace,
+    // The database refused the operation (full, codec error)
+    Storage,
+}
+
+/// Build "<ns>:<name>"
+/// The name may contain the separator, only the namespace may not.
+pub fn key<const L: usize>(ns: &str, name: &str) -> Result<String<L>, NamespaceError> {
+    if ns.is_empty() || ns.contains(SEPARATOR) {
+        return Err(NamespaceError::BadNamespace);
+    }
+    let mut key = String::new();
+    write!(key, "{}{}{}", ns, SEPARATOR, name).map_err(|_| NamespaceError::TooLong)?;
+    Ok(key)
+}
+
+/// Split a key into (namespace, name), None for keys without a namespace
+pub fn split(key: &str) -> Option<(&str, &str)> {
+    key.split_once(SEPARATOR)
+}
+
+/// The name part of key if it is in ns
+pub fn strip<'a>(key: &'a str, ns: &str) -> Option<&'a str> {
+    match split(key) {
+        Some((key_ns, name)) if key_ns == ns => Some(name),
+        _ => None,
+    }
+}
+
+/// One namespace of a database, the handle adds and strips the prefix
+/// Keys used through the handle are just the names, so e.g. "config" and
+/// "cal" can both have a "gain" without colliding in the flash image.
+///
+/// let mut config = db.namespace("config")?;
+/// config.put("gain", 12)?;
+/// let gain = config.get("gain")?;
+pub struct Namespace<
+    'a,
+    V,
+    C,
+    S,
+    CP,
+    const L: usize,
+    const N: usize,
+    const B: usize,
+    const CACH: usize,
+> where
+    C: Codec<V>,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    db: &'a mut Database<String<L>, V, C, N, B, CACH, S, CP>,
+    ns: &'a str,
+}
+
+impl<V, C, S, CP, const L: usize, const N: usize, const B: usize, const CACH: usize>
+    Database<String<L>, V, C, N, B, CACH, S, CP>
+where
+    C: Codec<V>,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<String<L>>,
+    CP: CachePolicy<String<L>>,
+{
+    /// Handle for the keys in namespace ns
+    pub fn namespace<'a>(
+        &'a mut self,
+        ns: &'a str,
+    ) -> Result<Namespace<'a, V, C, S, CP, L, N, B, CACH>, NamespaceError> {
+        if ns.is_empty() || ns.contains(SEPARATOR) {
+            return Err(NamespaceError::BadNamespace);
+        }
+        Ok(Namespace { db: self, ns })
+    }
+}
+
+impl<V, C, S, CP, const L: usize, const N: usize, const B: usize, const CACH: usize>
+    Namespace<'_, V, C, S, CP, L, N, B, CACH>
+where
+    C: Codec<V>,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<String<L>>,
+    CP: CachePolicy<String<L>>,
+{
+    pub fn put(&mut self, name: &str, val: V) -> Result<(), NamespaceError> {
+        let key = key(self.ns, name)?;
+        self.db.put(key, val).map_err(|_| NamespaceError::Storage)
+    }
+
+    pub fn get(&mut self, name: &str) -> Result<Option<V>, NamespaceError> {
+        let key = key(self.ns, name)?;
+        self.db.get(&key).map_err(|_| NamespaceError::Storage)
+    }
+
+    pub fn delete(&mut self, name: &str) -> Result<bool, NamespaceError> {
+        let key = key(self.ns, name)?;
+        Ok(self.db.delete(&key))
+    }
+
+    pub fn contains_key(&self, name: &str) -> Result<bool, NamespaceError> {
+        let key = key(self.ns, name)?;
+        Ok(self.db.contains_key(&key))
+    }
+
+    /// Names (without the prefix) of the keys in this namespace
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.db.keys().filter_map(|key| strip(key, self.ns))
+    }
+}
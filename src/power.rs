@@ -0,0 +1,289 @@
+// Low-power persistence
+// On a coin cell the flash is one of the biggest loads: a page erase on the
+// nRF52840 draws ~7.5mA for 85ms. save_to_flash erases and programs the
+// whole image in one go, LowPowerSaver spreads the same work out instead:
+//
+// - it alternates between two slots, and erases the stale one ahead of
+//   time (one page per idle wake), so a save usually only programs
+// - every step() does at most one page erase or one page program, so a
+//   wake never draws more than that from the cell
+// - it adds up the charge all of that took, from an EnergyModel of the
+//   flash, and EnergyModel::save_charge_nc estimates a save up front
+//
+// let mut saver: LowPowerSaver<8192> = LowPowerSaver::new([SLOT_A, SLOT_B], 4096, NRF52840);
+// saver.open(&mut db, &mut flash)?;            // loads the newer of the two
+// loop {
+//     cortex_m::asm::wfi();
+//     if db.needs_persist() && saver.is_idle() {
+//         saver.begin(&mut db)?;
+//     }
+//     saver.step(&mut db, &mut flash)?;
+// }
+//
+// The slot order is the sequence number in the image footer (image.rs).
+// Until the last page of a save is programmed, the previous image in the
+// other slot stays the one open() picks.
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::{Database, FlashError};
+use crate::emergency::Partition;
+use crate::image::{self, Footer, ImageHeader, FOOTER_SIZE, NO_SEQUENCE};
+use crate::kv::BlobStore;
+use embedded_storage::nor_flash::NorFlash;
+
+/// Charge the flash takes per operation, in nanocoulombs
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct EnergyModel {
+    pub erase_page_nc: u32,
+    /// Programming one write unit (word)
+    pub program_word_nc: u32,
+    pub word_size: usize,
+}
+
+/// nRF52840 internal flash, typical values from the datasheet
+/// 7.5mA for tERASEPAGE (85ms) and for tWRITE (41us per word)
+pub const NRF52840: EnergyModel = EnergyModel {
+    erase_page_nc: 637_500,
+    program_word_nc: 308,
+    word_size: 4,
+};
+
+impl EnergyModel {
+    /// Charge to save an image of image_len bytes
+    /// pre_erased: the target was erased ahead of time (LowPowerSaver)
+    pub fn save_charge_nc(&self, image_len: usize, erase_size: usize, pre_erased: bool) -> u64 {
+        let pages = image::region_len(erase_size) / erase_size;
+        let words = image_len.div_ceil(self.word_size) + FOOTER_SIZE / self.word_size;
+        let erase = if pre_erased {
+            0
+        } else {
+            pages as u64 * self.erase_page_nc as u64
+        };
+        erase + words as u64 * self.program_word_nc as u64
+    }
+}
+
+/// What one LowPowerSaver::step did
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum PowerStep {
+    Idle,
+    Erased,
+    Programmed,
+    // The last page of a save is written, it is the current image now
+    Committed,
+}
+
+// A save in progress, the image is in the saver's buffer
+#[derive(Debug, Clone, Copy)]
+struct Pending {
+    len: usize,
+    programmed: usize,
+    generation: u32,
+}
+
+/// See the top of the file
+/// L is the buffer for one image region (image::region_len, footer included).
+pub struct LowPowerSaver<const L: usize> {
+    slots: [Partition; 2],
+    erase_size: usize,
+    model: EnergyModel,
+    // Slot the next save goes to, and how many of its pages are erased
+    target: usize,
+    erased_pages: usize,
+    sequence: u32,
+    pending: Option<Pending>,
+    charge_nc: u64,
+    buffer: [u8; L],
+}
+
+impl<const L: usize> LowPowerSaver<L> {
+    /// Both slots need room for an image region (image::region_len)
+    pub const fn new(slots: [Partition; 2], erase_size: usize, model: EnergyModel) -> Self {
+        Self {
+            slots,
+            erase_size,
+            model,
+            target: 0,
+            erased_pages: 0,
+            sequence: 0,
+            pending: None,
+            charge_nc: 0,
+            buffer: [0; L],
+        }
+    }
+
+    /// Load the newer of the two slots into db
+    /// Returns Ok(None) if neither holds an image.
+    pub fn open<K, V, C, S, CP, F, const N: usize, const B: usize, const CACH: usize>(
+        &mut self,
+        db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+        flash: &mut F,
+    ) -> Result<Option<ImageHeader>, FlashError>
+    where
+        C: Codec<V>,
+        K: Eq + Clone + serde::de::DeserializeOwned,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+        F: NorFlash,
+    {
+        let a = self.slot_sequence(flash, 0)?;
+        let b = self.slot_sequence(flash, 1)?;
+        let newest = match (a, b) {
+            (Some(a), Some(b)) if b > a => Some((1, b)),
+            (Some(a), _) => Some((0, a)),
+            (None, Some(b)) => Some((1, b)),
+            (None, None) => None,
+        };
+
+        // Whatever is in the other slot gets erased before the next save
+        self.erased_pages = 0;
+        self.pending = None;
+        match newest {
+            Some((slot, sequence)) => {
+                self.target = 1 - slot;
+                self.sequence = sequence;
+                db.open(flash, self.slots[slot].offset)
+            }
+            None => {
+                self.target = 0;
+                self.sequence = 0;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Take a snapshot of db to save over the next steps
+    /// Changes to db after this go into the save after.
+    pub fn begin<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &mut self,
+        db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+    ) -> Result<(), FlashError>
+    where
+        C: Codec<V>,
+        K: Eq + Clone + serde::Serialize,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+    {
+        db.flush()?;
+        let offset = self.slots[self.target].offset;
+        let len = db
+            .to_artifact(offset, self.erase_size, &mut self.buffer)?
+            .bytes
+            .len();
+        if len > self.slots[self.target].len as usize {
+            return Err(FlashError::DatabaseFull);
+        }
+
+        let footer_at = len - FOOTER_SIZE;
+        let mut footer = Footer::from_bytes(&self.buffer[footer_at..len])?
+            .ok_or(FlashError::SerializationError)?;
+        footer.sequence = self.sequence.wrapping_add(1);
+        self.buffer[footer_at..len].copy_from_slice(&footer.to_bytes());
+
+        self.pending = Some(Pending {
+            len,
+            programmed: 0,
+            generation: db.generation(),
+        });
+        Ok(())
+    }
+
+    /// Do at most one page erase or page program
+    /// Call on every wake up, with nothing to save it erases the stale slot.
+    pub fn step<K, V, C, S, CP, F, const N: usize, const B: usize, const CACH: usize>(
+        &mut self,
+        db: &mut Database<K, V, C, N, B, CACH, S, CP>,
+        flash: &mut F,
+    ) -> Result<PowerStep, FlashError>
+    where
+        C: Codec<V>,
+        K: Eq + Clone,
+        V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+        F: NorFlash,
+    {
+        let slot = self.slots[self.target];
+        let slot_pages = slot.len as usize / self.erase_size;
+        let needed = match self.pending {
+            Some(pending) => pending.len / self.erase_size,
+            None => slot_pages,
+        };
+        if self.erased_pages < needed {
+            let from = slot.offset + (self.erased_pages * self.erase_size) as u32;
+            flash
+                .erase(from, from + self.erase_size as u32)
+                .map_err(|_| FlashError::EraseError)?;
+            self.erased_pages += 1;
+            self.charge_nc += self.model.erase_page_nc as u64;
+            return Ok(PowerStep::Erased);
+        }
+
+        let mut pending = match self.pending {
+            Some(pending) => pending,
+            None => return Ok(PowerStep::Idle),
+        };
+        let page = &self.buffer[pending.programmed..pending.programmed + self.erase_size];
+        // Erased flash already reads 0xFF, padding pages cost nothing
+        if page.iter().any(|b| *b != 0xFF) {
+            flash
+                .write(slot.offset + pending.programmed as u32, page)
+                .map_err(|_| FlashError::WriteError)?;
+            let words = (self.erase_size / self.model.word_size) as u64;
+            self.charge_nc += words * self.model.program_word_nc as u64;
+        }
+        pending.programmed += self.erase_size;
+
+        if pending.programmed < pending.len {
+            self.pending = Some(pending);
+            return Ok(PowerStep::Programmed);
+        }
+
+        db.mark_persisted(slot.offset, pending.generation);
+        self.sequence = self.sequence.wrapping_add(1);
+        self.target = 1 - self.target;
+        self.erased_pages = 0;
+        self.pending = None;
+        Ok(PowerStep::Committed)
+    }
+
+    /// No save in progress
+    pub fn is_idle(&self) -> bool {
+        self.pending.is_none()
+    }
+
+    /// Charge spent on erasing and programming so far, in nanocoulombs
+    pub fn charge_used_nc(&self) -> u64 {
+        self.charge_nc
+    }
+
+    // Sequence number of a valid image in slot, None if there is none
+    fn slot_sequence<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        slot: usize,
+    ) -> Result<Option<u32>, FlashError> {
+        let offset = self.slots[slot].offset;
+        match image::verify(flash, offset) {
+            Ok(Some(_)) => {}
+            // A torn or corrupt slot is as good as an empty one
+            Ok(None) | Err(FlashError::CrcMismatch) | Err(FlashError::BadHeader) => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e),
+        }
+        let mut bytes = [0u8; FOOTER_SIZE];
+        let at = image::region_len(self.erase_size) - FOOTER_SIZE;
+        flash
+            .read(offset + at as u32, &mut bytes)
+            .map_err(|_| FlashError::ReadError)?;
+        Ok(match Footer::from_bytes(&bytes)? {
+            Some(footer) if footer.sequence != NO_SEQUENCE => Some(footer.sequence),
+            // Written by a plain save, older than anything of ours
+            _ => Some(0),
+        })
+    }
+}
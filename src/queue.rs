@@ -0,0 +1,159 @@
+// Persistent ring buffer for telemetry
+// Samples that have to wait for the radio go into a Queue: enqueue() at the
+// back, dequeue() from the front, and once N items are waiting the oldest
+// is dropped for the new one. It is a Database underneath, keyed by a
+// running sequence number, so items go through the same codec and are
+// saved in the same image format (with the compact u32 keys, keycodec.rs).
+//
+// let mut samples: Queue<Sample, 64> = Queue::new();
+// samples.load_from_flash(&mut flash, QUEUE_ADDR)?;     // after a reboot
+// samples.enqueue(sample)?;
+// while let Some(sample) = samples.peek()? {
+//     if radio.send(&sample).is_err() { break; }
+//     samples.dequeue()?;
+// }
+// samples.save_to_flash(&mut flash, QUEUE_ADDR)?;
+//
+// Also available as db::Queue.
+
+use crate::codec::{Codec, Postcard};
+#[cfg(feature = "persistence")]
+use crate::db::FlashError;
+use crate::db::{Database, DbError};
+#[cfg(feature = "persistence")]
+use crate::image::ImageHeader;
+#[cfg(feature = "persistence")]
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+/// Up to N items of T, each at most B bytes encoded
+pub struct Queue<T, const N: usize, C = Postcard, const B: usize = 64>
+where
+    C: Codec<T>,
+    T: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    // Only one item is read at a time, one cache slot is plenty
+    db: Database<u32, T, C, N, B, 1>,
+    // Sequence numbers of the oldest item and of the next one enqueued
+    head: u32,
+    tail: u32,
+}
+
+impl<T, C, const N: usize, const B: usize> Default for Queue<T, N, C, B>
+where
+    C: Codec<T>,
+    T: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<T, C, const N: usize, const B: usize> Queue<T, N, C, B>
+where
+    C: Codec<T>,
+    T: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    pub const fn new() -> Self {
+        Self {
+            db: Database::new(),
+            head: 0,
+            tail: 0,
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.tail.wrapping_sub(self.head) as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.head == self.tail
+    }
+
+    pub fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Add item at the back
+    /// Returns the oldest item if it was dropped to make room (None if that
+    /// one didn't decode). An item that doesn't encode drops nothing.
+    pub fn enqueue(&mut self, item: T) -> Result<Option<T>, DbError<C::Error>> {
+        // Encode it once up front, put() can't fail after that
+        let mut tmp = [0u8; B];
+        C::encode(&mut tmp, &item).map_err(DbError::Encode)?;
+        let dropped = if self.len() >= N {
+            self.dequeue().ok().flatten()
+        } else {
+            None
+        };
+        self.db.put(self.tail, item)?;
+        self.tail = self.tail.wrapping_add(1);
+        Ok(dropped)
+    }
+
+    /// Take the oldest item
+    /// An item that doesn't decode is returned as the error and dropped all
+    /// the same, the next call gets the one behind it.
+    pub fn dequeue(&mut self) -> Result<Option<T>, DbError<C::Error>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        let item = self.peek();
+        self.db.delete(&self.head);
+        self.head = self.head.wrapping_add(1);
+        item
+    }
+
+    /// The oldest item, left in the queue
+    pub fn peek(&self) -> Result<Option<T>, DbError<C::Error>> {
+        if self.is_empty() {
+            return Ok(None);
+        }
+        self.db.get_uncached(&self.head)
+    }
+
+    /// Drop every item
+    pub fn clear(&mut self) {
+        self.db.clear();
+        self.head = 0;
+        self.tail = 0;
+    }
+
+    /// Whether anything was enqueued or dequeued since the last save or load
+    #[cfg(feature = "persistence")]
+    pub fn needs_persist(&self) -> bool {
+        self.db.needs_persist()
+    }
+
+    /// Save the items as an image, see Database::save_to_flash
+    #[cfg(feature = "persistence")]
+    pub fn save_to_flash<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        flash_offset: u32,
+    ) -> Result<(), FlashError> {
+        self.db
+            .save_to_flash(flash, core::mem::size_of::<u32>(), flash_offset)
+    }
+
+    /// Load what save_to_flash wrote, Ok(None) if the flash is erased
+    #[cfg(feature = "persistence")]
+    pub fn load_from_flash<F: ReadNorFlash>(
+        &mut self,
+        flash: &mut F,
+        flash_offset: u32,
+    ) -> Result<Option<ImageHeader>, FlashError> {
+        let header = self.db.open(flash, flash_offset)?;
+        // The items are a run of sequence numbers, find where it starts.
+        // The run may wrap around u32::MAX, then it starts at the key whose
+        // predecessor is missing.
+        let head = self
+            .db
+            .keys()
+            .find(|seq| !self.db.contains_key(&seq.wrapping_sub(1)))
+            .copied()
+            .unwrap_or(0);
+        self.head = head;
+        self.tail = head.wrapping_add(self.db.len() as u32);
+        Ok(header)
+    }
+}
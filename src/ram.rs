@@ -0,0 +1,194 @@
+// RAM-only database
+// Bootloaders and host-side test crates often just want the key/value and
+// codec layers. A RamDb is a Database with only those methods: nothing
+// that takes a flash (save, load, open, export...) can be called on it.
+// The methods it does have are the Database ones, same names and errors,
+// so code written against a RamDb moves to a Database as is.
+//
+// let mut cfg: RamDb<u8, u32, Postcard, 8, 8, 2> = RamDb::new();
+// cfg.put(KEY_BOOT_SLOT, 1)?;
+// let slot = cfg.get(&KEY_BOOT_SLOT)?;
+//
+// Built with --no-default-features (no persistence tier) the Database has
+// no flash methods at all and image.rs isn't compiled, so a RamDb binary
+// can't pull any of it in. CI builds bin/ram_size.rs that way and fails if
+// it outgrows its flash budget, see .github/workflows/size.yml.
+
+use crate::cache::{CachePolicy, Lru};
+use crate::codec::{BorrowedCodec, Codec, FieldCodec, FieldError};
+use crate::db::{Database, DbError, Entry, Stats, Transaction, TxnError};
+use crate::kv::{BlobStore, KvStore};
+use heapless::Vec;
+
+// The store a RamDb keeps its values in, the Database default
+type Store<K, const N: usize, const B: usize> = KvStore<K, Vec<u8, B>, N>;
+
+/// Database without persistence, see the top of the file
+pub struct RamDb<
+    K,
+    V,
+    C,
+    const N: usize,
+    const B: usize,
+    const CACH: usize,
+    S = Store<K, N, B>,
+    CP = Lru<K, CACH>,
+> where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    db: Database<K, V, C, N, B, CACH, S, CP>,
+}
+
+impl<K, V, C, const N: usize, const B: usize, const CACH: usize> Default
+    for RamDb<K, V, C, N, B, CACH>
+where
+    C: Codec<V>,
+    K: Eq + core::hash::Hash + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, C, const N: usize, const B: usize, const CACH: usize> RamDb<K, V, C, N, B, CACH>
+where
+    C: Codec<V>,
+    K: Eq + core::hash::Hash + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+{
+    pub const fn new() -> Self {
+        Self {
+            db: Database::new(),
+        }
+    }
+}
+
+impl<K, V, C, S, const N: usize, const B: usize, const CACH: usize> RamDb<K, V, C, N, B, CACH, S>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+{
+    /// RamDb on top of a specific store, e.g. SortedStore::new()
+    pub const fn with_store(store: S) -> Self {
+        Self {
+            db: Database::with_store(store),
+        }
+    }
+}
+
+impl<K, V, C, S, CP, const N: usize, const B: usize, const CACH: usize>
+    RamDb<K, V, C, N, B, CACH, S, CP>
+where
+    C: Codec<V>,
+    K: Eq + Clone,
+    V: serde::Serialize + serde::de::DeserializeOwned + Clone,
+    S: BlobStore<K>,
+    CP: CachePolicy<K>,
+{
+    pub fn get(&mut self, key: &K) -> Result<Option<V>, DbError<C::Error>> {
+        self.db.get(key)
+    }
+
+    pub fn get_many(&mut self, keys: &[K], out: &mut [Option<V>]) -> Result<(), DbError<C::Error>> {
+        self.db.get_many(keys, out)
+    }
+
+    pub fn get_uncached(&self, key: &K) -> Result<Option<V>, DbError<C::Error>> {
+        self.db.get_uncached(key)
+    }
+
+    pub fn get_with<'s, T, R>(
+        &'s mut self,
+        key: &K,
+        f: impl FnOnce(T) -> R,
+    ) -> Result<Option<R>, DbError<<C as Codec<V>>::Error>>
+    where
+        C: BorrowedCodec<'s, T, Error = <C as Codec<V>>::Error>,
+    {
+        self.db.get_with(key, f)
+    }
+
+    pub fn put(&mut self, key: K, val: V) -> Result<(), DbError<C::Error>> {
+        self.db.put(key, val)
+    }
+
+    pub fn put_with_ttl(
+        &mut self,
+        key: K,
+        val: V,
+        ttl_ticks: u32,
+    ) -> Result<(), DbError<C::Error>> {
+        self.db.put_with_ttl(key, val, ttl_ticks)
+    }
+
+    pub fn tick(&mut self, elapsed: u32) -> usize {
+        self.db.tick(elapsed)
+    }
+
+    pub fn update_field(&mut self, key: &K, field: &str, value: &[u8]) -> Result<bool, FieldError>
+    where
+        C: FieldCodec<V>,
+    {
+        self.db.update_field(key, field, value)
+    }
+
+    #[allow(clippy::type_complexity)]
+    pub fn entry(
+        &mut self,
+        key: K,
+    ) -> Result<Entry<'_, K, V, C, N, B, CACH, S, CP>, DbError<C::Error>> {
+        self.db.entry(key)
+    }
+
+    pub fn transaction<R, T>(&mut self, f: T) -> Result<R, TxnError>
+    where
+        T: FnOnce(&mut Transaction<'_, K, V, C, N, B, CACH, S, CP>) -> Result<R, TxnError>,
+    {
+        self.db.transaction(f)
+    }
+
+    pub fn delete(&mut self, key: &K) -> bool {
+        self.db.delete(key)
+    }
+
+    pub fn rename(&mut self, old_key: &K, new_key: K) -> Result<bool, DbError<C::Error>> {
+        self.db.rename(old_key, new_key)
+    }
+
+    pub fn contains_key(&self, key: &K) -> bool {
+        self.db.contains_key(key)
+    }
+
+    pub fn clear(&mut self) {
+        self.db.clear()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, Result<V, C::Error>)> {
+        self.db.iter()
+    }
+
+    pub fn keys(&self) -> impl Iterator<Item = &K> {
+        self.db.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.db.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.db.len() == 0
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.db.capacity()
+    }
+
+    pub fn stats(&self) -> Stats {
+        self.db.stats()
+    }
+}
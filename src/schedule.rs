@@ -0,0 +1,114 @@
+// Weekly on/off schedule
+// One bit per half hour, 48 bits per day, 42 bytes for the week. Fixed size
+// arrays serialize without a length prefix, so Postcard stores it as is.
+//
+// let mut heating = Schedule::new();
+// heating.set_window(Weekday::Mon, 6 * 60, 8 * 60 + 30, true)?;
+// heating.set_window(Weekday::Sat, 9 * 60, 23 * 60, true)?;
+// db.put(KEY_HEATING, heating)?;
+// if heating.is_active(Weekday::Mon, 7 * 60) { ... }
+
+/// Minutes per schedule slot
+pub const SLOT_MINUTES: u16 = 30;
+pub const SLOTS_PER_DAY: usize = 24 * 60 / SLOT_MINUTES as usize;
+const MINUTES_PER_DAY: u16 = 24 * 60;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Weekday {
+    Mon,
+    Tue,
+    Wed,
+    Thu,
+    Fri,
+    Sat,
+    Sun,
+}
+
+impl Weekday {
+    const ALL: [Weekday; 7] = [
+        Weekday::Mon,
+        Weekday::Tue,
+        Weekday::Wed,
+        Weekday::Thu,
+        Weekday::Fri,
+        Weekday::Sat,
+        Weekday::Sun,
+    ];
+
+    /// Weekday of a unix timestamp (seconds, already shifted to local time)
+    pub fn from_unix(t: u64) -> Self {
+        // 1970-01-01 was a Thursday
+        Self::ALL[((t / 86_400 + 3) % 7) as usize]
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum ScheduleError {
+    // Window times have to be on a slot boundary
+    Unaligned,
+    // start >= end, or end past midnight
+    BadWindow,
+}
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, Default, defmt::Format, serde::Serialize, serde::Deserialize,
+)]
+pub struct Schedule {
+    days: [[u8; SLOTS_PER_DAY / 8]; 7],
+}
+
+impl Schedule {
+    /// Off all week
+    pub const fn new() -> Self {
+        Self {
+            days: [[0; SLOTS_PER_DAY / 8]; 7],
+        }
+    }
+
+    /// Turn start..end (minutes since midnight) on or off for one day
+    /// Windows over midnight have to be split into two calls.
+    pub fn set_window(
+        &mut self,
+        day: Weekday,
+        start: u16,
+        end: u16,
+        on: bool,
+    ) -> Result<(), ScheduleError> {
+        if !start.is_multiple_of(SLOT_MINUTES) || !end.is_multiple_of(SLOT_MINUTES) {
+            return Err(ScheduleError::Unaligned);
+        }
+        if start >= end || end > MINUTES_PER_DAY {
+            return Err(ScheduleError::BadWindow);
+        }
+
+        let bits = &mut self.days[day as usize];
+        for slot in (start / SLOT_MINUTES) as usize..(end / SLOT_MINUTES) as usize {
+            if on {
+                bits[slot / 8] |= 1 << (slot % 8);
+            } else {
+                bits[slot / 8] &= !(1 << (slot % 8));
+            }
+        }
+        Ok(())
+    }
+
+    /// Turn the whole day off
+    pub fn clear_day(&mut self, day: Weekday) {
+        self.days[day as usize] = [0; SLOTS_PER_DAY / 8];
+    }
+
+    /// Is the schedule on at minute (since midnight) of day
+    pub fn is_active(&self, day: Weekday, minute: u16) -> bool {
+        if minute >= MINUTES_PER_DAY {
+            return false;
+        }
+        let slot = (minute / SLOT_MINUTES) as usize;
+        self.days[day as usize][slot / 8] & (1 << (slot % 8)) != 0
+    }
+
+    /// Same as is_active for a unix timestamp in local time
+    pub fn is_active_at(&self, t: u64) -> bool {
+        let minute = ((t % 86_400) / 60) as u16;
+        self.is_active(Weekday::from_unix(t), minute)
+    }
+}
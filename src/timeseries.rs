@@ -0,0 +1,367 @@
+// Append-only time series in flash
+// Samples are logged, not looked up by key, so instead of a Database they
+// go into a TimeSeries: fixed-size records written one after the other
+// across the pages of a partition, oldest page erased when the log wraps.
+// Values go through the same codecs as a Database and any NorFlash works.
+//
+// static LOG: Partition = Partition::new(0x000F_0000, 4 * 4096);
+// let mut temps: TimeSeries<i16, Postcard, 32> =
+//     TimeSeries::new(LOG, 4096, Retention { max_count: 500, max_age: 0 });
+// temps.open(&mut flash)?;                       // finds the end of the log
+// temps.append(&mut flash, now_s, &reading)?;
+// temps.for_each(&mut flash, now_s, |t, reading| { ... })?;   // oldest first
+//
+// Every record is R bytes (little endian, erased padding after the value):
+// [magic: u16][val_len: u16][timestamp: u64][val][crc32: u32]
+// Records never straddle a page, erase_size / R of them fit in one. The
+// timestamps are whatever the application counts in (seconds, HybridTime
+// packed with to_u64()), they only have to go up.
+//
+// Retention is by count and/or age; what falls out of it is skipped when
+// reading and prune() erases pages that hold nothing else. Without a
+// retention the log keeps as much as fits in the partition.
+
+use crate::codec::Codec;
+use crate::db::FlashError;
+use crate::emergency::Partition;
+use crate::image::CRC32;
+use embedded_storage::nor_flash::NorFlash;
+
+const MAGIC: u16 = 0x75E5;
+// magic, val_len and timestamp
+const RECORD_HEADER: usize = 12;
+const RECORD_OVERHEAD: usize = RECORD_HEADER + 4;
+
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub enum TimeSeriesError<E> {
+    Encode(E),
+    Decode(E),
+    // The encoded sample doesn't fit in a record of R bytes
+    TooLarge,
+    // The timestamp is older than the last one appended
+    OutOfOrder,
+    Flash(FlashError),
+}
+
+impl<E> From<FlashError> for TimeSeriesError<E> {
+    fn from(e: FlashError) -> Self {
+        TimeSeriesError::Flash(e)
+    }
+}
+
+/// What for_each() still returns, 0 turns a limit off
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, defmt::Format)]
+pub struct Retention {
+    // Only the newest max_count samples
+    pub max_count: u32,
+    // Only samples with timestamp + max_age >= now
+    pub max_age: u64,
+}
+
+/// See the top of the file
+/// R is the record size, a multiple of the flash write size.
+pub struct TimeSeries<T, C, const R: usize>
+where
+    C: Codec<T>,
+{
+    partition: Partition,
+    erase_size: usize,
+    retention: Retention,
+    // Offset in the partition the next record goes to
+    head: u32,
+    // Records in flash, and the timestamp of the newest
+    count: u32,
+    last: Option<u64>,
+    _t: core::marker::PhantomData<(T, C)>,
+}
+
+impl<T, C, const R: usize> TimeSeries<T, C, R>
+where
+    C: Codec<T>,
+{
+    /// partition has to be on erase_size boundaries, see flash::check_layout
+    pub const fn new(partition: Partition, erase_size: usize, retention: Retention) -> Self {
+        Self {
+            partition,
+            erase_size,
+            retention,
+            head: 0,
+            count: 0,
+            last: None,
+            _t: core::marker::PhantomData,
+        }
+    }
+
+    /// Records in flash, including those out of retention
+    pub fn len(&self) -> usize {
+        self.count as usize
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.count == 0
+    }
+
+    /// How many records the partition holds
+    pub fn capacity(&self) -> usize {
+        self.pages() * self.per_page()
+    }
+
+    /// Timestamp of the newest record
+    pub fn last_timestamp(&self) -> Option<u64> {
+        self.last
+    }
+
+    /// Find the end of the log, call once before anything else
+    /// Returns how many records there are.
+    pub fn open<F: NorFlash>(&mut self, flash: &mut F) -> Result<usize, FlashError> {
+        // The page written last is the one that starts with the newest record
+        let mut newest: Option<(usize, u64)> = None;
+        self.count = 0;
+        for page in 0..self.pages() {
+            if let Some(t) = self.timestamp_at(flash, self.slot(page, 0))? {
+                if newest.is_none_or(|(_, n)| t >= n) {
+                    newest = Some((page, t));
+                }
+            }
+            self.count += self.records_in(flash, page)? as u32;
+        }
+
+        let (page, _) = match newest {
+            Some(newest) => newest,
+            None => {
+                self.head = 0;
+                self.last = None;
+                return Ok(0);
+            }
+        };
+        let used = self.records_in(flash, page)?;
+        self.last = self.timestamp_at(flash, self.slot(page, used - 1))?;
+        self.head = if used == self.per_page() {
+            self.slot((page + 1) % self.pages(), 0)
+        } else {
+            self.slot(page, used)
+        };
+        Ok(self.count as usize)
+    }
+
+    /// Write sample as the newest record
+    /// Starting a page erases it, which drops the oldest records.
+    pub fn append<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        timestamp: u64,
+        sample: &T,
+    ) -> Result<(), TimeSeriesError<C::Error>> {
+        if self.last.is_some_and(|last| timestamp < last) {
+            return Err(TimeSeriesError::OutOfOrder);
+        }
+        let mut record = [0xFFu8; R];
+        if R < RECORD_OVERHEAD {
+            return Err(TimeSeriesError::TooLarge);
+        }
+        let val_len = C::encode(&mut record[RECORD_HEADER..R - 4], sample)
+            .map_err(TimeSeriesError::Encode)?;
+        record[0..2].copy_from_slice(&MAGIC.to_le_bytes());
+        record[2..4].copy_from_slice(&(val_len as u16).to_le_bytes());
+        record[4..12].copy_from_slice(&timestamp.to_le_bytes());
+        let crc = CRC32.checksum(&record[..RECORD_HEADER + val_len]);
+        record[R - 4..].copy_from_slice(&crc.to_le_bytes());
+
+        if let (page, 0) = self.position(self.head) {
+            self.erase_page(flash, page)?;
+        }
+        flash
+            .write(self.partition.offset + self.head, &record)
+            .map_err(|_| FlashError::WriteError)?;
+
+        self.count += 1;
+        self.last = Some(timestamp);
+        let (page, index) = self.position(self.head);
+        self.head = if index + 1 == self.per_page() {
+            self.slot((page + 1) % self.pages(), 0)
+        } else {
+            self.slot(page, index + 1)
+        };
+        Ok(())
+    }
+
+    /// Call f with every sample in retention, oldest first
+    /// now is for the max_age check. Returns how many samples f got.
+    pub fn for_each<F, G>(
+        &self,
+        flash: &mut F,
+        now: u64,
+        mut f: G,
+    ) -> Result<usize, TimeSeriesError<C::Error>>
+    where
+        F: NorFlash,
+        G: FnMut(u64, T),
+    {
+        let mut skip = match self.retention.max_count {
+            0 => 0,
+            max => self.count.saturating_sub(max),
+        };
+        let mut seen = 0;
+        let mut record = [0u8; R];
+        let oldest = self.oldest_page();
+        for n in 0..self.pages() {
+            let page = (oldest + n) % self.pages();
+            for index in 0..self.per_page() {
+                let slot = self.slot(page, index);
+                if !self.read_record(flash, slot, &mut record)? {
+                    continue;
+                }
+                if skip > 0 {
+                    skip -= 1;
+                    continue;
+                }
+                let timestamp = record_timestamp(&record);
+                if self.retention.max_age != 0
+                    && timestamp.saturating_add(self.retention.max_age) < now
+                {
+                    continue;
+                }
+                let val_len = u16::from_le_bytes([record[2], record[3]]) as usize;
+                let sample = C::decode(&record[RECORD_HEADER..RECORD_HEADER + val_len])
+                    .map_err(TimeSeriesError::Decode)?;
+                f(timestamp, sample);
+                seen += 1;
+            }
+        }
+        Ok(seen)
+    }
+
+    /// Erase the pages whose records are all out of retention
+    /// The page records are being added to is left alone. Returns how many
+    /// pages were erased.
+    pub fn prune<F: NorFlash>(&mut self, flash: &mut F, now: u64) -> Result<usize, FlashError> {
+        let (head_page, index) = self.position(self.head);
+        let oldest = self.oldest_page();
+        let mut erased = 0;
+        let mut older = self.count;
+        for n in 0..self.pages() {
+            let page = (oldest + n) % self.pages();
+            if page == head_page && index != 0 {
+                break;
+            }
+            let records = self.records_in(flash, page)? as u32;
+            if records == 0 {
+                continue;
+            }
+            // Everything from this page on is newer
+            older -= records;
+            let newest = self.timestamp_at(flash, self.slot(page, records as usize - 1))?;
+            let too_many = self.retention.max_count != 0 && older >= self.retention.max_count;
+            let too_old = self.retention.max_age != 0
+                && newest.is_some_and(|t| t.saturating_add(self.retention.max_age) < now);
+            if !(too_many || too_old) {
+                break;
+            }
+            self.erase_page(flash, page)?;
+            erased += 1;
+        }
+        Ok(erased)
+    }
+
+    /// Erase the whole log
+    pub fn clear<F: NorFlash>(&mut self, flash: &mut F) -> Result<(), FlashError> {
+        flash
+            .erase(
+                self.partition.offset,
+                self.partition.offset + self.partition.len,
+            )
+            .map_err(|_| FlashError::EraseError)?;
+        self.head = 0;
+        self.count = 0;
+        self.last = None;
+        Ok(())
+    }
+
+    // The page after the one being written, or the head page itself if the
+    // next record starts it (then it still holds the oldest records)
+    fn oldest_page(&self) -> usize {
+        match self.position(self.head) {
+            (page, 0) => page,
+            (page, _) => (page + 1) % self.pages(),
+        }
+    }
+
+    fn pages(&self) -> usize {
+        self.partition.len as usize / self.erase_size
+    }
+
+    fn per_page(&self) -> usize {
+        self.erase_size / R
+    }
+
+    // Offset in the partition of record index of page
+    fn slot(&self, page: usize, index: usize) -> u32 {
+        (page * self.erase_size + index * R) as u32
+    }
+
+    fn position(&self, slot: u32) -> (usize, usize) {
+        let slot = slot as usize;
+        (slot / self.erase_size, slot % self.erase_size / R)
+    }
+
+    fn erase_page<F: NorFlash>(&mut self, flash: &mut F, page: usize) -> Result<(), FlashError> {
+        self.count -= self.records_in(flash, page)? as u32;
+        let from = self.partition.offset + self.slot(page, 0);
+        flash
+            .erase(from, from + self.erase_size as u32)
+            .map_err(|_| FlashError::EraseError)
+    }
+
+    // Records are written front to back, the first erased slot ends a page
+    fn records_in<F: NorFlash>(&self, flash: &mut F, page: usize) -> Result<usize, FlashError> {
+        let mut header = [0u8; 2];
+        for index in 0..self.per_page() {
+            flash
+                .read(self.partition.offset + self.slot(page, index), &mut header)
+                .map_err(|_| FlashError::ReadError)?;
+            if u16::from_le_bytes(header) != MAGIC {
+                return Ok(index);
+            }
+        }
+        Ok(self.per_page())
+    }
+
+    fn timestamp_at<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        slot: u32,
+    ) -> Result<Option<u64>, FlashError> {
+        let mut record = [0u8; R];
+        Ok(self
+            .read_record(flash, slot, &mut record)?
+            .then(|| record_timestamp(&record)))
+    }
+
+    // Read the record at slot, false if it is erased or torn
+    fn read_record<F: NorFlash>(
+        &self,
+        flash: &mut F,
+        slot: u32,
+        record: &mut [u8; R],
+    ) -> Result<bool, FlashError> {
+        flash
+            .read(self.partition.offset + slot, record)
+            .map_err(|_| FlashError::ReadError)?;
+        if R < RECORD_OVERHEAD || u16::from_le_bytes([record[0], record[1]]) != MAGIC {
+            return Ok(false);
+        }
+        let val_len = u16::from_le_bytes([record[2], record[3]]) as usize;
+        if RECORD_OVERHEAD + val_len > R {
+            return Ok(false);
+        }
+        let stored =
+            u32::from_le_bytes([record[R - 4], record[R - 3], record[R - 2], record[R - 1]]);
+        Ok(CRC32.checksum(&record[..RECORD_HEADER + val_len]) == stored)
+    }
+}
+
+fn record_timestamp(record: &[u8]) -> u64 {
+    let mut bytes = [0u8; 8];
+    bytes.copy_from_slice(&record[4..12]);
+    u64::from_le_bytes(bytes)
+}
@@ -0,0 +1,885 @@
+// Type-length-value encoding for codec::Tlv and Database::save_tlv
+// For sharing values with C code that already parses TLV records: the bytes
+// are fixed here and don't depend on serde, postcard or the Rust version.
+//
+// A value is encoded as:
+// - integers little endian in the width of the Rust type (two's complement),
+//   bool as 1 byte 0/1, f32/f64 as IEEE 754 little endian, char as a u32
+// - str and bytes as they are, no terminator or length (the record has it)
+// - (), unit structs and unit enum variants as nothing
+// - structs, tuples, arrays and sequences as records, one per field or
+//   element: [tag: u8][len: u8][value], tag counts from 1 in declaration
+//   order. A field that is None is left out, Some(v) is just v
+// - maps as records too, key tagged 1 and its value tagged 2, alternating
+// - enums as a single record, tag = variant number from 1, holding the
+//   fields of the variant
+// - newtypes as what they wrap
+//
+// A blob is the encoding of the value itself, so a u32 value is 4 bytes and
+// a struct value its records one after the other:
+//
+// struct Climate { temp_c10: i16, rh_pct: u8, label: Option<&str> }
+// Climate { temp_c10: 215, rh_pct: 41, label: Some("hall") } =>
+// 01 02 d7 00   02 01 29   03 04 68 61 6c 6c
+//
+// Reading takes integers of any width up to 8 bytes for any integer field
+// the value fits (C can widen a field without breaking old firmware) and
+// skips records with tags the struct doesn't have. A record can hold at
+// most 255 bytes and a struct at most 255 fields. Formats that need
+// deserialize_any (untagged enums, flatten, serde_json::Value) aren't
+// supported.
+//
+// save_tlv writes a Database with integer keys as a flash region of:
+// [tag: u16][len: u16][value]...[0xFFFE][4][crc32: u32]
+// tag is the key, the value is the stored blob as is and the last record
+// holds the CRC32 (image::CRC32) of all records before it. Keys 0xFFFE and
+// 0xFFFF can't be stored, 0xFFFF is what erased flash reads as. In C,
+// stopping at the end record, at erased flash and at the end of the region:
+//
+// for (p = region; p + 4 <= region_end; p += 4 + get_u16(p + 2)) {
+//     uint16_t tag = get_u16(p), len = get_u16(p + 2);
+//     if (tag == 0xFFFE || tag == 0xFFFF || p + 4 + len > region_end) break;
+//     handle(tag, p + 4, len);
+// }
+
+use serde::de::{self, DeserializeSeed, IntoDeserializer, Visitor};
+use serde::ser::{self, Serialize};
+
+/// Tag of the record that ends a save_tlv region
+pub const REGION_END: u16 = 0xFFFE;
+/// Size of [tag: u16][len: u16] in front of every region record
+pub const REGION_HEADER: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum TlvError {
+    // Not enough room in the buffer for the encoding
+    BufferFull,
+    // A record longer than 255 bytes, or more than 255 fields or elements
+    TooLarge,
+    // A record cut short
+    Eof,
+    // A value whose length doesn't fit its type (a 3 byte integer)
+    BadLength,
+    // A str that isn't UTF-8, or a char that isn't one
+    BadUtf8,
+    // A record out of order, or an enum that isn't exactly one record
+    BadTag,
+    // deserialize_any and 128 bit integers
+    Unsupported,
+    // A Serialize or Deserialize impl rejected the value
+    Custom,
+}
+
+impl core::fmt::Display for TlvError {
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self)
+    }
+}
+
+impl ser::StdError for TlvError {}
+
+impl ser::Error for TlvError {
+    fn custom<T: core::fmt::Display>(_msg: T) -> Self {
+        TlvError::Custom
+    }
+}
+
+impl de::Error for TlvError {
+    fn custom<T: core::fmt::Display>(_msg: T) -> Self {
+        TlvError::Custom
+    }
+}
+
+/// Encode v into dst, returns the length
+pub fn to_slice<T: Serialize + ?Sized>(v: &T, dst: &mut [u8]) -> Result<usize, TlvError> {
+    let mut ser = Serializer {
+        buf: dst,
+        pos: 0,
+        none: false,
+    };
+    v.serialize(&mut ser)?;
+    Ok(ser.pos)
+}
+
+/// Decode a T from all of src
+pub fn from_slice<'de, T: de::Deserialize<'de>>(src: &'de [u8]) -> Result<T, TlvError> {
+    // An empty blob is what a top level None encodes to
+    let value = if src.is_empty() { None } else { Some(src) };
+    T::deserialize(Deserializer { value })
+}
+
+/// Split the first record off bytes: (tag, value, rest)
+pub fn split_record(bytes: &[u8]) -> Result<(u8, &[u8], &[u8]), TlvError> {
+    match bytes {
+        [tag, len, rest @ ..] if rest.len() >= *len as usize => {
+            let (value, rest) = rest.split_at(*len as usize);
+            Ok((*tag, value, rest))
+        }
+        _ => Err(TlvError::Eof),
+    }
+}
+
+/// Tag of the field called name of struct T, None if T has no such field
+/// or isn't a struct
+pub fn field_tag<'de, T: de::Deserialize<'de>>(name: &str) -> Option<u8> {
+    let mut fields: &'static [&'static str] = &[];
+    let _ = T::deserialize(FieldNames {
+        fields: &mut fields,
+    });
+    let pos = fields.iter().position(|field| *field == name)?;
+    u8::try_from(pos + 1).ok()
+}
+
+/// Write blob with the value of the record tagged tag replaced by value
+/// into dst, returns the length. Without such a record (a field that is
+/// None) one is added in tag order.
+pub fn replace_record(
+    blob: &[u8],
+    tag: u8,
+    value: &[u8],
+    dst: &mut [u8],
+) -> Result<usize, TlvError> {
+    let value_len = u8::try_from(value.len()).map_err(|_| TlvError::TooLarge)?;
+    // Where the old record is, or where the new one goes
+    let (mut start, mut end) = (blob.len(), blob.len());
+    let mut rest = blob;
+    while !rest.is_empty() {
+        let at = blob.len() - rest.len();
+        let (record_tag, _, next) = split_record(rest)?;
+        if record_tag >= tag {
+            start = at;
+            end = if record_tag == tag {
+                blob.len() - next.len()
+            } else {
+                at
+            };
+            break;
+        }
+        rest = next;
+    }
+
+    let len = blob.len() - (end - start) + 2 + value.len();
+    let out = dst.get_mut(..len).ok_or(TlvError::BufferFull)?;
+    out[..start].copy_from_slice(&blob[..start]);
+    out[start] = tag;
+    out[start + 1] = value_len;
+    out[start + 2..start + 2 + value.len()].copy_from_slice(value);
+    out[start + 2 + value.len()..].copy_from_slice(&blob[end..]);
+    Ok(len)
+}
+
+/// Write a save_tlv region record at out, returns its length
+pub fn write_region_record(out: &mut [u8], tag: u16, value: &[u8]) -> Option<usize> {
+    let len = u16::try_from(value.len()).ok()?;
+    let record = out.get_mut(..REGION_HEADER + value.len())?;
+    record[0..2].copy_from_slice(&tag.to_le_bytes());
+    record[2..4].copy_from_slice(&len.to_le_bytes());
+    record[REGION_HEADER..].copy_from_slice(value);
+    Some(record.len())
+}
+
+struct Serializer<'a> {
+    buf: &'a mut [u8],
+    pos: usize,
+    // The value just serialized was None, so its record is dropped
+    none: bool,
+}
+
+impl Serializer<'_> {
+    fn write(&mut self, bytes: &[u8]) -> Result<(), TlvError> {
+        let end = self.pos + bytes.len();
+        self.buf
+            .get_mut(self.pos..end)
+            .ok_or(TlvError::BufferFull)?
+            .copy_from_slice(bytes);
+        self.pos = end;
+        Ok(())
+    }
+
+    // Write the header of record tag, returns where its length goes
+    fn open(&mut self, tag: usize) -> Result<usize, TlvError> {
+        let tag = u8::try_from(tag).map_err(|_| TlvError::TooLarge)?;
+        self.write(&[tag, 0])?;
+        Ok(self.pos - 1)
+    }
+
+    // Fill in the length of the record opened at len_at
+    fn close(&mut self, len_at: usize) -> Result<(), TlvError> {
+        let len = self.pos - len_at - 1;
+        self.buf[len_at] = u8::try_from(len).map_err(|_| TlvError::TooLarge)?;
+        Ok(())
+    }
+
+    // v as record tag, left out if v is None
+    fn record<T: Serialize + ?Sized>(&mut self, tag: usize, v: &T) -> Result<(), TlvError> {
+        let start = self.pos;
+        let len_at = self.open(tag)?;
+        self.none = false;
+        v.serialize(&mut *self)?;
+        if self.none {
+            self.pos = start;
+            self.none = false;
+            return Ok(());
+        }
+        self.close(len_at)
+    }
+}
+
+impl core::fmt::Write for Serializer<'_> {
+    fn write_str(&mut self, s: &str) -> core::fmt::Result {
+        self.write(s.as_bytes()).map_err(|_| core::fmt::Error)
+    }
+}
+
+// Records of a struct, tuple, sequence or map being written
+struct Compound<'a, 'b> {
+    ser: &'a mut Serializer<'b>,
+    // Tag of the next record
+    next: usize,
+    // The enum variant record around them, closed at the end
+    variant: Option<usize>,
+}
+
+impl Compound<'_, '_> {
+    fn element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), TlvError> {
+        self.ser.record(self.next, v)?;
+        self.next += 1;
+        Ok(())
+    }
+
+    fn end(self) -> Result<(), TlvError> {
+        match self.variant {
+            Some(len_at) => self.ser.close(len_at),
+            None => Ok(()),
+        }
+    }
+}
+
+impl<'a, 'b> ser::Serializer for &'a mut Serializer<'b> {
+    type Ok = ();
+    type Error = TlvError;
+    type SerializeSeq = Compound<'a, 'b>;
+    type SerializeTuple = Compound<'a, 'b>;
+    type SerializeTupleStruct = Compound<'a, 'b>;
+    type SerializeTupleVariant = Compound<'a, 'b>;
+    type SerializeMap = Compound<'a, 'b>;
+    type SerializeStruct = Compound<'a, 'b>;
+    type SerializeStructVariant = Compound<'a, 'b>;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    fn serialize_bool(self, v: bool) -> Result<(), TlvError> {
+        self.write(&[v as u8])
+    }
+    fn serialize_i8(self, v: i8) -> Result<(), TlvError> {
+        self.write(&v.to_le_bytes())
+    }
+    fn serialize_i16(self, v: i16) -> Result<(), TlvError> {
+        self.write(&v.to_le_bytes())
+    }
+    fn serialize_i32(self, v: i32) -> Result<(), TlvError> {
+        self.write(&v.to_le_bytes())
+    }
+    fn serialize_i64(self, v: i64) -> Result<(), TlvError> {
+        self.write(&v.to_le_bytes())
+    }
+    fn serialize_u8(self, v: u8) -> Result<(), TlvError> {
+        self.write(&[v])
+    }
+    fn serialize_u16(self, v: u16) -> Result<(), TlvError> {
+        self.write(&v.to_le_bytes())
+    }
+    fn serialize_u32(self, v: u32) -> Result<(), TlvError> {
+        self.write(&v.to_le_bytes())
+    }
+    fn serialize_u64(self, v: u64) -> Result<(), TlvError> {
+        self.write(&v.to_le_bytes())
+    }
+    fn serialize_f32(self, v: f32) -> Result<(), TlvError> {
+        self.write(&v.to_le_bytes())
+    }
+    fn serialize_f64(self, v: f64) -> Result<(), TlvError> {
+        self.write(&v.to_le_bytes())
+    }
+    fn serialize_char(self, v: char) -> Result<(), TlvError> {
+        self.write(&(v as u32).to_le_bytes())
+    }
+    fn serialize_str(self, v: &str) -> Result<(), TlvError> {
+        self.write(v.as_bytes())
+    }
+    fn serialize_bytes(self, v: &[u8]) -> Result<(), TlvError> {
+        self.write(v)
+    }
+    fn serialize_none(self) -> Result<(), TlvError> {
+        self.none = true;
+        Ok(())
+    }
+    fn serialize_some<T: Serialize + ?Sized>(self, v: &T) -> Result<(), TlvError> {
+        v.serialize(self)
+    }
+    fn serialize_unit(self) -> Result<(), TlvError> {
+        Ok(())
+    }
+    fn serialize_unit_struct(self, _: &'static str) -> Result<(), TlvError> {
+        Ok(())
+    }
+    fn serialize_unit_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+    ) -> Result<(), TlvError> {
+        self.record(index as usize + 1, &())
+    }
+    fn serialize_newtype_struct<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        v: &T,
+    ) -> Result<(), TlvError> {
+        v.serialize(self)
+    }
+    fn serialize_newtype_variant<T: Serialize + ?Sized>(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        v: &T,
+    ) -> Result<(), TlvError> {
+        self.record(index as usize + 1, v)
+    }
+    fn serialize_seq(self, _: Option<usize>) -> Result<Compound<'a, 'b>, TlvError> {
+        Ok(Compound {
+            ser: self,
+            next: 1,
+            variant: None,
+        })
+    }
+    fn serialize_tuple(self, len: usize) -> Result<Compound<'a, 'b>, TlvError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_struct(
+        self,
+        _: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a, 'b>, TlvError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_tuple_variant(
+        self,
+        _: &'static str,
+        index: u32,
+        _: &'static str,
+        _: usize,
+    ) -> Result<Compound<'a, 'b>, TlvError> {
+        let len_at = self.open(index as usize + 1)?;
+        Ok(Compound {
+            ser: self,
+            next: 1,
+            variant: Some(len_at),
+        })
+    }
+    fn serialize_map(self, len: Option<usize>) -> Result<Compound<'a, 'b>, TlvError> {
+        self.serialize_seq(len)
+    }
+    fn serialize_struct(self, _: &'static str, len: usize) -> Result<Compound<'a, 'b>, TlvError> {
+        self.serialize_seq(Some(len))
+    }
+    fn serialize_struct_variant(
+        self,
+        name: &'static str,
+        index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<Compound<'a, 'b>, TlvError> {
+        self.serialize_tuple_variant(name, index, variant, len)
+    }
+    fn collect_str<T: core::fmt::Display + ?Sized>(self, v: &T) -> Result<(), TlvError> {
+        use core::fmt::Write;
+        write!(self, "{}", v).map_err(|_| TlvError::BufferFull)
+    }
+}
+
+impl ser::SerializeSeq for Compound<'_, '_> {
+    type Ok = ();
+    type Error = TlvError;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), TlvError> {
+        self.element(v)
+    }
+    fn end(self) -> Result<(), TlvError> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTuple for Compound<'_, '_> {
+    type Ok = ();
+    type Error = TlvError;
+    fn serialize_element<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), TlvError> {
+        self.element(v)
+    }
+    fn end(self) -> Result<(), TlvError> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for Compound<'_, '_> {
+    type Ok = ();
+    type Error = TlvError;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), TlvError> {
+        self.element(v)
+    }
+    fn end(self) -> Result<(), TlvError> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeTupleVariant for Compound<'_, '_> {
+    type Ok = ();
+    type Error = TlvError;
+    fn serialize_field<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), TlvError> {
+        self.element(v)
+    }
+    fn end(self) -> Result<(), TlvError> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeMap for Compound<'_, '_> {
+    type Ok = ();
+    type Error = TlvError;
+    fn serialize_key<T: Serialize + ?Sized>(&mut self, k: &T) -> Result<(), TlvError> {
+        self.ser.record(1, k)
+    }
+    fn serialize_value<T: Serialize + ?Sized>(&mut self, v: &T) -> Result<(), TlvError> {
+        self.ser.record(2, v)
+    }
+    fn end(self) -> Result<(), TlvError> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStruct for Compound<'_, '_> {
+    type Ok = ();
+    type Error = TlvError;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _: &'static str,
+        v: &T,
+    ) -> Result<(), TlvError> {
+        self.element(v)
+    }
+    fn skip_field(&mut self, _: &'static str) -> Result<(), TlvError> {
+        // Skipped fields keep their number, the ones after don't move
+        self.next += 1;
+        Ok(())
+    }
+    fn end(self) -> Result<(), TlvError> {
+        Compound::end(self)
+    }
+}
+
+impl ser::SerializeStructVariant for Compound<'_, '_> {
+    type Ok = ();
+    type Error = TlvError;
+    fn serialize_field<T: Serialize + ?Sized>(
+        &mut self,
+        _: &'static str,
+        v: &T,
+    ) -> Result<(), TlvError> {
+        self.element(v)
+    }
+    fn skip_field(&mut self, _: &'static str) -> Result<(), TlvError> {
+        self.next += 1;
+        Ok(())
+    }
+    fn end(self) -> Result<(), TlvError> {
+        Compound::end(self)
+    }
+}
+
+// One value, None if its record was left out
+struct Deserializer<'de> {
+    value: Option<&'de [u8]>,
+}
+
+impl<'de> Deserializer<'de> {
+    fn bytes(&self) -> &'de [u8] {
+        self.value.unwrap_or(&[])
+    }
+
+    fn unsigned(&self) -> Result<u64, TlvError> {
+        let bytes = self.bytes();
+        if bytes.is_empty() || bytes.len() > 8 {
+            return Err(TlvError::BadLength);
+        }
+        let mut word = [0u8; 8];
+        word[..bytes.len()].copy_from_slice(bytes);
+        Ok(u64::from_le_bytes(word))
+    }
+
+    fn signed(&self) -> Result<i64, TlvError> {
+        let bytes = self.bytes();
+        if bytes.is_empty() || bytes.len() > 8 {
+            return Err(TlvError::BadLength);
+        }
+        // Sign extend from the top bit of the last byte
+        let fill = if bytes[bytes.len() - 1] & 0x80 != 0 {
+            0xFF
+        } else {
+            0
+        };
+        let mut word = [fill; 8];
+        word[..bytes.len()].copy_from_slice(bytes);
+        Ok(i64::from_le_bytes(word))
+    }
+
+    fn fixed<const L: usize>(&self) -> Result<[u8; L], TlvError> {
+        self.bytes().try_into().map_err(|_| TlvError::BadLength)
+    }
+}
+
+macro_rules! deserialize_int {
+    ($($method:ident => $read:ident, $visit:ident;)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+                visitor.$visit(self.$read()?)
+            }
+        )*
+    };
+}
+
+impl<'de> de::Deserializer<'de> for Deserializer<'de> {
+    type Error = TlvError;
+
+    fn is_human_readable(&self) -> bool {
+        false
+    }
+
+    // The bytes don't say what they are
+    fn deserialize_any<V: Visitor<'de>>(self, _: V) -> Result<V::Value, TlvError> {
+        Err(TlvError::Unsupported)
+    }
+
+    deserialize_int! {
+        deserialize_u8 => unsigned, visit_u64;
+        deserialize_u16 => unsigned, visit_u64;
+        deserialize_u32 => unsigned, visit_u64;
+        deserialize_u64 => unsigned, visit_u64;
+        deserialize_i8 => signed, visit_i64;
+        deserialize_i16 => signed, visit_i64;
+        deserialize_i32 => signed, visit_i64;
+        deserialize_i64 => signed, visit_i64;
+    }
+
+    fn deserialize_bool<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        visitor.visit_bool(self.fixed::<1>()?[0] != 0)
+    }
+
+    fn deserialize_f32<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        visitor.visit_f32(f32::from_le_bytes(self.fixed()?))
+    }
+
+    fn deserialize_f64<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        match self.bytes().len() {
+            4 => visitor.visit_f64(f32::from_le_bytes(self.fixed()?) as f64),
+            _ => visitor.visit_f64(f64::from_le_bytes(self.fixed()?)),
+        }
+    }
+
+    fn deserialize_char<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        let c = char::from_u32(u32::from_le_bytes(self.fixed()?)).ok_or(TlvError::BadUtf8)?;
+        visitor.visit_char(c)
+    }
+
+    fn deserialize_str<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        let s = core::str::from_utf8(self.bytes()).map_err(|_| TlvError::BadUtf8)?;
+        visitor.visit_borrowed_str(s)
+    }
+
+    fn deserialize_string<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        self.deserialize_str(visitor)
+    }
+
+    fn deserialize_bytes<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        visitor.visit_borrowed_bytes(self.bytes())
+    }
+
+    fn deserialize_byte_buf<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        self.deserialize_bytes(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        match self.value {
+            Some(_) => visitor.visit_some(self),
+            None => visitor.visit_none(),
+        }
+    }
+
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TlvError> {
+        visitor.visit_unit()
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, TlvError> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        visitor.visit_seq(Elements::new(self.bytes(), None))
+    }
+
+    fn deserialize_tuple<V: Visitor<'de>>(
+        self,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TlvError> {
+        visitor.visit_seq(Elements::new(self.bytes(), Some(len)))
+    }
+
+    fn deserialize_tuple_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        len: usize,
+        visitor: V,
+    ) -> Result<V::Value, TlvError> {
+        self.deserialize_tuple(len, visitor)
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        visitor.visit_map(Entries {
+            rest: self.bytes(),
+            value: None,
+        })
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TlvError> {
+        visitor.visit_map(Fields {
+            rest: self.bytes(),
+            value: &[],
+        })
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _: &'static str,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TlvError> {
+        let (tag, value, rest) = split_record(self.bytes())?;
+        if tag == 0 || !rest.is_empty() {
+            return Err(TlvError::BadTag);
+        }
+        visitor.visit_enum(Variant { tag, value })
+    }
+
+    fn deserialize_identifier<V: Visitor<'de>>(self, _: V) -> Result<V::Value, TlvError> {
+        Err(TlvError::Unsupported)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, TlvError> {
+        visitor.visit_unit()
+    }
+}
+
+// Only notes the field names deserialize_struct is called with, for
+// field_tag
+struct FieldNames<'a> {
+    fields: &'a mut &'static [&'static str],
+}
+
+impl<'de> de::Deserializer<'de> for FieldNames<'_> {
+    type Error = TlvError;
+
+    fn deserialize_any<V: Visitor<'de>>(self, _visitor: V) -> Result<V::Value, TlvError> {
+        Err(TlvError::Unsupported)
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        fields: &'static [&'static str],
+        _visitor: V,
+    ) -> Result<V::Value, TlvError> {
+        *self.fields = fields;
+        Err(TlvError::Unsupported)
+    }
+
+    serde::forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf option unit unit_struct newtype_struct seq tuple
+        tuple_struct map enum identifier ignored_any
+    }
+}
+
+// Elements of a tuple or sequence, a missing tag is an element left out
+struct Elements<'de> {
+    rest: &'de [u8],
+    next: usize,
+    len: Option<usize>,
+}
+
+impl<'de> Elements<'de> {
+    fn new(rest: &'de [u8], len: Option<usize>) -> Self {
+        Self { rest, next: 1, len }
+    }
+}
+
+impl<'de> de::SeqAccess<'de> for Elements<'de> {
+    type Error = TlvError;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>, TlvError> {
+        match self.len {
+            Some(len) if self.next > len => return Ok(None),
+            None if self.rest.is_empty() => return Ok(None),
+            _ => {}
+        }
+        let mut value = None;
+        if !self.rest.is_empty() {
+            let (tag, bytes, rest) = split_record(self.rest)?;
+            if (tag as usize) < self.next {
+                return Err(TlvError::BadTag);
+            }
+            if tag as usize == self.next {
+                value = Some(bytes);
+                self.rest = rest;
+            }
+        }
+        self.next += 1;
+        seed.deserialize(Deserializer { value }).map(Some)
+    }
+}
+
+// Fields of a struct, keyed by field number
+struct Fields<'de> {
+    rest: &'de [u8],
+    value: &'de [u8],
+}
+
+impl<'de> de::MapAccess<'de> for Fields<'de> {
+    type Error = TlvError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, TlvError> {
+        if self.rest.is_empty() {
+            return Ok(None);
+        }
+        let (tag, value, rest) = split_record(self.rest)?;
+        if tag == 0 {
+            return Err(TlvError::BadTag);
+        }
+        self.rest = rest;
+        self.value = value;
+        // Derived field identifiers take the field index
+        seed.deserialize((tag as u32 - 1).into_deserializer())
+            .map(Some)
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, TlvError> {
+        seed.deserialize(Deserializer {
+            value: Some(self.value),
+        })
+    }
+}
+
+// Map entries, key records tagged 1 each followed by a value tagged 2
+struct Entries<'de> {
+    rest: &'de [u8],
+    value: Option<&'de [u8]>,
+}
+
+impl<'de> de::MapAccess<'de> for Entries<'de> {
+    type Error = TlvError;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, TlvError> {
+        if self.rest.is_empty() {
+            return Ok(None);
+        }
+        let (tag, key, rest) = split_record(self.rest)?;
+        if tag != 1 {
+            return Err(TlvError::BadTag);
+        }
+        self.rest = rest;
+        self.value = None;
+        // A value that is None was left out
+        if let Ok((2, value, rest)) = split_record(self.rest) {
+            self.value = Some(value);
+            self.rest = rest;
+        }
+        seed.deserialize(Deserializer { value: Some(key) })
+            .map(Some)
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, TlvError> {
+        seed.deserialize(Deserializer { value: self.value })
+    }
+}
+
+// The record of an enum variant
+struct Variant<'de> {
+    tag: u8,
+    value: &'de [u8],
+}
+
+impl<'de> de::EnumAccess<'de> for Variant<'de> {
+    type Error = TlvError;
+    type Variant = Self;
+
+    fn variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<(T::Value, Self), TlvError> {
+        let index: de::value::U32Deserializer<TlvError> = (self.tag as u32 - 1).into_deserializer();
+        Ok((seed.deserialize(index)?, self))
+    }
+}
+
+impl<'de> de::VariantAccess<'de> for Variant<'de> {
+    type Error = TlvError;
+
+    fn unit_variant(self) -> Result<(), TlvError> {
+        Ok(())
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value, TlvError> {
+        seed.deserialize(Deserializer {
+            value: Some(self.value),
+        })
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, len: usize, visitor: V) -> Result<V::Value, TlvError> {
+        visitor.visit_seq(Elements::new(self.value, Some(len)))
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, TlvError> {
+        visitor.visit_map(Fields {
+            rest: self.value,
+            value: &[],
+        })
+    }
+}
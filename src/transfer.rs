@@ -0,0 +1,294 @@
+// Chunked image transfer over a flaky link
+// Moves a complete flash image (as written by save_to_flash) between two
+// devices over anything that can carry small frames: BLE, a UART, a modem
+// with TCP offload. The crate handles offsets, resume and the final CRC,
+// the application only provides the Transport.
+//
+// Backup, on the device:
+// transfer::send_image(&mut ble, &mut flash, FLASH_STORAGE_ADDR).await?;
+//
+// Restore, on the device:
+// let mut rx = Receiver::new(STAGING_ADDR, MAX_IMAGE_SIZE);
+// rx.run(&mut flash, &mut ble).await?;       // call again after an error to resume
+// db.open(&mut flash, STAGING_ADDR)?;
+//
+// Frames (little endian), the sender only moves on once they are acked:
+// [START][total_len: u32][crc32: u32]   -> [ACK][next: u32]
+// [DATA][offset: u32][bytes...]         -> [ACK][next: u32]
+// [COMMIT]                              -> [DONE][ok: u8]
+// next is where the receiver wants to continue, so a lost frame or a
+// restarted sender just picks up from there. A START for the same image
+// while a transfer is in progress resumes it instead of starting over.
+// Progress only lives in RAM, a reset on the receiving side starts over.
+
+use crate::db::FlashError;
+use crate::image::{self, HEADER_SIZE};
+use core::future::Future;
+use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+
+/// Image bytes per DATA frame
+pub const CHUNK_SIZE: usize = 128;
+/// Largest frame either side sends
+pub const MAX_FRAME: usize = 5 + CHUNK_SIZE;
+/// How often the sender repeats a frame before giving up
+pub const MAX_RETRIES: usize = 5;
+
+const OP_START: u8 = 1;
+const OP_DATA: u8 = 2;
+const OP_ACK: u8 = 3;
+const OP_COMMIT: u8 = 4;
+const OP_DONE: u8 = 5;
+
+/// Something that carries whole frames, e.g. one BLE write/notification each
+pub trait Transport {
+    type Error;
+    fn send(&mut self, frame: &[u8]) -> impl Future<Output = Result<(), Self::Error>>;
+    /// Wait for the next frame, returns its length
+    /// Implementations should give up (return an error) after a timeout so
+    /// the sender can repeat its last frame.
+    fn recv(&mut self, buf: &mut [u8]) -> impl Future<Output = Result<usize, Self::Error>>;
+}
+
+#[derive(Debug, Clone, Copy, defmt::Format)]
+pub enum TransferError {
+    // The transport failed MAX_RETRIES times in a row
+    Transport,
+    Flash(FlashError),
+    // A frame that doesn't fit the protocol
+    BadFrame,
+    // The image is bigger than the receiving region
+    TooLarge,
+    // The receiver got everything but the CRC doesn't match
+    CrcMismatch,
+    // Nothing stored at the sending side
+    NoImage,
+}
+
+impl From<FlashError> for TransferError {
+    fn from(e: FlashError) -> Self {
+        TransferError::Flash(e)
+    }
+}
+
+/// Send the image at flash_offset to a Receiver on the other end
+pub async fn send_image<T, F>(
+    transport: &mut T,
+    flash: &mut F,
+    flash_offset: u32,
+) -> Result<(), TransferError>
+where
+    T: Transport,
+    F: ReadNorFlash,
+{
+    let header = image::verify(flash, flash_offset)?.ok_or(TransferError::NoImage)?;
+    let total = HEADER_SIZE + header.payload_len as usize;
+
+    // The receiver checks the whole image, header included
+    let mut digest = image::CRC32.digest();
+    let mut chunk = [0u8; CHUNK_SIZE];
+    let mut pos = 0;
+    while pos < total {
+        let n = CHUNK_SIZE.min(total - pos);
+        read(flash, flash_offset + pos as u32, &mut chunk[..n])?;
+        digest.update(&chunk[..n]);
+        pos += n;
+    }
+    let crc = digest.finalize();
+
+    let mut frame = [0u8; MAX_FRAME];
+    let mut reply = [0u8; MAX_FRAME];
+
+    frame[0] = OP_START;
+    frame[1..5].copy_from_slice(&(total as u32).to_le_bytes());
+    frame[5..9].copy_from_slice(&crc.to_le_bytes());
+    let mut next = exchange(transport, &frame[..9], &mut reply).await?;
+
+    while next < total {
+        let n = CHUNK_SIZE.min(total - next);
+        frame[0] = OP_DATA;
+        frame[1..5].copy_from_slice(&(next as u32).to_le_bytes());
+        read(flash, flash_offset + next as u32, &mut frame[5..5 + n])?;
+        next = exchange(transport, &frame[..5 + n], &mut reply).await?;
+    }
+
+    frame[0] = OP_COMMIT;
+    for _ in 0..MAX_RETRIES {
+        if transport.send(&frame[..1]).await.is_err() {
+            continue;
+        }
+        match transport.recv(&mut reply).await {
+            Ok(2) if reply[0] == OP_DONE => {
+                return match reply[1] {
+                    1 => Ok(()),
+                    _ => Err(TransferError::CrcMismatch),
+                };
+            }
+            _ => continue,
+        }
+    }
+    Err(TransferError::Transport)
+}
+
+// Send frame until an ACK comes back, returns the offset it asks for
+async fn exchange<T: Transport>(
+    transport: &mut T,
+    frame: &[u8],
+    reply: &mut [u8],
+) -> Result<usize, TransferError> {
+    for _ in 0..MAX_RETRIES {
+        if transport.send(frame).await.is_err() {
+            continue;
+        }
+        match transport.recv(reply).await {
+            Ok(5) if reply[0] == OP_ACK => return Ok(read_u32(&reply[1..5]) as usize),
+            _ => continue,
+        }
+    }
+    Err(TransferError::Transport)
+}
+
+/// Receiving side, writes the image into a staging region
+/// Keep the Receiver around after an error, run() resumes where it stopped.
+pub struct Receiver {
+    flash_offset: u32,
+    max_len: usize,
+    // (total_len, crc) of the image in progress
+    current: Option<(usize, u32)>,
+    next: usize,
+}
+
+impl Receiver {
+    /// max_len is the size of the region at flash_offset
+    pub const fn new(flash_offset: u32, max_len: usize) -> Self {
+        Self {
+            flash_offset,
+            max_len,
+            current: None,
+            next: 0,
+        }
+    }
+
+    /// Bytes received so far and the total, once a transfer started
+    pub fn progress(&self) -> Option<(usize, usize)> {
+        self.current.map(|(total, _)| (self.next, total))
+    }
+
+    /// Answer frames until an image was received and committed
+    /// Returns the length of the image in the staging region.
+    pub async fn run<F, T>(
+        &mut self,
+        flash: &mut F,
+        transport: &mut T,
+    ) -> Result<usize, TransferError>
+    where
+        F: NorFlash,
+        T: Transport,
+    {
+        let mut frame = [0u8; MAX_FRAME];
+        let mut reply = [0u8; MAX_FRAME];
+        loop {
+            let len = transport
+                .recv(&mut frame)
+                .await
+                .map_err(|_| TransferError::Transport)?;
+            let (reply_len, done) = self.handle(flash, &frame[..len], &mut reply)?;
+            transport
+                .send(&reply[..reply_len])
+                .await
+                .map_err(|_| TransferError::Transport)?;
+            if let Some(total) = done {
+                return Ok(total);
+            }
+        }
+    }
+
+    /// Handle one frame without a Transport, for applications that get
+    /// frames some other way (e.g. from a BLE stack callback)
+    /// Fills in reply and returns its length, plus the image length once
+    /// the image was committed.
+    pub fn handle<F: NorFlash>(
+        &mut self,
+        flash: &mut F,
+        frame: &[u8],
+        reply: &mut [u8],
+    ) -> Result<(usize, Option<usize>), TransferError> {
+        match frame.first() {
+            Some(&OP_START) if frame.len() == 9 => {
+                let image = (read_u32(&frame[1..5]) as usize, read_u32(&frame[5..9]));
+                if image.0 > self.max_len {
+                    return Err(TransferError::TooLarge);
+                }
+                if self.current != Some(image) {
+                    let end = image.0.div_ceil(F::ERASE_SIZE) * F::ERASE_SIZE;
+                    flash
+                        .erase(self.flash_offset, self.flash_offset + end as u32)
+                        .map_err(|_| FlashError::EraseError)?;
+                    self.current = Some(image);
+                    self.next = 0;
+                }
+            }
+            Some(&OP_DATA) if frame.len() > 5 => {
+                let (total, _) = self.current.ok_or(TransferError::BadFrame)?;
+                let data = &frame[5..];
+                // Anything but the next chunk is a repeat, just ack our position
+                if read_u32(&frame[1..5]) as usize == self.next {
+                    if self.next + data.len() > total || data.len() > CHUNK_SIZE {
+                        return Err(TransferError::BadFrame);
+                    }
+                    // The last chunk may need padding to a whole write unit
+                    let mut chunk = [0xFFu8; CHUNK_SIZE];
+                    chunk[..data.len()].copy_from_slice(data);
+                    let n = data.len().next_multiple_of(F::WRITE_SIZE);
+                    flash
+                        .write(self.flash_offset + self.next as u32, &chunk[..n])
+                        .map_err(|_| FlashError::WriteError)?;
+                    self.next += data.len();
+                }
+            }
+            Some(&OP_COMMIT) => {
+                let (total, crc) = self.current.ok_or(TransferError::BadFrame)?;
+                if self.next != total {
+                    return Err(TransferError::BadFrame);
+                }
+                let ok = self.check(flash, total, crc)?;
+                reply[0] = OP_DONE;
+                reply[1] = ok as u8;
+                // Start over either way, a bad image has to be sent again
+                self.current = None;
+                self.next = 0;
+                return Ok((2, ok.then_some(total)));
+            }
+            _ => return Err(TransferError::BadFrame),
+        }
+
+        reply[0] = OP_ACK;
+        reply[1..5].copy_from_slice(&(self.next as u32).to_le_bytes());
+        Ok((5, None))
+    }
+
+    fn check<F: ReadNorFlash>(
+        &self,
+        flash: &mut F,
+        total: usize,
+        crc: u32,
+    ) -> Result<bool, TransferError> {
+        let mut digest = image::CRC32.digest();
+        let mut chunk = [0u8; CHUNK_SIZE];
+        let mut pos = 0;
+        while pos < total {
+            let n = CHUNK_SIZE.min(total - pos);
+            read(flash, self.flash_offset + pos as u32, &mut chunk[..n])?;
+            digest.update(&chunk[..n]);
+            pos += n;
+        }
+        Ok(digest.finalize() == crc)
+    }
+}
+
+fn read<F: ReadNorFlash>(flash: &mut F, offset: u32, buf: &mut [u8]) -> Result<(), FlashError> {
+    flash.read(offset, buf).map_err(|_| FlashError::ReadError)
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
@@ -0,0 +1,195 @@
+// Unit tagged values
+// A Quantity is a number plus the unit it was measured in. Keys get an
+// expected unit in a static table, UnitMap checks it on put and on get so a
+// Fahrenheit value can't end up under a Celsius key:
+//
+// const UNITS: &[KeyUnit<u32>] = &[
+//     KeyUnit::new(KEY_ALARM_TEMP, Unit::Celsius),
+//     KeyUnit::new(KEY_BATTERY_MIN, Unit::Millivolt),
+// ];
+// let units = UnitMap::new(UNITS);
+// units.put(&mut db, KEY_ALARM_TEMP, Quantity::new(75.0, Unit::Celsius))?;
+// let f = units.get(&mut db, &KEY_ALARM_TEMP, Unit::Fahrenheit)?; // Some(167.0)
+//
+// Values in a compatible unit can be converted before storing with
+// Quantity::to, put itself never converts.
+
+use crate::cache::CachePolicy;
+use crate::codec::Codec;
+use crate::db::Database;
+use crate::kv::BlobStore;
+
+#[derive(
+    Debug, Clone, Copy, PartialEq, Eq, defmt::Format, serde::Serialize, serde::Deserialize,
+)]
+pub enum Unit {
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+    Percent,
+    Millivolt,
+    Volt,
+    Pascal,
+    Kilopascal,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum Dimension {
+    Temperature,
+    Ratio,
+    Voltage,
+    Pressure,
+}
+
+impl Unit {
+    pub fn dimension(&self) -> Dimension {
+        match self {
+            Unit::Celsius | Unit::Fahrenheit | Unit::Kelvin => Dimension::Temperature,
+            Unit::Percent => Dimension::Ratio,
+            Unit::Millivolt | Unit::Volt => Dimension::Voltage,
+            Unit::Pascal | Unit::Kilopascal => Dimension::Pressure,
+        }
+    }
+
+    // Conversions go through one base unit per dimension (°C, %, mV, Pa)
+    fn to_base(self, v: f32) -> f32 {
+        match self {
+            Unit::Fahrenheit => (v - 32.0) * 5.0 / 9.0,
+            Unit::Kelvin => v - 273.15,
+            Unit::Volt => v * 1000.0,
+            Unit::Kilopascal => v * 1000.0,
+            Unit::Celsius | Unit::Percent | Unit::Millivolt | Unit::Pascal => v,
+        }
+    }
+
+    fn base_to_unit(self, v: f32) -> f32 {
+        match self {
+            Unit::Fahrenheit => v * 9.0 / 5.0 + 32.0,
+            Unit::Kelvin => v + 273.15,
+            Unit::Volt => v / 1000.0,
+            Unit::Kilopascal => v / 1000.0,
+            Unit::Celsius | Unit::Percent | Unit::Millivolt | Unit::Pascal => v,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, defmt::Format, serde::Serialize, serde::Deserialize)]
+pub struct Quantity {
+    pub value: f32,
+    pub unit: Unit,
+}
+
+impl Quantity {
+    pub const fn new(value: f32, unit: Unit) -> Self {
+        Self { value, unit }
+    }
+
+    /// The same quantity in another unit
+    /// None if the units measure different things (e.g. mV to °C).
+    pub fn to(&self, unit: Unit) -> Option<Quantity> {
+        if self.unit.dimension() != unit.dimension() {
+            return None;
+        }
+        Some(Quantity::new(
+            unit.base_to_unit(self.unit.to_base(self.value)),
+            unit,
+        ))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub struct KeyUnit<K> {
+    pub key: K,
+    pub unit: Unit,
+}
+
+impl<K> KeyUnit<K> {
+    pub const fn new(key: K, unit: Unit) -> Self {
+        Self { key, unit }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, defmt::Format)]
+pub enum UnitError {
+    // The key isn't in the unit table
+    UnknownKey,
+    // Stored or given value isn't in the key's unit
+    WrongUnit { expected: Unit, found: Unit },
+    // Asked for a unit of a different dimension than the key's
+    Incompatible,
+    Storage,
+}
+
+pub struct UnitMap<'a, K> {
+    units: &'a [KeyUnit<K>],
+}
+
+impl<'a, K> UnitMap<'a, K>
+where
+    K: Eq + Clone,
+{
+    pub const fn new(units: &'a [KeyUnit<K>]) -> Self {
+        Self { units }
+    }
+
+    /// Unit values under key must be stored in
+    pub fn unit_of(&self, key: &K) -> Result<Unit, UnitError> {
+        self.units
+            .iter()
+            .find(|u| &u.key == key)
+            .map(|u| u.unit)
+            .ok_or(UnitError::UnknownKey)
+    }
+
+    /// Store q under key, q has to be in exactly the key's unit
+    pub fn put<C, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &self,
+        db: &mut Database<K, Quantity, C, N, B, CACH, S, CP>,
+        key: K,
+        q: Quantity,
+    ) -> Result<(), UnitError>
+    where
+        C: Codec<Quantity>,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+    {
+        let expected = self.unit_of(&key)?;
+        if q.unit != expected {
+            return Err(UnitError::WrongUnit {
+                expected,
+                found: q.unit,
+            });
+        }
+        db.put(key, q).map_err(|_| UnitError::Storage)
+    }
+
+    /// Read key converted to unit
+    /// Fails with WrongUnit if what's stored doesn't match the table (e.g. it
+    /// was written with a plain db.put).
+    pub fn get<C, S, CP, const N: usize, const B: usize, const CACH: usize>(
+        &self,
+        db: &mut Database<K, Quantity, C, N, B, CACH, S, CP>,
+        key: &K,
+        unit: Unit,
+    ) -> Result<Option<f32>, UnitError>
+    where
+        C: Codec<Quantity>,
+        S: BlobStore<K>,
+        CP: CachePolicy<K>,
+    {
+        let expected = self.unit_of(key)?;
+        let q = match db.get(key).map_err(|_| UnitError::Storage)? {
+            Some(q) => q,
+            None => return Ok(None),
+        };
+        if q.unit != expected {
+            return Err(UnitError::WrongUnit {
+                expected,
+                found: q.unit,
+            });
+        }
+        q.to(unit)
+            .map(|q| Some(q.value))
+            .ok_or(UnitError::Incompatible)
+    }
+}
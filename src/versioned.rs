@@ -0,0 +1,125 @@
+// Schema versions for stored values
+// Adding or changing a field of a struct makes the values already in flash
+// undecodable with Postcard. Versioned<C> writes a version byte in front of
+// every blob, and a blob of an older version is decoded as the type of that
+// version and then upgraded one step at a time to the current one:
+//
+// #[derive(Serialize, Deserialize)]
+// struct ConfigV1 { interval_s: u16 }
+// #[derive(Serialize, Deserialize)]
+// struct Config { interval_s: u32, retries: u8 }
+//
+// impl Schema for ConfigV1 {
+//     const VERSION: u8 = 1;
+//     type Previous = Initial;
+//     fn upgrade(never: Initial) -> Self { match never {} }
+// }
+// impl Schema for Config {
+//     const VERSION: u8 = 2;
+//     type Previous = ConfigV1;
+//     fn upgrade(old: ConfigV1) -> Self {
+//         Config { interval_s: old.interval_s as u32, retries: 3 }
+//     }
+// }
+// type Db = Database<u8, Config, Versioned<Postcard>, 16, 64, 4>;
+//
+// A v1 blob then decodes as ConfigV1 and goes through Config::upgrade; a V3
+// later only needs Previous = Config and its own upgrade. Old versions stay
+// readable as long as their type is kept around, new values are always
+// written with the current VERSION. Blob layout: [version: u8][C's encoding].
+
+use crate::codec::{with_overhead, Codec};
+use core::marker::PhantomData;
+
+/// One version of a stored type, see the top of the file
+pub trait Schema: Sized {
+    /// Written in front of every blob, higher than Previous::VERSION
+    const VERSION: u8;
+    /// The type of the version before, Initial for the first one
+    type Previous;
+    fn upgrade(old: Self::Previous) -> Self;
+}
+
+/// Previous of the first version, there is nothing before it
+pub enum Initial {}
+
+/// Types C can decode at some version of the chain and upgrade to Self
+pub trait Upgrade<C> {
+    /// Decode body written at version, None if that didn't work
+    fn decode_old(version: u8, body: &[u8]) -> Option<Self>
+    where
+        Self: Sized;
+}
+
+impl<C> Upgrade<C> for Initial {
+    fn decode_old(_: u8, _: &[u8]) -> Option<Self> {
+        None
+    }
+}
+
+impl<C, T> Upgrade<C> for T
+where
+    T: Schema,
+    C: Codec<T>,
+    T::Previous: Upgrade<C>,
+{
+    fn decode_old(version: u8, body: &[u8]) -> Option<Self> {
+        if version == T::VERSION {
+            C::decode(body).ok()
+        } else if version < T::VERSION {
+            T::Previous::decode_old(version, body).map(T::upgrade)
+        } else {
+            None
+        }
+    }
+}
+
+pub enum VersionedError<E> {
+    Inner(E),
+    // A blob without even the version byte, or no room to write it
+    Empty,
+    // Written by newer firmware
+    TooNew(u8),
+    // An older version no type of the chain decodes
+    Upgrade(u8),
+}
+
+/// C's encoding with a Schema version in front
+pub struct Versioned<C>(PhantomData<C>);
+
+impl<T, C> Codec<T> for Versioned<C>
+where
+    T: Schema,
+    C: Codec<T>,
+    T::Previous: Upgrade<C>,
+{
+    type Error = VersionedError<C::Error>;
+    const MAX_ENCODED_SIZE: Option<usize> = with_overhead(C::MAX_ENCODED_SIZE, 1);
+
+    fn encode(dst: &mut [u8], v: &T) -> Result<usize, Self::Error> {
+        let (version, body) = dst.split_first_mut().ok_or(VersionedError::Empty)?;
+        *version = T::VERSION;
+        Ok(1 + C::encode(body, v).map_err(VersionedError::Inner)?)
+    }
+
+    fn decode(src: &[u8]) -> Result<T, Self::Error> {
+        let (version, body) = src.split_first().ok_or(VersionedError::Empty)?;
+        match *version {
+            v if v == T::VERSION => C::decode(body).map_err(VersionedError::Inner),
+            v if v > T::VERSION => Err(VersionedError::TooNew(v)),
+            v => T::Previous::decode_old(v, body)
+                .map(T::upgrade)
+                .ok_or(VersionedError::Upgrade(v)),
+        }
+    }
+
+    fn preview(src: &[u8], out: &mut dyn core::fmt::Write) -> core::fmt::Result {
+        match src.split_first() {
+            Some((version, body)) => {
+                write!(out, "v{} ", version)?;
+                C::preview(body, out)
+            }
+            None => Ok(()),
+        }
+    }
+}
@@ -1,16 +1,5093 @@
 #![no_std]
 #![no_main]
 
+use core::future::Future;
+use core::pin::pin;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+use core::task::{Context, Poll, Waker};
 use embedded_db as _; // memory layout + panic handler
+use embedded_db::cache::CachePolicy;
+use embedded_db::canopen::OdEntry;
+use embedded_db::clock::Clock;
+use embedded_db::codec::{Codec, Postcard};
+use embedded_db::compress::Dictionary;
+use embedded_db::crypto::ValueKey;
+use embedded_db::db::Database;
+use embedded_db::entropy::Entropy;
+use embedded_db::flash::{SoftDeviceFlashOps, NRF_ERROR_BUSY, NRF_SUCCESS};
+use embedded_db::modbus::RegisterMapping;
+use embedded_db::mqtt::DiscoveryEntry;
+use embedded_db::transfer::{Receiver, Transport, MAX_FRAME};
+use embedded_db::units::{KeyUnit, Unit};
+use embedded_db::versioned::{Initial, Schema};
+use embedded_storage::nor_flash::{
+    ErrorType, NorFlash, NorFlashError, NorFlashErrorKind, ReadNorFlash,
+};
+use heapless::String;
+use nrf52840_hal::pac;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+// NOR flash in RAM, four 4 KiB pages like the nRF52840's: erase sets bytes
+// to 0xFF, writes can only clear bits
+pub struct RamFlash {
+    pub bytes: [u8; 4 * 4096],
+}
+
+impl RamFlash {
+    pub fn erased() -> Self {
+        RamFlash {
+            bytes: [0xFF; 4 * 4096],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct OutOfRange;
+
+impl NorFlashError for OutOfRange {
+    fn kind(&self) -> NorFlashErrorKind {
+        NorFlashErrorKind::OutOfBounds
+    }
+}
+
+impl ErrorType for RamFlash {
+    type Error = OutOfRange;
+}
+
+impl ReadNorFlash for RamFlash {
+    const READ_SIZE: usize = 1;
+
+    fn read(&mut self, offset: u32, bytes: &mut [u8]) -> Result<(), OutOfRange> {
+        let at = offset as usize;
+        bytes.copy_from_slice(self.bytes.get(at..at + bytes.len()).ok_or(OutOfRange)?);
+        Ok(())
+    }
+
+    fn capacity(&self) -> usize {
+        self.bytes.len()
+    }
+}
+
+impl NorFlash for RamFlash {
+    const WRITE_SIZE: usize = 4;
+    const ERASE_SIZE: usize = 4096;
+
+    fn erase(&mut self, from: u32, to: u32) -> Result<(), OutOfRange> {
+        let range = from as usize..to as usize;
+        self.bytes.get_mut(range).ok_or(OutOfRange)?.fill(0xFF);
+        Ok(())
+    }
+
+    fn write(&mut self, offset: u32, bytes: &[u8]) -> Result<(), OutOfRange> {
+        let at = offset as usize;
+        let dst = self.bytes.get_mut(at..at + bytes.len()).ok_or(OutOfRange)?;
+        for (d, s) in dst.iter_mut().zip(bytes) {
+            *d &= *s;
+        }
+        Ok(())
+    }
+}
+
+// First page of the last 64 KiB, which FlashStorage reports as its capacity.
+// Tests that drive the NVMC erase and write it.
+pub const TEST_PAGE: u32 = 0x000F_0000;
+
+pub fn nvmc() -> pac::NVMC {
+    // Each test takes the NVMC again, none of them keeps it
+    unsafe { pac::Peripherals::steal() }.NVMC
+}
+
+// Two holding register pairs and one input register pair
+pub const REGISTERS: &[RegisterMapping<u16>] = &[
+    RegisterMapping::holding(0, 1),
+    RegisterMapping::holding(2, 2),
+    RegisterMapping::input(0, 3),
+];
+
+// import() reader over a stream in RAM
+pub fn take(stream: &mut &[u8], buf: &mut [u8]) -> Result<(), OutOfRange> {
+    if stream.len() < buf.len() {
+        return Err(OutOfRange);
+    }
+    let (head, tail) = stream.split_at(buf.len());
+    buf.copy_from_slice(head);
+    *stream = tail;
+    Ok(())
+}
+
+// A writable setpoint and a read-only serial number
+pub const OD: &[OdEntry<u16>] = &[OdEntry::rw(0x2000, 1, 1), OdEntry::ro(0x2001, 0, 2)];
+
+// Counts up so every nonce differs, good enough for a test
+pub struct CountingEntropy(pub u8);
+
+impl Entropy for CountingEntropy {
+    fn fill_bytes(&mut self, dst: &mut [u8]) {
+        for b in dst {
+            self.0 = self.0.wrapping_add(1);
+            *b = self.0;
+        }
+    }
+}
+
+pub const EXPOSED: &[DiscoveryEntry<u16>] = &[
+    DiscoveryEntry::new(1, "number", "setpoint", "Setpoint"),
+    DiscoveryEntry::new(2, "sensor", "temp", "Temperature"),
+];
+
+pub const UNITS: &[KeyUnit<u16>] = &[
+    KeyUnit::new(1, Unit::Celsius),
+    KeyUnit::new(2, Unit::Millivolt),
+];
+
+// Stands in for the SoftDevice and records what it was asked to do
+#[derive(Default)]
+pub struct FakeSoftDevice {
+    // Starts that report NRF_ERROR_BUSY before one goes through
+    pub busy: u32,
+    // Flash events that report NRF_EVT_FLASH_OPERATION_ERROR
+    pub failed_events: u32,
+    pub erased: heapless::Vec<u32, 8>,
+    pub written: heapless::Vec<(u32, usize), 8>,
+}
+
+impl FakeSoftDevice {
+    fn start(&mut self) -> bool {
+        if self.busy > 0 {
+            self.busy -= 1;
+            return false;
+        }
+        true
+    }
+}
+
+impl SoftDeviceFlashOps for &mut FakeSoftDevice {
+    fn flash_write(&mut self, addr: u32, words: &[u32]) -> u32 {
+        if !self.start() {
+            return NRF_ERROR_BUSY;
+        }
+        self.written.push((addr, words.len())).unwrap();
+        NRF_SUCCESS
+    }
+
+    fn flash_page_erase(&mut self, page: u32) -> u32 {
+        if !self.start() {
+            return NRF_ERROR_BUSY;
+        }
+        self.erased.push(page).unwrap();
+        NRF_SUCCESS
+    }
+
+    fn wait_flash_event(&mut self) -> bool {
+        if self.failed_events > 0 {
+            self.failed_events -= 1;
+            return false;
+        }
+        true
+    }
+}
+
+// Supply state for the set_supply_check tests
+pub static SUPPLY_OK: AtomicBool = AtomicBool::new(true);
+
+pub fn test_supply() -> bool {
+    SUPPLY_OK.load(Ordering::Relaxed)
+}
+
+pub fn skey(s: &str) -> String<32> {
+    String::try_from(s).unwrap()
+}
+
+// "cal" and "user" entries, the source of the namespace import tests
+pub fn namespaced_export() -> heapless::Vec<u8, 128> {
+    let mut src: Database<String<32>, u32, Postcard, 8, 16, 2> = Database::new();
+    src.put(skey("cal:a"), 1).unwrap();
+    src.put(skey("cal:b"), 2).unwrap();
+    src.put(skey("user:x"), 3).unwrap();
+    let mut stream = heapless::Vec::new();
+    src.export_namespace("cal", |bytes| stream.extend_from_slice(bytes).unwrap())
+        .unwrap();
+    stream
+}
+
+pub fn namespaced_target() -> Database<String<32>, u32, Postcard, 8, 16, 2> {
+    let mut db = Database::new();
+    db.put(skey("cal:a"), 9).unwrap();
+    db.put(skey("cal:c"), 7).unwrap();
+    db.put(skey("user:x"), 4).unwrap();
+    db
+}
+
+// Keys 100 and up hold crash reports and the like
+pub fn critical(key: &u16) -> bool {
+    *key >= 100
+}
+
+// The fake transports never wait, so this just polls until done
+pub fn block_on<F: Future>(fut: F) -> F::Output {
+    let mut fut = pin!(fut);
+    let mut cx = Context::from_waker(Waker::noop());
+    loop {
+        if let Poll::Ready(out) = fut.as_mut().poll(&mut cx) {
+            return out;
+        }
+    }
+}
+
+// Hands every frame straight to a Receiver, losing some on the way
+pub struct Loopback<'a> {
+    pub rx: &'a mut Receiver,
+    pub flash: &'a mut RamFlash,
+    // Every drop_every-th frame gets lost (0 = none)
+    pub drop_every: usize,
+    pub frames: usize,
+    pub done: Option<usize>,
+    reply: [u8; MAX_FRAME],
+    reply_len: usize,
+}
+
+impl<'a> Loopback<'a> {
+    pub fn new(rx: &'a mut Receiver, flash: &'a mut RamFlash, drop_every: usize) -> Self {
+        Self {
+            rx,
+            flash,
+            drop_every,
+            frames: 0,
+            done: None,
+            reply: [0; MAX_FRAME],
+            reply_len: 0,
+        }
+    }
+}
+
+impl Transport for Loopback<'_> {
+    type Error = ();
+
+    async fn send(&mut self, frame: &[u8]) -> Result<(), ()> {
+        self.frames += 1;
+        if self.drop_every != 0 && self.frames.is_multiple_of(self.drop_every) {
+            return Err(());
+        }
+        let (n, done) = self
+            .rx
+            .handle(self.flash, frame, &mut self.reply)
+            .map_err(|_| ())?;
+        self.reply_len = n;
+        self.done = self.done.or(done);
+        Ok(())
+    }
+
+    async fn recv(&mut self, buf: &mut [u8]) -> Result<usize, ()> {
+        let n = core::mem::take(&mut self.reply_len);
+        if n == 0 {
+            return Err(());
+        }
+        buf[..n].copy_from_slice(&self.reply[..n]);
+        Ok(n)
+    }
+}
+
+// Fake microsecond clock, every reading is 10 us after the one before
+pub static NOW_US: AtomicU32 = AtomicU32::new(0);
+
+pub fn test_clock() -> u64 {
+    NOW_US.fetch_add(10, Ordering::Relaxed) as u64 + 10
+}
+
+// Cache policy that keeps whatever got cached first
+pub struct NeverEvict;
+
+impl CachePolicy<u16> for NeverEvict {
+    fn touch(&mut self, _: &u16) {}
+    fn removed(&mut self, _: &u16) {}
+    fn clear(&mut self) {}
+    fn victim(&mut self) -> Option<u16> {
+        None
+    }
+}
+
+// Postcard, counting how often values get decoded
+pub static DECODES: AtomicU32 = AtomicU32::new(0);
+
+pub struct CountingPostcard;
+
+impl<T: Serialize + DeserializeOwned> Codec<T> for CountingPostcard {
+    type Error = postcard::Error;
+
+    fn encode(dst: &mut [u8], v: &T) -> Result<usize, Self::Error> {
+        Postcard::encode(dst, v)
+    }
+
+    fn decode(src: &[u8]) -> Result<T, Self::Error> {
+        DECODES.fetch_add(1, Ordering::Relaxed);
+        Postcard::decode(src)
+    }
+}
+
+// Dictionary for the Compressed tests, typical words of the values
+pub struct Words;
+impl Dictionary for Words {
+    const ID: u8 = 2;
+    const BYTES: &'static [u8] = b"temperature humidity pressure";
+}
+
+// A value with named fields, for update_field
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Setpoint {
+    temp_c10: i16,
+    fan: bool,
+}
+
+// Clock the TTL tests move by hand
+pub static TICKS: AtomicU32 = AtomicU32::new(0);
+
+pub struct TestTicks;
+impl Clock for TestTicks {
+    fn now() -> u64 {
+        TICKS.load(Ordering::Relaxed) as u64
+    }
+}
+
+// An f32 field stored as Milli, see fixed.rs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Probe {
+    #[serde(with = "embedded_db::fixed::milli")]
+    temp_c: f32,
+}
+
+// A key with an order but no Hash impl, for SortedStore
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct SensorId(u8, u8);
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Reading {
+    id: u16,
+    temp: i32,
+    ok: bool,
+    name: heapless::String<8>,
+    scale: f32,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum Mode {
+    Off,
+    Level(u8),
+    Span { lo: i8, hi: i8 },
+}
+
+pub fn reading() -> Reading {
+    Reading {
+        id: 7,
+        temp: -40,
+        ok: true,
+        name: heapless::String::try_from("probe").unwrap(),
+        scale: 0.5,
+    }
+}
+
+// Encodes v, checks the bytes and decodes them back to v
+pub fn msgpack_vector<T>(v: T, bytes: &[u8])
+where
+    T: Serialize + DeserializeOwned + PartialEq,
+{
+    let mut buf = [0u8; 64];
+    let n = embedded_db::msgpack::to_slice(&v, &mut buf).unwrap();
+    defmt::assert_eq!(&buf[..n], bytes);
+    defmt::assert!(embedded_db::msgpack::from_slice::<T>(bytes).ok() == Some(v));
+}
+
+// Fixed key and nonce, so Encrypted output can be compared with a vector
+pub struct TestKey;
+
+impl ValueKey for TestKey {
+    fn key() -> [u8; 32] {
+        [0x42; 32]
+    }
+    fn nonce() -> [u8; 12] {
+        [0x07; 12]
+    }
+}
+
+// Two versions of a stored struct, see versioned.rs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ConfigV1 {
+    interval_s: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Config {
+    interval_s: u32,
+    retries: u8,
+}
+
+impl Schema for ConfigV1 {
+    const VERSION: u8 = 1;
+    type Previous = Initial;
+    fn upgrade(never: Initial) -> Self {
+        match never {}
+    }
+}
+
+impl Schema for Config {
+    const VERSION: u8 = 2;
+    type Previous = ConfigV1;
+    fn upgrade(old: ConfigV1) -> Self {
+        Config {
+            interval_s: old.interval_s as u32,
+            retries: 3,
+        }
+    }
+}
+
+// The struct of the example in tlv.rs
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Climate {
+    temp_c10: i16,
+    rh_pct: u8,
+    label: Option<heapless::String<8>>,
+}
 
 // See https://crates.io/crates/defmt-test/0.3.0 for more documentation (e.g. about the 'state'
 // feature)
 #[defmt_test::tests]
 mod tests {
-    use defmt::assert;
+    use super::{
+        block_on, critical, msgpack_vector, namespaced_export, namespaced_target, nvmc, reading,
+        skey, take, test_clock, test_supply, Climate, Config, ConfigV1, CountingEntropy,
+        CountingPostcard, FakeSoftDevice, Loopback, Mode, NeverEvict, Probe, RamFlash, Reading,
+        SensorId, Setpoint, TestKey, TestTicks, Words, DECODES, EXPOSED, OD, REGISTERS, SUPPLY_OK,
+        TEST_PAGE, TICKS, UNITS,
+    };
+    use core::sync::atomic::Ordering;
+    use defmt::{assert, assert_eq, assert_ne};
+    use embedded_db::cache::Fifo;
+    use embedded_db::cal::{CalCodec, CalError, CalTable};
+    use embedded_db::canopen::{ObjectDictionary, SdoAbort};
+    use embedded_db::cli::{CliError, DbCommand};
+    use embedded_db::codec::{
+        BorrowedCodec, Checked, CheckedError, Codec, CodecErrorKind, Encrypted, EncryptedError,
+        FieldCodec, FieldError, Format, Json, Multi, MultiError, Postcard, StreamError, Tagged,
+        Tlv, STREAM_CHUNK, STREAM_STAGING,
+    };
+    use embedded_db::compress::{self, CompressError, Compressed, Dictionary, MAX_RAW};
+    use embedded_db::crc16::CRC16;
+    use embedded_db::crypto::{CryptoError, HmacSha256, SoftwareCcm};
+    use embedded_db::db::{
+        self, Database, DbError, FlashError, FlashProgress, ImageSource, ImportPolicy, LoadTiming,
+        TxnError, MAX_COMPUTED, MAX_TXN_OPS,
+    };
+    use embedded_db::delta::{Delta, DeltaError};
+    use embedded_db::emergency::{self, emergency_put_bytes, Partition};
+    use embedded_db::entropy::{Entropy, HardwareRng};
+    use embedded_db::errcode;
+    use embedded_db::fixed::{Fixed, FixedError, Milli, Q16};
+    use embedded_db::flags::{self, Flag, FlagError};
+    use embedded_db::flash::{
+        self, FlashError as StorageError, FlashStorage, LayoutError, SoftDeviceFlash, PAGE_SIZE,
+        SD_FLASH_RETRIES,
+    };
+    use embedded_db::frames;
+    use embedded_db::geo::{GeoCodec, GeoError, GeoPoint, Geofence};
+    use embedded_db::history::History;
+    use embedded_db::hmi::Pager;
+    use embedded_db::hybrid::HybridTime;
+    use embedded_db::image::{
+        self, Footer, ImageHeader, Sealing, FOOTER_SIZE, HEADER_SIZE, MAX_IMAGE_LEN,
+    };
+    use embedded_db::init::{self, ScratchTest};
+    use embedded_db::keycodec;
+    use embedded_db::keys::{KeyError, KeyPolicy};
+    use embedded_db::kv::{ArenaStore, BlobStore, KvStore, SortedStore, StoreError};
+    use embedded_db::l10n::{L10nError, Translations};
+    use embedded_db::lazy::LazyDatabase;
+    use embedded_db::list::List;
+    use embedded_db::maintenance::{
+        Maintenance, MaintenancePolicy, MaintenanceStep, PersistPolicy,
+    };
+    use embedded_db::meta::{Meta, Stamped};
+    use embedded_db::modbus::{ModbusError, RegisterKind, RegisterMap};
+    use embedded_db::mqtt::{Discovery, DiscoveryError, MAX_TOPIC};
+    use embedded_db::msgpack::{self, MsgPackError};
+    use embedded_db::namespace::{self, NamespaceError};
+    use embedded_db::power::{self, LowPowerSaver, PowerStep};
+    use embedded_db::queue::Queue;
+    use embedded_db::ram::RamDb;
+    use embedded_db::schedule::{Schedule, ScheduleError, Weekday};
+    use embedded_db::timeseries::{Retention, TimeSeries, TimeSeriesError};
+    use embedded_db::tlv::{self, TlvError};
+    use embedded_db::transfer::{self, Receiver, TransferError, CHUNK_SIZE, MAX_FRAME};
+    use embedded_db::units::{Quantity, Unit, UnitError, UnitMap};
+    use embedded_db::versioned::{Versioned, VersionedError};
+    use embedded_storage::nor_flash::{NorFlash, ReadNorFlash};
+    use heapless::String;
+    use nrf52840_hal::pac;
+    use nrf52840_hal::pac::power::pofcon::THRESHOLD_A;
 
     #[test]
     fn it_works() {
         assert!(true)
     }
+
+    #[test]
+    fn image_header_stamps_versions() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        // Nothing saved yet
+        assert!(matches!(db.open(&mut flash, 0), Ok(None)));
+
+        db.set_version_stamp(0x0001_0200, 0x1122_3344_5566_7788);
+        db.put(1, 10).unwrap();
+        db.put(2, 20).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        let header = ImageHeader::from_bytes(&flash.bytes[..HEADER_SIZE])
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.format_version, image::FORMAT_VERSION);
+        assert_eq!(header.app_version, 0x0001_0200);
+        assert_eq!(header.device_id, 0x1122_3344_5566_7788);
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        assert!(copy.open(&mut flash, 0).unwrap() == Some(header));
+        assert_eq!(copy.get(&2).unwrap(), Some(20));
+    }
+
+    #[test]
+    fn image_header_rejects_damage() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        let mut bad = RamFlash::erased();
+        bad.bytes = flash.bytes;
+        bad.bytes[HEADER_SIZE] ^= 0x01;
+        assert!(matches!(db.open(&mut bad, 0), Err(FlashError::CrcMismatch)));
+
+        bad.bytes = flash.bytes;
+        bad.bytes[0] = 0;
+        assert!(matches!(db.open(&mut bad, 0), Err(FlashError::BadHeader)));
+
+        // A format version from newer firmware
+        bad.bytes = flash.bytes;
+        bad.bytes[4] = 0xFF;
+        assert!(matches!(
+            db.open(&mut bad, 0),
+            Err(FlashError::UnsupportedVersion)
+        ));
+    }
+
+    #[test]
+    fn partial_erase_in_slices() {
+        let mut flash = FlashStorage::new(nvmc());
+        let page_end = TEST_PAGE + PAGE_SIZE as u32;
+        flash.write(TEST_PAGE, &[0x00; 8]).unwrap();
+
+        // Unaligned ranges are refused and nothing is started
+        assert!(flash.begin_partial_erase(TEST_PAGE + 4, page_end) == Err(StorageError::Unaligned));
+        assert!(!flash.partial_erase_pending());
+
+        // 85 ms in 20 ms slices takes five steps
+        flash.set_erase_slice_ms(20);
+        flash.begin_partial_erase(TEST_PAGE, page_end).unwrap();
+        let mut steps = 1;
+        while !flash.erase_partial_step().unwrap() {
+            assert!(flash.partial_erase_pending());
+            steps += 1;
+        }
+        assert_eq!(steps, 5);
+        assert!(!flash.partial_erase_pending());
+
+        let mut word = [0u8; 8];
+        flash.read(TEST_PAGE, &mut word).unwrap();
+        assert_eq!(word, [0xFF; 8]);
+        // Nothing left to do
+        assert!(flash.erase_partial_step().unwrap());
+    }
+
+    #[test]
+    fn hardware_rng_fills_bytes() {
+        let mut rng = HardwareRng::new(unsafe { pac::Peripherals::steal() }.RNG);
+        let mut a = [0u8; 32];
+        let mut b = [0u8; 32];
+        rng.fill_bytes(&mut a);
+        rng.fill_bytes(&mut b);
+        assert!(a != b);
+        assert!(a != [0; 32]);
+
+        // Odd lengths are filled too
+        let mut odd = [0u8; 5];
+        rng.fill_bytes(&mut odd);
+        assert!(odd != [0; 5]);
+        assert!(rng.next_u32() != rng.next_u32());
+    }
+
+    #[test]
+    fn maintenance_scrubs_and_repairs() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        let mut maintenance = Maintenance::new(MaintenancePolicy {
+            flash_offset: 0,
+            scrub_every: 2,
+            autosave_every: 0,
+        });
+        assert_eq!(
+            maintenance.step(&mut db, &mut flash).unwrap(),
+            MaintenanceStep::Idle
+        );
+        assert_eq!(
+            maintenance.step(&mut db, &mut flash).unwrap(),
+            MaintenanceStep::Scrubbed
+        );
+
+        // A flipped bit is rewritten from RAM by the next scrub
+        flash.bytes[HEADER_SIZE + 4] ^= 0x01;
+        assert!(matches!(
+            image::verify(&mut flash, 0),
+            Err(FlashError::CrcMismatch)
+        ));
+        assert_eq!(
+            maintenance.step(&mut db, &mut flash).unwrap(),
+            MaintenanceStep::Idle
+        );
+        assert_eq!(
+            maintenance.step(&mut db, &mut flash).unwrap(),
+            MaintenanceStep::Repaired
+        );
+        assert!(image::verify(&mut flash, 0).unwrap().is_some());
+    }
+
+    #[test]
+    fn maintenance_autosaves_and_reports_flash_errors() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+
+        let mut maintenance = Maintenance::new(MaintenancePolicy {
+            flash_offset: 0,
+            scrub_every: 0,
+            autosave_every: 1,
+        });
+        assert_eq!(
+            maintenance.step(&mut db, &mut flash).unwrap(),
+            MaintenanceStep::Saved
+        );
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.get(&1).unwrap(), Some(10));
+
+        // An image past the end of the flash can't be read
+        let mut far = Maintenance::new(MaintenancePolicy {
+            flash_offset: 0x1_0000,
+            scrub_every: 1,
+            autosave_every: 0,
+        });
+        assert!(matches!(
+            far.step(&mut db, &mut flash),
+            Err(FlashError::ReadError)
+        ));
+    }
+
+    #[test]
+    fn scrub_rewrites_an_erased_image() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        let mut maintenance = Maintenance::new(MaintenancePolicy {
+            flash_offset: 0,
+            scrub_every: 1,
+            autosave_every: 0,
+        });
+        // The page was erased under the database, that's as lost as a bad CRC
+        flash.bytes[..4096].fill(0xFF);
+        assert!(image::verify(&mut flash, 0).unwrap().is_none());
+        assert_eq!(
+            maintenance.step(&mut db, &mut flash).unwrap(),
+            MaintenanceStep::Repaired
+        );
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.get(&1).unwrap(), Some(10));
+
+        // Nothing was ever saved at the other offset, erased is fine there
+        let mut other = Maintenance::new(MaintenancePolicy {
+            flash_offset: 0x2000,
+            scrub_every: 1,
+            autosave_every: 0,
+        });
+        assert_eq!(
+            other.step(&mut db, &mut flash).unwrap(),
+            MaintenanceStep::Scrubbed
+        );
+        assert!(flash.bytes[0x2000..].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn verify_bounds_the_payload_length() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        // A length past the largest image isn't read at all
+        let mut header = ImageHeader::from_bytes(&flash.bytes[..HEADER_SIZE])
+            .unwrap()
+            .unwrap();
+        header.payload_len = 0x4000;
+        flash.bytes[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+        assert!(matches!(
+            image::verify(&mut flash, 0),
+            Err(FlashError::BadHeader)
+        ));
+        let mut maintenance = Maintenance::new(MaintenancePolicy {
+            flash_offset: 0,
+            scrub_every: 1,
+            autosave_every: 0,
+        });
+        assert_eq!(
+            maintenance.step(&mut db, &mut flash).unwrap(),
+            MaintenanceStep::Repaired
+        );
+        assert!(image::verify(&mut flash, 0).unwrap().is_some());
+    }
+
+    #[test]
+    fn save_and_load_report_progress() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        for k in 0..3 {
+            db.put(k, k as u32 * 100).unwrap();
+        }
+
+        let mut calls = 0;
+        let mut last = FlashProgress::default();
+        db.save_to_flash_with_progress(&mut flash, 4, 0, |p| {
+            calls += 1;
+            last = p;
+        })
+        .unwrap();
+        // Three entries, then the erase and write steps
+        assert!(calls > 3);
+        assert_eq!((last.entries_processed, last.entries_total), (3, 3));
+        assert_eq!(last.pages_erased, last.pages_total);
+        assert_eq!(last.bytes_written, last.bytes_total);
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut loaded = 0;
+        copy.load_from_flash_with_progress(&mut flash, 0, |p| loaded = p.entries_processed)
+            .unwrap();
+        assert_eq!(loaded, 3);
+        assert_eq!(copy.get(&2).unwrap(), Some(200));
+
+        // An erase past the end of the flash fails before anything is written
+        let mut calls = 0;
+        let end = flash.bytes.len() as u32;
+        assert!(matches!(
+            db.save_to_flash_with_progress(&mut flash, 4, end, |_| calls += 1),
+            Err(FlashError::EraseError)
+        ));
+        assert_eq!(calls, 3);
+    }
+
+    #[test]
+    fn pager_pages_through_entries() {
+        let mut db: Database<u32, u32, Json, 8, 16, 2> = Database::new();
+        assert!(db.put(1, 10).is_ok());
+        assert!(db.put(2, 20).is_ok());
+        assert!(db.put(1234567, 30000).is_ok());
+
+        let mut pager: Pager<10, 2> = Pager::new();
+        assert_eq!(pager.page_count(&db), 2);
+        let lines = pager.lines(&db);
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0].as_str(), "1:10");
+        assert_eq!(lines[1].as_str(), "2:20");
+
+        // Stays on the last page. Keys get half the width, the value what is left
+        pager.page_down(&db);
+        pager.page_down(&db);
+        assert_eq!(pager.page(), 1);
+        assert_eq!(pager.lines(&db)[0].as_str(), "12345:3000");
+
+        // The page is pulled back once its entries are gone
+        assert!(db.delete(&1234567));
+        assert_eq!(pager.lines(&db).len(), 2);
+        assert_eq!(pager.page(), 0);
+        pager.page_up();
+        assert_eq!(pager.page(), 0);
+
+        // Codecs that aren't text are shown as hex
+        let mut raw: Database<u32, u32, Postcard, 8, 16, 2> = Database::new();
+        raw.put(7, 300).unwrap();
+        assert_eq!(Pager::<10, 2>::new().lines(&raw)[0].as_str(), "7:ac02");
+    }
+
+    #[test]
+    fn lazy_database_reads_values_on_demand() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 300).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        let mut lazy: LazyDatabase<u16, u32, Postcard, 8, 16, 2> = LazyDatabase::new();
+        assert!(lazy.load_from_flash(&mut flash, 0).unwrap().is_some());
+        assert_eq!(lazy.len(), 2);
+        // Only the index is in RAM, the value is still in flash
+        let at = lazy.value_ref(&2).unwrap();
+        assert_eq!(at.len, 2);
+        assert_eq!(&flash.bytes[at.offset as usize..][..2], &[0xac, 0x02]);
+        assert_eq!(lazy.get(&mut flash, &2).unwrap(), Some(300));
+        assert_eq!(lazy.get(&mut flash, &3).unwrap(), None);
+    }
+
+    #[test]
+    fn lazy_database_refuses_bad_images() {
+        let mut flash = RamFlash::erased();
+        let mut lazy: LazyDatabase<u16, u32, Postcard, 8, 16, 2> = LazyDatabase::new();
+        assert!(matches!(lazy.load_from_flash(&mut flash, 0), Ok(None)));
+
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        flash.bytes[HEADER_SIZE + 4] ^= 0x01;
+        assert!(matches!(
+            lazy.load_from_flash(&mut flash, 0),
+            Err(FlashError::CrcMismatch)
+        ));
+        assert!(lazy.is_empty());
+    }
+
+    #[test]
+    fn get_in_flash_reads_mapped_flash() {
+        let mut flash = FlashStorage::new(nvmc());
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        // Nothing persisted yet
+        assert!(unsafe { db.get_in_flash(&1) }.is_none());
+
+        db.put(1, 300).unwrap();
+        db.save_to_flash(&mut flash, 4, TEST_PAGE).unwrap();
+        // Puts after the save aren't in flash
+        db.put(1, 7).unwrap();
+        db.put(2, 20).unwrap();
+        assert_eq!(unsafe { db.get_in_flash(&1) }, Some(&[0xac, 0x02][..]));
+        assert!(unsafe { db.get_in_flash(&2) }.is_none());
+    }
+
+    #[test]
+    fn cli_runs_db_commands() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut out: heapless::String<128> = heapless::String::new();
+        for args in [
+            &["set", "7", "300"][..],
+            &["get", "7"],
+            &["list"],
+            &["save"],
+        ] {
+            let cmd = DbCommand::parse(args).unwrap();
+            cmd.run(&mut db, &mut flash, 0, &mut out).unwrap();
+        }
+        assert_eq!(
+            out.as_str(),
+            "ok\r\n300\r\n7 = 300\r\n1/8 entries\r\nsaved\r\n"
+        );
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.get(&7).unwrap(), Some(300));
+    }
+
+    #[test]
+    fn cli_rejects_bad_commands() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut out: heapless::String<128> = heapless::String::new();
+        assert!(matches!(
+            DbCommand::parse(&["erase"]),
+            Err(CliError::UnknownCommand)
+        ));
+        assert!(matches!(
+            DbCommand::parse(&["set", "7"]),
+            Err(CliError::MissingArgument)
+        ));
+
+        let mut run = |args: &[&str]| {
+            let cmd = DbCommand::parse(args).unwrap();
+            cmd.run(&mut db, &mut flash, 0, &mut out)
+        };
+        assert!(matches!(run(&["get", "x"]), Err(CliError::BadKey)));
+        assert!(matches!(run(&["get", "9"]), Err(CliError::NotFound)));
+        assert!(matches!(
+            run(&["set", "7", "\"abc\""]),
+            Err(CliError::BadValue)
+        ));
+        assert!(out.is_empty());
+        assert_eq!(db.len(), 0);
+    }
+
+    #[test]
+    fn modbus_maps_u32_to_register_pairs() {
+        let map = RegisterMap::new(REGISTERS);
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 100_000).unwrap();
+        db.put(3, 42).unwrap();
+
+        // High word first, key 2 was never stored
+        let mut regs = [0u16; 4];
+        map.read(&mut db, RegisterKind::Holding, 0, &mut regs)
+            .unwrap();
+        assert_eq!(regs, [0x0001, 0x86A0, 0, 0]);
+        let mut input = [0u16; 2];
+        map.read(&mut db, RegisterKind::Input, 0, &mut input)
+            .unwrap();
+        assert_eq!(input, [0, 42]);
+
+        // Writing only the low word keeps the high word
+        map.write(&mut db, 1, &[0x1234]).unwrap();
+        assert_eq!(db.get(&1).unwrap(), Some(0x0001_1234));
+        map.write(&mut db, 2, &[0xABCD, 0x0001]).unwrap();
+        assert_eq!(db.get(&2).unwrap(), Some(0xABCD_0001));
+    }
+
+    #[test]
+    fn modbus_rejects_unmapped_registers() {
+        let map = RegisterMap::new(REGISTERS);
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut regs = [0u16; 5];
+        assert_eq!(
+            map.read(&mut db, RegisterKind::Holding, 0, &mut regs),
+            Err(ModbusError::IllegalDataAddress)
+        );
+        assert_eq!(
+            map.read(&mut db, RegisterKind::Input, 0, &mut []),
+            Err(ModbusError::IllegalDataValue)
+        );
+        // Only holding registers can be written
+        assert_eq!(
+            map.write(&mut db, 3, &[1, 2]),
+            Err(ModbusError::IllegalDataAddress)
+        );
+        assert_eq!(db.len(), 0);
+        assert_eq!(ModbusError::IllegalDataAddress.exception_code(), 0x02);
+    }
+
+    #[test]
+    fn export_import_round_trip() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 300).unwrap();
+        let mut stream: heapless::Vec<u8, 128> = heapless::Vec::new();
+        db.export(|bytes| stream.extend_from_slice(bytes).unwrap())
+            .unwrap();
+        assert_eq!(&stream[..4], &image::SNAPSHOT_MAGIC.to_le_bytes());
+
+        // Import replaces what was there
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.put(9, 9).unwrap();
+        let mut rest = &stream[..];
+        copy.import(|buf| take(&mut rest, buf)).unwrap();
+        assert!(rest.is_empty());
+        assert_eq!(copy.len(), 2);
+        assert_eq!(copy.get(&2).unwrap(), Some(300));
+        assert_eq!(copy.get(&9).unwrap(), None);
+    }
+
+    #[test]
+    fn import_rejects_damaged_streams() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        let mut stream: heapless::Vec<u8, 128> = heapless::Vec::new();
+        db.export(|bytes| stream.extend_from_slice(bytes).unwrap())
+            .unwrap();
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.put(9, 9).unwrap();
+        let mut damaged = stream.clone();
+        // The last value byte, just before the CRC
+        let at = damaged.len() - 5;
+        damaged[at] ^= 0x01;
+        let mut rest = &damaged[..];
+        assert!(matches!(
+            copy.import(|buf| take(&mut rest, buf)),
+            Err(FlashError::CrcMismatch)
+        ));
+        // The old contents are kept
+        assert_eq!(copy.len(), 1);
+        assert_eq!(copy.get(&9).unwrap(), Some(9));
+
+        let mut short = &stream[..stream.len() - 2];
+        assert!(matches!(
+            copy.import(|buf| take(&mut short, buf)),
+            Err(FlashError::ReadError)
+        ));
+        let mut other = &stream[1..];
+        assert!(matches!(
+            copy.import(|buf| take(&mut other, buf)),
+            Err(FlashError::BadHeader)
+        ));
+        assert_eq!(copy.get(&9).unwrap(), Some(9));
+    }
+
+    #[test]
+    fn backup_region_takes_over_a_damaged_primary() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.set_backup_region(0, 0x2000, 4096).unwrap();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        assert_eq!(&flash.bytes[..64], &flash.bytes[0x2000..0x2040]);
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.set_backup_region(0, 0x2000, 4096).unwrap();
+        assert!(copy.open(&mut flash, 0).unwrap().is_some());
+        assert_eq!(copy.loaded_from(), Some(ImageSource::Primary));
+
+        // Power lost while the primary was being erased
+        flash.bytes[..0x1000].fill(0xFF);
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.set_backup_region(0, 0x2000, 4096).unwrap();
+        assert!(copy.open(&mut flash, 0).unwrap().is_some());
+        assert_eq!(copy.loaded_from(), Some(ImageSource::Backup));
+        assert_eq!(copy.get(&1).unwrap(), Some(10));
+    }
+
+    #[test]
+    fn backup_region_reports_the_primary_error() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.set_backup_region(0, 0x2000, 4096).unwrap();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        // A corrupt primary and an erased backup
+        flash.bytes[HEADER_SIZE + 4] ^= 0x01;
+        flash.bytes[0x2000..0x3000].fill(0xFF);
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.set_backup_region(0, 0x2000, 4096).unwrap();
+        assert!(matches!(
+            copy.open(&mut flash, 0),
+            Err(FlashError::CrcMismatch)
+        ));
+        assert_eq!(copy.loaded_from(), None);
+        assert_eq!(copy.len(), 0);
+    }
+
+    #[test]
+    fn canopen_sdo_round_trip() {
+        let od = ObjectDictionary::new(OD);
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        od.sdo_download(&mut db, 0x2000, 1, &[0x78, 0x56, 0x34, 0x12])
+            .unwrap();
+        assert_eq!(db.get(&1).unwrap(), Some(0x1234_5678));
+
+        let mut data = [0u8; 8];
+        let n = od.sdo_upload(&mut db, 0x2000, 1, &mut data).unwrap();
+        assert_eq!(&data[..n], &[0x78, 0x56, 0x34, 0x12]);
+        // Read-only entries can still be read
+        db.put(2, 42).unwrap();
+        let n = od.sdo_upload(&mut db, 0x2001, 0, &mut data).unwrap();
+        assert_eq!(&data[..n], &[42, 0, 0, 0]);
+    }
+
+    #[test]
+    fn canopen_sdo_aborts() {
+        let od = ObjectDictionary::new(OD);
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut data = [0u8; 4];
+        assert_eq!(
+            od.sdo_upload(&mut db, 0x2000, 1, &mut data),
+            Err(SdoAbort::NoDataAvailable)
+        );
+        assert_eq!(
+            od.sdo_upload(&mut db, 0x2000, 2, &mut data),
+            Err(SdoAbort::SubindexDoesNotExist)
+        );
+        assert_eq!(
+            od.sdo_upload(&mut db, 0x3000, 0, &mut data),
+            Err(SdoAbort::ObjectDoesNotExist)
+        );
+        assert_eq!(
+            od.sdo_download(&mut db, 0x2001, 0, &[1, 0, 0, 0]),
+            Err(SdoAbort::WriteToReadOnly)
+        );
+        assert_eq!(
+            od.sdo_download(&mut db, 0x2000, 1, &[1, 0]),
+            Err(SdoAbort::LengthMismatch)
+        );
+        assert_eq!(db.len(), 0);
+        assert_eq!(SdoAbort::WriteToReadOnly.code(), 0x0601_0002);
+    }
+
+    #[test]
+    fn encrypted_image_round_trip() {
+        let mut flash = RamFlash::erased();
+        let mut cipher = SoftwareCcm::new([0x42; 16], CountingEntropy(0));
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 0x1234_5678).unwrap();
+        db.save_to_flash_sealed(&mut flash, 4, 0, &mut cipher)
+            .unwrap();
+        // The value isn't in flash in the clear
+        assert!(!flash.bytes[..256]
+            .windows(4)
+            .any(|w| w == [0xf8, 0xac, 0xd1, 0x91]));
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        assert!(copy
+            .open_sealed(&mut flash, 0, &mut cipher)
+            .unwrap()
+            .is_some());
+        assert_eq!(copy.get(&1).unwrap(), Some(0x1234_5678));
+    }
+
+    #[test]
+    fn encrypted_image_needs_the_right_key() {
+        let mut flash = RamFlash::erased();
+        let mut cipher = SoftwareCcm::new([0x42; 16], CountingEntropy(0));
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.save_to_flash_sealed(&mut flash, 4, 0, &mut cipher)
+            .unwrap();
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        assert!(matches!(copy.open(&mut flash, 0), Err(FlashError::Sealed)));
+        let mut wrong = SoftwareCcm::new([0x24; 16], CountingEntropy(0));
+        assert!(matches!(
+            copy.open_sealed(&mut flash, 0, &mut wrong),
+            Err(FlashError::AuthenticationFailed)
+        ));
+
+        // A plain image can't stand in for a sealed one
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        assert!(matches!(
+            copy.open_sealed(&mut flash, 0, &mut cipher),
+            Err(FlashError::AuthenticationFailed)
+        ));
+        assert_eq!(copy.len(), 0);
+    }
+
+    #[test]
+    fn mqtt_discovery_messages() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 21).unwrap();
+        let mut msgs = Discovery::new(&mut db, EXPOSED, "homeassistant", "boiler_01");
+
+        let msg = msgs.next().unwrap().unwrap();
+        assert_eq!(
+            msg.topic.as_str(),
+            "homeassistant/number/boiler_01/setpoint/config"
+        );
+        assert_eq!(
+            msg.payload.as_str(),
+            r#"{"name":"Setpoint","unique_id":"boiler_01_setpoint","state_topic":"boiler_01/setpoint/state"}"#
+        );
+        assert!(msg.retain);
+        let msg = msgs.next().unwrap().unwrap();
+        assert_eq!(msg.topic.as_str(), "boiler_01/setpoint/state");
+        assert_eq!(msg.payload.as_str(), "21");
+
+        // No value stored for the sensor, so only its config
+        let msg = msgs.next().unwrap().unwrap();
+        assert_eq!(
+            msg.topic.as_str(),
+            "homeassistant/sensor/boiler_01/temp/config"
+        );
+        assert!(msgs.next().is_none());
+    }
+
+    #[test]
+    fn mqtt_discovery_rejects_long_topics() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let node = core::str::from_utf8(&[b'n'; MAX_TOPIC]).unwrap();
+        let mut msgs = Discovery::new(&mut db, EXPOSED, "homeassistant", node);
+        assert!(matches!(msgs.next(), Some(Err(DiscoveryError::TooLong))));
+    }
+
+    #[test]
+    fn authenticated_image_round_trip() {
+        let mut flash = RamFlash::erased();
+        let mut hmac = HmacSha256::new([7; 32]);
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.save_to_flash_sealed(&mut flash, 4, 0, &mut hmac)
+            .unwrap();
+        let header = ImageHeader::from_bytes(&flash.bytes[..HEADER_SIZE])
+            .unwrap()
+            .unwrap();
+        assert_eq!(header.sealing, Sealing::Authenticated);
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        assert!(copy
+            .open_sealed(&mut flash, 0, &mut hmac)
+            .unwrap()
+            .is_some());
+        assert_eq!(copy.get(&1).unwrap(), Some(10));
+
+        // Maintenance keeps the image sealed when it repairs it
+        let mut maintenance = Maintenance::new(MaintenancePolicy {
+            flash_offset: 0,
+            scrub_every: 1,
+            autosave_every: 0,
+        });
+        flash.bytes[HEADER_SIZE + 2] ^= 0x01;
+        assert_eq!(
+            maintenance
+                .step_sealed(&mut copy, &mut flash, &mut hmac)
+                .unwrap(),
+            MaintenanceStep::Repaired
+        );
+        assert!(copy
+            .open_sealed(&mut flash, 0, &mut hmac)
+            .unwrap()
+            .is_some());
+    }
+
+    #[test]
+    fn authenticated_image_catches_edits() {
+        let mut flash = RamFlash::erased();
+        let mut hmac = HmacSha256::new([7; 32]);
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.save_to_flash_sealed(&mut flash, 4, 0, &mut hmac)
+            .unwrap();
+
+        // Someone with SWD access changes the value and fixes up the CRC
+        let mut header = ImageHeader::from_bytes(&flash.bytes[..HEADER_SIZE])
+            .unwrap()
+            .unwrap();
+        let end = HEADER_SIZE + header.payload_len as usize;
+        let at = flash.bytes[HEADER_SIZE..end]
+            .iter()
+            .rposition(|&b| b == 10)
+            .unwrap();
+        flash.bytes[HEADER_SIZE + at] = 11;
+        header.payload_crc = image::CRC32.checksum(&flash.bytes[HEADER_SIZE..end]);
+        flash.bytes[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        assert!(matches!(
+            copy.open_sealed(&mut flash, 0, &mut hmac),
+            Err(FlashError::AuthenticationFailed)
+        ));
+        let mut wrong = HmacSha256::new([8; 32]);
+        db.save_to_flash_sealed(&mut flash, 4, 0, &mut hmac)
+            .unwrap();
+        assert!(matches!(
+            copy.open_sealed(&mut flash, 0, &mut wrong),
+            Err(FlashError::AuthenticationFailed)
+        ));
+        assert_eq!(copy.len(), 0);
+    }
+
+    #[test]
+    fn calibrate_interpolates_and_clamps() {
+        let table = CalTable::new([0.0, 512.0, 1023.0], [-40.0, 25.0, 125.0]).unwrap();
+        let mut db: Database<u16, CalTable<3>, CalCodec<3>, 4, 32, 1> = Database::new();
+        db.put(1, table).unwrap();
+        assert_eq!(db.calibrate(&1, 256.0), Ok(-7.5));
+        assert_eq!(db.calibrate(&1, 1023.0), Ok(125.0));
+        // Readings outside the table are clamped
+        assert_eq!(db.calibrate(&1, -100.0), Ok(-40.0));
+        assert_eq!(db.calibrate(&1, 2000.0), Ok(125.0));
+        assert_eq!(db.calibrate(&2, 256.0), Err(CalError::NotFound));
+
+        let mut buf = [0u8; 24];
+        assert_eq!(CalCodec::<3>::encode(&mut buf, &table), Ok(24));
+        assert_eq!(&buf[..4], &0f32.to_le_bytes());
+        assert!(CalCodec::<3>::decode(&buf) == Ok(table));
+    }
+
+    #[test]
+    fn cal_table_rejects_bad_points() {
+        assert_eq!(
+            CalTable::new([0.0, 512.0, 512.0], [0.0, 1.0, 2.0]).err(),
+            Some(CalError::NotMonotonic)
+        );
+        assert_eq!(
+            CalTable::new([0.0, f32::NAN], [0.0, 1.0]).err(),
+            Some(CalError::NotMonotonic)
+        );
+        assert_eq!(
+            CalTable::<1>::new([0.0], [0.0]).err(),
+            Some(CalError::NotMonotonic)
+        );
+
+        let table = CalTable::new([0.0, 1.0], [0.0, 1.0]).unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(
+            CalCodec::<2>::encode(&mut buf[..15], &table),
+            Err(CalError::BufferTooSmall)
+        );
+        CalCodec::<2>::encode(&mut buf, &table).unwrap();
+        assert_eq!(
+            CalCodec::<2>::decode(&buf[..8]).err(),
+            Some(CalError::BadLength)
+        );
+        // Swap the two raw values so they go down
+        buf.copy_within(0..4, 8);
+        buf[..4].copy_from_slice(&1f32.to_le_bytes());
+        assert_eq!(
+            CalCodec::<2>::decode(&buf).err(),
+            Some(CalError::NotMonotonic)
+        );
+    }
+
+    #[test]
+    fn secure_wipe_erases_both_copies() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.set_backup_region(0, 0x2000, 4096).unwrap();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        db.secure_wipe(&mut flash, Partition::new(0, 0x4000))
+            .unwrap();
+        assert!(flash.bytes.iter().all(|&b| b == 0xFF));
+        assert_eq!(db.len(), 0);
+        assert_eq!(db.get(&1).unwrap(), None);
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.set_backup_region(0, 0x2000, 4096).unwrap();
+        assert!(copy.open(&mut flash, 0).unwrap().is_none());
+    }
+
+    #[test]
+    fn secure_wipe_reports_flash_errors() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        // Not on erase pages, refused before anything is written
+        assert!(matches!(
+            db.secure_wipe(&mut flash, Partition::new(0x800, 0x1000)),
+            Err(FlashError::EraseError)
+        ));
+        assert!(image::verify(&mut flash, 0).unwrap().is_some());
+        // The region runs off the end of the flash
+        assert!(matches!(
+            db.secure_wipe(&mut flash, Partition::new(0x2000, 0x4000)),
+            Err(FlashError::WriteError)
+        ));
+        // Nothing cleared in RAM, the wipe can be retried
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+    }
+
+    #[test]
+    fn units_convert_on_get() {
+        let units = UnitMap::new(UNITS);
+        let mut db: Database<u16, Quantity, Postcard, 4, 16, 1> = Database::new();
+        units
+            .put(&mut db, 1, Quantity::new(75.0, Unit::Celsius))
+            .unwrap();
+        assert_eq!(units.get(&mut db, &1, Unit::Fahrenheit), Ok(Some(167.0)));
+        assert_eq!(units.get(&mut db, &1, Unit::Celsius), Ok(Some(75.0)));
+        assert_eq!(units.get(&mut db, &2, Unit::Volt), Ok(None));
+
+        // Convert first to store a value given in another unit
+        let q = Quantity::new(3.0, Unit::Volt).to(Unit::Millivolt).unwrap();
+        units.put(&mut db, 2, q).unwrap();
+        assert_eq!(units.get(&mut db, &2, Unit::Millivolt), Ok(Some(3000.0)));
+    }
+
+    #[test]
+    fn units_reject_mismatches() {
+        let units = UnitMap::new(UNITS);
+        let mut db: Database<u16, Quantity, Postcard, 4, 16, 1> = Database::new();
+        assert_eq!(
+            units.put(&mut db, 1, Quantity::new(167.0, Unit::Fahrenheit)),
+            Err(UnitError::WrongUnit {
+                expected: Unit::Celsius,
+                found: Unit::Fahrenheit
+            })
+        );
+        assert_eq!(
+            units.put(&mut db, 3, Quantity::new(1.0, Unit::Percent)),
+            Err(UnitError::UnknownKey)
+        );
+        assert_eq!(db.len(), 0);
+
+        // Written around the unit table
+        db.put(2, Quantity::new(3.0, Unit::Volt)).unwrap();
+        assert_eq!(
+            units.get(&mut db, &2, Unit::Volt),
+            Err(UnitError::WrongUnit {
+                expected: Unit::Millivolt,
+                found: Unit::Volt
+            })
+        );
+        units
+            .put(&mut db, 1, Quantity::new(20.0, Unit::Celsius))
+            .unwrap();
+        assert_eq!(
+            units.get(&mut db, &1, Unit::Volt),
+            Err(UnitError::Incompatible)
+        );
+        assert_eq!(Quantity::new(1.0, Unit::Pascal).to(Unit::Percent), None);
+    }
+
+    #[test]
+    fn geofence_contains_nearby_points() {
+        let home = GeoPoint::from_degrees(52.370_216, 4.895_168).unwrap();
+        assert_eq!(home.lat_e7(), 523_702_160);
+        let mut db: Database<u16, Geofence, GeoCodec, 4, 16, 1> = Database::new();
+        db.put(1, Geofence::new(home, 250)).unwrap();
+        let fence = db.get(&1).unwrap().unwrap();
+        assert_eq!(fence.center, home);
+
+        // 0.001 degrees north is about 111 m, 0.01 degrees about 1.1 km
+        let near = GeoPoint::new(523_712_160, 48_951_680).unwrap();
+        let far = GeoPoint::new(523_802_160, 48_951_680).unwrap();
+        assert!((home.distance_m(&near) - 111.2).abs() < 0.5);
+        assert!(fence.contains(&near));
+        assert!(!fence.contains(&far));
+        assert!(fence.distance_to_edge_m(&near) < 0.0);
+        assert!(fence.distance_to_edge_m(&far) > 800.0);
+    }
+
+    #[test]
+    fn geo_rejects_out_of_range_points() {
+        assert_eq!(GeoPoint::from_degrees(90.5, 0.0), Err(GeoError::OutOfRange));
+        assert_eq!(
+            GeoPoint::from_degrees(0.0, -180.5),
+            Err(GeoError::OutOfRange)
+        );
+        assert_eq!(GeoPoint::new(0, 1_800_000_001), Err(GeoError::OutOfRange));
+
+        let mut buf = [0u8; 12];
+        buf[..4].copy_from_slice(&900_000_001i32.to_le_bytes());
+        assert_eq!(
+            <GeoCodec as Codec<GeoPoint>>::decode(&buf[..8]),
+            Err(GeoError::OutOfRange)
+        );
+        assert_eq!(
+            <GeoCodec as Codec<Geofence>>::decode(&buf[..8]),
+            Err(GeoError::BadLength)
+        );
+        let fence = Geofence::new(GeoPoint::new(0, 0).unwrap(), 10);
+        assert_eq!(
+            GeoCodec::encode(&mut buf[..11], &fence),
+            Err(GeoError::BufferTooSmall)
+        );
+    }
+
+    #[test]
+    fn softdevice_flash_retries_busy_and_failed_operations() {
+        let mut sd = FakeSoftDevice {
+            busy: 2,
+            failed_events: 1,
+            ..Default::default()
+        };
+        let mut flash = SoftDeviceFlash::new(&mut sd);
+        flash.erase(0x1000, 0x3000).unwrap();
+        // 260 bytes go out as a full 64 word write and a padded one word write
+        flash.write(0x1000, &[0xAB; 260]).unwrap();
+        // Page 1 was started again after its event failed
+        assert_eq!(&sd.erased[..], &[1, 1, 2]);
+        assert_eq!(&sd.written[..], &[(0x1000, 64), (0x1100, 1)]);
+    }
+
+    #[test]
+    fn softdevice_flash_gives_up() {
+        let mut sd = FakeSoftDevice {
+            failed_events: SD_FLASH_RETRIES as u32 + 1,
+            ..Default::default()
+        };
+        let mut flash = SoftDeviceFlash::new(&mut sd);
+        assert!(flash.erase(0x1000, 0x2000) == Err(StorageError::Other));
+        assert!(flash.erase(0x1004, 0x2000) == Err(StorageError::Unaligned));
+        assert!(flash.write(0x1002, &[0; 4]) == Err(StorageError::Unaligned));
+        assert_eq!(sd.erased.len(), SD_FLASH_RETRIES as usize + 1);
+        assert!(sd.written.is_empty());
+    }
+
+    #[test]
+    fn softdevice_flash_gives_up_when_busy() {
+        let mut sd = FakeSoftDevice {
+            busy: SD_FLASH_RETRIES as u32 + 1,
+            ..Default::default()
+        };
+        let mut flash = SoftDeviceFlash::new(&mut sd);
+        assert!(flash.erase(0x1000, 0x2000) == Err(StorageError::Other));
+        assert!(flash.write(0x1000, &[0; 4]) == Ok(()));
+        assert!(sd.erased.is_empty());
+        assert_eq!(&sd.written[..], &[(0x1000, 1)]);
+    }
+
+    #[test]
+    fn low_supply_blocks_saves_and_wipes() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.set_supply_check(test_supply);
+        db.put(1, 10).unwrap();
+
+        SUPPLY_OK.store(false, Ordering::Relaxed);
+        assert!(matches!(
+            db.save_to_flash(&mut flash, 4, 0),
+            Err(FlashError::LowVoltage)
+        ));
+        assert!(flash.bytes.iter().all(|&b| b == 0xFF));
+
+        SUPPLY_OK.store(true, Ordering::Relaxed);
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        SUPPLY_OK.store(false, Ordering::Relaxed);
+        assert!(matches!(
+            db.secure_wipe(&mut flash, Partition::new(0, 0x4000)),
+            Err(FlashError::LowVoltage)
+        ));
+        SUPPLY_OK.store(true, Ordering::Relaxed);
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+        assert!(image::verify(&mut flash, 0).unwrap().is_some());
+    }
+
+    #[test]
+    fn pof_warning_reports_low_supply() {
+        let power = unsafe { pac::Peripherals::steal() }.POWER;
+        // Lowest threshold, a board on USB or a fresh cell stays above it
+        flash::enable_pof_warning(&power, THRESHOLD_A::V17);
+        assert!(flash::supply_ok());
+
+        // Raise the event by hand the way the comparator would
+        power.events_pofwarn.write(|w| unsafe { w.bits(1) });
+        assert!(!flash::supply_ok());
+        flash::clear_pof_warning();
+        assert!(flash::supply_ok());
+        power.pofcon.reset();
+    }
+
+    #[test]
+    fn schedule_windows_round_trip() {
+        let mut heating = Schedule::new();
+        heating
+            .set_window(Weekday::Mon, 6 * 60, 8 * 60 + 30, true)
+            .unwrap();
+        heating
+            .set_window(Weekday::Mon, 7 * 60, 7 * 60 + 30, false)
+            .unwrap();
+        let mut db: Database<u16, Schedule, Postcard, 4, 64, 1> = Database::new();
+        db.put(1, heating).unwrap();
+        let heating = db.get(&1).unwrap().unwrap();
+
+        assert!(!heating.is_active(Weekday::Mon, 5 * 60 + 59));
+        assert!(heating.is_active(Weekday::Mon, 6 * 60));
+        assert!(!heating.is_active(Weekday::Mon, 7 * 60 + 10));
+        assert!(heating.is_active(Weekday::Mon, 8 * 60 + 29));
+        assert!(!heating.is_active(Weekday::Mon, 8 * 60 + 30));
+        assert!(!heating.is_active(Weekday::Tue, 6 * 60));
+
+        // 1970-01-05 was a Monday
+        assert_eq!(Weekday::from_unix(0), Weekday::Thu);
+        assert!(heating.is_active_at(4 * 86_400 + 6 * 3600 + 15 * 60));
+        assert!(!heating.is_active_at(11 * 86_400 + 9 * 3600));
+    }
+
+    #[test]
+    fn schedule_rejects_bad_windows() {
+        let mut schedule = Schedule::new();
+        assert_eq!(
+            schedule.set_window(Weekday::Sun, 6 * 60 + 15, 7 * 60, true),
+            Err(ScheduleError::Unaligned)
+        );
+        assert_eq!(
+            schedule.set_window(Weekday::Sun, 7 * 60, 7 * 60, true),
+            Err(ScheduleError::BadWindow)
+        );
+        assert_eq!(
+            schedule.set_window(Weekday::Sun, 23 * 60, 25 * 60, true),
+            Err(ScheduleError::BadWindow)
+        );
+        assert_eq!(schedule, Schedule::new());
+        assert!(!schedule.is_active(Weekday::Sun, 24 * 60));
+
+        schedule.set_window(Weekday::Sun, 0, 24 * 60, true).unwrap();
+        schedule.clear_day(Weekday::Sun);
+        assert_eq!(schedule, Schedule::new());
+    }
+
+    #[test]
+    fn iter_walks_every_entry() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        for k in 1..=3 {
+            db.put(k, k as u32 * 100).unwrap();
+        }
+        let mut sum = 0;
+        for (k, v) in db.iter() {
+            assert_eq!(v.unwrap(), *k as u32 * 100);
+            sum += *k;
+        }
+        assert_eq!(sum, 6);
+        assert_eq!(db.keys().count(), 3);
+        assert_eq!(db.values().map(|v| v.unwrap()).sum::<u32>(), 600);
+        // 300 is a two byte varint
+        let blob = db.blobs().find(|(k, _)| **k == 3).unwrap().1;
+        assert_eq!(blob, &[0xac, 0x02]);
+    }
+
+    #[test]
+    fn iter_reports_values_that_do_not_decode() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 300_000).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        // Open the image with a value type that is too narrow for one of them
+        let mut narrow: Database<u16, u16, Postcard, 8, 16, 2> = Database::new();
+        narrow.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(narrow.keys().count(), 2);
+        for (k, v) in narrow.iter() {
+            match k {
+                1 => assert_eq!(v.unwrap(), 10),
+                _ => assert!(v.is_err()),
+            }
+        }
+        assert_eq!(narrow.values().filter(|v| v.is_err()).count(), 1);
+    }
+
+    #[test]
+    fn translations_fall_back() {
+        let l10n = Translations::new(&["en"]);
+        let mut db: Database<String<32>, String<16>, Postcard, 8, 32, 2> = Database::new();
+        l10n.put(
+            &mut db,
+            "en",
+            "greeting",
+            String::try_from("Hello").unwrap(),
+        )
+        .unwrap();
+        l10n.put(
+            &mut db,
+            "de",
+            "greeting",
+            String::try_from("Hallo").unwrap(),
+        )
+        .unwrap();
+        l10n.put(
+            &mut db,
+            "de-AT",
+            "greeting",
+            String::try_from("Servus").unwrap(),
+        )
+        .unwrap();
+        l10n.put(&mut db, "en", "bye", String::try_from("Bye").unwrap())
+            .unwrap();
+
+        let get = |db: &mut Database<String<32>, String<16>, Postcard, 8, 32, 2>, loc, id| {
+            l10n.get(db, loc, id).unwrap()
+        };
+        assert_eq!(
+            get(&mut db, "de-AT", "greeting").unwrap().as_str(),
+            "Servus"
+        );
+        assert_eq!(get(&mut db, "de-CH", "greeting").unwrap().as_str(), "Hallo");
+        assert_eq!(get(&mut db, "de-CH", "bye").unwrap().as_str(), "Bye");
+        assert!(get(&mut db, "fr", "missing").is_none());
+
+        let key: String<32> = Translations::key("de", "greeting").unwrap();
+        assert_eq!(key.as_str(), "l10n:de:greeting");
+        assert_eq!(namespace::strip(&key, "l10n"), Some("de:greeting"));
+        assert_eq!(namespace::strip(&key, "flag"), None);
+    }
+
+    #[test]
+    fn namespaced_keys_reject_bad_input() {
+        assert_eq!(
+            namespace::key::<32>("", "x").err(),
+            Some(NamespaceError::BadNamespace)
+        );
+        assert_eq!(
+            namespace::key::<32>("a:b", "x").err(),
+            Some(NamespaceError::BadNamespace)
+        );
+        assert_eq!(
+            namespace::key::<8>("l10n", "greeting").err(),
+            Some(NamespaceError::TooLong)
+        );
+        assert_eq!(namespace::split("plain"), None);
+
+        let l10n = Translations::new(&[]);
+        let mut db: Database<String<12>, String<16>, Postcard, 8, 32, 2> = Database::new();
+        assert_eq!(
+            l10n.put(&mut db, "de", "greeting", String::new()),
+            Err(L10nError::Key(NamespaceError::TooLong))
+        );
+        assert_eq!(db.len(), 0);
+    }
+
+    #[test]
+    fn flag_rollout_only_adds_devices() {
+        let mut db: Database<String<32>, Flag, Postcard, 8, 8, 2> = Database::new();
+        flags::set(&mut db, "always", Flag::On).unwrap();
+        flags::set(&mut db, "never", Flag::Off).unwrap();
+        assert_eq!(flags::get(&mut db, "always"), Ok(Some(Flag::On)));
+
+        let mut enabled = [false; 64];
+        let mut last = 0;
+        for pct in [0, 10, 50, 100] {
+            flags::set(&mut db, "new_ui", Flag::Rollout(pct)).unwrap();
+            let mut count = 0;
+            for (device, was_on) in enabled.iter_mut().enumerate() {
+                let on = flags::is_enabled(&mut db, "new_ui", device as u64);
+                assert_eq!(on, flags::bucket("new_ui", device as u64) < pct as u32);
+                // Nobody flips back off
+                assert!(on || !*was_on);
+                *was_on = on;
+                count += on as u32;
+            }
+            assert!(count >= last);
+            last = count;
+        }
+        assert_eq!(last, 64);
+        assert!(flags::is_enabled(&mut db, "always", 1));
+        assert!(!flags::is_enabled(&mut db, "never", 1));
+    }
+
+    #[test]
+    fn flags_reject_bad_settings() {
+        let mut db: Database<String<12>, Flag, Postcard, 8, 8, 2> = Database::new();
+        assert_eq!(
+            flags::set(&mut db, "new_ui", Flag::Rollout(101)),
+            Err(FlagError::BadPercentage)
+        );
+        assert_eq!(
+            flags::set(&mut db, "much_too_long", Flag::On),
+            Err(FlagError::Key(NamespaceError::TooLong))
+        );
+        assert_eq!(db.len(), 0);
+        // Unknown flags are off
+        assert!(!flags::is_enabled(&mut db, "new_ui", 1));
+        assert_eq!(flags::get(&mut db, "new_ui"), Ok(None));
+    }
+
+    #[test]
+    fn cohort_is_assigned_once() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<String<32>, Flag, Postcard, 8, 8, 2> = Database::new();
+        let arm = flags::cohort(&mut db, "checkout_v2", 2, 0x5eed, 42).unwrap();
+        assert!(arm < 2);
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        // After a reboot a new salt doesn't move the device
+        let mut db: Database<String<32>, Flag, Postcard, 8, 8, 2> = Database::new();
+        db.load_from_flash(&mut flash, 0).unwrap();
+        for salt in 0..8 {
+            assert_eq!(flags::cohort(&mut db, "checkout_v2", 2, salt, 42), Ok(arm));
+        }
+        let key: String<32> = namespace::key(flags::EXPERIMENT_NAMESPACE, "checkout_v2").unwrap();
+        assert_eq!(db.get(&key).unwrap(), Some(Flag::Cohort(arm)));
+    }
+
+    #[test]
+    fn cohort_reassigns_arms_that_went_away() {
+        let mut db: Database<String<32>, Flag, Postcard, 8, 8, 2> = Database::new();
+        assert_eq!(
+            flags::cohort(&mut db, "checkout_v2", 0, 0x5eed, 42),
+            Err(FlagError::NoArms)
+        );
+        assert_eq!(db.len(), 0);
+
+        let key: String<32> = namespace::key(flags::EXPERIMENT_NAMESPACE, "checkout_v2").unwrap();
+        db.put(key.clone(), Flag::Cohort(3)).unwrap();
+        let arm = flags::cohort(&mut db, "checkout_v2", 2, 0x5eed, 42).unwrap();
+        assert!(arm < 2);
+        assert_eq!(db.get(&key).unwrap(), Some(Flag::Cohort(arm)));
+    }
+
+    #[test]
+    fn sorted_store_ranges_in_key_order() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u32, u32, Postcard, 8, 16, 2, SortedStore<u32, 8, 16>> =
+            Database::with_store(SortedStore::new());
+        for t in [300, 100, 500, 200, 400] {
+            db.put(t, t / 100).unwrap();
+        }
+        assert!(db.keys().copied().eq([100, 200, 300, 400, 500]));
+        let hits: heapless::Vec<(u32, u32), 8> =
+            db.range(200..400).map(|(k, v)| (*k, v.unwrap())).collect();
+        assert_eq!(&hits[..], &[(200, 2), (300, 3)]);
+        assert_eq!(db.range(450..).count(), 1);
+        assert_eq!(db.range(..=100).count(), 1);
+
+        // Survives a save and load
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut copy: Database<u32, u32, Postcard, 8, 16, 2, SortedStore<u32, 8, 16>> =
+            Database::with_store(SortedStore::new());
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert!(copy.keys().copied().eq([100, 200, 300, 400, 500]));
+        assert!(copy.delete(&300));
+        assert_eq!(copy.range(200..=400).count(), 2);
+    }
+
+    #[test]
+    fn sorted_store_reports_full_and_oversized() {
+        let mut store: SortedStore<u32, 2, 4> = SortedStore::new();
+        store.insert(2, &[1, 2, 3, 4]).unwrap();
+        assert_eq!(store.insert(1, &[0; 5]), Err(StoreError::TooLarge));
+        store.insert(1, &[1]).unwrap();
+        assert_eq!(store.insert(3, &[1]), Err(StoreError::Full));
+        // Replacing an existing key still works when full
+        store.insert(2, &[9]).unwrap();
+        assert_eq!(store.get(&2), Some(&[9][..]));
+        assert_eq!(store.len(), 2);
+
+        // An image with more entries than the store holds
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u32, u32, Postcard, 8, 16, 2> = Database::new();
+        for k in 0..3 {
+            db.put(k, k).unwrap();
+        }
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut small: Database<u32, u32, Postcard, 2, 16, 2, SortedStore<u32, 2, 16>> =
+            Database::with_store(SortedStore::new());
+        assert!(matches!(
+            small.load_from_flash(&mut flash, 0),
+            Err(FlashError::DatabaseFull)
+        ));
+    }
+
+    #[test]
+    fn transaction_applies_all_changes() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        let old = db
+            .transaction(|txn| {
+                let old = txn.get(&1)?;
+                txn.put(2, 20)?;
+                txn.put(2, 21)?;
+                // Reads see the staged changes
+                assert_eq!(txn.get(&2)?, Some(21));
+                txn.delete(&1)?;
+                assert_eq!(txn.get(&1)?, None);
+                Ok(old)
+            })
+            .unwrap();
+        assert_eq!(old, Some(10));
+        assert_eq!(db.get(&1).unwrap(), None);
+        assert_eq!(db.get(&2).unwrap(), Some(21));
+
+        db.transaction_and_save(&mut flash, 0, |txn| txn.put(3, 30))
+            .unwrap();
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.len(), 2);
+        assert_eq!(copy.get(&3).unwrap(), Some(30));
+    }
+
+    #[test]
+    fn transaction_rolls_back() {
+        let mut db: Database<u16, u32, Postcard, 2, 16, 1> = Database::new();
+        db.put(1, 10).unwrap();
+        // Get the cache involved too
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+
+        let aborted = db.transaction(|txn| -> Result<(), TxnError> {
+            txn.put(1, 11)?;
+            Err(TxnError::Aborted)
+        });
+        assert!(matches!(aborted, Err(TxnError::Aborted)));
+        let full = db.transaction(|txn| {
+            txn.put(1, 11)?;
+            txn.put(2, 20)?;
+            txn.put(3, 30)
+        });
+        assert!(matches!(full, Err(TxnError::Full)));
+        let too_many = db.transaction(|txn| {
+            for k in 0..=MAX_TXN_OPS as u16 {
+                txn.delete(&k)?;
+            }
+            Ok(())
+        });
+        assert!(matches!(too_many, Err(TxnError::TooManyOps)));
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+
+        // Applied in RAM even though the save failed
+        let mut flash = RamFlash::erased();
+        let saved = db.transaction_and_save(&mut flash, 0x1_0000, |txn| txn.put(1, 12));
+        assert!(matches!(saved, Err(TxnError::Flash(_))));
+        assert_eq!(db.get(&1).unwrap(), Some(12));
+    }
+
+    #[test]
+    fn key_policy_normalizes_cli_keys() {
+        let key: String<32> = KeyPolicy::STRICT.normalize(" WiFi_SSID\t").unwrap();
+        assert_eq!(key.as_str(), "wifi_ssid");
+        let key: String<32> = KeyPolicy::STRICT.normalize("net:SSID").unwrap();
+        assert_eq!(key.as_str(), "net:ssid");
+
+        let mut flash = RamFlash::erased();
+        let mut db: Database<String<32>, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut out: String<64> = String::new();
+        for args in [
+            &["set", " WiFi_Channel ", "6"][..],
+            &["get", "wifi_channel"],
+        ] {
+            DbCommand::parse(args)
+                .unwrap()
+                .run_with_policy(&KeyPolicy::STRICT, &mut db, &mut flash, 0, &mut out)
+                .unwrap();
+        }
+        assert_eq!(out.as_str(), "ok\r\n6\r\n");
+        assert_eq!(db.keys().next().unwrap().as_str(), "wifi_channel");
+    }
+
+    #[test]
+    fn key_policy_rejects_bad_keys() {
+        assert_eq!(
+            KeyPolicy::STRICT.normalize::<32>("  ").err(),
+            Some(KeyError::Empty)
+        );
+        assert_eq!(
+            KeyPolicy::STRICT.normalize::<32>("wifi ssid").err(),
+            Some(KeyError::Reserved(' '))
+        );
+        assert_eq!(
+            KeyPolicy::STRICT.normalize::<4>("wifi_ssid").err(),
+            Some(KeyError::TooLong)
+        );
+        // NONE keeps the key as typed
+        let key: String<32> = KeyPolicy::NONE.normalize(" A ").unwrap();
+        assert_eq!(key.as_str(), " A ");
+
+        let mut flash = RamFlash::erased();
+        let mut db: Database<String<32>, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut out: String<64> = String::new();
+        let cmd = DbCommand::parse(&["set", "\"ssid\"", "6"]).unwrap();
+        assert!(matches!(
+            cmd.run_with_policy(&KeyPolicy::STRICT, &mut db, &mut flash, 0, &mut out),
+            Err(CliError::BadKey)
+        ));
+        assert_eq!(db.len(), 0);
+    }
+
+    #[test]
+    fn import_namespace_policies() {
+        let stream = namespaced_export();
+        let get =
+            |db: &mut Database<String<32>, u32, Postcard, 8, 16, 2>, k| db.get(&skey(k)).unwrap();
+
+        let mut db = namespaced_target();
+        let mut rest = &stream[..];
+        db.import_namespace("cal", |buf| take(&mut rest, buf), ImportPolicy::Merge)
+            .unwrap();
+        assert_eq!(get(&mut db, "cal:a"), Some(1));
+        assert_eq!(get(&mut db, "cal:b"), Some(2));
+        assert_eq!(get(&mut db, "cal:c"), Some(7));
+        assert_eq!(get(&mut db, "user:x"), Some(4));
+
+        let mut db = namespaced_target();
+        let mut rest = &stream[..];
+        db.import_namespace("cal", |buf| take(&mut rest, buf), ImportPolicy::Replace)
+            .unwrap();
+        assert_eq!(get(&mut db, "cal:a"), Some(1));
+        assert_eq!(get(&mut db, "cal:c"), None);
+        assert_eq!(db.len(), 3);
+
+        let mut db = namespaced_target();
+        let mut rest = &stream[..];
+        db.import_namespace(
+            "cal",
+            |buf| take(&mut rest, buf),
+            ImportPolicy::SkipExisting,
+        )
+        .unwrap();
+        assert_eq!(get(&mut db, "cal:a"), Some(9));
+        assert_eq!(get(&mut db, "cal:b"), Some(2));
+
+        // The user entry wasn't exported
+        let mut db = namespaced_target();
+        let mut rest = &stream[..];
+        db.import_namespace("user", |buf| take(&mut rest, buf), ImportPolicy::Replace)
+            .unwrap();
+        assert_eq!(get(&mut db, "user:x"), None);
+        assert_eq!(get(&mut db, "cal:a"), Some(9));
+    }
+
+    #[test]
+    fn import_namespace_rejects_damaged_streams() {
+        let mut stream = namespaced_export();
+        let at = stream.len() - 5;
+        stream[at] ^= 0x01;
+
+        let mut db = namespaced_target();
+        let mut rest = &stream[..];
+        assert!(matches!(
+            db.import_namespace("cal", |buf| take(&mut rest, buf), ImportPolicy::Replace),
+            Err(FlashError::CrcMismatch)
+        ));
+        // Nothing changed, not even in the namespace
+        assert_eq!(db.get(&skey("cal:a")).unwrap(), Some(9));
+        assert_eq!(db.get(&skey("cal:c")).unwrap(), Some(7));
+        assert_eq!(db.get(&skey("user:x")).unwrap(), Some(4));
+
+        // A stream cut short is refused the same way
+        let stream = namespaced_export();
+        let mut short = &stream[..stream.len() - 2];
+        assert!(matches!(
+            db.import_namespace("cal", |buf| take(&mut short, buf), ImportPolicy::Merge),
+            Err(FlashError::ReadError)
+        ));
+        assert_eq!(db.get(&skey("cal:a")).unwrap(), Some(9));
+        assert_eq!(db.len(), 3);
+    }
+
+    #[test]
+    fn update_and_get_or_insert_with() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut calls = 0;
+        let mut default = || {
+            calls += 1;
+            42
+        };
+        assert_eq!(db.get_or_insert_with(1, &mut default).unwrap(), 42);
+        assert_eq!(db.get_or_insert_with(1, &mut default).unwrap(), 42);
+        assert_eq!(calls, 1);
+
+        assert!(db.update(1, 43).unwrap());
+        assert_eq!(db.get(&1).unwrap(), Some(43));
+    }
+
+    #[test]
+    fn update_does_not_create_keys() {
+        let mut db: Database<u16, u32, Postcard, 2, 16, 1> = Database::new();
+        assert!(!db.update(1, 10).unwrap());
+        assert_eq!(db.len(), 0);
+
+        db.put(1, 10).unwrap();
+        db.put(2, 20).unwrap();
+        // No room for a third key
+        assert!(db.get_or_insert_with(3, || 30).is_err());
+        assert_eq!(db.get(&3).unwrap(), None);
+        assert_eq!(db.len(), 2);
+    }
+
+    #[test]
+    fn clone_to_copies_the_persisted_image() {
+        let mut flash = RamFlash::erased();
+        let mut external = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 300).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        // Not saved yet, so not copied
+        db.put(3, 30).unwrap();
+
+        db.clone_to(&mut flash, &mut external, 0x1000).unwrap();
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut external, 0x1000).unwrap();
+        assert_eq!(copy.len(), 2);
+        assert_eq!(copy.get(&2).unwrap(), Some(300));
+        assert_eq!(&external.bytes[0x1000..0x1040], &flash.bytes[..0x40]);
+    }
+
+    #[test]
+    fn clone_to_refuses_bad_sources() {
+        let mut flash = RamFlash::erased();
+        let mut external = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        assert!(matches!(
+            db.clone_to(&mut flash, &mut external, 0),
+            Err(FlashError::NotPersisted)
+        ));
+
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        assert!(matches!(
+            db.clone_to(&mut flash, &mut external, 0x1_0000),
+            Err(FlashError::EraseError)
+        ));
+        flash.bytes[HEADER_SIZE + 2] ^= 0x01;
+        assert!(matches!(
+            db.clone_to(&mut flash, &mut external, 0),
+            Err(FlashError::CrcMismatch)
+        ));
+        assert!(external.bytes.iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn contains_key_and_get_key_value() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(7, 300).unwrap();
+        assert!(db.contains_key(&7));
+        assert!(!db.contains_key(&8));
+        assert_eq!(db.get_key_value(&7), Some((&7, &[0xac, 0x02][..])));
+        assert_eq!(db.get_key_value(&8), None);
+
+        let mut sorted: Database<u16, u32, Postcard, 8, 16, 2, SortedStore<u16, 8, 16>> =
+            Database::with_store(SortedStore::new());
+        sorted.put(7, 300).unwrap();
+        sorted.put(3, 1).unwrap();
+        assert_eq!(sorted.get_key_value(&7), Some((&7, &[0xac, 0x02][..])));
+        assert!(sorted.delete(&7));
+        assert!(!sorted.contains_key(&7));
+    }
+
+    #[test]
+    fn contains_key_skips_the_codec() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 300_000).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        // The value doesn't fit the narrower type, but the entry is there
+        let mut narrow: Database<u16, u16, Postcard, 8, 16, 2> = Database::new();
+        narrow.load_from_flash(&mut flash, 0).unwrap();
+        assert!(narrow.get(&1).is_err());
+        assert!(narrow.contains_key(&1));
+        let (_, blob) = narrow.get_key_value(&1).unwrap();
+        assert_eq!(blob, &[0xe0, 0xa7, 0x12]);
+        // update() only checks for the key, it still refuses unknown ones
+        assert!(narrow.update(1, 5).unwrap());
+        assert!(!narrow.update(2, 5).unwrap());
+    }
+
+    #[test]
+    fn shrink_to_fit_drops_lowest_priority_first() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 1).unwrap();
+        db.put(2, 2).unwrap();
+        let two = db.image_size().unwrap();
+        db.put(3, 3).unwrap();
+        let full = db.image_size().unwrap();
+        let record = full - two;
+        // Key 1 is telemetry, key 3 the calibration
+        let priority = |k: &u16| *k as u8;
+        assert_eq!(db.shrink_to_fit(full, priority, |_| {}).unwrap(), 0);
+
+        let mut dropped: heapless::Vec<u16, 4> = heapless::Vec::new();
+        let n = db
+            .shrink_to_fit(full - record - 1, priority, |k| dropped.push(*k).unwrap())
+            .unwrap();
+        assert_eq!(n, 2);
+        assert_eq!(&dropped[..], &[1, 2]);
+        assert_eq!(db.image_size().unwrap(), full - 2 * record);
+
+        let mut flash = RamFlash::erased();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let header = image::verify(&mut flash, 0).unwrap().unwrap();
+        assert_eq!(HEADER_SIZE + header.payload_len as usize, full - 2 * record);
+    }
+
+    #[test]
+    fn shrink_to_fit_below_an_empty_image() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 1).unwrap();
+        db.put(2, 2).unwrap();
+        let mut dropped = 0;
+        assert!(matches!(
+            db.shrink_to_fit(HEADER_SIZE, |_| 0, |_| dropped += 1),
+            Err(FlashError::BufferTooSmall)
+        ));
+        // Everything went on the way there
+        assert_eq!(dropped, 2);
+        assert_eq!(db.len(), 0);
+    }
+
+    #[test]
+    fn ttl_entries_expire_on_tick() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put_with_ttl(1, 10, 5).unwrap();
+        db.put_with_ttl(2, 20, 10).unwrap();
+        db.put(3, 30).unwrap();
+        // Cached values go too
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+
+        assert_eq!(db.tick(4), 0);
+        assert_eq!(db.tick(1), 1);
+        assert_eq!(db.get(&1).unwrap(), None);
+        assert!(db.contains_key(&2));
+        assert_eq!(db.tick(100), 1);
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.get(&3).unwrap(), Some(30));
+    }
+
+    #[test]
+    fn ttl_is_cleared_by_plain_puts() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put_with_ttl(1, 10, 5).unwrap();
+        db.put(1, 11).unwrap();
+        db.put_with_ttl(2, 20, 5).unwrap();
+        db.delete(&2);
+        db.put(2, 21).unwrap();
+        assert_eq!(db.tick(10), 0);
+        assert_eq!(db.get(&1).unwrap(), Some(11));
+        assert_eq!(db.get(&2).unwrap(), Some(21));
+
+        // A put that fails leaves no deadline behind
+        let mut small: Database<u16, u32, Postcard, 2, 16, 1> = Database::new();
+        small.put(1, 1).unwrap();
+        small.put(2, 2).unwrap();
+        assert!(small.put_with_ttl(3, 3, 1).is_err());
+        assert_eq!(small.purge_expired(), 0);
+        assert_eq!(small.tick(1), 0);
+        assert_eq!(small.len(), 2);
+    }
+
+    #[test]
+    fn entry_counts_boots() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        for expected in 1..=3 {
+            let boots = db
+                .entry(1)
+                .unwrap()
+                .and_modify(|n| *n += 1)
+                .or_insert(1)
+                .unwrap();
+            assert_eq!(boots, expected);
+        }
+        assert_eq!(db.get(&1).unwrap(), Some(3));
+        let entry = db.entry(2).unwrap();
+        assert_eq!(*entry.key(), 2);
+        assert_eq!(entry.or_insert_with(|| 7).unwrap(), 7);
+        // An existing value is returned untouched
+        assert_eq!(db.entry(2).unwrap().or_insert_with(|| 8).unwrap(), 7);
+    }
+
+    #[test]
+    fn entry_reports_full_and_bad_values() {
+        let mut db: Database<u16, u32, Postcard, 2, 16, 1> = Database::new();
+        db.put(1, 1).unwrap();
+        db.put(2, 2).unwrap();
+        assert!(db.entry(3).unwrap().or_insert(3).is_err());
+        assert_eq!(db.len(), 2);
+
+        let mut flash = RamFlash::erased();
+        let mut wide: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        wide.put(1, 300_000).unwrap();
+        wide.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut narrow: Database<u16, u16, Postcard, 8, 16, 2> = Database::new();
+        narrow.load_from_flash(&mut flash, 0).unwrap();
+        assert!(narrow.entry(1).is_err());
+    }
+
+    #[test]
+    fn reserve_keeps_slots_for_critical_keys() {
+        let mut db: Database<u16, u32, Postcard, 4, 16, 2> = Database::new();
+        db.reserve(critical, 1, 0);
+        for k in 1..=3 {
+            db.put(k, k as u32).unwrap();
+        }
+        // The last slot is kept for a critical key
+        assert!(db.put(4, 4).is_err());
+        assert!(db.get_or_insert_with(4, || 4).is_err());
+        assert_eq!(db.len(), 3);
+        // Existing keys can still change
+        db.put(1, 11).unwrap();
+        db.put(100, 1).unwrap();
+        assert_eq!(db.get(&100).unwrap(), Some(1));
+        assert_eq!(db.len(), 4);
+    }
+
+    #[test]
+    fn reserve_keeps_bytes_for_critical_keys() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        // Other keys get two value bytes between them
+        db.reserve(critical, 0, MAX_IMAGE_LEN - HEADER_SIZE - 2);
+        db.put(1, 10).unwrap();
+        assert!(db.put(2, 300).is_err());
+        db.put(2, 20).unwrap();
+        assert!(db.put(3, 1).is_err());
+        assert_eq!(db.len(), 2);
+        db.put(100, 300_000).unwrap();
+        assert_eq!(db.get(&100).unwrap(), Some(300_000));
+    }
+
+    #[test]
+    fn transfer_survives_lost_frames() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 16, 16, 2> = Database::new();
+        for k in 0..16 {
+            db.put(k, 1_000_000 + k as u32).unwrap();
+        }
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let total =
+            HEADER_SIZE + image::verify(&mut flash, 0).unwrap().unwrap().payload_len as usize;
+        // More than one chunk
+        assert!(total > CHUNK_SIZE);
+
+        let mut staging = RamFlash::erased();
+        let mut rx = Receiver::new(0x2000, 0x2000);
+        let mut link = Loopback::new(&mut rx, &mut staging, 3);
+        block_on(transfer::send_image(&mut link, &mut flash, 0)).unwrap();
+        assert_eq!(link.done, Some(total));
+        assert_eq!(rx.progress(), None);
+
+        let mut copy: Database<u16, u32, Postcard, 16, 16, 2> = Database::new();
+        copy.open(&mut staging, 0x2000).unwrap();
+        assert_eq!(copy.len(), 16);
+        assert_eq!(copy.get(&15).unwrap(), Some(1_000_015));
+    }
+
+    #[test]
+    fn transfer_rejects_bad_images_and_frames() {
+        let mut flash = RamFlash::erased();
+        let mut staging = RamFlash::erased();
+        let mut rx = Receiver::new(0, 64);
+        let mut link = Loopback::new(&mut rx, &mut staging, 0);
+        assert!(matches!(
+            block_on(transfer::send_image(&mut link, &mut flash, 0)),
+            Err(TransferError::NoImage)
+        ));
+        // Too big for the receiver, every START is refused
+        let mut db: Database<u16, u32, Postcard, 16, 16, 2> = Database::new();
+        for k in 0..8 {
+            db.put(k, k as u32).unwrap();
+        }
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        assert!(matches!(
+            block_on(transfer::send_image(&mut link, &mut flash, 0)),
+            Err(TransferError::Transport)
+        ));
+        assert!(matches!(
+            rx.handle(&mut staging, &[0xFF; 9], &mut [0; MAX_FRAME]),
+            Err(TransferError::BadFrame)
+        ));
+
+        // An image whose CRC doesn't match, with a resumed START halfway
+        let mut reply = [0u8; MAX_FRAME];
+        let start = [1, 8, 0, 0, 0, 0xEF, 0xBE, 0xAD, 0xDE];
+        rx.handle(&mut staging, &start, &mut reply).unwrap();
+        rx.handle(&mut staging, &[2, 0, 0, 0, 0, 1, 2, 3, 4], &mut reply)
+            .unwrap();
+        // Committing early isn't allowed
+        assert!(matches!(
+            rx.handle(&mut staging, &[4], &mut reply),
+            Err(TransferError::BadFrame)
+        ));
+        assert_eq!(
+            rx.handle(&mut staging, &start, &mut reply).unwrap(),
+            (5, None)
+        );
+        assert_eq!(&reply[..5], &[3, 4, 0, 0, 0]);
+        assert_eq!(rx.progress(), Some((4, 8)));
+        rx.handle(&mut staging, &[2, 4, 0, 0, 0, 5, 6, 7, 8], &mut reply)
+            .unwrap();
+        assert_eq!(
+            rx.handle(&mut staging, &[4], &mut reply).unwrap(),
+            (2, None)
+        );
+        assert_eq!(&reply[..2], &[5, 0]);
+        assert_eq!(rx.progress(), None);
+    }
+
+    #[test]
+    fn compare_and_swap_writes_on_match() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        assert!(db.compare_and_swap(1, &10, 11).unwrap());
+        assert_eq!(db.get(&1).unwrap(), Some(11));
+    }
+
+    #[test]
+    fn compare_and_swap_leaves_mismatches_alone() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        assert!(!db.compare_and_swap(1, &9, 11).unwrap());
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+        // A missing key never matches, not even the default
+        assert!(!db.compare_and_swap(2, &0, 1).unwrap());
+        assert_eq!(db.get(&2).unwrap(), None);
+    }
+
+    #[test]
+    fn incr_and_decr_counters() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        assert_eq!(db.incr(1, 5).unwrap(), 5);
+        assert_eq!(db.incr(1, 2).unwrap(), 7);
+        assert_eq!(db.decr(1, 3).unwrap(), 4);
+        assert_eq!(db.get(&1).unwrap(), Some(4));
+    }
+
+    #[test]
+    fn incr_and_decr_saturate() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, u32::MAX - 1).unwrap();
+        assert_eq!(db.incr(1, 10).unwrap(), u32::MAX);
+        assert_eq!(db.decr(2, 1).unwrap(), 0);
+
+        // A full store keeps the counter out
+        let mut full: Database<u16, u32, Postcard, 2, 16, 1> = Database::new();
+        full.put(1, 1).unwrap();
+        full.put(2, 2).unwrap();
+        assert!(full.incr(3, 1).is_err());
+        assert_eq!(full.get(&3).unwrap(), None);
+    }
+
+    #[test]
+    fn history_keeps_the_last_values() {
+        let mut db: Database<u16, History<u16, 4>, Postcard, 4, 16, 2> = Database::new();
+        assert_eq!(db.latest(&1).unwrap(), None);
+        for mv in [3700, 3710, 3720, 3730, 3740] {
+            db.record(1, mv).unwrap();
+        }
+        assert_eq!(db.latest(&1).unwrap(), Some(3740));
+        // Newest first, 3700 was dropped
+        assert_eq!(&db.history(&1, 8).unwrap()[..], &[3740, 3730, 3720, 3710]);
+        assert_eq!(&db.history(&1, 2).unwrap()[..], &[3740, 3730]);
+        assert!(db.history(&2, 2).unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_keeps_the_old_values_when_a_record_does_not_fit() {
+        // Room for two short values but not three
+        let mut db: Database<u16, History<u16, 4>, Postcard, 4, 6, 2> = Database::new();
+        db.record(1, 3700).unwrap();
+        db.record(1, 3710).unwrap();
+        assert!(db.record(1, 3720).is_err());
+        assert_eq!(&db.history(&1, 4).unwrap()[..], &[3710, 3700]);
+    }
+
+    #[test]
+    fn computed_keys_follow_their_inputs() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.compute(100, |db| Some(db.get_uncached(&1).ok()?? * 2))
+            .unwrap();
+        assert_eq!(db.get(&100).unwrap(), None);
+        db.put(1, 21).unwrap();
+        assert_eq!(db.get(&100).unwrap(), Some(42));
+        db.put(1, 5).unwrap();
+        assert_eq!(db.get_uncached(&100).unwrap(), Some(10));
+
+        // Never persisted
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.len(), 1);
+        assert_eq!(copy.get(&100).unwrap(), None);
+    }
+
+    #[test]
+    fn computed_keys_are_bounded() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        for key in 0..MAX_COMPUTED as u16 {
+            db.compute(100 + key, |_| Some(1)).unwrap();
+        }
+        assert!(db.compute(200, |_| Some(1)).is_err());
+        assert_eq!(db.get(&200).unwrap(), None);
+        // Replacing one is still fine
+        db.compute(100, |_| Some(2)).unwrap();
+        assert_eq!(db.get(&100).unwrap(), Some(2));
+    }
+
+    #[test]
+    fn namespace_handles_keep_names_apart() {
+        let mut db: Database<String<16>, u32, Postcard, 4, 16, 2> = Database::new();
+        db.namespace("config").unwrap().put("gain", 12).unwrap();
+        db.namespace("cal").unwrap().put("gain", 7).unwrap();
+
+        let mut config = db.namespace("config").unwrap();
+        assert_eq!(config.get("gain").unwrap(), Some(12));
+        assert!(config.contains_key("gain").unwrap());
+        assert!(!config.contains_key("offset").unwrap());
+        assert!(config.names().eq(["gain"]));
+        assert!(config.delete("gain").unwrap());
+
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.namespace("cal").unwrap().get("gain").unwrap(), Some(7));
+    }
+
+    #[test]
+    fn namespace_handles_reject_bad_input() {
+        let mut db: Database<String<16>, u32, Postcard, 4, 16, 2> = Database::new();
+        assert_eq!(db.namespace("").err(), Some(NamespaceError::BadNamespace));
+        assert_eq!(
+            db.namespace("a:b").err(),
+            Some(NamespaceError::BadNamespace)
+        );
+
+        let mut config = db.namespace("config").unwrap();
+        assert_eq!(
+            config.put("a_very_long_name", 1),
+            Err(NamespaceError::TooLong)
+        );
+        for name in ["a", "b", "c", "d"] {
+            config.put(name, 1).unwrap();
+        }
+        assert_eq!(config.put("e", 1), Err(NamespaceError::Storage));
+        assert_eq!(config.get("e").unwrap(), None);
+    }
+
+    #[test]
+    fn emergency_records_are_read_back() {
+        let mut flash = RamFlash::erased();
+        let log = Partition::new(0x3000, 0x1000);
+        emergency_put_bytes(&mut flash, log, b"pc", &0x0800_1234u32.to_le_bytes()).unwrap();
+        emergency_put_bytes(&mut flash, log, b"lr", &[1, 2, 3]).unwrap();
+        emergency_put_bytes(&mut flash, log, b"sp", &[4]).unwrap();
+
+        // A record torn by a reset is skipped, the ones after it are kept
+        flash.bytes[0x3000 + 20 + 8 + 2] ^= 0x01;
+        let mut seen: heapless::Vec<u8, 4> = heapless::Vec::new();
+        emergency::for_each(&mut flash, log, |key, bytes| {
+            seen.push(key[0]).unwrap();
+            if key == b"pc" {
+                assert_eq!(bytes, &0x0800_1234u32.to_le_bytes());
+            }
+        })
+        .unwrap();
+        assert_eq!(&seen[..], b"ps");
+
+        emergency::clear(&mut flash, log).unwrap();
+        emergency::for_each(&mut flash, log, |_, _| panic!()).unwrap();
+    }
+
+    #[test]
+    fn emergency_records_are_bounded() {
+        let mut flash = RamFlash::erased();
+        let log = Partition::new(0x3000, 32);
+        assert!(matches!(
+            emergency_put_bytes(&mut flash, log, b"big", &[0; emergency::MAX_RECORD]),
+            Err(FlashError::BufferTooSmall)
+        ));
+        emergency_put_bytes(&mut flash, log, b"pc", &[1, 2, 3, 4]).unwrap();
+        assert!(matches!(
+            emergency_put_bytes(&mut flash, log, b"lr", &[1, 2, 3, 4]),
+            Err(FlashError::DatabaseFull)
+        ));
+        // Nothing was written past the partition
+        assert!(flash.bytes[0x3000 + 32..0x3040].iter().all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn find_by_index_follows_puts_and_deletes() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        // Nothing without an index
+        assert_eq!(db.find_by_index(0).count(), 0);
+
+        db.put(1, 105).unwrap();
+        db.set_index(|v| v / 100);
+        db.put(2, 110).unwrap();
+        db.put(3, 250).unwrap();
+        let mut hundreds: heapless::Vec<u16, 4> = db.find_by_index(1).copied().collect();
+        hundreds.sort_unstable();
+        assert_eq!(&hundreds[..], &[1, 2]);
+
+        db.put(2, 290).unwrap();
+        db.delete(&3);
+        assert!(db.find_by_index(1).eq([&1]));
+        assert!(db.find_by_index(2).eq([&2]));
+    }
+
+    #[test]
+    fn find_by_index_skips_values_that_do_not_decode() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 300_000).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        // The index is rebuilt from the loaded image, 300_000 isn't a u16
+        let mut narrow: Database<u16, u16, Postcard, 8, 16, 2> = Database::new();
+        narrow.set_index(|_| 7);
+        narrow.put(9, 9).unwrap();
+        narrow.load_from_flash(&mut flash, 0).unwrap();
+        assert!(narrow.find_by_index(7).eq([&1]));
+    }
+
+    // Only the registration can be checked here, a panic ends the test run
+    #[cfg(feature = "panic-handler")]
+    #[test]
+    fn panic_hook_waits_for_a_panic() {
+        use core::sync::atomic::{AtomicBool, Ordering};
+
+        static HOOK_RAN: AtomicBool = AtomicBool::new(false);
+        fn hook() {
+            HOOK_RAN.store(true, Ordering::Relaxed);
+        }
+        embedded_db::set_panic_hook(hook);
+        embedded_db::set_panic_hook(hook);
+        assert!(!HOOK_RAN.load(Ordering::Relaxed));
+    }
+
+    #[test]
+    fn multi_writes_the_selected_format() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Multi, 8, 16, 2> = Database::new();
+        // Keys from 100 on are edited by the host tools
+        db.set_format_selector(|key| {
+            if *key >= 100 {
+                Format::Json
+            } else {
+                Format::Postcard
+            }
+        });
+        assert!(db.put(1, 300).is_ok());
+        assert!(db.put(100, 300).is_ok());
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        // Decoding doesn't need the selector, the tag says it all
+        let mut copy: Database<u16, u32, Multi, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.get(&1).ok(), Some(Some(300)));
+        assert_eq!(copy.get(&100).ok(), Some(Some(300)));
+
+        let mut buf = [0u8; 8];
+        let n = <Multi as Codec<u32>>::encode_as(&mut buf, &300, Format::Json)
+            .ok()
+            .unwrap();
+        assert_eq!(&buf[..n], b"\x02300");
+        let n = <Multi as Codec<u32>>::encode(&mut buf, &300).ok().unwrap();
+        assert_eq!(&buf[..n], &[0x01, 0xac, 0x02]);
+    }
+
+    #[test]
+    fn multi_rejects_unknown_tags() {
+        assert!(matches!(
+            <Multi as Codec<u32>>::decode(&[]),
+            Err(MultiError::Empty)
+        ));
+        assert!(matches!(
+            <Multi as Codec<u32>>::decode(&[0x07, 0x01]),
+            Err(MultiError::UnknownFormat(0x07))
+        ));
+        assert!(matches!(
+            <Multi as Codec<u32>>::decode(b"\x02abc"),
+            Err(MultiError::Json(_))
+        ));
+        assert!(matches!(
+            <Multi as Codec<u32>>::encode(&mut [], &1),
+            Err(MultiError::Empty)
+        ));
+    }
+
+    #[test]
+    fn records_and_scan_list_raw_entries() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 300).unwrap();
+        db.put(2, 7).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        let mut records = image::records(&flash.bytes).unwrap();
+        assert!(records.all(|(_, value)| value == [0x07] || value == [0xac, 0x02]));
+        assert_eq!(image::records(&flash.bytes).unwrap().count(), 2);
+
+        let mut buf = [0u8; 16];
+        let mut seen = 0;
+        let header = image::scan(&mut flash, 0, &mut buf, |key, value| {
+            assert!(!key.is_empty());
+            assert!(value == [0x07] || value == [0xac, 0x02]);
+            seen += 1;
+        })
+        .unwrap();
+        assert!(header.is_some());
+        assert_eq!(seen, 2);
+    }
+
+    #[test]
+    fn records_and_scan_reject_bad_images() {
+        let mut flash = RamFlash::erased();
+        let mut buf = [0u8; 16];
+        assert!(matches!(
+            image::records(&flash.bytes),
+            Err(FlashError::BadHeader)
+        ));
+        assert!(matches!(
+            image::scan(&mut flash, 0, &mut buf, |_, _| panic!()),
+            Ok(None)
+        ));
+
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 300).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        // Cut short, and a buffer too small for the entry
+        assert!(matches!(
+            image::records(&flash.bytes[..HEADER_SIZE + 2]),
+            Err(FlashError::BufferTooSmall)
+        ));
+        assert!(matches!(
+            image::scan(&mut flash, 0, &mut buf[..2], |_, _| panic!()),
+            Err(FlashError::BufferTooSmall)
+        ));
+        // scan checks the CRC before calling f
+        flash.bytes[HEADER_SIZE + 4] ^= 0x01;
+        assert!(matches!(
+            image::scan(&mut flash, 0, &mut buf, |_, _| panic!()),
+            Err(FlashError::CrcMismatch)
+        ));
+    }
+
+    #[test]
+    fn db_errors_pass_through_question_mark() {
+        fn bump(
+            db: &mut Database<u16, u32, Postcard, 8, 16, 2>,
+        ) -> Result<u32, DbError<postcard::Error>> {
+            let val = db.get(&1)?.unwrap_or(0) + 1;
+            db.put(1, val)?;
+            Ok(val)
+        }
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        assert_eq!(bump(&mut db).unwrap(), 1);
+        assert_eq!(bump(&mut db).unwrap(), 2);
+    }
+
+    #[test]
+    fn db_errors_say_what_went_wrong() {
+        let mut flash = RamFlash::erased();
+        // 300_000 takes three bytes
+        let mut small: Database<u16, u32, Postcard, 2, 2, 1> = Database::new();
+        assert!(matches!(small.put(1, 300_000), Err(DbError::Encode(_))));
+        small.put(1, 1).unwrap();
+        small.put(2, 2).unwrap();
+        assert!(matches!(small.put(3, 3), Err(DbError::Full)));
+
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        for key in 0..MAX_COMPUTED as u16 {
+            db.compute(100 + key, |_| None).unwrap();
+        }
+        assert!(matches!(
+            db.compute(200, |_| None),
+            Err(DbError::TooManyComputed)
+        ));
+
+        db.put(1, 300_000).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut narrow: Database<u16, u16, Postcard, 8, 16, 2> = Database::new();
+        narrow.load_from_flash(&mut flash, 0).unwrap();
+        assert!(matches!(narrow.get(&1), Err(DbError::Decode(_))));
+    }
+
+    #[test]
+    fn load_timing_covers_every_phase() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        // No clock, no timing
+        assert_eq!(copy.load_timing(), LoadTiming::default());
+
+        copy.set_clock(test_clock);
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        let timing = copy.load_timing();
+        assert!(timing.flash_read_us > 0);
+        assert!(timing.crc_us > 0);
+        assert!(timing.decode_us > 0);
+        assert!(
+            timing.total_us
+                >= timing.flash_read_us + timing.crc_us + timing.unseal_us + timing.decode_us
+        );
+    }
+
+    #[test]
+    fn load_timing_stops_at_a_failed_phase() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        flash.bytes[HEADER_SIZE + 4] ^= 0x01;
+
+        db.set_clock(test_clock);
+        assert!(matches!(
+            db.load_from_flash(&mut flash, 0),
+            Err(FlashError::CrcMismatch)
+        ));
+        let timing = db.load_timing();
+        assert!(timing.flash_read_us > 0);
+        assert_eq!(timing.crc_us, 0);
+        assert_eq!(timing.decode_us, 0);
+        assert!(timing.total_us > 0);
+    }
+
+    #[test]
+    fn stats_count_cache_use() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 20).unwrap();
+        // The cache holds two, one has to go
+        db.put(3, 300).unwrap();
+        assert_eq!(db.get(&3).unwrap(), Some(300));
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+
+        let stats = db.stats();
+        assert_eq!(stats.cache_hits, 1);
+        assert_eq!(stats.cache_misses, 1);
+        assert_eq!(stats.evictions, 2);
+        assert_eq!(stats.entries, 3);
+        assert_eq!(stats.blob_bytes, 4);
+
+        db.reset_stats();
+        let stats = db.stats();
+        assert_eq!(
+            (stats.cache_hits, stats.cache_misses, stats.evictions),
+            (0, 0, 0)
+        );
+        assert_eq!(stats.entries, 3);
+    }
+
+    #[test]
+    fn stats_count_codec_errors() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 2, 2> = Database::new();
+        assert!(db.put(1, 300_000).is_err());
+        assert_eq!(db.stats().encode_errors, 1);
+        assert_eq!(db.stats().entries, 0);
+
+        let mut wide: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        wide.put(1, 300_000).unwrap();
+        wide.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut narrow: Database<u16, u16, Postcard, 8, 16, 2> = Database::new();
+        narrow.load_from_flash(&mut flash, 0).unwrap();
+        assert!(narrow.get(&1).is_err());
+        assert!(narrow.get(&1).is_err());
+        assert_eq!(narrow.stats().decode_errors, 2);
+        assert_eq!(narrow.stats().cache_misses, 2);
+    }
+
+    #[test]
+    fn cache_evicts_the_least_recently_used() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 20).unwrap();
+        // 1 was used last, so 2 goes
+        db.get(&1).unwrap();
+        db.put(3, 30).unwrap();
+        db.reset_stats();
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+        assert_eq!(db.get(&3).unwrap(), Some(30));
+        assert_eq!(db.stats().cache_hits, 2);
+        assert_eq!(db.get(&2).unwrap(), Some(20));
+        assert_eq!(db.stats().cache_misses, 1);
+    }
+
+    #[test]
+    fn cache_keeps_entries_on_misses_and_failed_puts() {
+        let mut db: Database<u16, u32, Postcard, 8, 2, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 20).unwrap();
+        assert_eq!(db.get(&9).unwrap(), None);
+        assert!(db.put(3, 300_000).is_err());
+        // Putting a cached key again doesn't evict the other one
+        db.put(1, 11).unwrap();
+        db.reset_stats();
+        assert_eq!(db.get(&1).unwrap(), Some(11));
+        assert_eq!(db.get(&2).unwrap(), Some(20));
+        assert_eq!(db.stats().cache_hits, 2);
+        assert_eq!(db.stats().evictions, 0);
+    }
+
+    #[test]
+    fn crc32_matches_the_zlib_check_value() {
+        assert_eq!(image::CRC32.checksum(b"123456789"), 0xCBF4_3926);
+        assert_eq!(image::CRC32.checksum(b""), 0);
+        assert_eq!(
+            image::CRC32.checksum(b"The quick brown fox jumps over the lazy dog"),
+            0x414F_A339
+        );
+
+        // Pieces that don't line up with words give the same result
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let mut digest = image::CRC32.digest();
+        for piece in data.chunks(7) {
+            digest.update(piece);
+        }
+        assert_eq!(digest.finalize(), 0x414F_A339);
+    }
+
+    #[test]
+    fn crc32_catches_single_bit_errors() {
+        let mut data = *b"123456789abcdef";
+        let good = image::CRC32.checksum(&data);
+        for i in 0..data.len() {
+            for bit in 0..8 {
+                data[i] ^= 1 << bit;
+                assert_ne!(image::CRC32.checksum(&data), good);
+                data[i] ^= 1 << bit;
+            }
+        }
+    }
+
+    #[test]
+    fn saved_images_end_in_a_footer() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 300).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        let mut out = [0u8; 0x2000];
+        let artifact = db.to_artifact(0, 4096, &mut out).unwrap();
+        assert_eq!(artifact.offset, 0);
+        let end = artifact.bytes.len();
+        assert_eq!(end % 4096, 0);
+
+        let footer = Footer::from_bytes(&flash.bytes[end - FOOTER_SIZE..end])
+            .unwrap()
+            .unwrap();
+        let header = ImageHeader::from_bytes(&flash.bytes[..HEADER_SIZE])
+            .unwrap()
+            .unwrap();
+        let len = footer.image_len as usize;
+        assert_eq!(len, HEADER_SIZE + header.payload_len as usize);
+        assert_eq!(footer.image_crc, image::CRC32.checksum(&flash.bytes[..len]));
+        // The artifact is what save_to_flash wrote
+        assert_eq!(&artifact.bytes[..len], &flash.bytes[..len]);
+        assert_eq!(
+            &artifact.bytes[end - FOOTER_SIZE..],
+            &flash.bytes[end - FOOTER_SIZE..end]
+        );
+        assert!(artifact.bytes[len..end - FOOTER_SIZE]
+            .iter()
+            .all(|&b| b == 0xFF));
+    }
+
+    #[test]
+    fn footers_and_artifacts_reject_bad_input() {
+        let flash = RamFlash::erased();
+        assert!(matches!(
+            Footer::from_bytes(&flash.bytes[..FOOTER_SIZE]),
+            Ok(None)
+        ));
+        assert!(matches!(
+            Footer::from_bytes(&flash.bytes[..FOOTER_SIZE - 1]),
+            Err(FlashError::BufferTooSmall)
+        ));
+
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        let mut out = [0u8; 1024];
+        assert!(matches!(
+            db.to_artifact(0, 4096, &mut out),
+            Err(FlashError::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn fifo_policy_ignores_reads() {
+        let mut db: Database<
+            u16,
+            u32,
+            Postcard,
+            8,
+            16,
+            2,
+            KvStore<u16, heapless::Vec<u8, 16>, 8>,
+            Fifo<u16, 2>,
+        > = Database::with_store_and_policy(KvStore::new(), Fifo::new());
+        db.put(1, 10).unwrap();
+        db.put(2, 20).unwrap();
+        // Lru would keep 1 now, Fifo still evicts it first
+        db.get(&1).unwrap();
+        db.put(3, 30).unwrap();
+        db.reset_stats();
+        assert_eq!(db.get(&2).unwrap(), Some(20));
+        assert_eq!(db.get(&3).unwrap(), Some(30));
+        assert_eq!(db.stats().cache_hits, 2);
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+        assert_eq!(db.stats().cache_misses, 1);
+    }
+
+    #[test]
+    fn policy_without_a_victim_leaves_new_values_uncached() {
+        let mut db: Database<
+            u16,
+            u32,
+            Postcard,
+            8,
+            16,
+            2,
+            KvStore<u16, heapless::Vec<u8, 16>, 8>,
+            NeverEvict,
+        > = Database::with_store_and_policy(KvStore::new(), NeverEvict);
+        db.put(1, 10).unwrap();
+        db.put(2, 20).unwrap();
+        db.put(3, 30).unwrap();
+        db.reset_stats();
+        assert_eq!(db.get(&3).unwrap(), Some(30));
+        assert_eq!(db.get(&3).unwrap(), Some(30));
+        assert_eq!(db.stats().cache_misses, 2);
+        assert_eq!(db.stats().evictions, 0);
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+        assert_eq!(db.stats().cache_hits, 1);
+    }
+
+    #[test]
+    fn pinned_keys_stay_cached() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.cache_pin(&1).unwrap();
+        for key in 2..6 {
+            db.put(key, key as u32).unwrap();
+            db.get(&key).unwrap();
+        }
+        db.reset_stats();
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+        assert_eq!(db.stats().cache_hits, 1);
+
+        // Invalidating drops the value but keeps the pin
+        db.cache_invalidate(&1);
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+        assert_eq!(db.stats().cache_misses, 1);
+        db.put(6, 6).unwrap();
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+        assert_eq!(db.stats().cache_hits, 2);
+
+        db.cache_clear();
+        db.cache_unpin(&1);
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+        assert_eq!(db.stats().cache_misses, 2);
+    }
+
+    #[test]
+    fn cache_pin_reports_full_and_bad_values() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.cache_pin(&1).unwrap();
+        db.cache_pin(&2).unwrap();
+        // Pinning again is fine, a third key isn't
+        db.cache_pin(&1).unwrap();
+        assert!(matches!(db.cache_pin(&3), Err(DbError::Full)));
+
+        let mut flash = RamFlash::erased();
+        db.put(1, 300_000).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut narrow: Database<u16, u16, Postcard, 8, 16, 2> = Database::new();
+        narrow.load_from_flash(&mut flash, 0).unwrap();
+        assert!(matches!(narrow.cache_pin(&1), Err(DbError::Decode(_))));
+    }
+
+    #[test]
+    fn factory_artifacts_open_on_the_device() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 1> = Database::new();
+        db.set_version_stamp(0x0001_0000, 0xDEAD_BEEF);
+        db.put(1, 1234).unwrap();
+        let mut out = [0u8; 0x2000];
+        let artifact = db.to_artifact(0x1000, 4096, &mut out).unwrap();
+
+        // Programmed as is at artifact.offset
+        let mut flash = RamFlash::erased();
+        let at = artifact.offset as usize;
+        flash.bytes[at..at + artifact.bytes.len()].copy_from_slice(artifact.bytes);
+        let mut board: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let header = board.open(&mut flash, 0x1000).unwrap().unwrap();
+        assert_eq!(
+            (header.app_version, header.device_id),
+            (0x0001_0000, 0xDEAD_BEEF)
+        );
+        assert_eq!(board.get(&1).unwrap(), Some(1234));
+    }
+
+    #[test]
+    fn factory_artifacts_need_room_for_the_whole_region() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 1> = Database::new();
+        db.put(1, 1234).unwrap();
+        let mut out = [0u8; 0x2000];
+        let artifact = db.to_artifact(0, 4096, &mut out).unwrap();
+        let len = artifact.bytes.len();
+        assert!(matches!(
+            db.to_artifact(0, 4096, &mut out[..len - 1]),
+            Err(FlashError::BufferTooSmall)
+        ));
+    }
+
+    #[test]
+    fn write_back_keeps_puts_in_the_cache_until_flush() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.set_write_back(true).unwrap();
+        db.put(1, 11).unwrap();
+        db.put(1, 12).unwrap();
+        assert_eq!(db.get(&1).unwrap(), Some(12));
+        assert_eq!(db.get_uncached(&1).unwrap(), Some(10));
+        assert_eq!(db.flush().unwrap(), 1);
+        assert_eq!(db.get_uncached(&1).unwrap(), Some(12));
+        assert_eq!(db.flush().unwrap(), 0);
+
+        // Saving flushes first
+        db.put(1, 13).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.get(&1).unwrap(), Some(13));
+
+        // So does turning it off
+        db.put(1, 14).unwrap();
+        db.set_write_back(false).unwrap();
+        assert_eq!(db.get_uncached(&1).unwrap(), Some(14));
+    }
+
+    #[test]
+    fn write_back_values_can_be_lost_or_fail_late() {
+        let mut db: Database<u16, u32, Postcard, 8, 2, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.set_write_back(true).unwrap();
+
+        // Invalidating drops an unflushed value
+        db.put(1, 11).unwrap();
+        db.cache_invalidate(&1);
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+
+        // Encode errors show up in put(), nothing is left dirty
+        assert!(matches!(db.put(1, 300_000), Err(DbError::Encode(_))));
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+        assert_eq!(db.flush().unwrap(), 0);
+
+        // A new key or a value that grows is written through
+        db.put(2, 20).unwrap();
+        db.put(1, 300).unwrap();
+        assert_eq!(db.get_uncached(&2).unwrap(), Some(20));
+        assert_eq!(db.get_uncached(&1).unwrap(), Some(300));
+    }
+
+    #[test]
+    fn needs_persist_follows_changes_and_saves() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        assert!(!db.needs_persist());
+        let before = db.generation();
+        db.put(1, 10).unwrap();
+        assert!(db.needs_persist());
+        assert_ne!(db.generation(), before);
+
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        assert!(!db.needs_persist());
+        // Reads change nothing
+        db.get(&1).unwrap();
+        assert!(!db.needs_persist());
+        db.delete(&1);
+        assert!(db.needs_persist());
+
+        db.load_from_flash(&mut flash, 0).unwrap();
+        assert!(!db.needs_persist());
+    }
+
+    #[test]
+    fn needs_persist_ignores_failed_changes() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 2, 2, 1> = Database::new();
+        db.put(1, 1).unwrap();
+        db.put(2, 2).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let generation = db.generation();
+
+        assert!(db.put(3, 3).is_err());
+        assert!(db.put(1, 300_000).is_err());
+        assert!(!db.delete(&9));
+        assert_eq!(db.generation(), generation);
+        assert!(!db.needs_persist());
+
+        // A save that fails leaves it set
+        db.put(1, 5).unwrap();
+        let end = flash.bytes.len() as u32;
+        assert!(db.save_to_flash(&mut flash, 4, end).is_err());
+        assert!(db.needs_persist());
+    }
+
+    #[test]
+    fn check_layout_accepts_separate_pages() {
+        let layout = [
+            Partition::new(0x8000, 0x2000),
+            Partition::new(0xA000, 0x2000),
+            Partition::new(0xF000, 0x1000),
+        ];
+        assert_eq!(
+            flash::check_layout(&layout, 0x10000, 0x1000, 0x8000),
+            Ok(())
+        );
+        assert_eq!(flash::check_layout(&[], 0x10000, 0x1000, 0x8000), Ok(()));
+    }
+
+    #[test]
+    fn check_layout_rejects_bad_partitions() {
+        let check = |layout: &[Partition]| flash::check_layout(layout, 0x10000, 0x1000, 0x8000);
+        assert_eq!(
+            check(&[
+                Partition::new(0x8000, 0x1000),
+                Partition::new(0xF000, 0x2000)
+            ]),
+            Err(LayoutError::OutOfBounds(1))
+        );
+        assert_eq!(
+            check(&[Partition::new(0x8000, 0)]),
+            Err(LayoutError::OutOfBounds(0))
+        );
+        assert_eq!(
+            check(&[Partition::new(0x8800, 0x1000)]),
+            Err(LayoutError::Misaligned(0))
+        );
+        assert_eq!(
+            check(&[Partition::new(0x7000, 0x1000)]),
+            Err(LayoutError::OverlapsFirmware(0))
+        );
+        assert_eq!(
+            check(&[
+                Partition::new(0x8000, 0x1000),
+                Partition::new(0xA000, 0x2000),
+                Partition::new(0xB000, 0x1000),
+            ]),
+            Err(LayoutError::Overlap(1, 2))
+        );
+    }
+
+    #[test]
+    fn check_layout_rejects_overflowing_partitions() {
+        let check = |layout: &[Partition]| flash::check_layout(layout, 0x10000, 0x1000, 0x8000);
+        // The end of a later partition wraps around, it is out of bounds
+        // before it can be compared with the earlier ones
+        assert_eq!(
+            check(&[
+                Partition::new(0x8000, 0x1000),
+                Partition::new(0xFFFF_F000, 0x2000)
+            ]),
+            Err(LayoutError::OutOfBounds(1))
+        );
+        assert_eq!(
+            check(&[
+                Partition::new(0x8000, 0x1000),
+                Partition::new(0x9000, 0xFFFF_F000)
+            ]),
+            Err(LayoutError::OutOfBounds(1))
+        );
+        // Nothing is aligned to a zero erase size
+        assert_eq!(
+            flash::check_layout(&[Partition::new(0x8000, 0x1000)], 0x10000, 0, 0x8000),
+            Err(LayoutError::Misaligned(0))
+        );
+    }
+
+    #[test]
+    fn assert_layout_knows_where_the_firmware_ends() {
+        let end = flash::firmware_end();
+        assert!(end > 0 && end < flash::FLASH_SIZE);
+        assert_eq!(
+            flash::assert_layout(&[Partition::new(TEST_PAGE, 0x1000)]),
+            Ok(())
+        );
+        assert_eq!(
+            flash::assert_layout(&[Partition::new(0, 0x1000)]),
+            Err(LayoutError::OverlapsFirmware(0))
+        );
+    }
+
+    #[test]
+    fn maybe_persist_saves_when_a_limit_is_hit() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.set_persist_policy(PersistPolicy {
+            flash_offset: 0,
+            after_changes: 3,
+            after_bytes: 0,
+            should_persist: None,
+        });
+        assert!(!db.maybe_persist(&mut flash, None).unwrap());
+        db.put(1, 10).unwrap();
+        db.put(2, 20).unwrap();
+        assert!(!db.maybe_persist(&mut flash, None).unwrap());
+        db.put(3, 30).unwrap();
+        assert!(db.maybe_persist(&mut flash, None).unwrap());
+        assert!(!db.needs_persist());
+        assert!(!db.maybe_persist(&mut flash, None).unwrap());
+
+        // Bytes written, then time since the last save
+        db.set_persist_policy(PersistPolicy {
+            flash_offset: 0,
+            after_changes: 0,
+            after_bytes: 4,
+            should_persist: None,
+        });
+        db.put(1, 300).unwrap();
+        assert!(!db.maybe_persist(&mut flash, None).unwrap());
+        db.put(2, 300).unwrap();
+        assert!(db.maybe_persist(&mut flash, None).unwrap());
+
+        db.set_clock(test_clock);
+        db.set_persist_policy(PersistPolicy {
+            flash_offset: 0,
+            after_changes: 0,
+            after_bytes: 0,
+            should_persist: Some(|elapsed_us| elapsed_us >= 100),
+        });
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        db.put(1, 11).unwrap();
+        assert!(!db.maybe_persist(&mut flash, None).unwrap());
+        for _ in 0..10 {
+            test_clock();
+        }
+        assert!(db.maybe_persist(&mut flash, None).unwrap());
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.get(&1).unwrap(), Some(11));
+    }
+
+    #[test]
+    fn maybe_persist_needs_a_policy_and_working_flash() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        assert!(!db.maybe_persist(&mut flash, None).unwrap());
+
+        db.set_persist_policy(PersistPolicy {
+            flash_offset: flash.bytes.len() as u32,
+            after_changes: 1,
+            after_bytes: 0,
+            should_persist: None,
+        });
+        assert!(matches!(
+            db.maybe_persist(&mut flash, None),
+            Err(FlashError::EraseError)
+        ));
+        assert!(db.needs_persist());
+    }
+
+    #[test]
+    fn low_power_saver_alternates_slots() {
+        let mut flash = RamFlash::erased();
+        let slots = [Partition::new(0, 0x2000), Partition::new(0x2000, 0x2000)];
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut saver: LowPowerSaver<0x2000> = LowPowerSaver::new(slots, 4096, power::NRF52840);
+        assert!(saver.open(&mut db, &mut flash).unwrap().is_none());
+
+        for val in [10, 11] {
+            db.put(1, val).unwrap();
+            saver.begin(&mut db).unwrap();
+            assert!(!saver.is_idle());
+            let mut steps = 0;
+            while saver.step(&mut db, &mut flash).unwrap() != PowerStep::Committed {
+                steps += 1;
+            }
+            assert!(steps > 0);
+            assert!(saver.is_idle());
+            assert!(!db.needs_persist());
+        }
+        assert!(saver.charge_used_nc() > 0);
+        let model = power::NRF52840;
+        assert!(model.save_charge_nc(100, 4096, true) < model.save_charge_nc(100, 4096, false));
+
+        // The newer slot wins
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut other: LowPowerSaver<0x2000> = LowPowerSaver::new(slots, 4096, power::NRF52840);
+        other.open(&mut copy, &mut flash).unwrap().unwrap();
+        assert_eq!(copy.get(&1).unwrap(), Some(11));
+        assert_eq!(
+            other.step(&mut copy, &mut flash).unwrap(),
+            PowerStep::Erased
+        );
+    }
+
+    #[test]
+    fn low_power_saver_keeps_the_last_image_on_power_loss() {
+        let mut flash = RamFlash::erased();
+        let slots = [Partition::new(0, 0x2000), Partition::new(0x2000, 0x2000)];
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut saver: LowPowerSaver<0x2000> = LowPowerSaver::new(slots, 4096, power::NRF52840);
+        db.put(1, 10).unwrap();
+        saver.begin(&mut db).unwrap();
+        while saver.step(&mut db, &mut flash).unwrap() != PowerStep::Committed {}
+
+        // Power is lost right after the first erase of the next save
+        db.put(1, 11).unwrap();
+        saver.begin(&mut db).unwrap();
+        assert_eq!(saver.step(&mut db, &mut flash).unwrap(), PowerStep::Erased);
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut other: LowPowerSaver<0x2000> = LowPowerSaver::new(slots, 4096, power::NRF52840);
+        other.open(&mut copy, &mut flash).unwrap().unwrap();
+        assert_eq!(copy.get(&1).unwrap(), Some(10));
+
+        // A slot past the end of the flash can't be read
+        let bad = [Partition::new(0, 0x2000), Partition::new(0x4000, 0x2000)];
+        let mut saver: LowPowerSaver<0x2000> = LowPowerSaver::new(bad, 4096, power::NRF52840);
+        assert!(matches!(
+            saver.open(&mut copy, &mut flash),
+            Err(FlashError::ReadError)
+        ));
+    }
+
+    #[test]
+    fn decode_memo_skips_repeated_decodes() {
+        let mut db: Database<u16, u32, CountingPostcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.set_decode_memo(true);
+        DECODES.store(0, Ordering::Relaxed);
+        for _ in 0..3 {
+            assert_eq!(db.get_uncached(&1).unwrap(), Some(10));
+        }
+        assert_eq!(DECODES.load(Ordering::Relaxed), 1);
+
+        // A changed blob is decoded again
+        db.put(1, 11).unwrap();
+        assert_eq!(db.get_uncached(&1).unwrap(), Some(11));
+        assert_eq!(DECODES.load(Ordering::Relaxed), 2);
+
+        db.set_decode_memo(false);
+        db.get_uncached(&1).unwrap();
+        db.get_uncached(&1).unwrap();
+        assert_eq!(DECODES.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn decode_memo_does_not_keep_failures() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 300_000).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        let mut narrow: Database<u16, u16, CountingPostcard, 8, 16, 2> = Database::new();
+        narrow.load_from_flash(&mut flash, 0).unwrap();
+        narrow.set_decode_memo(true);
+        DECODES.store(0, Ordering::Relaxed);
+        assert!(narrow.get_uncached(&1).is_err());
+        assert!(narrow.get_uncached(&1).is_err());
+        assert_eq!(DECODES.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn delete_prefix_removes_a_namespace() {
+        let mut db: Database<String<16>, u32, Postcard, 8, 16, 2> = Database::new();
+        for key in ["log:1", "log:2", "logger", "cfg:gain"] {
+            db.put(String::try_from(key).unwrap(), 1).unwrap();
+        }
+        assert_eq!(db.delete_prefix("log:"), 2);
+        assert_eq!(db.len(), 2);
+        assert!(db.contains_key(&String::try_from("logger").unwrap()));
+
+        let generation = db.generation();
+        assert_eq!(db.delete_prefix("tmp:"), 0);
+        assert_eq!(db.generation(), generation);
+        // Every key starts with ""
+        assert_eq!(db.delete_prefix(""), 2);
+        assert_eq!(db.len(), 0);
+    }
+
+    #[test]
+    fn clear_leaves_flash_alone() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 20).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        db.clear();
+        assert_eq!(db.len(), 0);
+        assert_eq!(db.get(&1).unwrap(), None);
+        assert!(db.needs_persist());
+
+        // Until the next save the image still has everything
+        db.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(db.get(&2).unwrap(), Some(20));
+    }
+
+    #[test]
+    fn frames_carry_the_export_stream() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        for key in 0..6 {
+            db.put(key, key as u32 * 1000).unwrap();
+        }
+        let mut stream: heapless::Vec<u8, 128> = heapless::Vec::new();
+        db.export(|bytes| stream.extend_from_slice(bytes).unwrap())
+            .unwrap();
+
+        let mut joined: heapless::Vec<u8, 128> = heapless::Vec::new();
+        let mut count = 0;
+        for frame in db.frames(20).unwrap() {
+            assert!(frame.len() <= 20);
+            let (seq, payload) = frames::unframe(&frame).unwrap();
+            assert_eq!(seq, count);
+            joined.extend_from_slice(payload).unwrap();
+            count += 1;
+        }
+        assert!(count > 1);
+        assert_eq!(&joined[..], &stream[..]);
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        let mut rest = &joined[..];
+        copy.import(|buf| take(&mut rest, buf)).unwrap();
+        assert_eq!(copy.get(&5).unwrap(), Some(5000));
+    }
+
+    #[test]
+    fn unframe_rejects_damaged_frames() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        let frame = db.frames(64).unwrap().next().unwrap();
+
+        assert!(matches!(
+            frames::unframe(&frame[..4]),
+            Err(FlashError::BadHeader)
+        ));
+        assert!(matches!(
+            frames::unframe(&frame[..frame.len() - 1]),
+            Err(FlashError::BadHeader)
+        ));
+        let mut damaged = frame.clone();
+        damaged[5] ^= 0x01;
+        assert!(matches!(
+            frames::unframe(&damaged),
+            Err(FlashError::CrcMismatch)
+        ));
+    }
+
+    #[test]
+    fn blob_bytes_follow_puts_and_deletes() {
+        type Db = Database<u16, u32, Postcard, 8, 16, 2>;
+        const RAM: usize = Db::ram_footprint();
+        assert!(RAM >= 8 * 16);
+
+        let mut db: Db = Database::new();
+        assert_eq!(db.blob_bytes_capacity(), 8 * 16);
+        assert_eq!(db.blob_bytes_used(), 0);
+        db.put(1, 10).unwrap();
+        db.put(2, 300).unwrap();
+        assert_eq!(db.blob_bytes_used(), 3);
+        db.put(1, 300_000).unwrap();
+        assert_eq!(db.blob_bytes_used(), 5);
+        db.delete(&2);
+        assert_eq!(db.blob_bytes_used(), 3);
+        assert_eq!(db.stats().blob_bytes, 3);
+    }
+
+    #[test]
+    fn blob_bytes_ignore_failed_puts() {
+        let mut db: Database<u16, u32, Postcard, 2, 2, 1> = Database::new();
+        db.put(1, 300).unwrap();
+        db.put(2, 1).unwrap();
+        assert!(db.put(1, 300_000).is_err());
+        assert!(db.put(3, 1).is_err());
+        assert_eq!(db.blob_bytes_used(), 3);
+        assert_eq!(db.blob_bytes_capacity(), 4);
+    }
+
+    #[test]
+    fn stamped_values_count_versions() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, Stamped<u32>, Postcard, 8, 32, 2> = Database::new();
+        assert_eq!(db.put_stamped(1, 215, 1000).unwrap(), 1);
+        assert_eq!(db.put_stamped(1, 216, 1060).unwrap(), 2);
+        assert_eq!(
+            db.get_with_meta(&1).unwrap(),
+            Some((
+                216,
+                Meta {
+                    version: 2,
+                    timestamp: 1060
+                }
+            ))
+        );
+
+        // The meta is part of the value, so it is persisted
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut copy: Database<u16, Stamped<u32>, Postcard, 8, 32, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(
+            copy.meta(&1).unwrap(),
+            Some(Meta {
+                version: 2,
+                timestamp: 1060
+            })
+        );
+        assert_eq!(copy.meta(&2).unwrap(), None);
+    }
+
+    #[test]
+    fn stamped_versions_stay_on_failed_puts() {
+        let mut db: Database<u16, Stamped<u32>, Postcard, 2, 32, 1> = Database::new();
+        db.put_stamped(1, 215, 1000).unwrap();
+        db.put_stamped(2, 1, 1000).unwrap();
+        assert!(db.put_stamped(3, 1, 1000).is_err());
+        assert_eq!(db.meta(&3).unwrap(), None);
+        assert_eq!(db.put_stamped(1, 217, 1100).unwrap(), 2);
+    }
+
+    #[test]
+    fn error_codes_round_trip() {
+        assert_eq!(FlashError::CrcMismatch.code(), 0x010A);
+        assert_eq!(
+            errcode::group(FlashError::CrcMismatch.code()),
+            errcode::GROUP_FLASH
+        );
+        for code in 0x0101..=0x010F {
+            assert_eq!(FlashError::from_code(code).unwrap().code(), code);
+        }
+        assert_eq!(
+            ModbusError::from_code(0x1202),
+            Some(ModbusError::IllegalDataValue)
+        );
+        assert_eq!(DbError::<()>::Full.code(), 0x0203);
+
+        // Wrapped errors report what actually failed
+        let e = TxnError::Flash(FlashError::WriteError);
+        assert_eq!(e.code(), FlashError::WriteError.code());
+        assert!(matches!(
+            TxnError::from_code(e.code()),
+            Some(TxnError::Flash(FlashError::WriteError))
+        ));
+    }
+
+    #[test]
+    fn error_codes_reject_other_groups_and_data() {
+        assert!(FlashError::from_code(0x0203).is_none());
+        assert!(FlashError::from_code(0x01FF).is_none());
+        assert_eq!(ModbusError::from_code(0x0101), None);
+        // The codec error can't be rebuilt from a code
+        let encode = DbError::Encode(()).code();
+        assert_eq!(errcode::group(encode), errcode::GROUP_DB);
+        assert_eq!(DbError::<()>::from_code(encode), None);
+    }
+
+    #[test]
+    fn put_if_version_writes_the_expected_version() {
+        let mut db: Database<u16, Stamped<u32>, Postcard, 8, 32, 2> = Database::new();
+        // 0 stands for a key that doesn't exist yet
+        assert_eq!(db.put_if_version(1, 10, 0, 1000).unwrap(), 1);
+        let (count, meta) = db.get_with_meta(&1).unwrap().unwrap();
+        assert_eq!(
+            db.put_if_version(1, count + 1, meta.version, 1060).unwrap(),
+            2
+        );
+        assert_eq!(
+            db.get_with_meta(&1).unwrap(),
+            Some((
+                11,
+                Meta {
+                    version: 2,
+                    timestamp: 1060
+                }
+            ))
+        );
+    }
+
+    #[test]
+    fn put_if_version_rejects_stale_versions() {
+        let mut db: Database<u16, Stamped<u32>, Postcard, 8, 32, 2> = Database::new();
+        db.put_stamped(1, 10, 1000).unwrap();
+        let (_, meta) = db.get_with_meta(&1).unwrap().unwrap();
+        // Another writer got in between
+        db.put_stamped(1, 20, 1010).unwrap();
+        assert!(matches!(
+            db.put_if_version(1, 11, meta.version, 1020),
+            Err(DbError::VersionMismatch)
+        ));
+        assert!(matches!(
+            db.put_if_version(2, 1, 1, 1020),
+            Err(DbError::VersionMismatch)
+        ));
+        assert_eq!(
+            db.get_with_meta(&1).unwrap(),
+            Some((
+                20,
+                Meta {
+                    version: 2,
+                    timestamp: 1010
+                }
+            ))
+        );
+        assert_eq!(db.meta(&2).unwrap(), None);
+    }
+
+    #[test]
+    fn sync_tokens_are_persisted_by_a_save() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        let token = db.sync_epoch();
+        assert!(!db.is_persisted(token));
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        assert!(db.is_persisted(token));
+
+        // Later puts don't make an older token unpersisted
+        db.put(2, 20).unwrap();
+        assert!(db.is_persisted(token));
+        let later = db.sync_epoch();
+        assert!(!db.is_persisted(later));
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        assert!(db.is_persisted(later));
+    }
+
+    #[test]
+    fn sync_tokens_survive_failed_saves() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        let token = db.sync_epoch();
+        // An image past the end of the flash
+        assert!(db.save_to_flash(&mut flash, 4, 0x4000).is_err());
+        assert!(!db.is_persisted(token));
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        assert!(db.is_persisted(token));
+    }
+
+    #[test]
+    fn integer_keys_are_stored_fixed_width() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(300, 20).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let header = ImageHeader::from_bytes(&flash.bytes[..HEADER_SIZE])
+            .unwrap()
+            .unwrap();
+        assert!(header.format_version >= 2);
+        let records = image::records(&flash.bytes[..]).unwrap();
+        assert_eq!(records.key_width(), 2);
+        assert!(records
+            .map(|(key, _)| key)
+            .eq([&[1, 0][..], &[0x2c, 0x01][..]]));
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.get(&300).unwrap(), Some(20));
+
+        // Other keys keep the postcard layout
+        let mut named: Database<heapless::String<8>, u32, Postcard, 8, 16, 2> = Database::new();
+        named
+            .put(heapless::String::try_from("gain").unwrap(), 3)
+            .unwrap();
+        named.save_to_flash(&mut flash, 4, 0x2000).unwrap();
+        assert_eq!(
+            image::records(&flash.bytes[0x2000..]).unwrap().key_width(),
+            0
+        );
+    }
+
+    #[test]
+    fn fixed_width_keys_reject_other_widths() {
+        let mut out = [0u8; 4];
+        assert_eq!(keycodec::encode_fixed(&7u16, 2, &mut out), Some(2));
+        assert_eq!(keycodec::encode_fixed(&7u16, 4, &mut out), None);
+        assert_eq!(keycodec::encode_fixed(&7u16, 2, &mut out[..1]), None);
+        assert_eq!(keycodec::encode_fixed(&-1i16, 2, &mut out), None);
+        assert_eq!(keycodec::image_width([&1u8, &2u8]), 1);
+        assert_eq!(keycodec::image_width::<u8, _>([]), 0);
+
+        assert_eq!(keycodec::decode_fixed::<u16>(&[0x2c, 0x01]), Some(300));
+        // Out of range for the key type, or not a stored width
+        assert_eq!(keycodec::decode_fixed::<u8>(&[0x2c, 0x01]), None);
+        assert_eq!(keycodec::decode_fixed::<u32>(&[]), None);
+        assert_eq!(keycodec::decode_fixed::<u32>(&[1, 2, 3, 4, 5]), None);
+    }
+
+    #[test]
+    fn lists_push_and_pop_items() {
+        let mut flash = RamFlash::erased();
+        let mut lists: Database<u8, List<u16, 3>, Postcard, 4, 16, 2> = Database::new();
+        for code in [1, 2, 3] {
+            assert_eq!(lists.push(1, code).unwrap(), None);
+        }
+        // Full, the oldest item makes room
+        assert_eq!(lists.push(1, 4).unwrap(), Some(1));
+        assert!(lists.list_iter(&1).unwrap().eq([2, 3, 4]));
+        assert_eq!(lists.pop(&1).unwrap(), Some(4));
+
+        lists.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut copy: Database<u8, List<u16, 3>, Postcard, 4, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert!(copy.list_iter(&1).unwrap().eq([2, 3]));
+    }
+
+    #[test]
+    fn lists_stay_whole_on_failed_pushes() {
+        let mut lists: Database<u8, List<u16, 3>, Postcard, 4, 8, 2> = Database::new();
+        assert_eq!(lists.pop(&1).unwrap(), None);
+        assert_eq!(lists.list_len(&1).unwrap(), 0);
+        lists.push(1, 40_000).unwrap();
+        lists.push(1, 40_000).unwrap();
+        // A third item doesn't fit in B bytes
+        assert!(lists.push(1, 40_000).is_err());
+        assert_eq!(lists.list_len(&1).unwrap(), 2);
+
+        // The key goes with the last item
+        lists.pop(&1).unwrap();
+        assert_eq!(lists.pop(&1).unwrap(), Some(40_000));
+        assert!(!lists.contains_key(&1));
+    }
+
+    #[test]
+    fn queue_keeps_order_across_saves() {
+        let mut flash = RamFlash::erased();
+        let mut samples: Queue<u16, 4> = Queue::new();
+        for sample in [1, 2, 3, 4] {
+            assert_eq!(samples.enqueue(sample).unwrap(), None);
+        }
+        // Full, the oldest sample is dropped
+        assert_eq!(samples.enqueue(5).unwrap(), Some(1));
+        assert_eq!(samples.dequeue().unwrap(), Some(2));
+        assert_eq!(samples.len(), 3);
+        samples.save_to_flash(&mut flash, 0).unwrap();
+        assert!(!samples.needs_persist());
+
+        let mut copy: Queue<u16, 4> = Queue::new();
+        assert!(copy.load_from_flash(&mut flash, 0).unwrap().is_some());
+        assert_eq!(copy.peek().unwrap(), Some(3));
+        copy.enqueue(6).unwrap();
+        for sample in [3, 4, 5, 6] {
+            assert_eq!(copy.dequeue().unwrap(), Some(sample));
+        }
+        assert_eq!(copy.dequeue().unwrap(), None);
+    }
+
+    #[test]
+    fn queue_is_unchanged_by_failed_enqueues() {
+        let mut flash = RamFlash::erased();
+        let mut names: Queue<heapless::String<16>, 4, Postcard, 8> = Queue::new();
+        assert!(names.load_from_flash(&mut flash, 0).unwrap().is_none());
+        assert!(names.is_empty());
+        names
+            .enqueue(heapless::String::try_from("pump").unwrap())
+            .unwrap();
+        // Doesn't fit in B bytes
+        let long = heapless::String::try_from("circulation").unwrap();
+        assert!(names.enqueue(long).is_err());
+        assert_eq!(names.len(), 1);
+        assert_eq!(names.dequeue().unwrap().as_deref(), Some("pump"));
+        assert!(names.is_empty());
+    }
+
+    #[test]
+    fn boot_count_is_saved_with_the_image() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        assert_eq!(db.start_boot(), 1);
+        assert!(db.needs_persist());
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.boot_count(), 1);
+        assert_eq!(copy.start_boot(), 2);
+        copy.set_clock(test_clock);
+        let now = copy.hybrid_now();
+        assert_eq!(now.boot, 2);
+        assert!(now.ticks > 0);
+        // A later boot sorts after any time of an earlier one
+        let earlier = HybridTime {
+            boot: 1,
+            ticks: u32::MAX as u64,
+        };
+        assert!(earlier < now);
+        assert!(earlier.to_u64() < now.to_u64());
+        assert_eq!(HybridTime::from_u64(now.to_u64()), now);
+    }
+
+    #[test]
+    fn boot_count_needs_a_save_to_stick() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.start_boot();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        // Reset before the next save, the number is used again
+        db.start_boot();
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.start_boot(), 2);
+        // Without a clock there are no ticks
+        assert_eq!(copy.hybrid_now(), HybridTime { boot: 2, ticks: 0 });
+        // Only 16 bits of boot and 48 of ticks are packed
+        let wide = HybridTime {
+            boot: 0x1_0001,
+            ticks: 1 << 48,
+        };
+        assert_eq!(
+            HybridTime::from_u64(wide.to_u64()),
+            HybridTime { boot: 1, ticks: 0 }
+        );
+    }
+
+    #[test]
+    fn boot_count_stays_when_an_image_does_not_load() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.start_boot();
+        db.start_boot();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        // An entry count past the end of the payload, with a matching CRC
+        let mut header = ImageHeader::from_bytes(&flash.bytes[..HEADER_SIZE])
+            .unwrap()
+            .unwrap();
+        let payload = HEADER_SIZE..HEADER_SIZE + header.payload_len as usize;
+        flash.bytes[HEADER_SIZE..HEADER_SIZE + 4].copy_from_slice(&9u32.to_le_bytes());
+        header.payload_crc = image::CRC32.checksum(&flash.bytes[payload]);
+        flash.bytes[..HEADER_SIZE].copy_from_slice(&header.to_bytes());
+
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.start_boot();
+        assert!(matches!(
+            copy.load_from_flash(&mut flash, 0),
+            Err(FlashError::BufferTooSmall)
+        ));
+        assert_eq!(copy.boot_count(), 1);
+    }
+
+    #[test]
+    fn capacity_check_tests_the_last_page() {
+        let mut flash = RamFlash::erased();
+        let (_, report) = Database::<u16, u32, Postcard, 8, 16, 2>::with_capacity_check(
+            &mut flash,
+            Partition::new(0, 0x2000),
+            Some(0x3000),
+        );
+        assert!(report.is_ok());
+        assert_eq!(report.layout, Ok(()));
+        assert_eq!(report.scratch, ScratchTest::Passed);
+        assert!(matches!(report.image, Ok(None)));
+        // The scratch word is erased again
+        assert!(flash.bytes.iter().all(|b| *b == 0xFF));
+
+        // An image in the partition doesn't get in the way of the scratch page
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let report = init::check_partition(&mut flash, Partition::new(0, 0x2000), Some(0x3000));
+        assert!(matches!(report.image, Ok(Some(_))));
+        assert_eq!(report.scratch, ScratchTest::Passed);
+    }
+
+    #[test]
+    fn capacity_check_writes_nothing_it_should_not() {
+        let mut flash = RamFlash::erased();
+        // Past the end of the flash
+        let report =
+            init::check_partition(&mut flash, Partition::new(0x2000, 0x4000), Some(0x3000));
+        assert!(!report.is_ok());
+        assert!(report.layout.is_err());
+        assert_eq!(report.scratch, ScratchTest::Skipped);
+
+        // No scratch page, or one inside the partition
+        let report = init::check_partition(&mut flash, Partition::new(0, 0x2000), None);
+        assert!(report.is_ok());
+        assert_eq!(report.scratch, ScratchTest::Skipped);
+        let report = init::check_partition(&mut flash, Partition::new(0, 0x2000), Some(0x1000));
+        assert_eq!(report.scratch, ScratchTest::Skipped);
+
+        // A page that isn't blank is left as it is
+        flash.bytes[0x3010] = 0x00;
+        let report = init::check_partition(&mut flash, Partition::new(0, 0x2000), Some(0x3000));
+        assert!(report.is_ok());
+        assert_eq!(report.scratch, ScratchTest::Skipped);
+        assert_eq!(flash.bytes[0x3010], 0x00);
+        assert!(flash.bytes[..0x3010].iter().all(|b| *b == 0xFF));
+    }
+
+    #[test]
+    fn time_series_logs_samples_in_order() {
+        let mut flash = RamFlash::erased();
+        let log = Partition::new(0x2000, 0x2000);
+        let mut temps: TimeSeries<i16, Postcard, 32> =
+            TimeSeries::new(log, 4096, Retention::default());
+        assert_eq!(temps.open(&mut flash).unwrap(), 0);
+        for (t, temp) in [(10, 215), (20, -40), (30, 220)] {
+            temps.append(&mut flash, t, &temp).unwrap();
+        }
+        assert_eq!(temps.capacity(), 256);
+
+        // Found again after a reset
+        let mut copy: TimeSeries<i16, Postcard, 32> = TimeSeries::new(
+            log,
+            4096,
+            Retention {
+                max_count: 2,
+                max_age: 0,
+            },
+        );
+        assert_eq!(copy.open(&mut flash).unwrap(), 3);
+        assert_eq!(copy.last_timestamp(), Some(30));
+        let mut seen = 0;
+        copy.for_each(&mut flash, 30, |t, temp| {
+            assert_eq!((t, temp), [(20, -40), (30, 220)][seen]);
+            seen += 1;
+        })
+        .unwrap();
+        assert_eq!(seen, 2);
+        copy.append(&mut flash, 40, &1).unwrap();
+        assert_eq!(copy.len(), 4);
+    }
+
+    #[test]
+    fn time_series_rejects_bad_samples() {
+        let mut flash = RamFlash::erased();
+        let log = Partition::new(0x2000, 0x2000);
+        let mut temps: TimeSeries<i16, Postcard, 32> = TimeSeries::new(
+            log,
+            4096,
+            Retention {
+                max_count: 0,
+                max_age: 15,
+            },
+        );
+        temps.append(&mut flash, 20, &1).unwrap();
+        assert!(matches!(
+            temps.append(&mut flash, 10, &2),
+            Err(TimeSeriesError::OutOfOrder)
+        ));
+        // No room for the value in a record this small
+        let mut tiny: TimeSeries<i16, Postcard, 16> =
+            TimeSeries::new(log, 4096, Retention::default());
+        assert!(matches!(
+            tiny.append(&mut flash, 30, &3),
+            Err(TimeSeriesError::Encode(_))
+        ));
+        assert_eq!(tiny.len(), 0);
+
+        // A torn record is skipped, out of age ones too
+        temps.append(&mut flash, 25, &4).unwrap();
+        flash.bytes[0x2000 + 32 + 12] ^= 0x01;
+        let mut seen = 0;
+        assert_eq!(temps.for_each(&mut flash, 40, |_, _| seen += 1).unwrap(), 0);
+        assert_eq!(temps.for_each(&mut flash, 30, |_, _| seen += 1).unwrap(), 1);
+        assert_eq!(temps.len(), 2);
+    }
+
+    #[test]
+    fn compressed_values_use_the_dictionary() {
+        type Text = heapless::String<64>;
+        let value = Text::try_from("humidity temperature humidity").unwrap();
+        let mut blob = [0u8; 64];
+        let n = <Compressed<Postcard, Words> as Codec<Text>>::encode(&mut blob, &value)
+            .ok()
+            .unwrap();
+        assert_eq!(blob[0], Words::ID);
+        assert!(n < 10);
+
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u8, Text, Compressed<Postcard, Words>, 4, 16, 2> = Database::new();
+        assert!(db.put(1, value.clone()).is_ok());
+        // Nothing to gain, stored as is
+        assert!(db.put(2, Text::try_from("xyz").unwrap()).is_ok());
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut copy: Database<u8, Text, Compressed<Postcard, Words>, 4, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert!(copy.get(&1).ok() == Some(Some(value)));
+        assert!(copy.get(&2).ok() == Some(Some(Text::try_from("xyz").unwrap())));
+    }
+
+    #[test]
+    fn compressed_values_reject_bad_blobs() {
+        let mut raw = [0u8; MAX_RAW];
+        let mut blob = [0u8; 16];
+        let n = compress::compress::<()>(&mut blob, b"temperature", Words::ID, Words::BYTES)
+            .ok()
+            .unwrap();
+        // Written with a dictionary of a different ID
+        assert!(matches!(
+            compress::decompress::<()>(&mut raw, &blob[..n], 3, Words::BYTES),
+            Err(CompressError::WrongDictionary(2))
+        ));
+        assert!(matches!(
+            compress::decompress::<()>(&mut raw, &blob[..n - 1], Words::ID, Words::BYTES),
+            Err(CompressError::Corrupt)
+        ));
+        assert!(matches!(
+            compress::decompress::<()>(&mut raw, &[], Words::ID, Words::BYTES),
+            Err(CompressError::Corrupt)
+        ));
+        // A match reaching before the dictionary
+        assert!(matches!(
+            compress::decompress::<()>(&mut raw, &[Words::ID, 0x80, 0xFF, 0x00], Words::ID, b""),
+            Err(CompressError::Corrupt)
+        ));
+        // Repeats inside the value are found without a dictionary
+        let n = compress::compress::<()>(&mut blob, &[0x55; 32], 1, b"")
+            .ok()
+            .unwrap();
+        assert!(n < 8);
+        assert!(matches!(
+            compress::compress::<()>(&mut blob[..4], b"no repeats here", 1, b""),
+            Err(CompressError::TooLarge)
+        ));
+    }
+
+    #[test]
+    fn arena_store_shares_its_bytes() {
+        let mut store: ArenaStore<u16, 4, 32> = ArenaStore::new();
+        store.insert(1, &[1; 6]).unwrap();
+        store.insert(2, &[2; 6]).unwrap();
+        // Growing a value leaves a hole behind
+        store.insert(1, &[3; 8]).unwrap();
+        assert_eq!(store.free(), 18);
+        assert_eq!(store.fragmented(), 6);
+        store.compact();
+        assert_eq!(store.fragmented(), 0);
+        assert_eq!(store.get(&1), Some(&[3; 8][..]));
+        assert_eq!(store.get(&2), Some(&[2; 6][..]));
+        // Only compacted when a value doesn't fit at the end
+        store.insert(2, &[4; 7]).unwrap();
+        store.insert(3, &[5; 16]).unwrap();
+        assert_eq!(store.fragmented(), 0);
+        assert_eq!(store.free(), 1);
+        assert_eq!(store.get(&2), Some(&[4; 7][..]));
+
+        // More values than B per entry would allow, fewer bytes in total
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 4, 16, 2, ArenaStore<u16, 4, 12>> =
+            Database::with_store(ArenaStore::new());
+        assert_eq!(db.blob_bytes_capacity(), 12);
+        for key in 0..4 {
+            db.put(key, 70_000 + key as u32).unwrap();
+        }
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut copy: Database<u16, u32, Postcard, 4, 16, 2, ArenaStore<u16, 4, 12>> =
+            Database::with_store(ArenaStore::new());
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.get(&3).unwrap(), Some(70_003));
+    }
+
+    #[test]
+    fn arena_store_keeps_values_when_full() {
+        let mut store: ArenaStore<u16, 2, 16> = ArenaStore::new();
+        store.insert(1, &[1; 10]).unwrap();
+        assert_eq!(store.insert(2, &[2; 7]), Err(StoreError::Full));
+        assert_eq!(store.insert(1, &[1; 17]), Err(StoreError::Full));
+        assert_eq!(store.get(&1), Some(&[1; 10][..]));
+        store.insert(2, &[2; 6]).unwrap();
+        // No room for another key
+        assert_eq!(store.insert(3, &[]), Err(StoreError::Full));
+
+        // Removing frees the bytes for the next value
+        assert!(store.remove(&1));
+        store.insert(2, &[4; 16]).unwrap();
+        assert_eq!(store.free(), 0);
+        assert_eq!(store.len(), 1);
+        store.clear();
+        assert_eq!(store.free(), 16);
+    }
+
+    #[test]
+    fn get_many_fills_every_slot() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 20).unwrap();
+        db.put(3, 30).unwrap();
+        let mut out = [Some(99); 4];
+        db.get_many(&[3, 9, 1, 3], &mut out).unwrap();
+        assert_eq!(out, [Some(30), None, Some(10), Some(30)]);
+
+        // Fewer slots than keys, the rest is skipped
+        let mut short = [None; 1];
+        db.get_many(&[2, 3], &mut short).unwrap();
+        assert_eq!(short, [Some(20)]);
+    }
+
+    #[test]
+    fn get_many_stops_at_a_value_that_does_not_decode() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 300_000).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        // 300_000 isn't a u16
+        let mut narrow: Database<u16, u16, Postcard, 8, 16, 2> = Database::new();
+        narrow.load_from_flash(&mut flash, 0).unwrap();
+        let mut out = [None; 2];
+        assert!(matches!(
+            narrow.get_many(&[1, 2], &mut out),
+            Err(DbError::Decode(_))
+        ));
+        assert_eq!(narrow.stats().decode_errors, 1);
+        assert_eq!(narrow.get(&1).unwrap(), Some(10));
+    }
+
+    #[test]
+    fn update_field_swaps_one_field() {
+        let mut db: Database<u8, Setpoint, Json, 4, 48, 2> = Database::new();
+        assert!(db
+            .put(
+                1,
+                Setpoint {
+                    temp_c10: 215,
+                    fan: false
+                }
+            )
+            .is_ok());
+        assert_eq!(db.update_field(&1, "fan", b"true"), Ok(true));
+        assert_eq!(db.update_field(&1, "temp_c10", b"-40"), Ok(true));
+        assert!(
+            db.get(&1).ok()
+                == Some(Some(Setpoint {
+                    temp_c10: -40,
+                    fan: true
+                }))
+        );
+        assert_eq!(db.update_field(&2, "fan", b"true"), Ok(false));
+    }
+
+    #[test]
+    fn update_field_leaves_the_value_on_errors() {
+        let mut db: Database<u8, Setpoint, Json, 4, 32, 2> = Database::new();
+        let before = Setpoint {
+            temp_c10: 215,
+            fan: false,
+        };
+        assert!(db.put(1, before.clone()).is_ok());
+        assert_eq!(
+            db.update_field(&1, "rh_pct", b"41"),
+            Err(FieldError::NoField)
+        );
+        // Not a bool, the result doesn't decode
+        assert_eq!(
+            db.update_field(&1, "fan", b"\"on\""),
+            Err(FieldError::Malformed)
+        );
+        assert_eq!(
+            db.update_field(&1, "temp_c10", b"                       1"),
+            Err(FieldError::TooLarge)
+        );
+        assert!(db.get(&1).ok() == Some(Some(before)));
+
+        let mut out = [0u8; 32];
+        assert_eq!(
+            <Json as FieldCodec<Setpoint>>::update_field(b"[1, 2]", "fan", b"true", &mut out),
+            Err(FieldError::Malformed)
+        );
+    }
+
+    #[test]
+    fn ram_db_keeps_values_in_ram() {
+        let mut cfg: RamDb<u8, u32, Postcard, 4, 8, 2> = RamDb::new();
+        assert!(cfg.is_empty());
+        cfg.put(1, 10).unwrap();
+        cfg.put(2, 300_000).unwrap();
+        assert_eq!(cfg.get(&2).unwrap(), Some(300_000));
+        assert_eq!(cfg.get_uncached(&1).unwrap(), Some(10));
+        let mut out = [None; 2];
+        cfg.get_many(&[2, 3], &mut out).unwrap();
+        assert_eq!(out, [Some(300_000), None]);
+        assert!(cfg.delete(&1));
+        assert!(cfg.keys().eq([&2]));
+        assert_eq!(cfg.stats().cache_hits, 2);
+    }
+
+    #[test]
+    fn ram_db_reports_database_errors() {
+        let mut cfg: RamDb<u8, u32, Postcard, 2, 8, 2> = RamDb::new();
+        cfg.put(1, 10).unwrap();
+        cfg.put(2, 20).unwrap();
+        assert!(matches!(cfg.put(3, 30), Err(DbError::Full)));
+        assert!(!cfg.contains_key(&3));
+        assert_eq!(cfg.len(), cfg.capacity());
+        // Replacing a value still works when full
+        cfg.put(2, 21).unwrap();
+        assert!(!cfg.delete(&3));
+        cfg.clear();
+        assert!(cfg.is_empty());
+    }
+
+    #[test]
+    fn rename_moves_the_value() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.set_index(|v| *v % 10);
+        db.put(1, 300_000).unwrap();
+        assert!(db.rename(&1, 2).unwrap());
+        assert_eq!(db.get(&1).unwrap(), None);
+        assert_eq!(db.get(&2).unwrap(), Some(300_000));
+        assert!(db.find_by_index(0).eq([&2]));
+        assert!(db.needs_persist());
+        // Nothing to move
+        assert!(!db.rename(&1, 3).unwrap());
+        assert!(db.rename(&2, 2).unwrap());
+
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        let mut copy: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert!(copy.keys().eq([&2]));
+    }
+
+    #[test]
+    fn rename_changes_nothing_on_errors() {
+        let mut db: Database<u16, u32, Postcard, 2, 16, 2> = Database::new();
+        db.put(1, 10).unwrap();
+        db.put(2, 20).unwrap();
+        assert!(matches!(db.rename(&1, 2), Err(DbError::Exists)));
+        // The old slot is free again by the time the new key needs one
+        assert!(db.rename(&1, 3).unwrap());
+        assert_eq!(db.get(&3).unwrap(), Some(10));
+
+        // A critical key can't become an ordinary one in the reserved slot
+        let mut db: Database<u16, u32, Postcard, 4, 16, 2> = Database::new();
+        db.reserve(critical, 1, 0);
+        for k in [1, 2, 3, 100] {
+            db.put(k, k as u32).unwrap();
+        }
+        assert!(db.rename(&100, 4).is_err());
+        assert_eq!(db.get(&100).unwrap(), Some(100));
+        assert!(!db.contains_key(&4));
+        assert_eq!(
+            DbError::<()>::from_code(DbError::<()>::Exists.code()),
+            Some(DbError::Exists)
+        );
+    }
+
+    #[test]
+    fn tier_error_codes_round_trip() {
+        // persistence
+        let e = flash::FlashError::Unaligned;
+        assert_eq!(errcode::group(e.code()), errcode::GROUP_FLASH_DRIVER);
+        assert!(matches!(
+            flash::FlashError::from_code(e.code()),
+            Some(flash::FlashError::Unaligned)
+        ));
+        assert_eq!(
+            errcode::group(LayoutError::Overlap(0, 1).code()),
+            errcode::GROUP_LAYOUT
+        );
+        // sync
+        let e = DiscoveryError::BadValue;
+        assert_eq!(DiscoveryError::from_code(e.code()), Some(e));
+    }
+
+    #[test]
+    fn tier_error_codes_stay_in_their_groups() {
+        let driver = flash::FlashError::OutOfBounds.code();
+        // Not the database's FlashError, same variant number
+        assert!(db::FlashError::from_code(driver).is_none());
+        assert_eq!(DiscoveryError::from_code(driver), None);
+        assert!(flash::FlashError::from_code(errcode::GROUP_FLASH_DRIVER << 8).is_none());
+        // Layout errors carry the partition, they aren't rebuilt from a code
+        assert_eq!(
+            LayoutError::from_code(LayoutError::OutOfBounds(2).code()),
+            None
+        );
+    }
+
+    #[test]
+    fn ttl_clock_expires_entries_on_its_own() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.set_ttl_clock::<TestTicks>();
+        db.put_with_ttl(1, 10, 5).unwrap();
+        db.put(2, 20).unwrap();
+        TICKS.fetch_add(4, Ordering::Relaxed);
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        // Gone without a tick()
+        assert!(!db.contains_key(&1));
+        assert_eq!(db.get_uncached(&1).unwrap(), None);
+        assert_eq!(db.get(&1).unwrap(), None);
+        assert_eq!(db.len(), 1);
+        assert_eq!(db.get(&2).unwrap(), Some(20));
+    }
+
+    #[test]
+    fn ttl_clock_ignores_ticks_and_stale_deadlines() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.set_ttl_clock::<TestTicks>();
+        db.put_with_ttl(1, 10, 5).unwrap();
+        // The clock says what time it is
+        assert_eq!(db.tick(100), 0);
+        assert_eq!(db.get(&1).unwrap(), Some(10));
+
+        db.put_with_ttl(2, 20, 1).unwrap();
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        // An expired key can be renamed onto, the value keeps its own deadline
+        assert!(db.rename(&1, 2).unwrap());
+        TICKS.fetch_add(3, Ordering::Relaxed);
+        assert_eq!(db.get(&2).unwrap(), Some(10));
+        TICKS.fetch_add(1, Ordering::Relaxed);
+        assert_eq!(db.purge_expired(), 1);
+        assert_eq!(db.len(), 0);
+    }
+
+    #[test]
+    fn tagged_reads_old_json_and_writes_multi() {
+        type Old = Database<u16, u32, Json, 8, 16, 2>;
+        type New = Database<u16, u32, Tagged<Postcard>, 8, 16, 2>;
+        let mut flash = RamFlash::erased();
+        let mut old = Old::new();
+        assert!(old.put(1, 300).is_ok());
+        old.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        // Same image, now read with Tagged
+        let mut db = New::new();
+        db.load_from_flash(&mut flash, 0).unwrap();
+        assert!(db.get(&1).ok() == Some(Some(300)));
+        let mut buf = [0u8; 8];
+        let n = <Tagged<Postcard> as Codec<u32>>::encode(&mut buf, &300)
+            .ok()
+            .unwrap();
+        assert_eq!(&buf[..n], &[0x01, 0xac, 0x02]);
+        let n = <Tagged<Json> as Codec<u32>>::encode(&mut buf, &300)
+            .ok()
+            .unwrap();
+        assert_eq!(&buf[..n], b"\x02300");
+    }
+
+    #[test]
+    fn tagged_rejects_what_neither_format_reads() {
+        assert!(matches!(
+            <Tagged as Codec<u32>>::decode(b"abc"),
+            Err(MultiError::Json(_))
+        ));
+        assert!(matches!(
+            <Tagged as Codec<u32>>::decode(&[0x01]),
+            Err(MultiError::Postcard(_))
+        ));
+        assert!(matches!(
+            <Tagged as Codec<u32>>::encode(&mut [], &1),
+            Err(MultiError::Empty)
+        ));
+    }
+
+    #[test]
+    fn postcard_streams_values_in_chunks() {
+        let table = heapless::Vec::<u8, 100>::from_slice(&[0x5A; 100]).unwrap();
+        let mut stream: heapless::Vec<u8, 128> = heapless::Vec::new();
+        let mut pieces = 0;
+        let len = Postcard::encode_into(&table, &mut |piece| {
+            assert!(piece.len() <= STREAM_CHUNK);
+            pieces += 1;
+            stream.extend_from_slice(piece).map_err(|_| ())
+        })
+        .ok()
+        .unwrap();
+        assert_eq!((len, pieces), (101, 4));
+
+        let mut rest = &stream[..];
+        let copy: heapless::Vec<u8, 100> =
+            Postcard::decode_from(len, &mut |buf| take(&mut rest, buf).map_err(|_| ()))
+                .ok()
+                .unwrap();
+        assert!(copy == table);
+        assert!(rest.is_empty());
+    }
+
+    #[test]
+    fn streams_report_input_and_size_errors() {
+        let mut stream: heapless::Vec<u8, 16> = heapless::Vec::new();
+        let name = heapless::String::<16>::try_from("pump").unwrap();
+        Postcard::encode_into(&name, &mut |piece| {
+            stream.extend_from_slice(piece).map_err(|_| ())
+        })
+        .ok()
+        .unwrap();
+        // Postcard reads as it goes, an input that ends early is a short value
+        let mut rest = &stream[..2];
+        assert!(matches!(
+            <Postcard as Codec<heapless::String<16>>>::decode_from(stream.len(), &mut |buf| {
+                take(&mut rest, buf).map_err(|_| ())
+            }),
+            Err(StreamError::Codec(_))
+        ));
+        // Codecs that stage the value report the input failing as such
+        assert!(matches!(
+            <Json as Codec<u32>>::decode_from(2, &mut |_| Err(())),
+            Err(StreamError::Io)
+        ));
+        // Codecs that stage the value can't take more than STREAM_STAGING bytes
+        assert!(matches!(
+            <Json as Codec<u32>>::decode_from(STREAM_STAGING + 1, &mut |_| Ok(())),
+            Err(StreamError::TooLarge)
+        ));
+        let mut zeros = |buf: &mut [u8]| {
+            buf.fill(0);
+            Ok(())
+        };
+        assert!(matches!(
+            <Json as Codec<u32>>::decode_from(2, &mut zeros),
+            Err(StreamError::Codec(_))
+        ));
+        // A sink that fails stops the encoding
+        let table = heapless::Vec::<u8, 100>::from_slice(&[0x5A; 100]).unwrap();
+        let mut pieces = 0;
+        let failing = Postcard::encode_into(&table, &mut |_| {
+            pieces += 1;
+            Err(())
+        });
+        assert!(matches!(failing, Err(StreamError::Io)));
+        assert_eq!(pieces, 1);
+    }
+
+    #[test]
+    fn fixed_point_values_round_trip() {
+        let mut buf = [0u8; 8];
+        let n = <Fixed<Milli> as Codec<f32>>::encode(&mut buf, &21.5).unwrap();
+        assert_eq!(&buf[..n], &21_500i32.to_le_bytes());
+        assert_eq!(<Fixed<Milli> as Codec<f32>>::decode(&buf[..n]), Ok(21.5));
+        assert_eq!(Q16::from(1.5), Q16(0x1_8000));
+        assert_eq!(f32::from(Q16(-0x8000)), -0.5);
+        // Rounded to the nearest step
+        assert_eq!(Milli::from(0.0004), Milli(0));
+        assert_eq!(Milli::from(-1.0006), Milli(-1001));
+
+        // An f32 field through the serde adapter, postcard sees an i32
+        let probe = Probe { temp_c: -4.25 };
+        let n = postcard::to_slice(&probe, &mut buf).unwrap().len();
+        assert_eq!(&buf[..n], &[0xB3, 0x42]);
+        assert!(postcard::from_bytes::<Probe>(&buf[..n]).ok() == Some(probe));
+    }
+
+    #[test]
+    fn fixed_codec_rejects_bad_lengths() {
+        let mut small = [0u8; 3];
+        assert_eq!(
+            <Fixed<Q16> as Codec<f32>>::encode(&mut small, &1.0),
+            Err(FixedError::BufferTooSmall)
+        );
+        assert_eq!(
+            <Fixed<Q16> as Codec<f32>>::decode(&[0; 3]),
+            Err(FixedError::BadLength)
+        );
+        assert_eq!(
+            <Fixed<Q16> as Codec<f32>>::decode(&[0; 5]),
+            Err(FixedError::BadLength)
+        );
+        assert_eq!(
+            FixedError::from_code(FixedError::BadLength.code()),
+            Some(FixedError::BadLength)
+        );
+    }
+
+    #[test]
+    fn codec_errors_reduce_to_their_kind() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 300_000).unwrap();
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+        // 300_000 isn't a u16
+        let mut narrow: Database<u16, u16, Postcard, 8, 16, 2> = Database::new();
+        narrow.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(
+            narrow.get(&1).map_err(DbError::into_kind),
+            Err(DbError::Decode(CodecErrorKind::Malformed))
+        );
+        // A string that doesn't fit in B bytes
+        let mut names: Database<u16, heapless::String<16>, Postcard, 8, 4, 2> = Database::new();
+        let long = heapless::String::try_from("circulation").unwrap();
+        assert_eq!(
+            names.put(1, long).map_err(DbError::into_kind),
+            Err(DbError::Encode(CodecErrorKind::BufferTooSmall))
+        );
+
+        let kind: CodecErrorKind = VersionedError::<postcard::Error>::TooNew(3).into();
+        assert_eq!(kind, CodecErrorKind::UnsupportedVersion);
+        let kind: CodecErrorKind = CheckedError::<postcard::Error>::CrcMismatch.into();
+        assert_eq!(kind, CodecErrorKind::Integrity);
+    }
+
+    #[test]
+    fn codec_error_kinds_keep_the_other_errors() {
+        assert_eq!(DbError::<postcard::Error>::Full.into_kind(), DbError::Full);
+        assert_eq!(
+            DbError::<postcard::Error>::Exists.into_kind(),
+            DbError::Exists
+        );
+        let kind = CodecErrorKind::Integrity;
+        assert_eq!(errcode::group(kind.code()), errcode::GROUP_CODEC_KIND);
+        assert_eq!(CodecErrorKind::from_code(kind.code()), Some(kind));
+        assert_eq!(
+            CodecErrorKind::from_code(errcode::GROUP_CODEC_KIND << 8),
+            None
+        );
+    }
+
+    #[test]
+    fn get_with_lends_borrowed_values() {
+        let mut db: Database<u16, heapless::String<16>, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, heapless::String::try_from("pump").unwrap())
+            .unwrap();
+        assert_eq!(db.get_with(&1, |name: &str| name.len()).unwrap(), Some(4));
+        assert_eq!(db.get_with(&2, |name: &str| name.len()).unwrap(), None);
+        let first = db.get_with(&1, |name: &str| name.as_bytes()[0]).unwrap();
+        assert_eq!(first, Some(b'p'));
+        let n: Result<u16, _> = <Json as BorrowedCodec<u16>>::decode_borrowed(b"42");
+        assert_eq!(n.ok(), Some(42));
+    }
+
+    #[test]
+    fn get_with_reports_values_that_do_not_decode() {
+        let mut db: Database<u16, u32, Postcard, 8, 16, 2> = Database::new();
+        db.put(1, 300).unwrap();
+        let mut called = false;
+        // 0xac 0x02 reads as a 300 byte string
+        let result = db.get_with(&1, |_: &str| called = true);
+        assert!(matches!(result, Err(DbError::Decode(_))));
+        assert!(!called);
+        assert_eq!(db.stats().decode_errors, 1);
+        // A number isn't a string, borrowed or not
+        let number: Result<&str, _> = <Json as BorrowedCodec<&str>>::decode_borrowed(b"42");
+        assert!(number.is_err());
+    }
+
+    #[test]
+    fn max_encoded_size_adds_up_through_wrappers() {
+        assert_eq!(<Fixed<Milli> as Codec<f32>>::MAX_ENCODED_SIZE, Some(4));
+        assert_eq!(
+            <Checked<Fixed<Milli>> as Codec<f32>>::MAX_ENCODED_SIZE,
+            Some(6)
+        );
+        assert_eq!(<Postcard as Codec<u32>>::MAX_ENCODED_SIZE, None);
+
+        // B just big enough compiles and takes every value
+        let mut db: Database<u16, f32, Checked<Fixed<Milli>>, 4, 6, 2> = Database::new();
+        assert!(db.put(1, f32::MAX).is_ok());
+        assert!(db.put(2, -21.5).is_ok());
+        assert!(matches!(db.get(&2), Ok(Some(v)) if v == -21.5));
+    }
+
+    #[test]
+    fn codecs_without_a_max_are_checked_on_put() {
+        // Postcard can't tell, a u32 takes up to 5 bytes
+        let mut db: Database<u16, u32, Postcard, 4, 4, 2> = Database::new();
+        db.put(1, 0x0FFF_FFFF).unwrap();
+        assert!(matches!(db.put(2, u32::MAX), Err(DbError::Encode(_))));
+        assert!(!db.contains_key(&2));
+    }
+
+    #[test]
+    fn sorted_store_takes_keys_without_hash() {
+        type Db = Database<SensorId, u32, Postcard, 4, 8, 2, SortedStore<SensorId, 4, 8>>;
+        let mut flash = RamFlash::erased();
+        let mut db: Db = Database::with_store(SortedStore::new());
+        db.put(SensorId(2, 0), 20).unwrap();
+        db.put(SensorId(1, 7), 17).unwrap();
+        assert!(db.keys().eq([&SensorId(1, 7), &SensorId(2, 0)]));
+        db.save_to_flash(&mut flash, 4, 0).unwrap();
+
+        let mut copy: Db = Database::with_store(SortedStore::new());
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.get(&SensorId(1, 7)).unwrap(), Some(17));
+        assert_eq!(copy.get(&SensorId(1, 8)).unwrap(), None);
+    }
+
+    #[test]
+    fn sorted_store_without_hash_reports_full() {
+        let mut db: Database<SensorId, u32, Postcard, 2, 8, 2, SortedStore<SensorId, 2, 8>> =
+            Database::with_store(SortedStore::new());
+        db.put(SensorId(1, 0), 1).unwrap();
+        db.put(SensorId(3, 0), 3).unwrap();
+        assert!(matches!(db.put(SensorId(2, 0), 2), Err(DbError::Full)));
+        assert!(db.keys().eq([&SensorId(1, 0), &SensorId(3, 0)]));
+        assert!(db.delete(&SensorId(1, 0)));
+        db.put(SensorId(2, 0), 2).unwrap();
+        assert!(db.keys().eq([&SensorId(2, 0), &SensorId(3, 0)]));
+    }
+
+    #[test]
+    fn helpers_take_keys_without_hash() {
+        let mut lists: Database<
+            SensorId,
+            List<u16, 3>,
+            Postcard,
+            4,
+            16,
+            2,
+            SortedStore<SensorId, 4, 16>,
+        > = Database::with_store(SortedStore::new());
+        lists.push(SensorId(1, 0), 5).unwrap();
+        lists.push(SensorId(1, 0), 6).unwrap();
+        assert_eq!(lists.pop(&SensorId(1, 0)).unwrap(), Some(6));
+        assert_eq!(lists.list_len(&SensorId(1, 0)).unwrap(), 1);
+
+        let mut ram: RamDb<SensorId, u32, Postcard, 4, 8, 2, SortedStore<SensorId, 4, 8>> =
+            RamDb::with_store(SortedStore::new());
+        ram.put(SensorId(2, 1), 21).unwrap();
+        assert!(ram.keys().eq([&SensorId(2, 1)]));
+
+        let mut flash = RamFlash::erased();
+        let (mut db, report) = Database::<
+            SensorId,
+            u32,
+            Postcard,
+            4,
+            8,
+            2,
+            SortedStore<SensorId, 4, 8>,
+        >::with_capacity_check(
+            &mut flash, Partition::new(0, 0x2000), None
+        );
+        assert!(report.is_ok());
+        db.put(SensorId(1, 1), 11).unwrap();
+        let mut maintenance = Maintenance::new(MaintenancePolicy {
+            flash_offset: 0,
+            scrub_every: 0,
+            autosave_every: 1,
+        });
+        assert_eq!(
+            maintenance.step(&mut db, &mut flash).unwrap(),
+            MaintenanceStep::Saved
+        );
+        let mut copy: Database<SensorId, u32, Postcard, 4, 8, 2, SortedStore<SensorId, 4, 8>> =
+            Database::with_store(SortedStore::new());
+        copy.load_from_flash(&mut flash, 0).unwrap();
+        assert_eq!(copy.get(&SensorId(1, 1)).unwrap(), Some(11));
+    }
+
+    #[test]
+    fn helpers_without_hash_report_errors() {
+        let mut ram: RamDb<SensorId, u32, Postcard, 2, 8, 2, SortedStore<SensorId, 2, 8>> =
+            RamDb::with_store(SortedStore::new());
+        ram.put(SensorId(1, 0), 1).unwrap();
+        ram.put(SensorId(3, 0), 3).unwrap();
+        assert!(matches!(ram.put(SensorId(2, 0), 2), Err(DbError::Full)));
+        assert_eq!(ram.len(), 2);
+
+        // A list that no longer fits B stays as it was
+        let mut lists: Database<
+            SensorId,
+            List<u32, 3>,
+            Postcard,
+            4,
+            8,
+            2,
+            SortedStore<SensorId, 4, 8>,
+        > = Database::with_store(SortedStore::new());
+        lists.push(SensorId(1, 0), 40_000).unwrap();
+        lists.push(SensorId(1, 0), 40_000).unwrap();
+        assert!(lists.push(SensorId(1, 0), 40_000).is_err());
+        assert_eq!(lists.list_len(&SensorId(1, 0)).unwrap(), 2);
+
+        // An image past the end of the flash can't be read
+        let mut db: Database<SensorId, u32, Postcard, 4, 8, 2, SortedStore<SensorId, 4, 8>> =
+            Database::with_store(SortedStore::new());
+        let mut flash = RamFlash::erased();
+        let mut far = Maintenance::new(MaintenancePolicy {
+            flash_offset: 0x1_0000,
+            scrub_every: 1,
+            autosave_every: 0,
+        });
+        assert!(matches!(
+            far.step(&mut db, &mut flash),
+            Err(FlashError::ReadError)
+        ));
+    }
+
+    #[test]
+    fn kv_store_changes_values_in_place() {
+        let mut store: KvStore<u8, u32, 4> = KvStore::new();
+        for k in 1..=4 {
+            store.put(k, k as u32 * 10).unwrap();
+        }
+        for (_, v) in store.iter_mut() {
+            *v += 1;
+        }
+        // Odd keys go, the others are changed on the way
+        store.retain(|k, v| {
+            *v *= 2;
+            k % 2 == 0
+        });
+        assert!(store.iter().eq([(&2, &42), (&4, &82)]));
+    }
+
+    #[test]
+    fn kv_store_retain_frees_slots() {
+        let mut store: KvStore<u8, u32, 2> = KvStore::new();
+        store.put(1, 10).unwrap();
+        store.put(2, 20).unwrap();
+        assert_eq!(store.put(3, 30), Err((3, 30)));
+        store.retain(|_, _| false);
+        assert_eq!(store.len(), 0);
+        assert_eq!(store.put(3, 30), Ok(None));
+        assert_eq!(store.get(&3), Some(&30));
+    }
+
+    // The vectors are rmp-serde 1.3 output (to_vec_named)
+    #[test]
+    fn msgpack_integers_and_floats() {
+        msgpack_vector(0u8, &[0x00]);
+        msgpack_vector(200u8, &[0xcc, 0xc8]);
+        msgpack_vector(-1i8, &[0xff]);
+        msgpack_vector(-200i16, &[0xd1, 0xff, 0x38]);
+        msgpack_vector(70000u32, &[0xce, 0x00, 0x01, 0x11, 0x70]);
+        msgpack_vector(
+            u64::MAX,
+            &[0xcf, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff],
+        );
+        msgpack_vector(i64::MIN, &[0xd3, 0x80, 0, 0, 0, 0, 0, 0, 0]);
+        msgpack_vector(1.5f32, &[0xca, 0x3f, 0xc0, 0x00, 0x00]);
+        msgpack_vector(-2.25f64, &[0xcb, 0xc0, 0x02, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn msgpack_options_tuples_and_sequences() {
+        msgpack_vector(None::<u8>, &[0xc0]);
+        msgpack_vector(Some(5u8), &[0x05]);
+        msgpack_vector((1u8, true), &[0x92, 0x01, 0xc3]);
+        let seq = heapless::Vec::<u16, 4>::from_slice(&[1, 300]).unwrap();
+        msgpack_vector(seq, &[0x92, 0x01, 0xcd, 0x01, 0x2c]);
+    }
+
+    #[test]
+    fn msgpack_structs_and_enums() {
+        msgpack_vector(
+            reading(),
+            &[
+                0x85, 0xa2, b'i', b'd', 0x07, 0xa4, b't', b'e', b'm', b'p', 0xd0, 0xd8, 0xa2, b'o',
+                b'k', 0xc3, 0xa4, b'n', b'a', b'm', b'e', 0xa5, b'p', b'r', b'o', b'b', b'e', 0xa5,
+                b's', b'c', b'a', b'l', b'e', 0xca, 0x3f, 0x00, 0x00, 0x00,
+            ],
+        );
+        msgpack_vector(Mode::Off, &[0xa3, b'O', b'f', b'f']);
+        msgpack_vector(
+            Mode::Level(3),
+            &[0x81, 0xa5, b'L', b'e', b'v', b'e', b'l', 0x03],
+        );
+        msgpack_vector(
+            Mode::Span { lo: -1, hi: 9 },
+            &[
+                0x81, 0xa4, b'S', b'p', b'a', b'n', 0x82, 0xa2, b'l', b'o', 0xff, 0xa2, b'h', b'i',
+                0x09,
+            ],
+        );
+    }
+
+    #[test]
+    fn msgpack_reads_structs_as_arrays() {
+        // rmp-serde's to_vec
+        let bytes = [
+            0x95, 0x07, 0xd0, 0xd8, 0xc3, 0xa5, b'p', b'r', b'o', b'b', b'e', 0xca, 0x3f, 0x00,
+            0x00, 0x00,
+        ];
+        assert!(msgpack::from_slice::<Reading>(&bytes).ok() == Some(reading()));
+    }
+
+    #[test]
+    fn msgpack_rejects_bad_input() {
+        // bin32 and str32 lengths far past the end
+        let bin = [0xc6, 0xff, 0xff, 0xff, 0xff, 0x01];
+        assert_eq!(msgpack::from_slice::<&[u8]>(&bin), Err(MsgPackError::Eof));
+        let str = [0xdb, 0xff, 0xff, 0xff, 0xf0, 0x01];
+        assert_eq!(msgpack::from_slice::<&str>(&str), Err(MsgPackError::Eof));
+        assert_eq!(
+            msgpack::from_slice::<u8>(&[0x01, 0x02]),
+            Err(MsgPackError::TrailingBytes)
+        );
+        let mut small = [0u8; 4];
+        assert_eq!(
+            msgpack::to_slice(&reading(), &mut small),
+            Err(MsgPackError::BufferFull)
+        );
+    }
+
+    // The example of delta.rs
+    #[test]
+    fn delta_vectors() {
+        let mut buf = [0u8; 32];
+        let samples = [2150i16, 2151, 2151, 2149];
+        let bytes = [0xcc, 0x21, 0x02, 0x00, 0x03];
+        let n = Delta::encode(&mut buf, &samples).unwrap();
+        assert_eq!(&buf[..n], &bytes);
+        assert_eq!(<Delta as Codec<[i16; 4]>>::decode(&bytes), Ok(samples));
+
+        let vec = heapless::Vec::<i16, 8>::from_slice(&samples).unwrap();
+        let n = Delta::encode(&mut buf, &vec).unwrap();
+        assert_eq!(&buf[..n], &[0x04, 0xcc, 0x21, 0x02, 0x00, 0x03]);
+        assert!(<Delta as Codec<heapless::Vec<i16, 8>>>::decode(&buf[..n]).ok() == Some(vec));
+
+        // A jump across the whole u64 range wraps to a difference of -1
+        let n = Delta::encode(&mut buf, &[0u64, u64::MAX]).unwrap();
+        assert_eq!(&buf[..n], &[0x00, 0x01]);
+        assert_eq!(
+            <Delta as Codec<[u64; 2]>>::decode(&[0x00, 0x01]),
+            Ok([0, u64::MAX])
+        );
+    }
+
+    #[test]
+    fn delta_rejects_bad_input() {
+        assert_eq!(
+            <Delta as Codec<[i16; 2]>>::decode(&[0xcc]),
+            Err(DeltaError::Truncated)
+        );
+        assert_eq!(
+            <Delta as Codec<[i16; 1]>>::decode(&[0x02, 0x02]),
+            Err(DeltaError::TrailingBytes)
+        );
+        // 200 doesn't fit an i8
+        assert_eq!(
+            <Delta as Codec<[i8; 1]>>::decode(&[0x90, 0x03]),
+            Err(DeltaError::OutOfRange)
+        );
+        assert!(matches!(
+            <Delta as Codec<heapless::Vec<i16, 2>>>::decode(&[0x03, 0x00, 0x00, 0x00]),
+            Err(DeltaError::TooMany)
+        ));
+    }
+
+    // ChaCha20-Poly1305 output of Python's cryptography package
+    #[test]
+    fn encrypted_vector() {
+        type Sealed = Encrypted<Postcard, TestKey>;
+        let blob = [
+            0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, 0x07, // nonce
+            0x38, 0x6c, // postcard 1000 (e8 07), encrypted
+            0x34, 0xaa, 0xfb, 0xe9, 0x84, 0xe1, 0x3f, 0xc8, 0x9d, 0x10, 0x8f, 0x71, 0x09, 0xd2,
+            0xe5, 0xb8, // tag
+        ];
+        let mut buf = [0u8; 64];
+        let n = Sealed::encode(&mut buf, &1000u32).ok().unwrap();
+        assert_eq!(&buf[..n], &blob);
+        assert!(matches!(Sealed::decode(&blob), Ok(1000u32)));
+
+        let mut tampered = blob;
+        tampered[12] ^= 0x01;
+        assert!(matches!(
+            <Sealed as Codec<u32>>::decode(&tampered),
+            Err(EncryptedError::Crypto(CryptoError::AuthenticationFailed))
+        ));
+        assert!(matches!(
+            <Sealed as Codec<u32>>::decode(&blob[..20]),
+            Err(EncryptedError::Crypto(CryptoError::AuthenticationFailed))
+        ));
+    }
+
+    // CRC-16/CCITT-FALSE check values
+    #[test]
+    fn crc16_vectors() {
+        assert_eq!(CRC16.checksum(b"123456789"), 0x29B1);
+        assert_eq!(CRC16.checksum(b""), 0xFFFF);
+        assert_eq!(CRC16.checksum(&[0x00]), 0xE1F0);
+    }
+
+    #[test]
+    fn versioned_upgrades_old_blobs() {
+        let mut buf = [0u8; 16];
+        let config = Config {
+            interval_s: 60,
+            retries: 5,
+        };
+        let n = Versioned::<Postcard>::encode(&mut buf, &config)
+            .ok()
+            .unwrap();
+        assert_eq!(&buf[..n], &[0x02, 0x3c, 0x05]);
+        assert!(Versioned::<Postcard>::decode(&buf[..n]).ok() == Some(config));
+
+        // A ConfigV1 blob goes through Config::upgrade
+        let old = <Versioned<Postcard> as Codec<Config>>::decode(&[0x01, 0x3c]).ok();
+        assert!(
+            old == Some(Config {
+                interval_s: 60,
+                retries: 3
+            })
+        );
+        let n = Versioned::<Postcard>::encode(&mut buf, &ConfigV1 { interval_s: 60 })
+            .ok()
+            .unwrap();
+        assert_eq!(&buf[..n], &[0x01, 0x3c]);
+
+        assert!(matches!(
+            <Versioned<Postcard> as Codec<Config>>::decode(&[0x03, 0x3c]),
+            Err(VersionedError::TooNew(3))
+        ));
+        assert!(matches!(
+            <Versioned<Postcard> as Codec<Config>>::decode(&[]),
+            Err(VersionedError::Empty)
+        ));
+        assert!(matches!(
+            <Versioned<Postcard> as Codec<Config>>::decode(&[0x00, 0x3c]),
+            Err(VersionedError::Upgrade(0))
+        ));
+    }
+
+    // The example of tlv.rs
+    #[test]
+    fn tlv_struct_vector() {
+        let climate = Climate {
+            temp_c10: 215,
+            rh_pct: 41,
+            label: Some(heapless::String::try_from("hall").unwrap()),
+        };
+        let bytes = [
+            0x01, 0x02, 0xd7, 0x00, 0x02, 0x01, 0x29, 0x03, 0x04, b'h', b'a', b'l', b'l',
+        ];
+        let mut buf = [0u8; 32];
+        let n = tlv::to_slice(&climate, &mut buf).unwrap();
+        assert_eq!(&buf[..n], &bytes);
+        assert!(tlv::from_slice::<Climate>(&bytes).ok() == Some(climate.clone()));
+
+        // None leaves the record out
+        let unlabeled = Climate {
+            label: None,
+            ..climate
+        };
+        let n = tlv::to_slice(&unlabeled, &mut buf).unwrap();
+        assert_eq!(&buf[..n], &bytes[..7]);
+        assert!(tlv::from_slice::<Climate>(&bytes[..7]).ok() == Some(unlabeled));
+    }
+
+    #[test]
+    fn tlv_enums_and_sequences() {
+        let mut buf = [0u8; 16];
+        let n = tlv::to_slice(&Mode::Off, &mut buf).unwrap();
+        assert_eq!(&buf[..n], &[0x01, 0x00]);
+        let n = tlv::to_slice(&Mode::Level(3), &mut buf).unwrap();
+        assert_eq!(&buf[..n], &[0x02, 0x01, 0x03]);
+        let span = [0x03, 0x06, 0x01, 0x01, 0xff, 0x02, 0x01, 0x09];
+        let n = tlv::to_slice(&Mode::Span { lo: -1, hi: 9 }, &mut buf).unwrap();
+        assert_eq!(&buf[..n], &span);
+        assert!(tlv::from_slice::<Mode>(&span).ok() == Some(Mode::Span { lo: -1, hi: 9 }));
+
+        let seq = heapless::Vec::<u16, 4>::from_slice(&[1, 0x0203]).unwrap();
+        let bytes = [0x01, 0x02, 0x01, 0x00, 0x02, 0x02, 0x03, 0x02];
+        let n = tlv::to_slice(&seq, &mut buf).unwrap();
+        assert_eq!(&buf[..n], &bytes);
+        assert!(tlv::from_slice::<heapless::Vec<u16, 4>>(&bytes).ok() == Some(seq));
+    }
+
+    #[test]
+    fn tlv_reads_what_c_may_write() {
+        // temp_c10 in one byte, rh_pct in two and a record the struct
+        // doesn't have
+        let bytes = [0x01, 0x01, 0x05, 0x02, 0x02, 0x29, 0x00, 0x09, 0x01, 0x00];
+        let climate = tlv::from_slice::<Climate>(&bytes).unwrap();
+        assert_eq!((climate.temp_c10, climate.rh_pct), (5, 41));
+        assert!(climate.label.is_none());
+
+        // An empty temp_c10, and an rh_pct of 297
+        assert_eq!(
+            tlv::from_slice::<Climate>(&[0x01, 0x00]).err(),
+            Some(TlvError::BadLength)
+        );
+        assert_eq!(
+            tlv::from_slice::<Climate>(&[0x02, 0x02, 0x29, 0x01]).err(),
+            Some(TlvError::Custom)
+        );
+        assert_eq!(
+            tlv::from_slice::<Climate>(&[0x01, 0x02, 0xd7]).err(),
+            Some(TlvError::Eof)
+        );
+    }
+
+    // [tag: u16][len: u16][value]... then the CRC32 record
+    #[test]
+    fn save_tlv_region_vector() {
+        let mut flash = RamFlash {
+            bytes: [0; 4 * 4096],
+        };
+        let mut db: Database<u16, u32, Tlv, 4, 8, 2> = Database::new();
+        db.put(1, 0x1122_3344).unwrap();
+        db.put(0x0203, 7).unwrap();
+        assert_eq!(db.save_tlv(&mut flash, 0).unwrap(), 24);
+        let region = [
+            0x01, 0x00, 0x04, 0x00, 0x44, 0x33, 0x22, 0x11, // key 1
+            0x03, 0x02, 0x04, 0x00, 0x07, 0x00, 0x00, 0x00, // key 0x0203
+            0xfe, 0xff, 0x04, 0x00, 0xf7, 0x1d, 0xd7, 0x36, // CRC32 of the above
+        ];
+        assert_eq!(&flash.bytes[..24], &region);
+        // The rest of the erased page reads as 0xFFFF tags
+        assert!(flash.bytes[24..256].iter().all(|b| *b == 0xFF));
+
+        let mut copy: Database<u16, u32, Tlv, 4, 8, 2> = Database::new();
+        assert_eq!(copy.load_tlv(&mut flash, 0).unwrap(), 2);
+        assert_eq!(copy.get(&1).unwrap(), Some(0x1122_3344));
+        assert_eq!(copy.get(&0x0203).unwrap(), Some(7));
+    }
+
+    #[test]
+    fn load_tlv_rejects_torn_regions() {
+        let mut flash = RamFlash::erased();
+        let mut db: Database<u16, u32, Tlv, 4, 8, 2> = Database::new();
+        // Erased, no end record
+        assert!(matches!(
+            db.load_tlv(&mut flash, 0),
+            Err(FlashError::CrcMismatch)
+        ));
+
+        db.put(1, 0x1122_3344).unwrap();
+        db.save_tlv(&mut flash, 0).unwrap();
+        flash.bytes[4] ^= 0x01;
+        assert!(matches!(
+            db.load_tlv(&mut flash, 0),
+            Err(FlashError::CrcMismatch)
+        ));
+        assert_eq!(db.len(), 0);
+    }
 }